@@ -12,13 +12,13 @@ use stdlib::shell::shell;
 extern "C" fn _start() {
     stdlib::init_heap();
     main();
-    loop {};
+    stdlib::syscall::exit(0);
 }
 
 extern fn main() {
     print_str("In userspace\n");
     let s = "In userspace with heap\n".to_string();
-    stdlib::syscall::print(s.as_ptr(), s.len());
+    let _ = stdlib::syscall::print(s.as_ptr(), s.len());
     // let mut keys: Vec<String> = Vec::with_capacity(2);
     // keys.insert(0, "test".to_string());
     // keys.insert(1, "kvos".to_string());
@@ -33,6 +33,5 @@ extern fn main() {
     // println!("{}: {}", keys.get(1).unwrap(), res.get(1).unwrap());
     // run_bench();
     shell();
-    loop{}
 }
 
@@ -225,6 +225,58 @@ impl MADT {
     }
 }
 
+/// ECAM region of a pci segment, from the MCFG table
+#[derive(Copy, Clone, Debug)]
+pub struct MCFGInfo {
+    pub base : u64,
+    pub segment : u16,
+    pub start_bus : u8,
+    pub end_bus : u8,
+}
+
+#[derive(Debug)]
+pub struct MCFG {
+    ptr : *const u8,
+    mcfg_header : ACPISDTHeader,
+}
+
+impl MCFG {
+    pub fn new(ptr : *const u8) -> MCFG {
+
+        let mcfg_header = ACPISDTHeader::load(ptr);
+
+        MCFG { ptr, mcfg_header }
+    }
+
+    pub fn header(&self) -> ACPISDTHeader {
+        self.mcfg_header
+    }
+
+    pub fn num_entries(&self) -> usize {
+        // 8 reserved bytes after the header, then 16 bytes for each entry
+        (self.header().length as usize).saturating_sub(core::mem::size_of::<ACPISDTHeader>() + 8) / 16
+    }
+
+    pub fn entry(&self, i : usize) -> MCFGInfo {
+        let ptr = self.ptr.wrapping_add(core::mem::size_of::<ACPISDTHeader>() + 8 + 16 * i);
+        let mut base : u64 = 0;
+        let mut segment : u16 = 0;
+        unsafe {
+            core::ptr::copy_nonoverlapping(ptr, &mut base as *mut u64 as *mut u8, 8);
+            core::ptr::copy_nonoverlapping(ptr.wrapping_add(8), &mut segment as *mut u16 as *mut u8, 2);
+            MCFGInfo { base, segment, start_bus : *ptr.wrapping_add(10), end_bus : *ptr.wrapping_add(11) }
+        }
+    }
+
+    pub fn checksum_valid(&self) -> bool {
+        let mut checksum : u8 = 0;
+        for i in 0..self.header().length as usize {
+            checksum = checksum.wrapping_add(unsafe { *self.ptr.wrapping_add(i) });
+        }
+        checksum == 0
+    }
+}
+
 pub struct CPUData {
     cpus : [Option<LAPICInfo>; 64], // support up to 64 cores
     size_ : usize,
@@ -301,3 +353,29 @@ pub fn init(rsdt_addr : *const u8) -> Result<(IOAPICInfo, CPUData), ()> {
     Err(())
 }
 
+/// The ECAM region of pci segment 0 if the firmware has an MCFG table, the tables are only reachable
+/// before the kernel is remapped
+pub fn mcfg(rsdt_addr : *const u8) -> Option<MCFGInfo> {
+    let rsdt = RSDT::new(rsdt_addr);
+
+    for i in 0..rsdt.num_tables() {
+        let ptr = rsdt.table(i);
+        let header = ACPISDTHeader::load(ptr);
+
+        if header.signature_str() == "MCFG" {
+            let mcfg = MCFG::new(ptr);
+            if !mcfg.checksum_valid() {
+                serial_infoln!("MCFG checksum is invalid");
+                return None;
+            }
+            for entry in 0..mcfg.num_entries() {
+                let info = mcfg.entry(entry);
+                serial_infoln!("{:?}", info);
+                if info.segment == 0 {
+                    return Some(info);
+                }
+            }
+        }
+    }
+    None
+}
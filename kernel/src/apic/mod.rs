@@ -67,6 +67,16 @@ impl IOAPIC {
         serial_debugln!("R13 is {:x}", self.read_reg(0x13));
         serial_println!("Setting reg 13 to {:x} where local apic id is {:x}", lapic_id << 24, lapic_id);
         serial_debugln!("R12 is {:x} and R13 is {:x}", self.read_reg(0x12), self.read_reg(0x13));
+
+        // ATA primary and secondary bus, edge triggered and active high like every ISA irq
+        self.route(14, crate::interrupts::ATA_PRIMARY_VECTOR, lapic_id);
+        self.route(15, crate::interrupts::ATA_SECONDARY_VECTOR, lapic_id);
+    }
+
+    /// Send an irq to vector on the core with lapic_id
+    pub fn route(&self, irq : u8, vector : u8, lapic_id : u32) {
+        self.write_reg(0x10 + 2 * irq, vector as u32); // vector
+        self.write_reg(0x11 + 2 * irq, lapic_id << 24); // local apic id
     }
 }
 
@@ -232,6 +242,22 @@ impl LAPIC {
         }
     }
 
+    /// Send a fixed interrupt with the given vector to every other core
+    pub fn send_ipi_all_but_self(&self, vector : u8) {
+        let ptr = self.ptr.load(Ordering::Relaxed) as *mut u8;
+        if ptr.is_null() {
+            return;
+        }
+        unsafe {
+            // wait for the previous ipi to be delivered
+            while read_volatile(ptr.add(0x300) as *const u32) & 0x00001000 != 0 {
+                core::hint::spin_loop();
+            }
+            write_volatile(ptr.add(0x310) as *mut u32, 0);
+            write_volatile(ptr.add(0x300) as *mut u32, 0x000C0000 | 0x00004000 | vector as u32); // all excluding self, assert
+        }
+    }
+
     pub fn eoi(&self) {
         unsafe {
             let ptr = self.ptr.load(Ordering::Relaxed) as *mut u8;
@@ -1,4 +1,4 @@
-use alloc::string::{String, ToString};
+use alloc::string::ToString;
 
 use crate::kvstore::KVStore;
 use crate::cc::Transaction;
@@ -51,9 +51,9 @@ impl Random {
 
 pub fn populate(max_key: i32, pop:i32, rng: &mut Random) {
     for _ in 0..pop {
-        let key = rng.get_random(0, max_key as u64).to_string();
-        KVSTORE.transact(|tx| {
-            tx.write(&key, &String::from("value"));
+        let key = rng.get_random(0, max_key as u64).to_string().into_bytes();
+        let _ = KVSTORE.transact(|tx| {
+            tx.write(&key, &b"value".to_vec())
         }, false);
     }
 }
@@ -67,23 +67,23 @@ pub fn benchmark(max_key: i32,
     let start = asm::rdtsc();
     
     for _ in 1..iters {
-        let key = rng.get_random(0, max_key as u64).to_string();
+        let key = rng.get_random(0, max_key as u64).to_string().into_bytes();
         let op  = rng.get_random(0, 100 as u64);
 
         if op <= ratio {
             //read
-            KVSTORE.transact(|tx| {
-                tx.read(&key);
+            let _ = KVSTORE.transact(|tx| {
+                tx.read(&key)
             }, false);
         } else if op <= ratio + (100-ratio) / 2 {
             //remove
-            KVSTORE.transact(|tx| {
-                tx.delete(&key);
+            let _ = KVSTORE.transact(|tx| {
+                tx.delete(&key)
             }, false);
         } else {
             //insert
-            KVSTORE.transact(|tx| {
-                tx.write(&key, &String::from("value"));
+            let _ = KVSTORE.transact(|tx| {
+                tx.write(&key, &b"value".to_vec())
             }, false);
         }
     }
@@ -92,10 +92,11 @@ pub fn benchmark(max_key: i32,
 }
 
 pub fn clear_kvstore(max_key: i32) {
-    KVSTORE.transact(|tx| {
+    let _ = KVSTORE.transact(|tx| {
         for key in 0..max_key {
-            tx.delete(&key.to_string());
+            tx.delete(&key.to_string().into_bytes())?;
         }
+        Ok(())
     }, false);
 }
 
@@ -1,9 +1,11 @@
 pub mod redo;
+pub mod mvcc;
 extern crate alloc;
 use alloc::sync::Arc;
+use alloc::vec::Vec;
 use core::sync::atomic::{AtomicU64, Ordering};
 use redo::RedoLog;
-use crate::common::locktable::{LockTable, TryLockResult};
+use crate::common::locktable::{LockMode, LockResult, LockTable};
 use crate::common::set::SimpleSet;
 use crate::common::hash::Mix13Hash;
 use crate::common::map::Map;
@@ -16,23 +18,72 @@ use core::cell::RefCell;
 pub fn getpid()->i32 {
     0
 }
+//why a transaction gave up its locks
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum AbortReason {
+    //a younger transaction asked for a lock held by an older one
+    WaitDie,
+    //wound-wait: an older transaction wanted a lock we held
+    Wounded,
+    //waited too long for a lock, most likely a deadlock
+    LockTimeout,
+    //abort was called
+    User,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum TxError {
+    Aborted(AbortReason),
+    //another transaction committed a write to the same key first
+    Conflict,
+    //the persistent map failed to write its log
+    IoError,
+    //try_commit_persist on a store that is not backed by a disk
+    NotPersistent,
+    //the transaction already committed
+    Finished,
+}
+
+impl TxError {
+    //errors a new attempt of the same transaction can get past
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            TxError::Aborted(AbortReason::WaitDie)
+            | TxError::Aborted(AbortReason::Wounded)
+            | TxError::Aborted(AbortReason::LockTimeout)
+            | TxError::Conflict => true,
+            _ => false,
+        }
+    }
+}
+
 pub trait Transaction {
     type Key;
 
     type Value;
 
     // type Map: Map<Key = Self::Key, Value = Self::Value>;
-    fn read(&mut self, key: &Self::Key) -> Option<Self::Value>;
+    //Ok(None) means the key does not exist
+    fn read(&mut self, key: &Self::Key) -> Result<Option<Self::Value>, TxError>;
+
+    fn write(&mut self, key: &Self::Key, value: &Self::Value) -> Result<(), TxError>;
 
-    fn write(&mut self, key: &Self::Key, value: &Self::Value);
+    //Ok(false) means there was nothing to delete
+    fn delete(&mut self, key: &Self::Key) -> Result<bool, TxError>;
 
-    fn delete(&mut self, key: &Self::Key) -> Option<bool>;
+    //returns up to limit entries with start <= key < end in ascending key order
+    //no end scans to the last key in the store
+    fn scan(&mut self, start: &Self::Key, end: Option<&Self::Key>, limit: usize) -> Result<Vec<(Self::Key, Self::Value)>, TxError>;
 
-    fn abort(&self);
+    //returns up to limit entries whose key starts with prefix in ascending key order
+    fn scan_prefix(&mut self, prefix: &Self::Key, limit: usize) -> Result<Vec<(Self::Key, Self::Value)>, TxError>;
 
-    fn try_commit(&self) -> bool;
+    //releases everything the transaction holds, calling it again does nothing
+    fn abort(&mut self);
 
-    fn try_commit_persist(&self) -> bool;
+    fn try_commit(&mut self) -> Result<(), TxError>;
+
+    fn try_commit_persist(&mut self) -> Result<(), TxError>;
 }
 
 struct Counter {
@@ -55,346 +106,417 @@ lazy_static! {
     static ref GLOBAL_CLOCK: Counter= Counter::new();
 }
 
-pub struct WDTX<K:Eq + core::hash::Hash + Clone, V: Clone, M: Map>
-{
+//concurrency control used by the transactions of a store
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CCMode {
+    //two phase locking through the lock table with wait-die
+    WaitDie,
+    //two phase locking through the lock table with wound-wait
+    WoundWait,
+    //multiversion snapshot isolation, see mvcc.rs
+    Snapshot,
+}
+
+//stripe locked in place of the next key when a scan or an insert runs past the last key
+fn end_of_index(lock_table: &LockTable) -> u64 {
+    lock_table.size() - 1
+}
+
+//the state WDTX and WDTXPersist share: the stripes they hold and their uncommitted writes
+struct TxCore<K: Eq + Clone, V: Clone> {
     tid: u64,
+    //every stripe we hold, the ones held exclusively are also in write_locked
     locked_index: SimpleSet<u64>,
+    write_locked: SimpleSet<u64>,
     redo_log: RedoLog<K, V>,
     remove_log: SimpleSet<K>,
     lock_table: Arc<LockTable>,
-    map: Arc<M>,
-    is_aborted: bool,
+    //set once the transaction aborted or committed, later calls return this error
+    finished: Option<TxError>,
     hash: RefCell<Mix13Hash>,
 }
 
+impl<K: Eq + Clone, V: Clone> TxCore<K, V> {
+    fn new(lock_table: Arc<LockTable>) -> Self {
+        let _pid = getpid();
+        TxCore {
+            tid: GLOBAL_CLOCK.increment(),
+            locked_index: SimpleSet::new(),
+            write_locked: SimpleSet::new(),
+            redo_log: RedoLog::new(),
+            remove_log: SimpleSet::new(),
+            lock_table,
+            finished: None,
+            hash: RefCell::new(Mix13Hash::new()),
+        }
+    }
 
+    fn check_active(&mut self) -> Result<(), TxError> {
+        //an older transaction wounded us while we were not waiting on a lock
+        if self.finished.is_none() && self.lock_table.is_wounded(self.tid) {
+            self.finish(TxError::Aborted(AbortReason::Wounded));
+        }
+        match self.finished {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
 
-impl<K:Eq + core::hash::Hash + Clone + AsRef<[u8]>, V: Clone, M: Map> WDTX<K, V, M> {
-    pub fn new(_lock_table: Arc<LockTable>, _map: Arc<M>) -> Self {
-        let _pid = getpid();
-        let _tid= GLOBAL_CLOCK.increment();
-        let mut _hash = Mix13Hash::new();
-        let mut _locked_index = SimpleSet::new();
-        let mut _redo_log = RedoLog::new();
-        let mut _remove_log = SimpleSet::new();
+    //unlocks every stripe exactly once and ends the transaction with state
+    fn finish(&mut self, state: TxError) {
+        for key in self.locked_index.data.drain(..) {
+            self.lock_table.release(key, self.tid);
+        }
+        self.write_locked.data.clear();
+        self.lock_table.forget(self.tid);
+        self.finished = Some(state);
+    }
 
-        WDTX {
-            tid: _tid,
-            locked_index : _locked_index,
-            redo_log : _redo_log,
-            remove_log : _remove_log,
-            lock_table : _lock_table,
-            map : _map,
-            is_aborted : false,
-            hash: RefCell::new(Mix13Hash::new()),
+    fn abort(&mut self) {
+        if self.finished.is_none() {
+            self.finish(TxError::Aborted(AbortReason::User));
         }
     }
-    fn hash(&self, key:&K, size:u64) -> u64{
+
+    //locks a stripe until commit or abort, aborts the transaction if the lock table gives up on it
+    fn lock_stripe(&mut self, stripe: u64, mode: LockMode) -> Result<(), TxError> {
+        let held = match mode {
+            LockMode::Shared => self.locked_index.contains(&stripe),
+            LockMode::Exclusive => self.write_locked.contains(&stripe),
+        };
+        if held {
+            return Ok(());
+        }
+        let reason = match self.lock_table.lock_mode(stripe, self.tid, mode) {
+            LockResult::Acquired => {
+                self.locked_index.insert(&stripe);
+                if mode == LockMode::Exclusive {
+                    self.write_locked.insert(&stripe);
+                }
+                return Ok(());
+            },
+            LockResult::Die => AbortReason::WaitDie,
+            LockResult::Wounded => AbortReason::Wounded,
+            LockResult::TimedOut => AbortReason::LockTimeout,
+        };
+        let e = TxError::Aborted(reason);
+        self.finish(e);
+        Err(e)
+    }
+}
+
+impl<K: Eq + Clone + AsRef<[u8]>, V: Clone> TxCore<K, V> {
+    fn hash(&self, key: &K) -> u64 {
         let hasher = self.hash.borrow_mut();
-        let val = hasher.compute_hash(&key.as_ref().to_vec());
-        val % size
+        hasher.compute_hash(&key.as_ref().to_vec()) % self.lock_table.size()
+    }
+
+    fn lock_key(&mut self, key: &K, mode: LockMode) -> Result<(), TxError> {
+        self.lock_stripe(self.hash(key), mode)
     }
 }
 
+//a transaction dropped without commit or abort must not keep its stripes locked
+impl<K: Eq + Clone, V: Clone> Drop for TxCore<K, V> {
+    fn drop(&mut self) {
+        self.abort();
+    }
+}
 
+//range scans use next-key locking: every key returned is locked shared along with the first key
+//after the range, and inserting a new key locks its successor exclusively, so a concurrent insert
+//into a scanned range always conflicts with the scan and no phantoms can appear
+//reads take shared locks and writes exclusive ones, a read lock is upgraded when the key is written
+//WDTX and WDTXPersist only differ in the map they read committed data from and in how they commit
+trait LockingTx<K: Eq + Ord + Clone + AsRef<[u8]>, V: Clone> {
+    fn core(&mut self) -> &mut TxCore<K, V>;
 
-impl<K:Eq + core::hash::Hash + Clone + AsRef<[u8]>, V: Clone, M:Map<Key = K, Value = V>> Transaction for WDTX<K, V, M> {
-    type Key = K;
-    type Value = V;
-    fn read(&mut self,key: &Self::Key) -> Option<Self::Value> {
-        if self.is_aborted {
-            return None;
-        }
-        if let Some(v) = self.redo_log.get(key) {
-            return Some(v);
+    fn committed_get(&self, key: &K) -> Result<Option<V>, TxError>;
+
+    //returns up to limit committed entries with key >= start in ascending key order
+    fn committed_scan(&self, start: &K, limit: usize) -> Vec<(K, V)>;
+
+    //first committed key after from, or at from when inclusive
+    fn first_key_from(&self, from: &K, inclusive: bool) -> Option<K> {
+        self.committed_scan(from, 2).into_iter().map(|(k, _)| k).find(|k| inclusive || k != from)
+    }
+
+    //locks the first key after from (the end of index when there is none)
+    fn lock_next_key(&mut self, from: &K, inclusive: bool, mode: LockMode) -> Result<Option<K>, TxError> {
+        loop {
+            let next = self.first_key_from(from, inclusive);
+            let core = self.core();
+            let stripe = match &next {
+                Some(k) => core.hash(k),
+                None => end_of_index(&core.lock_table),
+            };
+            core.lock_stripe(stripe, mode)?;
+            //a key may have been committed in between before we got the lock
+            if self.first_key_from(from, inclusive) == next {
+                return Ok(next);
+            }
         }
-    
-        if self.locked_index.contains(&self.hash(key, self.lock_table.size())) {
-            if self.remove_log.contains(key) {
-                return None;
+    }
+
+    fn scan_while<F: Fn(&K) -> bool>(&mut self, start: &K, in_range: F, limit: usize) -> Result<Vec<(K, V)>, TxError> {
+        self.core().check_active()?;
+        let mut result = Vec::new();
+        let mut cursor = start.clone();
+        let mut inclusive = true;
+        let mut exhausted = false;
+        while result.len() < limit {
+            match self.lock_next_key(&cursor, inclusive, LockMode::Shared)? {
+                Some(key) if in_range(&key) => {
+                    let core = self.core();
+                    if !core.redo_log.contains(&key) && !core.remove_log.contains(&key) {
+                        if let Some(value) = self.committed_get(&key)? {
+                            result.push((key.clone(), value));
+                        }
+                    }
+                    cursor = key;
+                    inclusive = false;
+                },
+                //the key past the range stays locked so nothing can be inserted at the end of it
+                _ => {
+                    exhausted = true;
+                    break;
+                },
             }
-            return self.map.get(key);
         }
-        
-        let hash_value = self.hash(key, self.lock_table.size());
-        let ret = self.lock_table.try_lock(hash_value, self.tid);
-        loop {
-            if ret == TryLockResult::Success {
-                self.locked_index.insert(&self.hash(key, self.lock_table.size()));
-                return self.map.get(key);
-            } else if ret == TryLockResult::Wait {
-            } else {
-                self.is_aborted = true;
-                self.abort();
-                return None;
+        //merge in our own uncommitted writes that fall inside the part of the range we locked
+        for entry in &self.core().redo_log.entries {
+            if entry.key >= *start && in_range(&entry.key) && (exhausted || entry.key <= cursor) {
+                result.push((entry.key.clone(), entry.val.clone()));
             }
         }
+        result.sort_by(|a, b| a.0.cmp(&b.0));
+        result.truncate(limit);
+        Ok(result)
     }
 
-    fn write(&mut self, key: &Self::Key, value: &Self::Value) {
-        if self.is_aborted {
-            return;
+    fn locked_read(&mut self, key: &K) -> Result<Option<V>, TxError> {
+        let core = self.core();
+        core.check_active()?;
+        if let Some(v) = core.redo_log.get(key) {
+            return Ok(Some(v));
         }
-        if let Some(_) = self.redo_log.get(key) {
-            self.redo_log.update(key, value);
-            return;
+        //only keys we hold the lock for can be in the remove log
+        if core.remove_log.contains(key) {
+            return Ok(None);
         }
+        core.lock_key(key, LockMode::Shared)?;
+        self.committed_get(key)
+    }
 
-        if !self.locked_index.contains(&self.hash(key, self.lock_table.size())) {
-            let hash_value = self.hash(key, self.lock_table.size());
-            let ret = self.lock_table.try_lock(hash_value, self.tid);
-            // let ret = self.lock_table.borrow_mut().try_lock(self.hash(key, self.lock_table.borrow().size()), self.tid);
-            loop {
-                if ret == TryLockResult::Success {
-                    self.locked_index.insert(&self.hash(key, self.lock_table.size()));
-                    break;
-                } else if ret == TryLockResult::Wait {
-                } else {
-                    self.is_aborted = true;
-                    self.abort();
-                    return;
-                }
-            }
-        }
-        self.redo_log.insert(key, value);
+    fn locked_write(&mut self, key: &K, value: &V) -> Result<(), TxError> {
+        let core = self.core();
+        core.check_active()?;
+        if core.redo_log.contains(key) {
+            core.redo_log.update(key, value);
+            return Ok(());
+        }
+
+        //upgrades the shared lock if we read the key before
+        core.lock_key(key, LockMode::Exclusive)?;
+        //inserting a new key also locks its successor so it cannot appear inside a range
+        //another transaction has scanned
+        if self.committed_get(key)?.is_none() {
+            self.lock_next_key(key, false, LockMode::Exclusive)?;
+        }
+        let core = self.core();
+        core.remove_log.remove(key);
+        core.redo_log.insert(key, value);
+        Ok(())
     }
 
-    fn delete(&mut self, key: &Self::Key) -> Option<bool>{
-        if self.is_aborted {
-            return None;
+    fn locked_delete(&mut self, key: &K) -> Result<bool, TxError> {
+        let core = self.core();
+        core.check_active()?;
+        if core.remove_log.contains(key) {
+            return Ok(false);
         }
-        if self.remove_log.contains(key) == true {
-            return Some(false);
-        }
-        if let Some(_) = self.redo_log.get(key) {
-            self.redo_log.remove(key);
-            self.remove_log.insert(key);
-            return Some(true);
+        if core.redo_log.contains(key) {
+            core.redo_log.remove(key);
+            core.remove_log.insert(key);
+            return Ok(true);
         }
 
-        if !self.locked_index.contains(&self.hash(key, self.lock_table.size())) {
-            let ret = self.lock_table.try_lock(self.hash(key, self.lock_table.size()), self.tid);
-            loop {
-                if ret == TryLockResult::Success {
-                    self.locked_index.insert(&self.hash(key, self.lock_table.size()));
-                    break;
-                } else if ret == TryLockResult::Wait {
-                } else {
-                    self.is_aborted = true;
-                    return None;
-                }
-                
-            }
+        core.lock_key(key, LockMode::Exclusive)?;
+        if self.committed_get(key)?.is_some() {
+            self.core().remove_log.insert(key);
+            return Ok(true);
         }
-        if let Some(_) = self.map.get(key) {
-            self.remove_log.insert(key);
-            return Some(true);
-        }
-        return Some(false);
-        
+        Ok(false)
     }
 
-    fn abort(&self) {
-        for key in &self.locked_index.data {
-            self.lock_table.unlock(*key);
+    fn locked_scan(&mut self, start: &K, end: Option<&K>, limit: usize) -> Result<Vec<(K, V)>, TxError> {
+        match end {
+            Some(end) => self.scan_while(start, |k| k < end, limit),
+            None => self.scan_while(start, |_| true, limit),
         }
     }
 
-    fn try_commit(&self) -> bool{
-        if self.is_aborted {
-            return false;
+    fn locked_scan_prefix(&mut self, prefix: &K, limit: usize) -> Result<Vec<(K, V)>, TxError> {
+        self.scan_while(prefix, |k| k.as_ref().starts_with(prefix.as_ref()), limit)
+    }
+}
+
+pub struct WDTX<K:Eq + core::hash::Hash + Clone, V: Clone, M: Map>
+{
+    core: TxCore<K, V>,
+    map: Arc<M>,
+}
+
+impl<K:Eq + core::hash::Hash + Clone + AsRef<[u8]>, V: Clone, M: Map> WDTX<K, V, M> {
+    pub fn new(lock_table: Arc<LockTable>, map: Arc<M>) -> Self {
+        WDTX {
+            core: TxCore::new(lock_table),
+            map,
         }
-        
-        for entry in &self.redo_log.entries {
+    }
+}
+
+impl<K:Eq + Ord + core::hash::Hash + Clone + AsRef<[u8]>, V: Clone, M:Map<Key = K, Value = V>> LockingTx<K, V> for WDTX<K, V, M> {
+    fn core(&mut self) -> &mut TxCore<K, V> {
+        &mut self.core
+    }
+
+    fn committed_get(&self, key: &K) -> Result<Option<V>, TxError> {
+        Ok(self.map.get(key))
+    }
+
+    fn committed_scan(&self, start: &K, limit: usize) -> Vec<(K, V)> {
+        self.map.scan(start, limit)
+    }
+}
+
+impl<K:Eq + Ord + core::hash::Hash + Clone + AsRef<[u8]>, V: Clone, M:Map<Key = K, Value = V>> Transaction for WDTX<K, V, M> {
+    type Key = K;
+    type Value = V;
+    fn read(&mut self,key: &Self::Key) -> Result<Option<Self::Value>, TxError> {
+        self.locked_read(key)
+    }
+
+    fn write(&mut self, key: &Self::Key, value: &Self::Value) -> Result<(), TxError> {
+        self.locked_write(key, value)
+    }
+
+    fn delete(&mut self, key: &Self::Key) -> Result<bool, TxError> {
+        self.locked_delete(key)
+    }
+
+    fn scan(&mut self, start: &Self::Key, end: Option<&Self::Key>, limit: usize) -> Result<Vec<(Self::Key, Self::Value)>, TxError> {
+        self.locked_scan(start, end, limit)
+    }
+
+    fn scan_prefix(&mut self, prefix: &Self::Key, limit: usize) -> Result<Vec<(Self::Key, Self::Value)>, TxError> {
+        self.locked_scan_prefix(prefix, limit)
+    }
+
+    fn abort(&mut self) {
+        self.core.abort();
+    }
+
+    fn try_commit(&mut self) -> Result<(), TxError> {
+        self.core.check_active()?;
+        for entry in &self.core.redo_log.entries {
             self.map.insert(&entry.key, &entry.val.clone());
         }
-        for key in &self.remove_log.data {
+        for key in &self.core.remove_log.data {
             self.map.remove(key);
         }
-        for key in &self.locked_index.data {
-            self.lock_table.unlock(*key);
-        }
-        true
+        self.core.finish(TxError::Finished);
+        Ok(())
     }
 
-    fn try_commit_persist(&self) -> bool {
-        false
+    fn try_commit_persist(&mut self) -> Result<(), TxError> {
+        self.core.check_active()?;
+        //an in memory map has no log to write to
+        self.core.finish(TxError::NotPersistent);
+        Err(TxError::NotPersistent)
     }
 }
 ////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////
 pub struct WDTXPersist<K:Eq + core::hash::Hash + Clone, V: Clone, M: PersistentMap>
 {
-    tid: u64,
-    locked_index: SimpleSet<u64>,
-    redo_log: RedoLog<K, V>,
-    remove_log: SimpleSet<K>,
-    lock_table: Arc<LockTable>,
+    core: TxCore<K, V>,
     map: Arc<M>,
-    is_aborted: bool,
-    hash: RefCell<Mix13Hash>,
 }
 
 impl<K:Eq + core::hash::Hash + Clone + AsRef<[u8]>, V: Clone, M: PersistentMap> WDTXPersist<K, V, M> {
-    pub fn new(_lock_table: Arc<LockTable>, _map: Arc<M>) -> Self {
-        let _pid = getpid();
-        let _tid= GLOBAL_CLOCK.increment();
-        let mut _hash = Mix13Hash::new();
-        let mut _locked_index = SimpleSet::new();
-        let mut _redo_log = RedoLog::new();
-        let mut _remove_log = SimpleSet::new();
-
+    pub fn new(lock_table: Arc<LockTable>, map: Arc<M>) -> Self {
         WDTXPersist {
-            tid: _tid,
-            locked_index : _locked_index,
-            redo_log : _redo_log,
-            remove_log : _remove_log,
-            lock_table : _lock_table,
-            map : _map,
-            is_aborted : false,
-            hash: RefCell::new(Mix13Hash::new()),
+            core: TxCore::new(lock_table),
+            map,
         }
     }
-    fn hash(&self, key:&K, size:u64) -> u64{
-        let hasher = self.hash.borrow_mut();
-        let val = hasher.compute_hash(&key.as_ref().to_vec());
-        val % size
+}
+
+impl<K:Eq + Ord + core::hash::Hash + Clone + AsRef<[u8]>, V: Clone, M:PersistentMap<Key = K, Value = V>> LockingTx<K, V> for WDTXPersist<K, V, M> {
+    fn core(&mut self) -> &mut TxCore<K, V> {
+        &mut self.core
+    }
+
+    fn committed_get(&self, key: &K) -> Result<Option<V>, TxError> {
+        self.map.get(key).map_err(|_| TxError::IoError)
+    }
+
+    fn committed_scan(&self, start: &K, limit: usize) -> Vec<(K, V)> {
+        self.map.scan(start, limit)
     }
 }
 
-impl<K:Eq + core::hash::Hash + Clone + AsRef<[u8]>, V: Clone, M:PersistentMap<Key = K, Value = V>> Transaction for WDTXPersist<K, V, M> {
+impl<K:Eq + Ord + core::hash::Hash + Clone + AsRef<[u8]>, V: Clone, M:PersistentMap<Key = K, Value = V>> Transaction for WDTXPersist<K, V, M> {
     type Key = K;
     type Value = V;
-    fn read(&mut self,key: &Self::Key) -> Option<Self::Value> {
-        if self.is_aborted {
-            return None;
-        }
-
-        if let Some(v) = self.redo_log.get(key) {
-            return Some(v);
-        }
-    
-        if self.locked_index.contains(&self.hash(key, self.lock_table.size())) {
-            if self.remove_log.contains(key) {
-                return None;
-            }
-            return self.map.get(key);
-        }
-        
-        let hash_value = self.hash(key, self.lock_table.size());
-        let ret = self.lock_table.try_lock(hash_value, self.tid);
-        loop {
-            if ret == TryLockResult::Success {
-                self.locked_index.insert(&self.hash(key, self.lock_table.size()));
-                return self.map.get(key);
-            } else if ret == TryLockResult::Wait {
-            } else {
-                self.is_aborted = true;
-                self.abort();
-                return None;
-            }
-        }
+    fn read(&mut self,key: &Self::Key) -> Result<Option<Self::Value>, TxError> {
+        self.locked_read(key)
     }
 
-    fn write(&mut self, key: &Self::Key, value: &Self::Value) {
-        if self.is_aborted {
-            return;
-        }
-        if let Some(_) = self.redo_log.get(key) {
-            self.redo_log.update(key, value);
-            return;
-        }
+    fn write(&mut self, key: &Self::Key, value: &Self::Value) -> Result<(), TxError> {
+        self.locked_write(key, value)
+    }
 
-        if !self.locked_index.contains(&self.hash(key, self.lock_table.size())) {
-            let hash_value = self.hash(key, self.lock_table.size());
-            let ret = self.lock_table.try_lock(hash_value, self.tid);
-            // let ret = self.lock_table.borrow_mut().try_lock(self.hash(key, self.lock_table.borrow().size()), self.tid);
-            loop {
-                if ret == TryLockResult::Success {
-                    self.locked_index.insert(&self.hash(key, self.lock_table.size()));
-                    break;
-                } else if ret == TryLockResult::Wait {
-                } else {
-                    self.is_aborted = true;
-                    self.abort();
-                    return;
-                }
-            }
-        }
-        self.redo_log.insert(key, value);
+    fn delete(&mut self, key: &Self::Key) -> Result<bool, TxError> {
+        self.locked_delete(key)
     }
 
-    fn delete(&mut self, key: &Self::Key) -> Option<bool>{
-        if self.is_aborted {
-            return None;
-        }
-        if self.remove_log.contains(key) == true {
-            return Some(false);
-        }
-        if let Some(_) = self.redo_log.get(key) {
-            self.redo_log.remove(key);
-            self.remove_log.insert(key);
-            return Some(true);
-        }
+    fn scan(&mut self, start: &Self::Key, end: Option<&Self::Key>, limit: usize) -> Result<Vec<(Self::Key, Self::Value)>, TxError> {
+        self.locked_scan(start, end, limit)
+    }
 
-        if !self.locked_index.contains(&self.hash(key, self.lock_table.size())) {
-            let ret = self.lock_table.try_lock(self.hash(key, self.lock_table.size()), self.tid);
-            loop {
-                if ret == TryLockResult::Success {
-                    self.locked_index.insert(&self.hash(key, self.lock_table.size()));
-                    break;
-                } else if ret == TryLockResult::Wait {
-                } else {
-                    self.is_aborted = true;
-                    return None;
-                }
-                
-            }
-        }
-        if let Some(_) = self.map.get(key) {
-            self.remove_log.insert(key);
-            return Some(true);
-        }
-        return Some(false);
-        
+    fn scan_prefix(&mut self, prefix: &Self::Key, limit: usize) -> Result<Vec<(Self::Key, Self::Value)>, TxError> {
+        self.locked_scan_prefix(prefix, limit)
     }
 
-    fn abort(&self) {
-        for key in &self.locked_index.data {
-            self.lock_table.unlock(*key);
-        }
+    fn abort(&mut self) {
+        self.core.abort();
     }
 
-    fn try_commit(&self) -> bool{
-        if self.is_aborted {
-            return false;
-        }
-        
-        for entry in &self.redo_log.entries {
+    fn try_commit(&mut self) -> Result<(), TxError> {
+        self.core.check_active()?;
+        for entry in &self.core.redo_log.entries {
             let _ = self.map.insert_no_log(&entry.key, &entry.val.clone());
         }
-        for key in &self.remove_log.data {
+        for key in &self.core.remove_log.data {
             let _ = self.map.remove_no_log(key);
         }
-        for key in &self.locked_index.data {
-            self.lock_table.unlock(*key);
-        }
-        true
+        self.core.finish(TxError::Finished);
+        Ok(())
     }
 
-    fn try_commit_persist(&self) -> bool{
-        if self.is_aborted {
-            return false;
-        }
-        
-        for entry in &self.redo_log.entries {
-            let _ = self.map.insert(&entry.key, &entry.val.clone());
-        }
-        for key in &self.remove_log.data {
-            let _ = self.map.remove(key);
-        }
-        for key in &self.locked_index.data {
-            self.lock_table.unlock(*key);
-        }
-        true
+    fn try_commit_persist(&mut self) -> Result<(), TxError> {
+        self.core.check_active()?;
+        //a read only transaction has nothing to log
+        if self.core.redo_log.size() == 0 && self.core.remove_log.data.is_empty() {
+            self.core.finish(TxError::Finished);
+            return Ok(());
+        }
+        //the whole transaction is one log record, a failed write leaves the map untouched
+        let writes: Vec<(&K, &V)> = self.core.redo_log.entries.iter().map(|e| (&e.key, &e.val)).collect();
+        let removes: Vec<&K> = self.core.remove_log.data.iter().collect();
+        let result = self.map.commit_group(&writes, &removes).map_err(|_| TxError::IoError);
+        self.core.finish(result.err().unwrap_or(TxError::Finished));
+        result
     }
 }
@@ -0,0 +1,334 @@
+extern crate alloc;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::mutex::Mutex;
+use super::redo::RedoLog;
+use super::{AbortReason, Transaction, TxError, GLOBAL_CLOCK};
+use crate::common::map::Map;
+use crate::common::set::SimpleSet;
+use crate::disk::persistentmap::PersistentMap;
+use crate::map::SkipMap;
+
+#[derive(Clone)]
+struct Version<V> {
+    ts: u64,
+    //None marks a delete
+    value: Option<V>,
+}
+
+//committed versions of every key, each chain is replaced as a whole on commit
+//so readers only ever see complete chains and never lock anything
+pub struct VersionStore<K, V> {
+    chains: SkipMap<K, Arc<Vec<Version<V>>>>,
+    //(snapshot, tid) of every running transaction, the oldest one decides which versions can go
+    active: SkipMap<(u64, u64), ()>,
+    last_committed: AtomicU64,
+    commit_lock: Mutex<()>,
+}
+
+impl<K: Ord + Clone, V: Clone> VersionStore<K, V> {
+    pub fn new() -> Self {
+        Self {
+            chains: SkipMap::new(),
+            active: SkipMap::new(),
+            last_committed: AtomicU64::new(0),
+            commit_lock: Mutex::new(()),
+        }
+    }
+
+    //registers a transaction and returns its snapshot
+    fn begin(&self, tid: u64) -> (u64, u64) {
+        let registered = self.last_committed.load(Ordering::SeqCst);
+        self.active.insert(&(registered, tid), &());
+        //a commit that pruned before we registered kept everything newer than its horizon,
+        //which is at most what we read here
+        (registered, self.last_committed.load(Ordering::SeqCst))
+    }
+
+    //key as committed before any transaction ran, e.g. read from a persistent map
+    pub fn load(&self, key: &K, value: &V) {
+        self.chains.insert(key, &Arc::new(alloc::vec![Version { ts: 0, value: Some(value.clone()) }]));
+    }
+
+    fn end(&self, registered: u64, tid: u64) {
+        self.active.remove(&(registered, tid));
+    }
+
+    fn visible(chain: &[Version<V>], snapshot: u64) -> Option<V> {
+        chain.iter().rev().find(|v| v.ts <= snapshot).and_then(|v| v.value.clone())
+    }
+
+    pub fn get(&self, key: &K, snapshot: u64) -> Option<V> {
+        self.chains.get(key).and_then(|chain| Self::visible(&chain, snapshot))
+    }
+
+    //up to limit keys >= start with their value at the snapshot, None if not visible
+    fn scan(&self, start: &K, snapshot: u64, limit: usize) -> Vec<(K, Option<V>)> {
+        self.chains.scan(start, limit).into_iter().map(|(key, chain)| (key, Self::visible(&chain, snapshot))).collect()
+    }
+
+    fn horizon(&self) -> u64 {
+        let last = self.last_committed.load(Ordering::SeqCst);
+        match self.active.scan(&(0, 0), 1).first() {
+            Some(((oldest, _), _)) if *oldest < last => *oldest,
+            _ => last,
+        }
+    }
+
+    fn install(&self, key: &K, value: Option<V>, ts: u64, horizon: u64) {
+        let mut chain = match self.chains.get(key) {
+            Some(chain) => (*chain).clone(),
+            None => Vec::new(),
+        };
+        chain.push(Version { ts, value });
+        //every running snapshot reads the newest version at or below the horizon or a later one
+        if let Some(keep_from) = chain.iter().rposition(|v| v.ts <= horizon) {
+            chain.drain(..keep_from);
+        }
+        self.chains.insert(key, &Arc::new(chain));
+    }
+
+    //first committer wins, Conflict if any written key got a version after the snapshot
+    //log runs once the commit is validated and nothing is installed if it fails
+    fn commit<L>(&self, snapshot: u64, redo_log: &RedoLog<K, V>, remove_log: &SimpleSet<K>, log: L) -> Result<(), TxError>
+    where
+        L: FnOnce() -> Result<(), TxError>,
+    {
+        let _guard = self.commit_lock.lock();
+        let written = redo_log.entries.iter().map(|e| &e.key).chain(remove_log.data.iter());
+        for key in written {
+            if let Some(chain) = self.chains.get(key) {
+                if chain.last().map_or(false, |v| v.ts > snapshot) {
+                    return Err(TxError::Conflict);
+                }
+            }
+        }
+        log()?;
+        //increment returns the old value, which a snapshot may already be at
+        let ts = GLOBAL_CLOCK.increment() + 1;
+        let horizon = self.horizon();
+        for entry in &redo_log.entries {
+            self.install(&entry.key, Some(entry.val.clone()), ts, horizon);
+        }
+        for key in &remove_log.data {
+            self.install(key, None, ts, horizon);
+        }
+        //publishing the timestamp last makes the whole commit visible at once
+        self.last_committed.store(ts, Ordering::SeqCst);
+        Ok(())
+    }
+
+    //number of versions kept for key
+    pub fn versions(&self, key: &K) -> usize {
+        self.chains.get(key).map_or(0, |chain| chain.len())
+    }
+}
+
+//where try_commit_persist of a snapshot transaction writes its changes
+pub trait CommitLog<K, V>: Send + Sync {
+    fn log(&self, writes: &[(&K, &V)], removes: &[&K]) -> Result<(), ()>;
+}
+
+//the persistent map of a store in snapshot mode only gets the commits that are persisted,
+//the version store holds everything else
+impl<M: PersistentMap + Send + Sync> CommitLog<M::Key, M::Value> for M {
+    fn log(&self, writes: &[(&M::Key, &M::Value)], removes: &[&M::Key]) -> Result<(), ()> {
+        self.commit_group(writes, removes)
+    }
+}
+
+//snapshot isolation transaction, reads come from the snapshot taken at begin and
+//writes are buffered until commit, nothing is locked in the lock table
+pub struct SITX<K: Eq + Clone, V: Clone> {
+    tid: u64,
+    registered: u64,
+    snapshot: u64,
+    redo_log: RedoLog<K, V>,
+    remove_log: SimpleSet<K>,
+    store: Arc<VersionStore<K, V>>,
+    //None when the store has no disk to persist to
+    log: Option<Arc<dyn CommitLog<K, V>>>,
+    finished: Option<TxError>,
+}
+
+impl<K: Ord + Clone, V: Clone> SITX<K, V> {
+    pub fn new(store: Arc<VersionStore<K, V>>) -> Self {
+        Self::with_log(store, None)
+    }
+
+    pub fn with_log(store: Arc<VersionStore<K, V>>, log: Option<Arc<dyn CommitLog<K, V>>>) -> Self {
+        let tid = GLOBAL_CLOCK.increment();
+        let (registered, snapshot) = store.begin(tid);
+        SITX {
+            tid,
+            registered,
+            snapshot,
+            redo_log: RedoLog::new(),
+            remove_log: SimpleSet::new(),
+            store,
+            log,
+            finished: None,
+        }
+    }
+
+    fn commit_with<L>(&mut self, log: L) -> Result<(), TxError>
+    where
+        L: FnOnce(&RedoLog<K, V>, &SimpleSet<K>) -> Result<(), TxError>,
+    {
+        self.check_active()?;
+        //read only transactions saw a consistent snapshot and have nothing to validate or log
+        let result = if self.redo_log.size() == 0 && self.remove_log.data.is_empty() {
+            Ok(())
+        } else {
+            let (redo_log, remove_log) = (&self.redo_log, &self.remove_log);
+            self.store.commit(self.snapshot, redo_log, remove_log, || log(redo_log, remove_log))
+        };
+        self.finish(result.err().unwrap_or(TxError::Finished));
+        result
+    }
+
+    fn check_active(&self) -> Result<(), TxError> {
+        match self.finished {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    fn finish(&mut self, state: TxError) {
+        if self.finished.is_none() {
+            self.store.end(self.registered, self.tid);
+            self.finished = Some(state);
+        }
+    }
+
+    fn scan_while<F: Fn(&K) -> bool>(&mut self, start: &K, in_range: F, limit: usize) -> Result<Vec<(K, V)>, TxError> {
+        self.check_active()?;
+        let mut result = Vec::new();
+        let mut cursor = start.clone();
+        let mut inclusive = true;
+        let mut exhausted = false;
+        'pages: while result.len() < limit {
+            let page = self.store.scan(&cursor, self.snapshot, limit + 1);
+            let mut advanced = false;
+            for (key, value) in page {
+                if !inclusive && key == cursor {
+                    continue;
+                }
+                if !in_range(&key) {
+                    exhausted = true;
+                    break 'pages;
+                }
+                cursor = key;
+                inclusive = false;
+                advanced = true;
+                if let Some(value) = value {
+                    if !self.redo_log.contains(&cursor) && !self.remove_log.contains(&cursor) {
+                        result.push((cursor.clone(), value));
+                        if result.len() == limit {
+                            break 'pages;
+                        }
+                    }
+                }
+            }
+            if !advanced {
+                exhausted = true;
+            }
+            if exhausted {
+                break;
+            }
+        }
+        for entry in &self.redo_log.entries {
+            if entry.key >= *start && in_range(&entry.key) && (exhausted || entry.key <= cursor) {
+                result.push((entry.key.clone(), entry.val.clone()));
+            }
+        }
+        result.sort_by(|a, b| a.0.cmp(&b.0));
+        result.truncate(limit);
+        Ok(result)
+    }
+}
+
+impl<K: Ord + Clone + AsRef<[u8]>, V: Clone> Transaction for SITX<K, V> {
+    type Key = K;
+    type Value = V;
+
+    fn read(&mut self, key: &K) -> Result<Option<V>, TxError> {
+        self.check_active()?;
+        if let Some(v) = self.redo_log.get(key) {
+            return Ok(Some(v));
+        }
+        if self.remove_log.contains(key) {
+            return Ok(None);
+        }
+        Ok(self.store.get(key, self.snapshot))
+    }
+
+    fn write(&mut self, key: &K, value: &V) -> Result<(), TxError> {
+        self.check_active()?;
+        self.remove_log.remove(key);
+        self.redo_log.insert(key, value);
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &K) -> Result<bool, TxError> {
+        self.check_active()?;
+        if self.remove_log.contains(key) {
+            return Ok(false);
+        }
+        if self.redo_log.contains(key) {
+            self.redo_log.remove(key);
+            self.remove_log.insert(key);
+            return Ok(true);
+        }
+        if self.store.get(key, self.snapshot).is_some() {
+            self.remove_log.insert(key);
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    fn scan(&mut self, start: &K, end: Option<&K>, limit: usize) -> Result<Vec<(K, V)>, TxError> {
+        match end {
+            Some(end) => self.scan_while(start, |k| k < end, limit),
+            None => self.scan_while(start, |_| true, limit),
+        }
+    }
+
+    fn scan_prefix(&mut self, prefix: &K, limit: usize) -> Result<Vec<(K, V)>, TxError> {
+        self.scan_while(prefix, |k| k.as_ref().starts_with(prefix.as_ref()), limit)
+    }
+
+    fn abort(&mut self) {
+        self.finish(TxError::Aborted(AbortReason::User));
+    }
+
+    fn try_commit(&mut self) -> Result<(), TxError> {
+        self.commit_with(|_, _| Ok(()))
+    }
+
+    fn try_commit_persist(&mut self) -> Result<(), TxError> {
+        self.check_active()?;
+        let log = match self.log.clone() {
+            Some(log) => log,
+            None => {
+                self.finish(TxError::NotPersistent);
+                return Err(TxError::NotPersistent);
+            },
+        };
+        self.commit_with(|redo_log, remove_log| {
+            let writes: Vec<(&K, &V)> = redo_log.entries.iter().map(|e| (&e.key, &e.val)).collect();
+            let removes: Vec<&K> = remove_log.data.iter().collect();
+            log.log(&writes, &removes).map_err(|_| TxError::IoError)
+        })
+    }
+}
+
+impl<K: Eq + Clone, V: Clone> Drop for SITX<K, V> {
+    //a dropped transaction must not hold back pruning of old versions
+    fn drop(&mut self) {
+        if self.finished.is_none() {
+            self.store.active.remove(&(self.registered, self.tid));
+        }
+    }
+}
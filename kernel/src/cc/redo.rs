@@ -20,6 +20,10 @@ impl<K: Eq + Clone, V: Clone> RedoLog<K, V> {
         self.entries.iter().find(|e| &e.key == key).map(|e| e.val.clone())
     }
 
+    pub fn contains(&self, key: &K) -> bool {
+        self.entries.iter().any(|e| &e.key == key)
+    }
+
     pub fn insert(&mut self, key: &K, val: &V) {
         if let Some(entry) = self.entries.iter_mut().find(|e| e.key == *key) {
             entry.val = val.clone();
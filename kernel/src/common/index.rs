@@ -0,0 +1,35 @@
+extern crate alloc;
+use alloc::collections::BTreeSet;
+use alloc::vec::Vec;
+use spin::mutex::Mutex;
+
+//ordered set of the keys stored in an unordered map
+//lets the hash maps answer range queries without sorting every bucket
+pub struct KeyIndex<K> {
+    keys: Mutex<BTreeSet<K>>,
+}
+
+impl<K: Ord + Clone> KeyIndex<K> {
+    pub fn new() -> Self {
+        Self {
+            keys: Mutex::new(BTreeSet::new()),
+        }
+    }
+
+    pub fn insert(&self, key: &K) {
+        self.keys.lock().insert(key.clone());
+    }
+
+    pub fn remove(&self, key: &K) {
+        self.keys.lock().remove(key);
+    }
+
+    //returns up to limit keys that are >= start in ascending order
+    pub fn range_from(&self, start: &K, limit: usize) -> Vec<K> {
+        self.keys.lock().range(start.clone()..).take(limit).cloned().collect()
+    }
+
+    pub fn size(&self) -> usize {
+        self.keys.lock().len()
+    }
+}
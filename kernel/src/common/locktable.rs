@@ -1,10 +1,20 @@
 extern crate alloc;
 extern crate spin;
+use alloc::collections::BTreeSet;
 use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
 use spin::Mutex;
+use crate::drivers::timing;
+use crate::interrupts;
+
 struct LockValue {
+    //held exclusively by version_number
     locked: bool,
     version_number: u64,
+    //transactions sharing the stripe, never includes the exclusive holder
+    readers: Vec<u64>,
+    //transactions blocked in lock on this stripe
+    waiters: Vec<u64>,
 }
 
 #[derive(PartialEq, Debug)]
@@ -14,45 +24,225 @@ pub enum TryLockResult {
     Die,
 }
 
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LockMode {
+    //any number of transactions may read the stripe together
+    Shared,
+    //one transaction may write the stripe, a sole reader can upgrade to it
+    Exclusive,
+}
+
+//outcome of a blocking lock
+#[derive(PartialEq, Debug)]
+pub enum LockResult {
+    Acquired,
+    //wait-die: a younger transaction asked for a lock held by an older one
+    Die,
+    //wound-wait: an older transaction wants a lock we hold
+    Wounded,
+    //gave up after the wait timeout
+    TimedOut,
+}
+
+//what happens when a lock is held by another transaction, lower tids are older
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LockPolicy {
+    //older requesters wait, younger requesters die
+    WaitDie,
+    //older requesters wound the younger holder and wait, younger requesters wait
+    WoundWait,
+}
+
+//waits longer than this are treated as a deadlock, e.g. two transactions on the same core
+const DEFAULT_WAIT_TIMEOUT_NS: u64 = 100_000_000;
+
 pub struct LockTable {
     locks: Vec<Mutex<LockValue>>,
+    //bumped on every unlock so waiters know when to re-try
+    wake: Vec<AtomicU64>,
+    wounded: Mutex<BTreeSet<u64>>,
+    policy: LockPolicy,
+    wait_timeout_ns: u64,
     size: u64,
 }
 
 impl LockTable {
     pub fn new(size: u64) -> Self {
+        Self::with_policy(size, LockPolicy::WaitDie)
+    }
+
+    pub fn with_policy(size: u64, policy: LockPolicy) -> Self {
         let mut locks = Vec::with_capacity(size as usize);
+        let mut wake = Vec::with_capacity(size as usize);
         for _ in 0..size {
             locks.push(Mutex::new(LockValue {
                 locked: false,
                 version_number: 0,
+                readers: Vec::new(),
+                waiters: Vec::new(),
             }));
+            wake.push(AtomicU64::new(0));
+        }
+        Self {
+            locks:locks,
+            wake:wake,
+            wounded: Mutex::new(BTreeSet::new()),
+            policy:policy,
+            wait_timeout_ns: DEFAULT_WAIT_TIMEOUT_NS,
+            size:size,
         }
-        Self { locks:locks, size:size }
+    }
+
+    pub fn set_wait_timeout(&mut self, ns: u64) {
+        self.wait_timeout_ns = ns;
     }
 
     pub fn try_lock(&self, key: u64, transaction_id: u64) -> TryLockResult {
-        let mut lock_version = self.locks[key as usize].lock();
+        self.try_lock_mode(key, transaction_id, LockMode::Exclusive)
+    }
 
-        if !lock_version.locked {
-            lock_version.locked = true;
-            lock_version.version_number = transaction_id;
+    pub fn try_lock_mode(&self, key: u64, transaction_id: u64, mode: LockMode) -> TryLockResult {
+        let mut lock_value = self.locks[key as usize].lock();
+
+        if lock_value.locked && lock_value.version_number == transaction_id {
             return TryLockResult::Success;
-        } else if transaction_id > lock_version.version_number {
-            return TryLockResult::Die;
-        } else {
-            return TryLockResult::Wait;
         }
+        //the oldest transaction in our way, our own shared lock is not in the way of an upgrade
+        let oldest = match mode {
+            LockMode::Shared if !lock_value.locked => None,
+            LockMode::Shared => Some(lock_value.version_number),
+            LockMode::Exclusive => {
+                let readers = lock_value.readers.iter().copied().filter(|&t| t != transaction_id);
+                let holder = if lock_value.locked { Some(lock_value.version_number) } else { None };
+                readers.chain(holder).min()
+            },
+        };
+        let oldest = match oldest {
+            None => {
+                match mode {
+                    LockMode::Shared => {
+                        if !lock_value.readers.contains(&transaction_id) {
+                            lock_value.readers.push(transaction_id);
+                        }
+                    },
+                    LockMode::Exclusive => {
+                        lock_value.readers.retain(|&t| t != transaction_id);
+                        lock_value.locked = true;
+                        lock_value.version_number = transaction_id;
+                    },
+                }
+                return TryLockResult::Success;
+            },
+            Some(oldest) => oldest,
+        };
+        match self.policy {
+            //waiting is only allowed for transactions older than everyone in the way
+            LockPolicy::WaitDie if transaction_id > oldest => TryLockResult::Die,
+            LockPolicy::WaitDie => TryLockResult::Wait,
+            LockPolicy::WoundWait => {
+                let mut wounded = self.wounded.lock();
+                if lock_value.locked && lock_value.version_number > transaction_id {
+                    wounded.insert(lock_value.version_number);
+                }
+                if mode == LockMode::Exclusive {
+                    for &reader in lock_value.readers.iter().filter(|&&t| t > transaction_id) {
+                        wounded.insert(reader);
+                    }
+                }
+                TryLockResult::Wait
+            },
+        }
+    }
+
+    pub fn lock(&self, key: u64, transaction_id: u64) -> LockResult {
+        self.lock_mode(key, transaction_id, LockMode::Exclusive)
+    }
+
+    //blocks until the stripe is ours in mode or the policy says to give up
+    pub fn lock_mode(&self, key: u64, transaction_id: u64, mode: LockMode) -> LockResult {
+        let start = timing::now_ns();
+        let mut queued = false;
+        let result = loop {
+            if self.is_wounded(transaction_id) {
+                break LockResult::Wounded;
+            }
+            //read before trying so an unlock in between is not missed
+            let seen = self.wake[key as usize].load(Ordering::SeqCst);
+            match self.try_lock_mode(key, transaction_id, mode) {
+                TryLockResult::Success => break LockResult::Acquired,
+                TryLockResult::Die => break LockResult::Die,
+                TryLockResult::Wait => {},
+            }
+            if !queued {
+                self.locks[key as usize].lock().waiters.push(transaction_id);
+                queued = true;
+            }
+            while self.wake[key as usize].load(Ordering::SeqCst) == seen {
+                if timing::now_ns().saturating_sub(start) > self.wait_timeout_ns || self.is_wounded(transaction_id) {
+                    break;
+                }
+                interrupts::wait_for_wakeup();
+            }
+            if timing::now_ns().saturating_sub(start) > self.wait_timeout_ns {
+                break LockResult::TimedOut;
+            }
+        };
+        if queued {
+            self.locks[key as usize].lock().waiters.retain(|&t| t != transaction_id);
+        }
+        result
     }
 
+    //drops the exclusive lock on the stripe whoever holds it
     pub fn unlock(&self, key: u64) {
         let mut lock_value = self.locks[key as usize].lock();
         lock_value.locked = false;
         lock_value.version_number = 0;
+        self.wake_waiters(key, &lock_value);
+    }
+
+    //drops whichever lock transaction_id holds on the stripe
+    pub fn release(&self, key: u64, transaction_id: u64) {
+        let mut lock_value = self.locks[key as usize].lock();
+        if lock_value.locked && lock_value.version_number == transaction_id {
+            lock_value.locked = false;
+            lock_value.version_number = 0;
+        }
+        lock_value.readers.retain(|&t| t != transaction_id);
+        self.wake_waiters(key, &lock_value);
+    }
+
+    fn wake_waiters(&self, key: u64, lock_value: &LockValue) {
+        self.wake[key as usize].fetch_add(1, Ordering::SeqCst);
+        if !lock_value.waiters.is_empty() {
+            //waiters on other cores may be halted
+            interrupts::wake_cores();
+        }
+    }
+
+    pub fn is_wounded(&self, transaction_id: u64) -> bool {
+        self.wounded.lock().contains(&transaction_id)
+    }
+
+    //called once a transaction released all of its locks
+    pub fn forget(&self, transaction_id: u64) {
+        self.wounded.lock().remove(&transaction_id);
+    }
+
+    //number of transactions sharing the stripe
+    pub fn readers(&self, key: u64) -> usize {
+        self.locks[key as usize].lock().readers.len()
+    }
+
+    pub fn waiters(&self, key: u64) -> usize {
+        self.locks[key as usize].lock().waiters.len()
+    }
+
+    pub fn policy(&self) -> LockPolicy {
+        self.policy
     }
 
     pub fn size(&self) -> u64 {
         self.size
     }
 }
-
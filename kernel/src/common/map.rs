@@ -2,13 +2,17 @@ extern crate alloc;
 use alloc::vec::Vec;
 use spin::mutex::Mutex;
 use crate::common::hash::Mix13Hash;
+use crate::common::index::KeyIndex;
 pub trait Map {
     type Key;
     type Value;
     fn new() -> Self;
+    fn with_capacity(capacity: usize) -> Self;
     fn get(&self, key: &Self::Key) -> Option<Self::Value>;
     fn insert(&self, key: &Self::Key, value: &Self::Value) -> bool;
     fn remove(&self, key: &Self::Key) -> bool;
+    //returns up to limit entries with key >= start in ascending key order
+    fn scan(&self, start: &Self::Key, limit: usize) -> Vec<(Self::Key, Self::Value)>;
 }
 
 struct Entry<K, V> {
@@ -23,25 +27,17 @@ struct Bucket<K, V> {
 pub struct SimpleHashMap<K, V> {
     buckets: Vec<Bucket<K, V>>,
     num_buckets: usize,
+    index: KeyIndex<K>,
 }
-impl <K: Eq + core::hash::Hash + AsRef<[u8]>, V: Clone>  SimpleHashMap<K, V> {
-    pub fn with_capacity(num_buckets:usize) -> Self {
-        let mut buckets = Vec::with_capacity(num_buckets);
-        for _ in 0..num_buckets {
-            buckets.push(Bucket {
-                data: Mutex::new(Vec::new()),
-            });
-        }
-
-        SimpleHashMap { buckets, num_buckets }
-    } 
-}
-impl<K: Eq + Clone + core::hash::Hash + AsRef<[u8]>, V: Clone> Map for SimpleHashMap<K, V> {
+impl<K: Eq + Ord + Clone + core::hash::Hash + AsRef<[u8]>, V: Clone> Map for SimpleHashMap<K, V> {
     type Key = K;
     type Value = V;
 
     fn new() -> Self {
-        let num_buckets = 16; // Default number of buckets
+        Self::with_capacity(16) // Default number of buckets
+    }
+
+    fn with_capacity(num_buckets:usize) -> Self {
         let mut buckets = Vec::with_capacity(num_buckets);
         for _ in 0..num_buckets {
             buckets.push(Bucket {
@@ -49,7 +45,7 @@ impl<K: Eq + Clone + core::hash::Hash + AsRef<[u8]>, V: Clone> Map for SimpleHas
             });
         }
 
-        SimpleHashMap { buckets, num_buckets }
+        SimpleHashMap { buckets, num_buckets, index: KeyIndex::new() }
     }
 
     fn get(&self, key: &Self::Key) -> Option<Self::Value> {
@@ -69,6 +65,7 @@ impl<K: Eq + Clone + core::hash::Hash + AsRef<[u8]>, V: Clone> Map for SimpleHas
                 key: key.clone(),
                 value: value.clone(),
             });
+            self.index.insert(key);
             true
         }
     }
@@ -79,11 +76,24 @@ impl<K: Eq + Clone + core::hash::Hash + AsRef<[u8]>, V: Clone> Map for SimpleHas
         let mut bucket_data = bucket.data.lock();
         if let Some(pos) = bucket_data.iter().position(|e| &e.key == key) {
             bucket_data.remove(pos);
+            self.index.remove(key);
             true
         } else {
             false
         }
     }
+
+    fn scan(&self, start: &Self::Key, limit: usize) -> Vec<(Self::Key, Self::Value)> {
+        //keys are collected first so the index lock is never held while taking a bucket lock
+        let keys = self.index.range_from(start, limit);
+        let mut result = Vec::with_capacity(keys.len());
+        for key in keys {
+            if let Some(value) = self.get(&key) {
+                result.push((key, value));
+            }
+        }
+        result
+    }
 }
 
 impl<K: Eq + core::hash::Hash + AsRef<[u8]>, V> SimpleHashMap<K, V> {
@@ -0,0 +1,211 @@
+//!
+//! Kernel configuration from the multiboot2 command line
+//!
+//! Words are separated by spaces. `key=value` sets an option, a bare word is the boot mode, so
+//! `test log=warn kv=2:0 buckets=64 locks=512 durability=always cc=snapshot` runs the tests on a
+//! virtio disk. `format=yes` writes an empty store over whatever the kv disk holds.
+//!
+extern crate alloc;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use crate::cc::CCMode;
+use crate::disk;
+use crate::disk::disk_api::Disk;
+use crate::disk::persistentmap::{PersistentHashMap, PersistentMap};
+use crate::drivers::serial::LogLevel;
+use crate::kvstore::{Durability, TxKVStorePersist};
+
+//bounds of the store geometry, every bucket and lock lives in the kernel heap
+pub const MAX_BUCKETS: usize = 4096;
+pub const MAX_LOCKS: u64 = 4096;
+
+/// What the kernel does once it is up
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum BootMode {
+    /// Start the init process
+    Run,
+    /// Run the kernel tests and exit qemu
+    Test,
+    Bench,
+}
+
+/// Where the key value store lives
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum KvDevice {
+    /// The first virtio disk, bus 0 drive 1 without one
+    Default,
+    /// `kv=bus:drive`, bus 2 being the virtio disks
+    Drive { bus: u8, drive: u8 },
+    /// `kv=n`, the nth disk `disk::drives` finds
+    Index(usize),
+}
+
+/// Everything the command line can set
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Config {
+    pub mode: BootMode,
+    pub kv_device: KvDevice,
+    /// `buckets=n`, buckets of the persistent hash map
+    pub buckets: usize,
+    /// `locks=n`, entries of the lock table
+    pub locks: u64,
+    /// `durability=request|always|memory`
+    pub durability: Durability,
+    /// `cc=waitdie|woundwait|snapshot`, concurrency control of the store
+    pub cc_mode: CCMode,
+    /// `format=yes|no`, start an empty store instead of opening the one on the kv disk
+    pub format: bool,
+    /// `log=error|warn|info|debug|trace`
+    pub log_level: LogLevel,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            mode: BootMode::Run,
+            kv_device: KvDevice::Default,
+            buckets: 16,
+            locks: 1024,
+            durability: Durability::Request,
+            cc_mode: CCMode::WaitDie,
+            format: false,
+            log_level: LogLevel::Trace,
+        }
+    }
+}
+
+impl Config {
+    /// The options on command_line over the defaults. Every word that is not understood or out
+    /// of range leaves its option at the default and gets a message in the second vec.
+    pub fn parse(command_line: &str) -> (Self, Vec<String>) {
+        let mut config = Self::default();
+        let errors = command_line.split_whitespace()
+            .filter_map(|word| config.set(word).err())
+            .collect();
+        (config, errors)
+    }
+
+    fn set(&mut self, word: &str) -> Result<(), String> {
+        let (key, value) = word.split_once('=').unwrap_or(("mode", word));
+        match key {
+            "mode" => self.mode = match value {
+                "run" => BootMode::Run,
+                "test" => BootMode::Test,
+                "bench" => BootMode::Bench,
+                _ => return Err(format!("unknown option {}", word)),
+            },
+            "kv" => self.kv_device = parse_device(value)
+                .ok_or_else(|| format!("kv={} is not default, bus:drive or a drive index", value))?,
+            "buckets" => self.buckets = parse_number(key, value, 1, MAX_BUCKETS as u64)? as usize,
+            "locks" => self.locks = parse_number(key, value, 1, MAX_LOCKS)?,
+            "durability" => self.durability = match value {
+                "request" => Durability::Request,
+                "always" => Durability::Always,
+                "memory" => Durability::Memory,
+                _ => return Err(format!("durability={} is not request, always or memory", value)),
+            },
+            "cc" => self.cc_mode = match value {
+                "waitdie" => CCMode::WaitDie,
+                "woundwait" => CCMode::WoundWait,
+                "snapshot" => CCMode::Snapshot,
+                _ => return Err(format!("cc={} is not waitdie, woundwait or snapshot", value)),
+            },
+            "format" => self.format = match value {
+                "yes" => true,
+                "no" => false,
+                _ => return Err(format!("format={} is not yes or no", value)),
+            },
+            "log" => self.log_level = match value {
+                "error" => LogLevel::Error,
+                "warn" => LogLevel::Warn,
+                "info" => LogLevel::Info,
+                "debug" => LogLevel::Debug,
+                "trace" => LogLevel::Trace,
+                _ => return Err(format!("log={} is not error, warn, info, debug or trace", value)),
+            },
+            _ => return Err(format!("unknown option {}", key)),
+        }
+        Ok(())
+    }
+
+    /// (bus, drive) of the kv disk, an error if the configured one has no disk
+    pub fn kv_drive(&self) -> Result<(u8, u8), String> {
+        match self.kv_device {
+            KvDevice::Default => Ok(disk::kv_disk()),
+            KvDevice::Drive { bus, drive } => match disk::block_count(bus, drive) {
+                Some(_) => Ok((bus, drive)),
+                None => Err(format!("no disk at kv={}:{}", bus, drive)),
+            },
+            KvDevice::Index(index) => {
+                let drives = disk::drives();
+                drives.get(index).copied()
+                    .ok_or_else(|| format!("no disk at kv={}, {} disks found", index, drives.len()))
+            },
+        }
+    }
+}
+
+//bus:drive, a drive index or default
+fn parse_device(value: &str) -> Option<KvDevice> {
+    if value == "default" {
+        return Some(KvDevice::Default);
+    }
+    match value.split_once(':') {
+        Some((bus, drive)) => {
+            let bus: u8 = bus.parse().ok()?;
+            let drive: u8 = drive.parse().ok()?;
+            //ata buses have two drives, the virtio bus one per device
+            if bus > disk::VIRTIO_BUS || (bus < disk::VIRTIO_BUS && drive > 1) {
+                return None;
+            }
+            Some(KvDevice::Drive { bus, drive })
+        },
+        None => value.parse().ok().map(KvDevice::Index),
+    }
+}
+
+fn parse_number(key: &str, value: &str, min: u64, max: u64) -> Result<u64, String> {
+    match value.parse::<u64>() {
+        Ok(n) if (min..=max).contains(&n) => Ok(n),
+        _ => Err(format!("{}={} is not a number from {} to {}", key, value, min, max)),
+    }
+}
+
+lazy_static! {
+    static ref CONFIG: Mutex<Config> = Mutex::new(Config::default());
+}
+
+/// Makes config the one the kernel runs with, the log level applies right away, the rest
+/// once the store is built
+pub fn set(config: Config) {
+    crate::drivers::serial::set_log_level(config.log_level);
+    *CONFIG.lock() = config;
+}
+
+pub fn get() -> Config {
+    *CONFIG.lock()
+}
+
+/// The store the configuration describes, on the default kv disk if the configured one is missing,
+/// an error if that disk cannot be read or holds something other than a store
+pub fn build_store() -> Result<Arc<TxKVStorePersist<Vec<u8>, Vec<u8>>>, String> {
+    let config = get();
+    let (bus, drive) = config.kv_drive().unwrap_or_else(|e| {
+        serial_warnln!("{}, using the default kv disk", e);
+        disk::kv_disk()
+    });
+    serial_infoln!("KV store on {}:{}, {} buckets, {} locks, {:?} durability, {:?}", bus, drive, config.buckets, config.locks, config.durability, config.cc_mode);
+    if config.format {
+        serial_warnln!("formatting the kv disk {}:{}", bus, drive);
+        PersistentHashMap::<Vec<u8>, Vec<u8>>::new_on(Disk::new(bus, drive))
+            .map_err(|_| format!("could not format the kv disk {}:{}", bus, drive))?;
+    }
+    let store = TxKVStorePersist::with_disk(Disk::new(bus, drive), config.buckets as u64, config.locks, config.cc_mode)
+        .map_err(|_| format!("no store on the kv disk {}:{}, boot with format=yes to start an empty one", bus, drive))?;
+    store.set_durability(config.durability);
+    Ok(store)
+}
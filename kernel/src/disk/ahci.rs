@@ -0,0 +1,484 @@
+extern crate alloc;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::hint::spin_loop;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use crate::disk::ata::BLOCK_SIZE;
+use crate::disk::dma::Dma;
+use crate::disk::request::Request;
+pub use crate::disk::request::Pending;
+use crate::drivers::timing;
+use crate::memory::paging::PAGE_SIZE;
+use crate::pci::{self, Bar, Device, Driver};
+
+//blocks moved by one command, 64 KiB so the data fits in the prdt of a command table
+pub const MAX_BLOCKS: usize = 128;
+//commands in flight on a port, each one takes a command table from the heap
+const MAX_SLOTS: usize = 8;
+//commands a read or write of a range has queued at once, each holds a buffer of MAX_BLOCKS on
+//the heap until it is waited for
+const IN_FLIGHT: usize = 2;
+//one prd for every page the data touches
+const PRDT_ENTRIES: usize = MAX_BLOCKS * BLOCK_SIZE / PAGE_SIZE + 1;
+const COMMAND_TABLE_SIZE: usize = 0x80 + 16 * PRDT_ENTRIES;
+
+//hba registers
+const CAP: usize = 0x00;
+const GHC: usize = 0x04;
+const IS: usize = 0x08;
+const PI: usize = 0x0C;
+const CAP_NCQ: u32 = 1 << 30;
+const GHC_AE: u32 = 1 << 31;
+const GHC_IE: u32 = 1 << 1;
+
+//port registers, from 0x100 + 0x80 * port
+const PORT_CLB: usize = 0x00;
+const PORT_FB: usize = 0x08;
+const PORT_IS: usize = 0x10;
+const PORT_IE: usize = 0x14;
+const PORT_CMD: usize = 0x18;
+const PORT_TFD: usize = 0x20;
+const PORT_SIG: usize = 0x24;
+const PORT_SSTS: usize = 0x28;
+const PORT_SERR: usize = 0x30;
+const PORT_SACT: usize = 0x34;
+const PORT_CI: usize = 0x38;
+const CMD_ST: u32 = 1 << 0;
+const CMD_FRE: u32 = 1 << 4;
+const CMD_FR: u32 = 1 << 14;
+const CMD_CR: u32 = 1 << 15;
+//d2h register, set device bits, task file error and the fatal errors
+const PORT_IE_MASK: u32 = 1 << 0 | 1 << 3 | 1 << 30 | 1 << 29 | 1 << 28 | 1 << 27;
+const PORT_IS_ERROR: u32 = 1 << 30 | 1 << 29 | 1 << 28 | 1 << 27;
+const TFD_BSY: u32 = 1 << 7;
+const TFD_DRQ: u32 = 1 << 3;
+//signature of an ata disk, atapi and port multipliers are left alone
+const SATA_SIGNATURE: u32 = 0x0000_0101;
+
+//register host to device fis
+const FIS_H2D: u8 = 0x27;
+
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+#[repr(u8)]
+enum Command {
+    ReadDma = 0xC8,
+    ReadDmaExt = 0x25,
+    WriteDma = 0xCA,
+    WriteDmaExt = 0x35,
+    ReadFpdmaQueued = 0x60,
+    WriteFpdmaQueued = 0x61,
+    FlushCacheExt = 0xEA,
+    Identify = 0xEC,
+}
+
+//a command in a slot
+struct Slot {
+    write: bool,
+    data: Option<Dma>,
+    len: usize,
+    request: Arc<Request>,
+}
+
+impl Slot {
+    fn finish(self, result: Result<(), ()>) {
+        let data = match (self.write, self.data) {
+            (false, Some(mut data)) => data.bytes()[..self.len].to_vec(),
+            _ => Vec::new(),
+        };
+        //only the core the msi goes to wakes up for it
+        self.request.finish(result.map(|_| data), INTERRUPTS.load(Ordering::SeqCst));
+    }
+}
+
+struct Port {
+    number: u8,
+    base: usize,
+    blocks: u64,
+    lba48: bool,
+    ncq: bool,
+    command_list: Dma,
+    fis: Dma,
+    tables: Vec<Dma>,
+    busy: Vec<Option<Slot>>,
+}
+
+impl Port {
+    fn read(&self, reg: usize) -> u32 {
+        unsafe { read_volatile((self.base + reg) as *const u32) }
+    }
+
+    fn write(&self, reg: usize, value: u32) {
+        unsafe { write_volatile((self.base + reg) as *mut u32, value) }
+    }
+
+    //waits up to 500 ms for the bits in mask to be clear
+    fn wait_clear(&self, reg: usize, mask: u32) -> Result<(), ()> {
+        let start = timing::get_ticks();
+        while self.read(reg) & mask != 0 {
+            if timing::get_ticks() - start > 10 {
+                serial_errorln!("AHCI port {} hanged waiting for {:x} in {:x}", self.number, mask, reg);
+                return Err(());
+            }
+            spin_loop();
+        }
+        Ok(())
+    }
+
+    fn stop(&self) -> Result<(), ()> {
+        self.write(PORT_CMD, self.read(PORT_CMD) & !CMD_ST);
+        self.wait_clear(PORT_CMD, CMD_CR)?;
+        self.write(PORT_CMD, self.read(PORT_CMD) & !CMD_FRE);
+        self.wait_clear(PORT_CMD, CMD_FR)
+    }
+
+    fn start(&self) -> Result<(), ()> {
+        self.write(PORT_SERR, u32::MAX);
+        self.write(PORT_IS, u32::MAX);
+        self.write(PORT_CMD, self.read(PORT_CMD) | CMD_FRE);
+        self.wait_clear(PORT_TFD, TFD_BSY | TFD_DRQ)?;
+        self.write(PORT_CMD, self.read(PORT_CMD) | CMD_ST);
+        self.write(PORT_IE, PORT_IE_MASK);
+        Ok(())
+    }
+
+    //sets up the port memory and identifies the disk on it, None if there is no ata disk or no memory for the port
+    fn open(abar: usize, number: u8, slots: usize, hba_ncq: bool) -> Option<Self> {
+        let base = abar + 0x100 + 0x80 * number as usize;
+        let status = unsafe { read_volatile((base + PORT_SSTS) as *const u32) };
+        let signature = unsafe { read_volatile((base + PORT_SIG) as *const u32) };
+        //a device is there and the link is up
+        if status & 0xF != 3 || (status >> 8) & 0xF != 1 || signature != SATA_SIGNATURE {
+            return None;
+        }
+        let mut port = Self {
+            number,
+            base,
+            blocks: 0,
+            lba48: false,
+            ncq: false,
+            command_list: Dma::new(32 * 32, 1024)?,
+            fis: Dma::new(256, 256)?,
+            tables: (0..slots).map(|_| Dma::new(COMMAND_TABLE_SIZE, 1024)).collect::<Option<_>>()?,
+            busy: (0..slots).map(|_| None).collect(),
+        };
+        port.stop().ok()?;
+        let command_list = port.command_list.physical(0);
+        let fis = port.fis.physical(0);
+        port.write(PORT_CLB, command_list as u32);
+        port.write(PORT_CLB + 4, (command_list >> 32) as u32);
+        port.write(PORT_FB, fis as u32);
+        port.write(PORT_FB + 4, (fis >> 32) as u32);
+        port.start().ok()?;
+        let identify = port.identify().ok()?;
+        //word 83 bit 10 is set when words 100 to 103 hold the 48 bit block count
+        port.lba48 = identify[83] & (1 << 10) != 0;
+        port.blocks = if port.lba48 {
+            identify[100..104].iter().rev().fold(0, |blocks, word| blocks << 16 | *word as u64)
+        } else {
+            (identify[61] as u64) << 16 | identify[60] as u64
+        };
+        //word 76 bit 8 says the disk does ncq, word 75 has its queue depth - 1
+        if hba_ncq && port.lba48 && identify[76] & (1 << 8) != 0 {
+            port.ncq = true;
+            let depth = (identify[75] & 0x1F) as usize + 1;
+            port.busy.truncate(depth);
+            port.tables.truncate(depth);
+        }
+        serial_infoln!("AHCI port {}: {} blocks, ncq {}", number, port.blocks, port.ncq);
+        Some(port)
+    }
+
+    fn identify(&mut self) -> Result<[u16; 256], ()> {
+        let request = self.issue(Command::Identify, 0, 1, false, None)?;
+        self.drain();
+        let data = Pending::new(request, None, || {}).wait()?;
+        let mut words = [0; 256];
+        for (word, bytes) in words.iter_mut().zip(data.chunks(2)) {
+            *word = u16::from_le_bytes([bytes[0], bytes[1]]);
+        }
+        Ok(words)
+    }
+
+    fn free_slot(&self) -> Option<usize> {
+        self.busy.iter().position(Option::is_none)
+    }
+
+    //builds the command in a free slot and hands it to the hba, data is what a write writes, Err if
+    //no slot is free or the heap has no room for the buffer
+    fn issue(&mut self, command: Command, block: u64, blocks: usize, write: bool, data: Option<&[u8]>) -> Result<Arc<Request>, ()> {
+        let slot = self.free_slot().ok_or(())?;
+        let len = blocks * BLOCK_SIZE;
+        let mut buffer = Dma::new(len.max(BLOCK_SIZE), BLOCK_SIZE).ok_or(())?;
+        if let Some(data) = data {
+            buffer.bytes()[..len].copy_from_slice(data);
+        }
+        let queued = matches!(command, Command::ReadFpdmaQueued | Command::WriteFpdmaQueued);
+        let (count, features) = if queued { ((slot as u16) << 3, blocks as u16) } else { (blocks as u16, 0) };
+        let table = &mut self.tables[slot];
+        let table_address = table.physical(0);
+        let bytes = table.bytes();
+        bytes.fill(0);
+        //the register fis
+        let lba = block.to_le_bytes();
+        //28 bit commands keep the top of the block in the device register
+        let device = match command {
+            Command::ReadDma | Command::WriteDma => 0x40 | (lba[3] & 0x0F),
+            _ => 0x40,
+        };
+        let fis = [
+            FIS_H2D, 0x80, command as u8, features as u8, lba[0], lba[1], lba[2], device,
+            lba[3], lba[4], lba[5], (features >> 8) as u8, count as u8, (count >> 8) as u8, 0, 0,
+        ];
+        bytes[..fis.len()].copy_from_slice(&fis);
+        //one prd for every page the buffer touches, the buffer is contiguous in virtual memory only
+        let mut prds = 0;
+        for (address, part) in buffer.segments(0, len) {
+            let prd = &mut bytes[0x80 + 16 * prds..0x80 + 16 * (prds + 1)];
+            prd[..8].copy_from_slice(&address.to_le_bytes());
+            prd[12..16].copy_from_slice(&(part as u32 - 1).to_le_bytes());
+            prds += 1;
+        }
+        //the command header, fis length in dwords, write bit and prdt length
+        let header = &mut self.command_list.bytes()[32 * slot..32 * (slot + 1)];
+        header.fill(0);
+        let flags = 5 | (write as u32) << 6 | (prds as u32) << 16;
+        header[..4].copy_from_slice(&flags.to_le_bytes());
+        header[8..16].copy_from_slice(&table_address.to_le_bytes());
+        let request = Request::new();
+        self.busy[slot] = Some(Slot { write, data: Some(buffer), len, request: request.clone() });
+        if queued {
+            self.write(PORT_SACT, 1 << slot);
+        }
+        self.write(PORT_CI, 1 << slot);
+        Ok(request)
+    }
+
+    //finishes the commands the hba is done with, called by the interrupt handler and by waiters
+    fn service(&mut self) {
+        let status = self.read(PORT_IS);
+        self.write(PORT_IS, status);
+        if status & PORT_IS_ERROR != 0 {
+            serial_errorln!("AHCI port {}: error {:x}, task file {:x}", self.number, status, self.read(PORT_TFD));
+            //which queued command failed is only in the ncq error log, fail them all and restart
+            for slot in self.busy.iter_mut() {
+                if let Some(slot) = slot.take() {
+                    slot.finish(Err(()));
+                }
+            }
+            if self.stop().and_then(|_| self.start()).is_err() {
+                serial_errorln!("AHCI port {} did not restart", self.number);
+            }
+            return;
+        }
+        let running = self.read(PORT_CI) | self.read(PORT_SACT);
+        for (i, slot) in self.busy.iter_mut().enumerate() {
+            if slot.is_some() && running & (1 << i) == 0 {
+                slot.take().unwrap().finish(Ok(()));
+            }
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        self.busy.iter().all(Option::is_none)
+    }
+
+    //finishes every command in flight by polling
+    fn drain(&mut self) {
+        while !self.is_idle() {
+            self.service();
+            spin_loop();
+        }
+    }
+
+    fn transfer_command(&self, write: bool) -> Command {
+        match (write, self.ncq, self.lba48) {
+            (false, true, _) => Command::ReadFpdmaQueued,
+            (true, true, _) => Command::WriteFpdmaQueued,
+            (false, false, true) => Command::ReadDmaExt,
+            (true, false, true) => Command::WriteDmaExt,
+            (false, false, false) => Command::ReadDma,
+            (true, false, false) => Command::WriteDma,
+        }
+    }
+}
+
+struct Hba {
+    abar: usize,
+    //by port number
+    ports: Vec<Option<Port>>,
+}
+
+impl Hba {
+    fn port(&mut self, port: u8) -> Option<&mut Port> {
+        self.ports.get_mut(port as usize).and_then(Option::as_mut)
+    }
+}
+
+//the hba the driver took, the interrupt handler takes it too so everyone else takes it with
+//interrupts off
+static HBA: Mutex<Option<Hba>> = Mutex::new(None);
+//set once the hba interrupts reach the handler, until then waiters poll the ports
+static INTERRUPTS: AtomicBool = AtomicBool::new(false);
+
+fn with_port<R>(port: u8, f: impl FnOnce(&mut Port) -> R) -> Option<R> {
+    interrupts::without_interrupts(|| HBA.lock().as_mut().and_then(|hba| hba.port(port)).map(f))
+}
+
+static AHCI_DRIVER: Driver = Driver {
+    name: "ahci",
+    //ich9 (q35), ich8 and ich6 controllers
+    ids: &[(0x8086, 0x2922), (0x8086, 0x2829), (0x8086, 0x2821), (0x8086, 0x2652)],
+    probe,
+};
+
+//registers the driver, the first hba found keeps the disks
+pub fn init() {
+    pci::register_driver(&AHCI_DRIVER);
+}
+
+fn probe(device: &Device) -> Result<(), ()> {
+    if HBA.lock().is_some() {
+        return Err(());
+    }
+    //bar 5 holds the hba registers, mapped by pci
+    let abar = match device.bars[5] {
+        Some(Bar::Memory { addr, .. }) => addr as usize,
+        _ => return Err(()),
+    };
+    device.enable_bus_master();
+    let read = |reg: usize| unsafe { read_volatile((abar + reg) as *const u32) };
+    let write = |reg: usize, value: u32| unsafe { write_volatile((abar + reg) as *mut u32, value) };
+    write(GHC, read(GHC) | GHC_AE);
+    let cap = read(CAP);
+    let slots = ((cap >> 8) & 0x1F) as usize + 1;
+    let implemented = read(PI);
+    let ports = (0..32u8)
+        .map(|port| if implemented & (1 << port) != 0 { Port::open(abar, port, slots.min(MAX_SLOTS), cap & CAP_NCQ != 0) } else { None })
+        .collect();
+    interrupts::without_interrupts(|| *HBA.lock() = Some(Hba { abar, ports }));
+    write(IS, u32::MAX);
+    if device.enable_msi(handle_interrupt).is_ok() {
+        write(GHC, read(GHC) | GHC_IE);
+        INTERRUPTS.store(true, Ordering::SeqCst);
+    }
+    Ok(())
+}
+
+//msi handler of the hba
+fn handle_interrupt() {
+    //whoever holds the hba services its port anyway, a missed interrupt is caught up on the next tick
+    if let Some(mut hba) = HBA.try_lock() {
+        if let Some(hba) = hba.as_mut() {
+            let status = unsafe { read_volatile((hba.abar + IS) as *const u32) };
+            for port in hba.ports.iter_mut().flatten() {
+                if status & (1 << port.number) != 0 {
+                    port.service();
+                }
+            }
+            unsafe { write_volatile((hba.abar + IS) as *mut u32, status) };
+        }
+    }
+}
+
+//the ahci port of the disk the ata driver would call (bus, drive), q35 puts the -drive arguments
+//on the ports in the order piix puts them on the buses
+pub fn port_of(bus: u8, drive: u8) -> u8 {
+    bus * 2 + drive
+}
+
+//blocks of the disk on port, None if there is none
+pub fn block_count(port: u8) -> Option<u64> {
+    with_port(port, |port| port.blocks)
+}
+
+//queues a read of blocks blocks from block on, at most MAX_BLOCKS
+pub fn submit_read(port: u8, block: u64, blocks: usize) -> Pending {
+    submit(port, block, blocks, None)
+}
+
+//queues a write of buf, a multiple of BLOCK_SIZE and at most MAX_BLOCKS blocks, from block on
+pub fn submit_write(port: u8, block: u64, buf: &[u8]) -> Pending {
+    if buf.len() % BLOCK_SIZE != 0 {
+        return Pending::failed();
+    }
+    submit(port, block, buf.len() / BLOCK_SIZE, Some(buf))
+}
+
+fn submit(number: u8, block: u64, blocks: usize, data: Option<&[u8]>) -> Pending {
+    if blocks == 0 || blocks > MAX_BLOCKS {
+        return Pending::failed();
+    }
+    //every slot busy means waiting for one of them
+    loop {
+        let issued = with_port(number, |port| {
+            if block + blocks as u64 > port.blocks {
+                return Some(Err(()));
+            }
+            port.service();
+            port.free_slot()?;
+            let command = port.transfer_command(data.is_some());
+            Some(port.issue(command, block, blocks, data.is_some(), data))
+        });
+        match issued {
+            None => return Pending::failed(),
+            Some(None) => spin_loop(),
+            Some(Some(Ok(request))) => return pending(number, request),
+            Some(Some(Err(()))) => return Pending::failed(),
+        }
+    }
+}
+
+//read from the disk on port, the blocks from block on until buf is full, commands are queued
+//IN_FLIGHT at a time so an ncq disk can work on them at once
+pub fn read(port: u8, block: u64, buf: &mut [u8]) -> Result<(), ()> {
+    if buf.is_empty() || buf.len() % BLOCK_SIZE != 0 {
+        return Err(());
+    }
+    let finish = |(pending, chunk): (Pending, &mut [u8])| pending.wait().map(|data| chunk.copy_from_slice(&data));
+    let mut queued = VecDeque::new();
+    let mut result = Ok(());
+    for (i, chunk) in buf.chunks_mut(MAX_BLOCKS * BLOCK_SIZE).enumerate() {
+        if queued.len() == IN_FLIGHT {
+            result = result.and(finish(queued.pop_front().unwrap()));
+        }
+        queued.push_back((submit_read(port, block + (i * MAX_BLOCKS) as u64, chunk.len() / BLOCK_SIZE), chunk));
+    }
+    queued.into_iter().fold(result, |result, queued| result.and(finish(queued)))
+}
+
+//write buf.len() / BLOCK_SIZE blocks from block on to the disk on port, IN_FLIGHT commands at a time
+pub fn write(port: u8, block: u64, buf: &[u8]) -> Result<(), ()> {
+    if buf.is_empty() || buf.len() % BLOCK_SIZE != 0 {
+        return Err(());
+    }
+    let finish = |pending: Pending| pending.wait().map(|_| ());
+    let mut queued = VecDeque::new();
+    let mut result = Ok(());
+    for (i, chunk) in buf.chunks(MAX_BLOCKS * BLOCK_SIZE).enumerate() {
+        if queued.len() == IN_FLIGHT {
+            result = result.and(finish(queued.pop_front().unwrap()));
+        }
+        queued.push_back(submit_write(port, block + (i * MAX_BLOCKS) as u64, chunk));
+    }
+    queued.into_iter().fold(result, |result, pending| result.and(finish(pending)))
+}
+
+//makes the writes the disk on port finished durable, queued commands cannot run next to it
+pub fn flush(port: u8) -> Result<(), ()> {
+    let request = with_port(port, |port| {
+        port.drain();
+        port.issue(Command::FlushCacheExt, 0, 0, false, None)
+    }).ok_or(())??;
+    pending(port, request).wait().map(|_| ())
+}
+
+fn pending(port: u8, request: Arc<Request>) -> Pending {
+    Pending::new(request, Some(&INTERRUPTS), move || {
+        with_port(port, |port| port.service());
+    })
+}
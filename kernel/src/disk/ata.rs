@@ -20,32 +20,53 @@
 //  LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
 //  OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
 //  THE SOFTWARE.
+use alloc::collections::VecDeque;
 use alloc::string::String;
+use alloc::sync::Arc;
 use alloc::vec::Vec;
 use bit_field::BitField;
 use core::{convert::TryInto, hint::spin_loop};
 use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
 use lazy_static::lazy_static;
 use spin::Mutex;
+use x86_64::instructions::interrupts;
 use x86_64::instructions::port::{Port, PortReadOnly, PortWriteOnly};
 use crate::serial_println;
 use crate::drivers::timing;
+use crate::disk::request::Request;
+pub use crate::disk::request::Pending;
 
 // See "Information Technology - AT Attachment with Packet Interface Extension (ATA/ATAPI-4)" (1998)
 
 //this block size is important and is set in the QEMU settings
 pub const BLOCK_SIZE: usize = 512;
 
+//blocks past this need the 48 bit commands
+const LBA28_LIMIT: u64 = 1 << 28;
+//most blocks one command can move, a sector count of 0 means the maximum
+const LBA28_MAX_BLOCKS: usize = 256;
+const LBA48_MAX_BLOCKS: usize = 65536;
+
 #[repr(u16)]
 #[derive(Debug, Clone, Copy)]
 enum Command {
     Read = 0x20,
+    ReadExt = 0x24,
+    ReadMultipleExt = 0x29,
     Write = 0x30,
+    WriteExt = 0x34,
+    WriteMultipleExt = 0x39,
+    ReadMultiple = 0xC4,
+    WriteMultiple = 0xC5,
+    SetMultipleMode = 0xC6,
+    FlushCache = 0xE7,
+    FlushCacheExt = 0xEA,
     Identify = 0xEC,
 }
 
 enum IdentifyResponse {
-    Ata([u32; 128]),
+    Ata([u16; 256]),
     Atapi,
     Sata,
     None,
@@ -65,8 +86,28 @@ enum Status {
     BSY  = 7, // Busy
 }
 
+//a transfer waiting in the queue of its bus or being moved
+struct Transfer {
+    drive: u8,
+    block: u64,
+    write: bool,
+    buf: Vec<u8>,
+    //blocks moved so far, where the command in flight stops and how many each data request moves
+    moved: usize,
+    command_end: usize,
+    per_request: usize,
+    request: Arc<Request>,
+}
+
+impl Transfer {
+    fn finish(self, result: Result<(), ()>) {
+        let data = if self.write { Vec::new() } else { self.buf };
+        //only the core the IOAPIC sends the interrupt to wakes up for it
+        self.request.finish(result.map(|_| data), INTERRUPTS.load(Ordering::SeqCst));
+    }
+}
+
 #[allow(dead_code)]
-#[derive(Debug, Clone)]
 //all the ports to read/write from
 pub struct Bus {
     id: u8,
@@ -84,6 +125,13 @@ pub struct Bus {
     alternate_status_register: PortReadOnly<u8>,
     control_register: PortWriteOnly<u8>,
     drive_blockess_register: PortReadOnly<u8>,
+    //blocks per data request of READ/WRITE MULTIPLE for each drive, 0 if it is not set up
+    multiple: [u16; 2],
+    //whether each drive takes the 48 bit commands, set once it is identified
+    lba48: [bool; 2],
+    //transfers in the order they were submitted, the first one is on the bus
+    queue: VecDeque<Transfer>,
+    active: Option<Transfer>,
 }
 
 impl Bus {
@@ -103,6 +151,10 @@ impl Bus {
             alternate_status_register: PortReadOnly::new(ctrl_base + 0),
             control_register: PortWriteOnly::new(ctrl_base + 0),
             drive_blockess_register: PortReadOnly::new(ctrl_base + 1),
+            multiple: [0; 2],
+            lba48: [false; 2],
+            queue: VecDeque::new(),
+            active: None,
         }
     }
 
@@ -173,16 +225,29 @@ impl Bus {
         self.poll(Status::DRQ, false)?;
         Ok(())
     }
-    //get the ATA ready to receive the cmd
-    fn write_command_params(&mut self, drive: u8, block: u32) -> Result<(), ()> {
+    //get the ATA ready to receive the cmd, count blocks starting at block
+    fn write_command_params(&mut self, drive: u8, block: u64, count: usize, lba48: bool) -> Result<(), ()> {
         let lba = true;
         let mut bytes = block.to_le_bytes();
+        //a count of 0 asks for the most blocks the command can move
+        let count = (count as u32).to_le_bytes();
+        unsafe {
+            if lba48 {
+                //the high bytes go first, the registers keep both
+                self.sector_count_register.write(count[1]);
+                self.lba0_register.write(bytes[3]);
+                self.lba1_register.write(bytes[4]);
+                self.lba2_register.write(bytes[5]);
+                //the drive register has no block bits with 48 bit commands
+                bytes[3] = 0;
+            }
+        }
         bytes[3].set_bit(4, drive > 0);
         bytes[3].set_bit(5, true);
         bytes[3].set_bit(6, lba);
         bytes[3].set_bit(7, true);
         unsafe {
-            self.sector_count_register.write(1);
+            self.sector_count_register.write(count[0]);
             self.lba0_register.write(bytes[0]);
             self.lba1_register.write(bytes[1]);
             self.lba2_register.write(bytes[2]);
@@ -210,53 +275,194 @@ impl Bus {
         Ok(())
     }
     //set up the PIO to have a cmd written
-    fn setup_pio(&mut self, drive: u8, block: u32) -> Result<(), ()> {
+    fn setup_pio(&mut self, drive: u8, block: u64, count: usize, lba48: bool) -> Result<(), ()> {
         self.select_drive(drive)?;
-        self.write_command_params(drive, block)?;
+        self.write_command_params(drive, block, count, lba48)?;
         Ok(())
     }
 
-    fn read(&mut self, drive: u8, block: u32, buf: &mut [u8]) -> Result<(), ()> {
-        if buf.len() != BLOCK_SIZE {
-            return Err(());
+    //the command for a transfer and the blocks it moves per data request, multiple mode
+    //moves several between two requests
+    fn transfer_command(&self, drive: u8, write: bool, lba48: bool) -> (Command, usize) {
+        let multiple = self.multiple[drive as usize & 1] as usize;
+        let command = match (write, multiple > 0, lba48) {
+            (false, false, false) => Command::Read,
+            (false, false, true) => Command::ReadExt,
+            (false, true, false) => Command::ReadMultiple,
+            (false, true, true) => Command::ReadMultipleExt,
+            (true, false, false) => Command::Write,
+            (true, false, true) => Command::WriteExt,
+            (true, true, false) => Command::WriteMultiple,
+            (true, true, true) => Command::WriteMultipleExt,
+        };
+        (command, multiple.max(1))
+    }
+
+    //whether a transfer needs the 48 bit commands and the most blocks one command moves
+    fn transfer_limits(block: u64, buf_len: usize) -> (bool, usize) {
+        if block + (buf_len / BLOCK_SIZE) as u64 > LBA28_LIMIT {
+            (true, LBA48_MAX_BLOCKS)
+        } else {
+            (false, LBA28_MAX_BLOCKS)
+        }
+    }
+
+    //queues a transfer and puts it on the bus if nothing else is
+    fn submit(&mut self, transfer: Transfer) {
+        self.queue.push_back(transfer);
+        self.start_next();
+    }
+
+    //puts the next queued transfer on the bus, failing the ones the drive does not take
+    fn start_next(&mut self) {
+        while self.active.is_none() {
+            let mut transfer = match self.queue.pop_front() {
+                Some(transfer) => transfer,
+                None => return,
+            };
+            match self.issue(&mut transfer) {
+                Ok(()) => self.active = Some(transfer),
+                Err(()) => transfer.finish(Err(())),
+            }
+        }
+    }
+
+    //sends the command for the next blocks of transfer, a write also gets its first data in
+    fn issue(&mut self, transfer: &mut Transfer) -> Result<(), ()> {
+        let (lba48, max) = Self::transfer_limits(transfer.block, transfer.buf.len());
+        let blocks = (transfer.buf.len() / BLOCK_SIZE - transfer.moved).min(max);
+        let (command, per_request) = self.transfer_command(transfer.drive, transfer.write, lba48);
+        self.setup_pio(transfer.drive, transfer.block + transfer.moved as u64, blocks % max, lba48)?;
+        transfer.command_end = transfer.moved + blocks;
+        transfer.per_request = per_request;
+        unsafe { self.command_register.write(command as u8) }
+        self.wait();
+        if transfer.write {
+            //the drive asks for the first data without an interrupt
+            self.poll(Status::BSY, false)?;
+            self.poll(Status::DRQ, true)?;
+            self.write_next(transfer);
         }
-        self.setup_pio(drive, block)?;
-        //writes the read command
-        self.write_command(Command::Read)?;
-        //reads data from the port into the buffer
-        for chunk in buf.chunks_mut(4) {
+        Ok(())
+    }
+
+    //bytes of the buffer the next data request moves
+    fn next_request(transfer: &Transfer) -> (usize, usize) {
+        let start = transfer.moved * BLOCK_SIZE;
+        (start, start + transfer.per_request.min(transfer.command_end - transfer.moved) * BLOCK_SIZE)
+    }
+
+    //reads the data of one request from the port into the buffer
+    fn read_next(&mut self, transfer: &mut Transfer) {
+        let (start, end) = Self::next_request(transfer);
+        for chunk in transfer.buf[start..end].chunks_mut(4) {
             let data = self.read_data().to_le_bytes();
             chunk.clone_from_slice(&data);
         }
-        if self.is_error() {
-            serial_println!("ATA read: data error");
+        transfer.moved = end / BLOCK_SIZE;
+    }
+
+    //writes the data of one request to the port to be written to the disk
+    fn write_next(&mut self, transfer: &mut Transfer) {
+        let (start, end) = Self::next_request(transfer);
+        for chunk in transfer.buf[start..end].chunks(4) {
+            let data = u32::from_le_bytes(chunk.try_into().unwrap());
+            self.write_data(data);
+        }
+        transfer.moved = end / BLOCK_SIZE;
+        //give the drive time to go busy before anyone looks at the status
+        self.wait();
+    }
+
+    //moves the transfer on the bus along as far as the drive lets it, called by the interrupt
+    //handler and by waiters, reading the status also acknowledges the interrupt
+    fn service(&mut self) {
+        let status = self.clear_interrupt();
+        let mut transfer = match self.active.take() {
+            Some(transfer) => transfer,
+            None => return,
+        };
+        if status.get_bit(Status::BSY as usize) {
+            self.active = Some(transfer);
+            return;
+        }
+        if status.get_bit(Status::ERR as usize) || status.get_bit(Status::DF as usize) {
+            serial_errorln!("ATA {}: data error", if transfer.write { "write" } else { "read" });
             self.debug();
-            Err(())
+            transfer.finish(Err(()));
+            self.start_next();
+            return;
+        }
+        let drq = status.get_bit(Status::DRQ as usize);
+        if transfer.moved < transfer.command_end {
+            //the drive is not ready for the next request yet
+            if !drq {
+                self.active = Some(transfer);
+                return;
+            }
+            if transfer.write {
+                //the drive says it wrote the data with the next interrupt
+                self.write_next(&mut transfer);
+                self.active = Some(transfer);
+                return;
+            }
+            self.read_next(&mut transfer);
+            if transfer.moved < transfer.command_end {
+                self.active = Some(transfer);
+                return;
+            }
+        } else if drq {
+            self.active = Some(transfer);
+            return;
+        }
+        //the command is done, a transfer bigger than one command gets the next one
+        if transfer.moved == transfer.buf.len() / BLOCK_SIZE {
+            transfer.finish(Ok(()));
+        } else if self.issue(&mut transfer).is_ok() {
+            self.active = Some(transfer);
         } else {
-            Ok(())
+            transfer.finish(Err(()));
         }
+        self.start_next();
     }
-    //write the ATA device
-    fn write(&mut self, drive: u8, block: u32, buf: &[u8]) -> Result<(), ()> {
-        //ensure the buf has the length of the block size
-        if buf.len() != BLOCK_SIZE {
-            return Err(());
-        }
-        self.setup_pio(drive, block)?;
-        //write the write command
-        self.write_command(Command::Write)?;
-        //writes the buf to the port to be written to the disk
-        for chunk in buf.chunks(4) {
-            let data = u32::from_le_bytes(chunk.try_into().unwrap());
-            self.write_data(data);
+
+    //finishes every queued transfer by polling
+    fn drain(&mut self) {
+        while self.active.is_some() {
+            self.service();
+            spin_loop();
         }
+    }
+
+    //turns on multiple mode with blocks per data request, the drive may refuse it
+    fn set_multiple_mode(&mut self, drive: u8, blocks: u16) -> Result<(), ()> {
+        self.select_drive(drive)?;
+        self.write_command_params(drive, 0, blocks as usize, false)?;
+        unsafe { self.command_register.write(Command::SetMultipleMode as u8) }
+        self.wait();
+        self.poll(Status::BSY, false)?;
         if self.is_error() {
-            serial_println!("ATA write: data error");
+            self.multiple[drive as usize & 1] = 0;
+            return Err(());
+        }
+        self.multiple[drive as usize & 1] = blocks;
+        Ok(())
+    }
+    //writes what the drive has in its cache to the disk, polled like identify so the queued
+    //transfers are finished first, a drive with 48 bit blocks gets the 48 bit command
+    fn flush_cache(&mut self, drive: u8) -> Result<(), ()> {
+        self.drain();
+        self.select_drive(drive)?;
+        let command = if self.lba48[drive as usize & 1] { Command::FlushCacheExt } else { Command::FlushCache };
+        unsafe { self.command_register.write(command as u8) }
+        self.wait();
+        self.poll(Status::BSY, false)?;
+        if self.is_error() || self.status().get_bit(Status::DF as usize) {
+            serial_errorln!("ATA drive {}:{}: flush cache failed", self.id, drive);
             self.debug();
-            Err(())
-        } else {
-            Ok(())
+            return Err(());
         }
+        Ok(())
     }
     //identifys the drive and gets it ready to function
     fn identify_drive(&mut self, drive: u8) -> Result<IdentifyResponse, ()> {
@@ -264,7 +470,7 @@ impl Bus {
             return Ok(IdentifyResponse::None);
         }
         self.select_drive(drive)?;
-        self.write_command_params(drive, 0)?;
+        self.write_command_params(drive, 0, 1, false)?;
         if self.write_command(Command::Identify).is_err() {
             if self.status() == 0 {
                 return Ok(IdentifyResponse::None);
@@ -273,7 +479,16 @@ impl Bus {
             }
         }
         match (self.lba1(), self.lba2()) {
-            (0x00, 0x00) => Ok(IdentifyResponse::Ata([(); 128].map(|_| { self.read_data() }))),
+            (0x00, 0x00) => {
+                //every 32 bit read of the data port gets two words, the first one in the low half
+                let mut words = [0; 256];
+                for pair in words.chunks_mut(2) {
+                    let data = self.read_data();
+                    pair[0] = data as u16;
+                    pair[1] = (data >> 16) as u16;
+                }
+                Ok(IdentifyResponse::Ata(words))
+            },
             (0x14, 0xEB) => Ok(IdentifyResponse::Atapi),
             (0x3C, 0xC3) => Ok(IdentifyResponse::Sata),
             (_, _) => Err(()),
@@ -298,22 +513,81 @@ impl Bus {
         }
     }
 }
-//lock for the bus
+//lock for the bus, the interrupt handler takes it too so everyone else takes it with
+//interrupts off
 lazy_static! {
     pub static ref BUSES: Mutex<Vec<Bus>> = Mutex::new(Vec::new());
 }
-//init a new bus
+
+//set once IRQ 14 and 15 reach the handler, until then waiters poll the drive
+static INTERRUPTS: AtomicBool = AtomicBool::new(false);
+
+fn with_bus<R>(bus: u8, f: impl FnOnce(&mut Bus) -> R) -> R {
+    interrupts::without_interrupts(|| f(&mut BUSES.lock()[bus as usize]))
+}
+
+//init the buses, once
 pub fn init() {
-    let mut buses = BUSES.lock();
-    buses.push(Bus::new(0, 0x1F0, 0x3F6, 14));
-    buses.push(Bus::new(1, 0x170, 0x376, 15));
+    interrupts::without_interrupts(|| {
+        let mut buses = BUSES.lock();
+        if buses.is_empty() {
+            buses.push(Bus::new(0, 0x1F0, 0x3F6, 14));
+            buses.push(Bus::new(1, 0x170, 0x376, 15));
+        }
+    });
+}
+
+//called once the IOAPIC sends IRQ 14 and 15 to the handler
+pub fn enable_interrupts() {
+    init();
+    interrupts::without_interrupts(|| {
+        for bus in BUSES.lock().iter_mut() {
+            //clear nIEN so the drives raise their interrupt
+            unsafe { bus.control_register.write(0) }
+        }
+    });
+    INTERRUPTS.store(true, Ordering::SeqCst);
+}
+
+//interrupt handler of the bus on irq
+pub fn handle_interrupt(irq: u8) {
+    //whoever holds the bus is on another core and services it anyway or is done soon, the
+    //waiter catches up on the next tick if this interrupt is missed
+    if let Some(mut buses) = BUSES.try_lock() {
+        if let Some(bus) = buses.iter_mut().find(|bus| bus.irq == irq) {
+            bus.service();
+        }
+    }
+}
+
+//queues a read of blocks blocks from block on
+pub fn submit_read(bus: u8, drive: u8, block: u64, blocks: usize) -> Pending {
+    submit(bus, drive, block, false, [0; BLOCK_SIZE].repeat(blocks))
+}
+
+//queues a write of buf, a multiple of BLOCK_SIZE, from block on
+pub fn submit_write(bus: u8, drive: u8, block: u64, buf: Vec<u8>) -> Pending {
+    submit(bus, drive, block, true, buf)
+}
+
+fn submit(bus: u8, drive: u8, block: u64, write: bool, buf: Vec<u8>) -> Pending {
+    if buf.is_empty() || buf.len() % BLOCK_SIZE != 0 || bus as usize >= 2 {
+        return Pending::failed();
+    }
+    init();
+    let request = Request::new();
+    let transfer = Transfer { drive, block, write, buf, moved: 0, command_end: 0, per_request: 1, request: request.clone() };
+    with_bus(bus, |bus| bus.submit(transfer));
+    Pending::new(request, Some(&INTERRUPTS), move || with_bus(bus, |bus| bus.service()))
 }
 
 #[derive(Clone)]
 pub struct Drive {
     pub bus: u8,
     pub dsk: u8,
-    blocks: u32,
+    blocks: u64,
+    //the drive takes the 48 bit commands
+    lba48: bool,
     model: String,
     serial: String,
 }
@@ -322,13 +596,31 @@ impl Drive {
     //opens a new drive
     pub fn open(bus: u8, dsk: u8) -> Option<Self> {
         //lock the buses
-        let mut buses = BUSES.lock();
+        interrupts::without_interrupts(|| Self::identify(&mut BUSES.lock(), bus, dsk))
+    }
+
+    fn identify(buses: &mut [Bus], bus: u8, dsk: u8) -> Option<Self> {
+        //identify polls the drive so it cannot share the bus with queued transfers
+        buses[bus as usize].drain();
         if let Ok(IdentifyResponse::Ata(res)) = buses[bus as usize].identify_drive(dsk) {
-            let buf = res.map(u32::to_be_bytes).concat();
+            //strings keep the first character of each word in its high byte
+            let buf = res.map(u16::to_be_bytes).concat();
             let serial = String::from_utf8_lossy(&buf[20..40]).trim().into();
             let model = String::from_utf8_lossy(&buf[54..94]).trim().into();
-            let blocks = u32::from_be_bytes(buf[120..124].try_into().unwrap()).rotate_left(16);
-            Some(Self { bus, dsk, model, serial, blocks })
+            //word 83 bit 10 is set when words 100 to 103 hold the 48 bit block count
+            let lba48 = res[83].get_bit(10);
+            let blocks = if lba48 {
+                res[100..104].iter().rev().fold(0, |blocks, word| blocks << 16 | *word as u64)
+            } else {
+                (res[61] as u64) << 16 | res[60] as u64
+            };
+            //the low byte of word 47 is the most blocks the drive moves per data request
+            buses[bus as usize].lba48[dsk as usize & 1] = lba48;
+            let multiple = res[47] & 0xFF;
+            if multiple > 1 && buses[bus as usize].set_multiple_mode(dsk, multiple).is_err() {
+                serial_warnln!("ATA drive {}:{} refused multiple mode", bus, dsk);
+            }
+            Some(Self { bus, dsk, model, serial, blocks, lba48 })
         } else {
             None
         }
@@ -338,9 +630,14 @@ impl Drive {
         BLOCK_SIZE as u32
     }
     //gets the number of blocks
-    pub fn block_count(&self) -> u32 {
+    pub fn block_count(&self) -> u64 {
         self.blocks
     }
+
+    //blocks past 2^28 can be used
+    pub fn is_lba48(&self) -> bool {
+        self.lba48
+    }
     //makes size of drive human readable
     fn humanized_size(&self) -> (usize, String) {
         let size = self.block_size() as usize;
@@ -375,14 +672,26 @@ pub fn list() -> Vec<Drive> {
     res
 }
 
-//read from ATA
-pub fn read(bus: u8, drive: u8, block: u32, buf: &mut [u8]) -> Result<(), ()> {
-    let mut buses = BUSES.lock();
-    buses[bus as usize].read(drive, block, buf)
+//read from ATA, the blocks from block on until buf is full, buf has to be a multiple of BLOCK_SIZE
+pub fn read(bus: u8, drive: u8, block: u64, buf: &mut [u8]) -> Result<(), ()> {
+    if buf.len() % BLOCK_SIZE != 0 {
+        return Err(());
+    }
+    let data = submit_read(bus, drive, block, buf.len() / BLOCK_SIZE).wait()?;
+    buf.copy_from_slice(&data);
+    Ok(())
 }
 
-//write to ATA
-pub fn write(bus: u8, drive: u8, block: u32, buf: &[u8]) -> Result<(), ()> {
-    let mut buses = BUSES.lock();
-    buses[bus as usize].write(drive, block, buf)
+//write to ATA, buf.len() / BLOCK_SIZE blocks from block on
+pub fn write(bus: u8, drive: u8, block: u64, buf: &[u8]) -> Result<(), ()> {
+    submit_write(bus, drive, block, buf.to_vec()).wait().map(|_| ())
+}
+
+//makes the writes the drive finished durable, they may sit in its write cache until then
+pub fn flush(bus: u8, drive: u8) -> Result<(), ()> {
+    if bus as usize >= 2 {
+        return Err(());
+    }
+    init();
+    with_bus(bus, |bus| bus.flush_cache(drive))
 }
@@ -0,0 +1,121 @@
+extern crate alloc;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::Mutex;
+use crate::disk;
+use crate::disk::ata::BLOCK_SIZE;
+use crate::disk::cache;
+
+//blocks of a ram disk made by default, 64 KiB so a few fit in the kernel heap
+pub const DEFAULT_RAM_BLOCKS: u64 = 128;
+
+//something that stores whole blocks, Disk keeps byte offsets on top of it
+//clones share the blocks
+pub trait BlockDevice: Clone + Send + Sync {
+    //reads the blocks from block on until buf is full, buf is a multiple of block_size
+    fn read_blocks(&self, block: u64, buf: &mut [u8]) -> Result<(), ()>;
+    //writes buf.len() / block_size() blocks from block on
+    fn write_blocks(&self, block: u64, buf: &[u8]) -> Result<(), ()>;
+    //makes the writes so far durable
+    fn flush(&self) -> Result<(), ()>;
+    //size in blocks, 0 if there is nothing there
+    fn block_count(&self) -> u64;
+    fn block_size(&self) -> usize {
+        BLOCK_SIZE
+    }
+}
+
+//an ata drive, or the ahci port or virtio disk standing in for it, read and written through
+//the block cache so writes only reach the drive on flush
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CachedDrive {
+    pub bus: u8,
+    pub drive: u8,
+    blocks: u64,
+}
+
+impl CachedDrive {
+    //the drive at (bus, drive), it has no blocks if there is no disk
+    pub fn open(bus: u8, drive: u8) -> Self {
+        let blocks = disk::block_count(bus, drive).unwrap_or(0);
+        Self { bus, drive, blocks }
+    }
+}
+
+//the disk the key value store lives on
+impl Default for CachedDrive {
+    fn default() -> Self {
+        let (bus, drive) = disk::kv_disk();
+        Self::open(bus, drive)
+    }
+}
+
+impl BlockDevice for CachedDrive {
+    fn read_blocks(&self, block: u64, buf: &mut [u8]) -> Result<(), ()> {
+        cache::read(self.bus, self.drive, block, buf)
+    }
+
+    fn write_blocks(&self, block: u64, buf: &[u8]) -> Result<(), ()> {
+        cache::write(self.bus, self.drive, block, buf)
+    }
+
+    fn flush(&self) -> Result<(), ()> {
+        cache::flush(self.bus, self.drive)
+    }
+
+    fn block_count(&self) -> u64 {
+        self.blocks
+    }
+}
+
+//blocks in the kernel heap, gone on reboot, for tests and for running without a disk
+#[derive(Clone)]
+pub struct RamDisk {
+    data: Arc<Mutex<Vec<u8>>>,
+}
+
+impl RamDisk {
+    //a zeroed disk of blocks blocks
+    pub fn new(blocks: u64) -> Self {
+        Self { data: Arc::new(Mutex::new(vec![0; blocks as usize * BLOCK_SIZE])) }
+    }
+
+    //byte range of len bytes from block on, None if it is not whole blocks on the disk
+    fn range(&self, len: usize, block: u64) -> Option<core::ops::Range<usize>> {
+        let start = (block as usize).checked_mul(BLOCK_SIZE)?;
+        let end = start.checked_add(len)?;
+        if len % BLOCK_SIZE != 0 || end > self.data.lock().len() {
+            return None;
+        }
+        Some(start..end)
+    }
+}
+
+impl Default for RamDisk {
+    fn default() -> Self {
+        Self::new(DEFAULT_RAM_BLOCKS)
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn read_blocks(&self, block: u64, buf: &mut [u8]) -> Result<(), ()> {
+        let range = self.range(buf.len(), block).ok_or(())?;
+        buf.copy_from_slice(&self.data.lock()[range]);
+        Ok(())
+    }
+
+    fn write_blocks(&self, block: u64, buf: &[u8]) -> Result<(), ()> {
+        let range = self.range(buf.len(), block).ok_or(())?;
+        self.data.lock()[range].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn block_count(&self) -> u64 {
+        (self.data.lock().len() / BLOCK_SIZE) as u64
+    }
+}
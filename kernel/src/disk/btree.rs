@@ -0,0 +1,615 @@
+extern crate alloc;
+use alloc::collections::BTreeSet;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+use spin::mutex::Mutex;
+use crate::common::hash::crc32;
+use crate::disk::block_device::{BlockDevice, CachedDrive};
+use crate::disk::disk_api::Disk;
+use crate::disk::persistentmap::{PersistentMap, ToBeBytes};
+
+//a copy-on-write b+tree: a change never overwrites a page the last superblock can reach,
+//it writes new copies of the pages from the leaf up to the root and then switches over by
+//writing the superblock, so a crash recovers either the old or the new tree
+//the superblock is magic, seq, root page, height, pages handed out and a crc32 over all of
+//it, it goes into block seq % 2 so a torn write leaves the previous one intact
+const SUPERBLOCK_MAGIC: &[u8; 8] = b"KVBTREE\0";
+pub const PAGE_SIZE: usize = 4096;
+const PAGES_START: usize = PAGE_SIZE;
+
+//every page starts with its kind, the entry count, the bytes used and a crc32 over the
+//entries, a leaf entry is key length, value length, key and value, an internal page is
+//its first child followed by key length, key and child for every key
+const PAGE_HEADER: usize = 16;
+const LEAF: u32 = 1;
+const INTERNAL: u32 = 2;
+
+//a page has to hold a few entries for splits to make progress
+pub const MAX_ENTRY_BYTES: usize = PAGE_SIZE / 4;
+//nodes below this after a remove are merged with a sibling or refilled from it
+const MIN_NODE_BYTES: usize = PAGE_SIZE / 4;
+
+enum Node<K, V> {
+    //sorted entries
+    Leaf(Vec<(K, V)>),
+    //children[i] holds the keys below keys[i], the last child all the others
+    Internal(Vec<K>, Vec<u64>),
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    u64::from_be_bytes([
+        bytes[0], bytes[1], bytes[2], bytes[3],
+        bytes[4], bytes[5], bytes[6], bytes[7],
+    ])
+}
+
+fn key_len<K: ToBeBytes>(key: &K) -> usize {
+    key.to_be_bytes().as_ref().len()
+}
+
+fn entry_len<K: ToBeBytes, V: ToBeBytes>(key: &K, value: &V) -> usize {
+    8 + key_len(key) + value.to_be_bytes().as_ref().len()
+}
+
+impl<K: Ord + Clone + ToBeBytes, V: Clone + ToBeBytes> Node<K, V> {
+    fn size(&self) -> usize {
+        match self {
+            Node::Leaf(entries) => PAGE_HEADER + entries.iter().map(|(k, v)| entry_len(k, v)).sum::<usize>(),
+            Node::Internal(keys, _) => PAGE_HEADER + 8 + keys.iter().map(|k| 12 + key_len(k)).sum::<usize>(),
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut page = vec![0; PAGE_HEADER];
+        let (kind, count) = match self {
+            Node::Leaf(entries) => {
+                for (key, value) in entries {
+                    let (key, value) = (key.to_vec(), value.to_vec());
+                    page.extend_from_slice(&(key.len() as u32).to_be_bytes());
+                    page.extend_from_slice(&(value.len() as u32).to_be_bytes());
+                    page.extend_from_slice(&key);
+                    page.extend_from_slice(&value);
+                }
+                (LEAF, entries.len())
+            },
+            Node::Internal(keys, children) => {
+                page.extend_from_slice(&children[0].to_be_bytes());
+                for (key, child) in keys.iter().zip(children[1..].iter()) {
+                    let key = key.to_vec();
+                    page.extend_from_slice(&(key.len() as u32).to_be_bytes());
+                    page.extend_from_slice(&key);
+                    page.extend_from_slice(&child.to_be_bytes());
+                }
+                (INTERNAL, keys.len())
+            },
+        };
+        let used = page.len();
+        let crc = crc32(0, &page[PAGE_HEADER..]);
+        page[0..4].copy_from_slice(&kind.to_be_bytes());
+        page[4..8].copy_from_slice(&(count as u32).to_be_bytes());
+        page[8..12].copy_from_slice(&(used as u32).to_be_bytes());
+        page[12..16].copy_from_slice(&crc.to_be_bytes());
+        page
+    }
+
+    fn decode(page: &[u8]) -> Result<Self, ()> {
+        let count = read_u32(&page[4..8]) as usize;
+        let used = read_u32(&page[8..12]) as usize;
+        if used < PAGE_HEADER || used > page.len() || crc32(0, &page[PAGE_HEADER..used]) != read_u32(&page[12..16]) {
+            return Err(());
+        }
+        let body = &page[PAGE_HEADER..used];
+        let mut pos = 0;
+        let mut take = |len: usize| -> Result<&[u8], ()> {
+            let bytes = body.get(pos..pos + len).ok_or(())?;
+            pos += len;
+            Ok(bytes)
+        };
+        match read_u32(&page[0..4]) {
+            LEAF => {
+                let mut entries = Vec::with_capacity(count.min(body.len() / 8));
+                for _ in 0..count {
+                    let lens = take(8)?;
+                    let (key_len, value_len) = (read_u32(&lens[0..4]) as usize, read_u32(&lens[4..8]) as usize);
+                    let key = K::from_vec(take(key_len)?.to_vec())?;
+                    let value = V::from_vec(take(value_len)?.to_vec())?;
+                    entries.push((key, value));
+                }
+                Ok(Node::Leaf(entries))
+            },
+            INTERNAL => {
+                let mut keys = Vec::with_capacity(count.min(body.len() / 12));
+                let mut children = vec![read_u64(take(8)?)];
+                for _ in 0..count {
+                    let key_len = read_u32(take(4)?) as usize;
+                    keys.push(K::from_vec(take(key_len)?.to_vec())?);
+                    children.push(read_u64(take(8)?));
+                }
+                Ok(Node::Internal(keys, children))
+            },
+            _ => Err(()),
+        }
+    }
+
+    //splits a node that does not fit in a page into two of about the same size and the
+    //key that separates them
+    fn split(self) -> (Self, K, Self) {
+        let half = self.size() / 2;
+        match self {
+            Node::Leaf(mut entries) => {
+                let mut bytes = PAGE_HEADER;
+                let mut at = 1;
+                while at < entries.len() - 1 && bytes + entry_len(&entries[at - 1].0, &entries[at - 1].1) < half {
+                    bytes += entry_len(&entries[at - 1].0, &entries[at - 1].1);
+                    at += 1;
+                }
+                let right = entries.split_off(at);
+                let separator = right[0].0.clone();
+                (Node::Leaf(entries), separator, Node::Leaf(right))
+            },
+            Node::Internal(mut keys, mut children) => {
+                //the middle key moves up to the parent
+                let mut bytes = PAGE_HEADER + 8;
+                let mut at = 1;
+                while at < keys.len() - 1 && bytes + 12 + key_len(&keys[at - 1]) < half {
+                    bytes += 12 + key_len(&keys[at - 1]);
+                    at += 1;
+                }
+                let right_keys = keys.split_off(at + 1);
+                let separator = keys.pop().unwrap();
+                let right_children = children.split_off(at + 1);
+                (Node::Internal(keys, children), separator, Node::Internal(right_keys, right_children))
+            },
+        }
+    }
+
+    //the node holding everything in left, then separator, then right
+    fn join(self, separator: K, right: Self) -> Self {
+        match (self, right) {
+            (Node::Leaf(mut left), Node::Leaf(mut right)) => {
+                left.append(&mut right);
+                Node::Leaf(left)
+            },
+            (Node::Internal(mut keys, mut children), Node::Internal(mut right_keys, mut right_children)) => {
+                keys.push(separator);
+                keys.append(&mut right_keys);
+                children.append(&mut right_children);
+                Node::Internal(keys, children)
+            },
+            //siblings are always at the same height
+            _ => unreachable!(),
+        }
+    }
+}
+
+//pages written for a node too big for one page, each but the first with the key that
+//separates it from the one before
+type Pieces<K> = Vec<(Option<K>, u64)>;
+
+//replaces children[at] by the pieces and adds their separators
+fn splice_pieces<K: Clone>(keys: &mut Vec<K>, children: &mut Vec<u64>, at: usize, pieces: Pieces<K>) {
+    children[at] = pieces[0].1;
+    for (i, (separator, page)) in pieces.into_iter().enumerate().skip(1) {
+        keys.insert(at + i - 1, separator.unwrap());
+        children.insert(at + i, page);
+    }
+}
+
+struct Tree<K, V, D: BlockDevice> {
+    disk: Disk<D>,
+    //what the superblock points to
+    root: u64,
+    height: u32,
+    seq: u64,
+    //the tree a change in progress builds, the same as root otherwise
+    working_root: u64,
+    working_height: u32,
+    //pages ever handed out, the ones not in the tree are in free
+    page_count: u64,
+    free: BTreeSet<u64>,
+    //pages of the committed tree the change in progress replaced, free once it is committed
+    replaced: Vec<u64>,
+    //pages the change in progress wrote
+    written: Vec<u64>,
+    _marker: PhantomData<(K, V)>,
+}
+
+impl<K: Ord + Clone + ToBeBytes, V: Clone + ToBeBytes, D: BlockDevice> Tree<K, V, D> {
+    fn read(&mut self, page: u64) -> Result<Node<K, V>, ()> {
+        let bytes = self.disk.read_bytes(PAGES_START + page as usize * PAGE_SIZE, PAGE_SIZE)?;
+        Node::decode(&bytes)
+    }
+
+    fn write(&mut self, node: &Node<K, V>) -> Result<u64, ()> {
+        let page = match self.free.pop_first() {
+            Some(page) => page,
+            None if PAGES_START + (self.page_count as usize + 1) * PAGE_SIZE <= self.disk.size() => {
+                self.page_count += 1;
+                self.page_count - 1
+            },
+            None => return Err(()),
+        };
+        if self.disk.write_blocks((PAGES_START + page as usize * PAGE_SIZE) / 512, &node.encode()).is_err() {
+            self.free.insert(page);
+            return Err(());
+        }
+        self.written.push(page);
+        Ok(page)
+    }
+
+    //page is no longer part of the tree being built
+    fn release(&mut self, page: u64) {
+        match self.written.iter().position(|&p| p == page) {
+            //never reachable from a superblock, it can be reused right away
+            Some(pos) => {
+                self.written.swap_remove(pos);
+                self.free.insert(page);
+            },
+            None => self.replaced.push(page),
+        }
+    }
+
+    //writes the node, split over as many pages as it needs
+    fn store(&mut self, node: Node<K, V>) -> Result<Pieces<K>, ()> {
+        if node.size() <= PAGE_SIZE {
+            return Ok(vec![(None, self.write(&node)?)]);
+        }
+        let (left, separator, right) = node.split();
+        let mut pieces = self.store(left)?;
+        let mut right = self.store(right)?;
+        right[0].0 = Some(separator);
+        pieces.append(&mut right);
+        Ok(pieces)
+    }
+
+    fn get(&mut self, key: &K) -> Result<Option<V>, ()> {
+        let mut page = self.root;
+        loop {
+            match self.read(page)? {
+                Node::Leaf(entries) => {
+                    return Ok(entries.binary_search_by(|(k, _)| k.cmp(key)).ok().map(|i| entries[i].1.clone()));
+                },
+                Node::Internal(keys, children) => page = children[keys.partition_point(|k| k <= key)],
+            }
+        }
+    }
+
+    fn insert(&mut self, key: &K, value: &V) -> Result<(), ()> {
+        let mut pieces = self.insert_into(self.working_root, key, value)?;
+        //the root split, the tree grows by one level
+        while pieces.len() > 1 {
+            let keys = pieces[1..].iter().map(|(separator, _)| separator.clone().unwrap()).collect();
+            let children = pieces.iter().map(|(_, page)| *page).collect();
+            pieces = self.store(Node::Internal(keys, children))?;
+            self.working_height += 1;
+        }
+        self.working_root = pieces[0].1;
+        Ok(())
+    }
+
+    fn insert_into(&mut self, page: u64, key: &K, value: &V) -> Result<Pieces<K>, ()> {
+        let node = self.read(page)?;
+        self.release(page);
+        match node {
+            Node::Leaf(mut entries) => {
+                match entries.binary_search_by(|(k, _)| k.cmp(key)) {
+                    Ok(i) => entries[i].1 = value.clone(),
+                    Err(i) => entries.insert(i, (key.clone(), value.clone())),
+                }
+                self.store(Node::Leaf(entries))
+            },
+            Node::Internal(mut keys, mut children) => {
+                let at = keys.partition_point(|k| k <= key);
+                let pieces = self.insert_into(children[at], key, value)?;
+                splice_pieces(&mut keys, &mut children, at, pieces);
+                self.store(Node::Internal(keys, children))
+            },
+        }
+    }
+
+    //false if there was nothing to remove
+    fn remove(&mut self, key: &K) -> Result<bool, ()> {
+        let node = match self.remove_from(self.working_root, key)? {
+            Some(node) => node,
+            None => return Ok(false),
+        };
+        match node {
+            //the root lost its last separator, the tree shrinks by one level
+            Node::Internal(keys, children) if keys.is_empty() => {
+                self.working_root = children[0];
+                self.working_height -= 1;
+            },
+            node => {
+                let pieces = self.store(node)?;
+                self.working_root = pieces[0].1;
+            },
+        }
+        Ok(true)
+    }
+
+    //the changed node, not written yet so the parent can merge it, None if key was not there
+    fn remove_from(&mut self, page: u64, key: &K) -> Result<Option<Node<K, V>>, ()> {
+        match self.read(page)? {
+            Node::Leaf(mut entries) => {
+                match entries.binary_search_by(|(k, _)| k.cmp(key)) {
+                    Ok(i) => {
+                        entries.remove(i);
+                        self.release(page);
+                        Ok(Some(Node::Leaf(entries)))
+                    },
+                    Err(_) => Ok(None),
+                }
+            },
+            Node::Internal(mut keys, mut children) => {
+                let at = keys.partition_point(|k| k <= key);
+                let child = match self.remove_from(children[at], key)? {
+                    Some(child) => child,
+                    None => return Ok(None),
+                };
+                self.release(page);
+                if child.size() < MIN_NODE_BYTES && children.len() > 1 {
+                    //merge with the right sibling, or the left one for the last child,
+                    //if both do not fit in a page store splits them evenly again
+                    let left_at = if at + 1 < children.len() { at } else { at - 1 };
+                    let sibling_at = if left_at == at { at + 1 } else { left_at };
+                    let sibling = self.read(children[sibling_at])?;
+                    self.release(children[sibling_at]);
+                    let separator = keys.remove(left_at);
+                    let joined = if left_at == at { child.join(separator, sibling) } else { sibling.join(separator, child) };
+                    let pieces = self.store(joined)?;
+                    children.remove(left_at + 1);
+                    splice_pieces(&mut keys, &mut children, left_at, pieces);
+                } else {
+                    let pieces = self.store(child)?;
+                    splice_pieces(&mut keys, &mut children, at, pieces);
+                }
+                Ok(Some(Node::Internal(keys, children)))
+            },
+        }
+    }
+
+    fn scan(&mut self, page: u64, start: &K, limit: usize, result: &mut Vec<(K, V)>) -> Result<(), ()> {
+        match self.read(page)? {
+            Node::Leaf(entries) => {
+                let first = entries.partition_point(|(k, _)| k < start);
+                let wanted = limit - result.len();
+                result.extend(entries.into_iter().skip(first).take(wanted));
+            },
+            Node::Internal(keys, children) => {
+                for child in children.into_iter().skip(keys.partition_point(|k| k <= start)) {
+                    if result.len() >= limit {
+                        break;
+                    }
+                    self.scan(child, start, limit, result)?;
+                }
+            },
+        }
+        Ok(())
+    }
+
+    //makes the change in progress durable by pointing the superblock at its root
+    fn commit(&mut self) -> Result<(), ()> {
+        if self.written.is_empty() && self.replaced.is_empty() {
+            return Ok(());
+        }
+        self.write_superblock(self.seq + 1, self.working_root, self.working_height)?;
+        self.seq += 1;
+        self.root = self.working_root;
+        self.height = self.working_height;
+        self.free.extend(self.replaced.drain(..));
+        self.written.clear();
+        Ok(())
+    }
+
+    //drops the change in progress, the committed tree is untouched
+    fn abort(&mut self) {
+        self.free.extend(self.written.drain(..));
+        self.replaced.clear();
+        self.working_root = self.root;
+        self.working_height = self.height;
+    }
+
+    fn write_superblock(&mut self, seq: u64, root: u64, height: u32) -> Result<(), ()> {
+        let mut superblock = vec![0; 512];
+        superblock[0..8].copy_from_slice(SUPERBLOCK_MAGIC);
+        superblock[8..16].copy_from_slice(&seq.to_be_bytes());
+        superblock[16..24].copy_from_slice(&root.to_be_bytes());
+        superblock[24..32].copy_from_slice(&(height as u64).to_be_bytes());
+        superblock[32..40].copy_from_slice(&self.page_count.to_be_bytes());
+        let crc = crc32(0, &superblock[0..40]) as u64;
+        superblock[40..48].copy_from_slice(&crc.to_be_bytes());
+        //the pages have to be on the disk before the superblock that points to them
+        self.disk.flush()?;
+        self.disk.write_block((seq % 2) as usize, &superblock)?;
+        self.disk.flush()
+    }
+
+    //marks every page reachable from page, only internal pages are read
+    fn mark(&mut self, page: u64, height: u32, used: &mut BTreeSet<u64>) -> Result<(), ()> {
+        used.insert(page);
+        if height > 1 {
+            if let Node::Internal(_, children) = self.read(page)? {
+                for child in children {
+                    self.mark(child, height - 1, used)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct PersistentBTree<K, V, D: BlockDevice = CachedDrive> {
+    //one change at a time, readers wait for it to be committed
+    tree: Mutex<Tree<K, V, D>>,
+}
+
+impl<K: Ord + Clone + ToBeBytes, V: Clone + ToBeBytes, D: BlockDevice> PersistentBTree<K, V, D> {
+    //writes an empty tree, whatever was on disk is lost
+    fn format(disk: Disk<D>) -> Result<Self, ()> {
+        let mut tree = Tree {
+            disk,
+            root: 0,
+            height: 1,
+            seq: 0,
+            working_root: 0,
+            working_height: 1,
+            page_count: 0,
+            free: BTreeSet::new(),
+            replaced: Vec::new(),
+            written: Vec::new(),
+            _marker: PhantomData,
+        };
+        //an old superblock in slot 0 must not win over the new one going into slot 1
+        tree.disk.write_block(0, &[0; 512])?;
+        let root = tree.write(&Node::Leaf(Vec::new()))?;
+        tree.working_root = root;
+        tree.commit()?;
+        Ok(Self { tree: Mutex::new(tree) })
+    }
+
+    //(seq, root, height, page count) of the newest valid superblock
+    fn read_superblock(disk: &mut Disk<D>) -> Option<(u64, u64, u32, u64)> {
+        let mut newest = None;
+        for slot in 0..2u64 {
+            let superblock = match disk.read_bytes(slot as usize * 512, 512) {
+                Ok(superblock) => superblock,
+                Err(_) => continue,
+            };
+            let seq = read_u64(&superblock[8..16]);
+            if &superblock[0..8] != SUPERBLOCK_MAGIC
+                || seq % 2 != slot
+                || read_u64(&superblock[40..48]) != crc32(0, &superblock[0..40]) as u64 {
+                continue;
+            }
+            let found = (seq, read_u64(&superblock[16..24]), read_u64(&superblock[24..32]) as u32, read_u64(&superblock[32..40]));
+            if newest.map_or(true, |(newest_seq, _, _, _)| seq > newest_seq) {
+                newest = Some(found);
+            }
+        }
+        newest
+    }
+
+    //runs a change and commits it, a failed change leaves the committed tree as it was
+    fn change<R, F: FnOnce(&mut Tree<K, V, D>) -> Result<R, ()>>(&self, f: F) -> Result<R, ()> {
+        let mut tree = self.tree.lock();
+        let result = f(&mut tree).and_then(|r| tree.commit().map(|_| r));
+        if result.is_err() {
+            tree.abort();
+        }
+        result
+    }
+
+    pub fn height(&self) -> u32 {
+        self.tree.lock().height
+    }
+
+    //pages the tree uses right now
+    pub fn pages_in_use(&self) -> usize {
+        let tree = self.tree.lock();
+        tree.page_count as usize - tree.free.len()
+    }
+}
+
+impl<K: Ord + Clone + ToBeBytes, V: Clone + ToBeBytes, D: BlockDevice> PersistentMap for PersistentBTree<K, V, D> {
+    type Key = K;
+    type Value = V;
+    type Device = D;
+
+    fn new_on(disk: Disk<D>) -> Result<Self, ()> {
+        Self::format(disk)
+    }
+
+    //opens the tree the newest superblock points to, only the internal pages are read to
+    //find the free ones
+    fn build_from(mut disk: Disk<D>) -> Result<Self, ()> {
+        let (seq, root, height, page_count) = match Self::read_superblock(&mut disk) {
+            Some(superblock) => superblock,
+            //only a disk that was never written is formatted
+            None if disk.read_bytes(0, 1024).map_or(false, |slots| slots.iter().all(|&b| b == 0)) => return Self::format(disk),
+            None => {
+                serial_errorln!("btree: no valid superblock, the disk holds something else or is corrupt");
+                return Err(());
+            },
+        };
+        let mut tree = Tree {
+            disk,
+            root,
+            height,
+            seq,
+            working_root: root,
+            working_height: height,
+            page_count,
+            free: BTreeSet::new(),
+            replaced: Vec::new(),
+            written: Vec::new(),
+            _marker: PhantomData,
+        };
+        let mut used = BTreeSet::new();
+        if tree.mark(root, height, &mut used).is_err() {
+            serial_warnln!("btree: could not read every internal page, unreachable pages are not reused");
+            return Ok(Self { tree: Mutex::new(tree) });
+        }
+        tree.free = (0..page_count).filter(|page| !used.contains(page)).collect();
+        Ok(Self { tree: Mutex::new(tree) })
+    }
+
+    fn get(&self, key: &Self::Key) -> Result<Option<Self::Value>, ()> {
+        self.tree.lock().get(key)
+    }
+
+    fn insert_no_log(&self, key: &Self::Key, value: &Self::Value) -> bool {
+        self.insert(key, value).is_ok()
+    }
+
+    fn insert(&self, key: &Self::Key, value: &Self::Value) -> Result<bool,()> {
+        if entry_len(key, value) > MAX_ENTRY_BYTES {
+            return Err(());
+        }
+        self.change(|tree| tree.insert(key, value))?;
+        Ok(true)
+    }
+
+    fn remove_no_log(&self, key: &Self::Key) -> bool {
+        self.remove(key).unwrap_or(false)
+    }
+
+    fn remove(&self, key: &Self::Key) -> Result<bool,()> {
+        self.change(|tree| tree.remove(key))
+    }
+
+    //there is no log, every change is written into the tree
+    fn compact_logs(&self) -> Result<(),()> {
+        Ok(())
+    }
+
+    //every change goes into the same new tree, one superblock write commits all of them
+    fn commit_group(&self, writes: &[(&Self::Key, &Self::Value)], removes: &[&Self::Key]) -> Result<(),()> {
+        if writes.iter().any(|(key, value)| entry_len(*key, *value) > MAX_ENTRY_BYTES) {
+            return Err(());
+        }
+        self.change(|tree| {
+            for (key, value) in writes {
+                tree.insert(key, value)?;
+            }
+            for key in removes {
+                tree.remove(key)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn scan(&self, start: &Self::Key, limit: usize) -> Vec<(Self::Key, Self::Value)> {
+        let mut result = Vec::new();
+        let mut tree = self.tree.lock();
+        let root = tree.root;
+        if limit > 0 && tree.scan(root, start, limit, &mut result).is_err() {
+            serial_warnln!("btree: could not read a page");
+        }
+        result
+    }
+}
@@ -0,0 +1,301 @@
+extern crate alloc;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use crate::disk;
+use crate::disk::ata::BLOCK_SIZE;
+
+//blocks kept in memory unless set_capacity says otherwise, 64 KiB so the cache fits in the
+//kernel heap next to everything else
+pub const DEFAULT_CAPACITY: usize = 128;
+
+//(bus, drive, block)
+type BlockId = (u8, u8, u64);
+
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    //blocks dropped to make room
+    pub evictions: u64,
+    //dirty blocks written to the disk
+    pub writebacks: u64,
+}
+
+struct CachedBlock {
+    data: Box<[u8; BLOCK_SIZE]>,
+    //changed since it was read or last written back
+    dirty: bool,
+    //pinned blocks are never evicted
+    pins: usize,
+    //tick of the last use, key in the lru map
+    used: u64,
+}
+
+//write-back cache of disk blocks, writes only reach the disk on flush, sync or eviction,
+//so whoever needs a write to be durable, or written before another one, has to flush
+pub struct BlockCache {
+    blocks: BTreeMap<BlockId, CachedBlock>,
+    //last use -> block, the first entry is the least recently used block
+    lru: BTreeMap<u64, BlockId>,
+    tick: u64,
+    capacity: usize,
+    stats: CacheStats,
+}
+
+impl BlockCache {
+    pub const fn new(capacity: usize) -> Self {
+        Self { blocks: BTreeMap::new(), lru: BTreeMap::new(), tick: 0, capacity, stats: CacheStats { hits: 0, misses: 0, evictions: 0, writebacks: 0 } }
+    }
+
+    //reads the blocks from id on until buf is full, buf has to be a multiple of BLOCK_SIZE
+    pub fn read(&mut self, id: BlockId, buf: &mut [u8]) -> Result<(), ()> {
+        if buf.is_empty() || buf.len() % BLOCK_SIZE != 0 {
+            return Err(());
+        }
+        let (bus, drive, first) = id;
+        let count = buf.len() / BLOCK_SIZE;
+        let mut i = 0;
+        while i < count {
+            //blocks missing one after the other are read with one command
+            let missing = (i..count).take_while(|j| !self.blocks.contains_key(&(bus, drive, first + *j as u64))).count();
+            if missing == 0 {
+                let block = self.load((bus, drive, first + i as u64))?;
+                buf[i * BLOCK_SIZE..(i + 1) * BLOCK_SIZE].copy_from_slice(&block.data[..]);
+                i += 1;
+                continue;
+            }
+            let run = &mut buf[i * BLOCK_SIZE..(i + missing) * BLOCK_SIZE];
+            disk::read_blocks(bus, drive, first + i as u64, run)?;
+            self.stats.misses += missing as u64;
+            for (j, data) in run.chunks(BLOCK_SIZE).enumerate() {
+                self.make_room()?;
+                self.insert((bus, drive, first + (i + j) as u64), Box::new(data.try_into().unwrap()));
+            }
+            i += missing;
+        }
+        Ok(())
+    }
+
+    //only marks the blocks dirty, the disk is written on flush
+    pub fn write(&mut self, id: BlockId, buf: &[u8]) -> Result<(), ()> {
+        if buf.is_empty() || buf.len() % BLOCK_SIZE != 0 {
+            return Err(());
+        }
+        let (bus, drive, first) = id;
+        for (i, data) in buf.chunks(BLOCK_SIZE).enumerate() {
+            let id = (bus, drive, first + i as u64);
+            //the whole block is replaced so a miss does not have to read it first
+            if self.blocks.contains_key(&id) {
+                self.stats.hits += 1;
+            } else {
+                self.stats.misses += 1;
+                self.make_room()?;
+                self.insert(id, Box::new([0; BLOCK_SIZE]));
+            }
+            self.touch(id);
+            let block = self.blocks.get_mut(&id).unwrap();
+            block.data.copy_from_slice(data);
+            block.dirty = true;
+        }
+        Ok(())
+    }
+
+    //keeps the block in memory until unpin, pins nest
+    pub fn pin(&mut self, id: BlockId) -> Result<(), ()> {
+        self.load(id)?.pins += 1;
+        Ok(())
+    }
+
+    pub fn unpin(&mut self, id: BlockId) {
+        if let Some(block) = self.blocks.get_mut(&id) {
+            block.pins = block.pins.saturating_sub(1);
+        }
+    }
+
+    //writes back the dirty blocks of one drive in block order, one command for each run
+    //of dirty blocks next to each other
+    pub fn flush(&mut self, bus: u8, drive: u8) -> Result<(), ()> {
+        let dirty: Vec<u64> = self.blocks.range((bus, drive, 0)..=(bus, drive, u64::MAX))
+            .filter(|(_, block)| block.dirty)
+            .map(|(id, _)| id.2)
+            .collect();
+        let mut start = 0;
+        while start < dirty.len() {
+            let len = (start..dirty.len()).take_while(|i| dirty[*i] == dirty[start] + (*i - start) as u64).count();
+            let mut run = Vec::with_capacity(len * BLOCK_SIZE);
+            for block in &dirty[start..start + len] {
+                run.extend_from_slice(&self.blocks[&(bus, drive, *block)].data[..]);
+            }
+            disk::write_blocks(bus, drive, dirty[start], &run)?;
+            for block in &dirty[start..start + len] {
+                self.blocks.get_mut(&(bus, drive, *block)).unwrap().dirty = false;
+            }
+            self.stats.writebacks += len as u64;
+            start += len;
+        }
+        if !dirty.is_empty() {
+            disk::flush_blocks(bus, drive)?;
+        }
+        Ok(())
+    }
+
+    //writes back the dirty blocks of every drive
+    pub fn sync(&mut self) -> Result<(), ()> {
+        let mut drives: Vec<(u8, u8)> = self.blocks.iter()
+            .filter(|(_, block)| block.dirty)
+            .map(|(id, _)| (id.0, id.1))
+            .collect();
+        drives.dedup();
+        for (bus, drive) in drives {
+            self.flush(bus, drive)?;
+        }
+        Ok(())
+    }
+
+    //forgets the blocks of a drive that was written without the cache, dirty ones are written back first
+    pub fn invalidate(&mut self, bus: u8, drive: u8) -> Result<(), ()> {
+        self.flush(bus, drive)?;
+        let ids: Vec<BlockId> = self.blocks.range((bus, drive, 0)..=(bus, drive, u64::MAX))
+            .filter(|(_, block)| block.pins == 0)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in ids {
+            self.remove(id);
+        }
+        Ok(())
+    }
+
+    //evicts blocks down to the new capacity if they are not pinned
+    pub fn set_capacity(&mut self, capacity: usize) -> Result<(), ()> {
+        self.capacity = capacity;
+        while self.blocks.len() > self.capacity {
+            if !self.evict()? {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    //blocks in memory right now
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    //the cached block, read from the disk on a miss
+    fn load(&mut self, id: BlockId) -> Result<&mut CachedBlock, ()> {
+        if self.blocks.contains_key(&id) {
+            self.stats.hits += 1;
+        } else {
+            self.stats.misses += 1;
+            let mut data = Box::new([0; BLOCK_SIZE]);
+            disk::read_blocks(id.0, id.1, id.2, &mut data[..])?;
+            self.make_room()?;
+            self.insert(id, data);
+        }
+        self.touch(id);
+        Ok(self.blocks.get_mut(&id).unwrap())
+    }
+
+    fn insert(&mut self, id: BlockId, data: Box<[u8; BLOCK_SIZE]>) {
+        self.tick += 1;
+        self.lru.insert(self.tick, id);
+        self.blocks.insert(id, CachedBlock { data, dirty: false, pins: 0, used: self.tick });
+    }
+
+    fn remove(&mut self, id: BlockId) {
+        if let Some(block) = self.blocks.remove(&id) {
+            self.lru.remove(&block.used);
+        }
+    }
+
+    //moves the block to the back of the lru order
+    fn touch(&mut self, id: BlockId) {
+        self.tick += 1;
+        let block = self.blocks.get_mut(&id).unwrap();
+        self.lru.remove(&block.used);
+        block.used = self.tick;
+        self.lru.insert(self.tick, id);
+    }
+
+    //evicts until there is room for one more block, with everything pinned the cache grows
+    fn make_room(&mut self) -> Result<(), ()> {
+        while self.blocks.len() >= self.capacity {
+            if !self.evict()? {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    //drops the least recently used block that is not pinned, false if there is none
+    fn evict(&mut self) -> Result<bool, ()> {
+        let id = match self.lru.values().find(|id| self.blocks[*id].pins == 0) {
+            Some(id) => *id,
+            None => return Ok(false),
+        };
+        if self.blocks[&id].dirty {
+            self.write_back(id)?;
+        }
+        self.remove(id);
+        self.stats.evictions += 1;
+        Ok(true)
+    }
+
+    fn write_back(&mut self, id: BlockId) -> Result<(), ()> {
+        let block = self.blocks.get_mut(&id).unwrap();
+        disk::write_blocks(id.0, id.1, id.2, &block.data[..])?;
+        block.dirty = false;
+        self.stats.writebacks += 1;
+        Ok(())
+    }
+}
+
+//one cache for every drive, taken before the ata bus and hba locks
+lazy_static! {
+    pub static ref CACHE: Mutex<BlockCache> = Mutex::new(BlockCache::new(DEFAULT_CAPACITY));
+}
+
+//read blocks through the cache
+pub fn read(bus: u8, drive: u8, block: u64, buf: &mut [u8]) -> Result<(), ()> {
+    CACHE.lock().read((bus, drive, block), buf)
+}
+
+//write blocks to the cache, they reach the disk on flush
+pub fn write(bus: u8, drive: u8, block: u64, buf: &[u8]) -> Result<(), ()> {
+    CACHE.lock().write((bus, drive, block), buf)
+}
+
+pub fn pin(bus: u8, drive: u8, block: u64) -> Result<(), ()> {
+    CACHE.lock().pin((bus, drive, block))
+}
+
+pub fn unpin(bus: u8, drive: u8, block: u64) {
+    CACHE.lock().unpin((bus, drive, block))
+}
+
+pub fn flush(bus: u8, drive: u8) -> Result<(), ()> {
+    CACHE.lock().flush(bus, drive)
+}
+
+pub fn sync() -> Result<(), ()> {
+    CACHE.lock().sync()
+}
+
+pub fn invalidate(bus: u8, drive: u8) -> Result<(), ()> {
+    CACHE.lock().invalidate(bus, drive)
+}
+
+pub fn set_capacity(capacity: usize) -> Result<(), ()> {
+    CACHE.lock().set_capacity(capacity)
+}
+
+pub fn stats() -> CacheStats {
+    CACHE.lock().stats()
+}
@@ -1,29 +1,86 @@
 extern crate alloc;
 use alloc::vec::Vec;
-use crate::disk::ata;
-use crate::disk::ata::write;
-use crate::disk::ata::read;
-use crate::disk::ata::Drive;
+use crate::disk::block_device::{BlockDevice, CachedDrive};
 
 //disk struct to store state of current disk
-pub struct Disk {
-    bus: u8,
-    drive: u8,
+//clones share the device but append at their own position
+#[derive(Clone)]
+pub struct Disk<D: BlockDevice = CachedDrive> {
+    device: D,
     current_block: usize,
     current_address_in_block: usize,
+    //size of the drive in 512 byte blocks, 0 if it could not be identified
+    blocks: usize,
 }
 
 impl Disk {
     //makes a new disk
     pub fn new(bus: u8, drive:u8) -> Self {
-        ata::init();
-        Drive::open(bus, drive);
+        Self::with_device(CachedDrive::open(bus, drive))
+    }
+
+    //the disk the key value store lives on, virtio when the machine has a virtio disk
+    pub fn kv() -> Self {
+        Self::with_device(CachedDrive::default())
+    }
+}
+
+impl<D: BlockDevice> Disk<D> {
+    //a disk on device, which has to have 512 byte blocks
+    pub fn with_device(device: D) -> Self {
+        let blocks = if device.block_size() == 512 { device.block_count() as usize } else { 0 };
         Self {
-            bus,
-            drive,
+            device,
             current_block: 0,
             current_address_in_block: 0,
+            blocks,
+        }
+    }
+
+    //size of the disk in bytes
+    pub fn size(&self) -> usize {
+        self.blocks * 512
+    }
+
+    //reads len bytes starting at byte offset
+    pub fn read_bytes(&mut self, offset: usize, len: usize) -> Result<Vec<u8>, ()> {
+        let first_block = offset / 512;
+        let last_block = (offset + len + 511) / 512;
+        if first_block == last_block {
+            return Ok(Vec::new());
         }
+        //all the blocks at once so the ones not cached are read with as few commands as possible
+        let mut result_buf = [0; 512].repeat(last_block - first_block);
+        self.device.read_blocks(first_block as u64, &mut result_buf)?;
+        let start = offset % 512;
+        Ok(result_buf[start..start+len].to_vec())
+    }
+
+    //writes one whole block
+    pub fn write_block(&mut self, block: usize, buf: &[u8]) -> Result<(), ()> {
+        self.device.write_blocks(block as u64, buf)
+    }
+
+    //writes buf starting at block, the last block is padded with zeros
+    //unlike append_to_disk nothing after the data is touched
+    pub fn write_blocks(&mut self, block: usize, buf: &[u8]) -> Result<(), ()> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let mut padded_buf = buf.to_vec();
+        padded_buf.resize((buf.len() + 511) / 512 * 512, 0);
+        self.device.write_blocks(block as u64, &padded_buf)
+    }
+
+    //makes the writes durable, for a drive that is writing back what it has dirty in the cache
+    pub fn flush(&mut self) -> Result<(), ()> {
+        self.device.flush()
+    }
+
+    //moves the end of the data, the next append starts at byte offset
+    pub fn set_end(&mut self, offset: usize) {
+        self.current_block = offset / 512;
+        self.current_address_in_block = offset % 512;
     }
     //overwrites what ever is on disk currently
     //use to save whole map / compact log
@@ -34,7 +91,7 @@ impl Disk {
         for i in 0..(length / 512) {
             let start_index = i * 512;
             let end_index = (i + 1) * 512;
-            write(self.bus, self.drive, i as u32, &buf_to_write[start_index..end_index])?;
+            self.device.write_blocks(i as u64, &buf_to_write[start_index..end_index])?;
         }
         //save the current block and block adress so we can add logs
         self.current_block = length / 512;
@@ -47,12 +104,12 @@ impl Disk {
             let mut padded_last_buf: Vec<u8> = [0; 512].to_vec();
             padded_last_buf[..last_block_size].copy_from_slice(&last_buf);
     
-            write(self.bus, self.drive, self.current_block as u32, &padded_last_buf)?;
+            self.device.write_blocks(self.current_block as u64, &padded_last_buf)?;
             self.current_address_in_block = self.current_address_in_block + length %512;
         }
         //write a whole block of 0 to signify end of data
         let empty_buf = [0; 512].to_vec();
-        write(self.bus, self.drive, (self.current_block +1) as u32, &empty_buf)?;
+        self.device.write_blocks((self.current_block +1) as u64, &empty_buf)?;
         Ok(())
     }
 
@@ -70,7 +127,7 @@ impl Disk {
             //give the buf a place to read into
             result_buf.extend_from_slice(&[0; 512]);
             //read current block
-            read(self.bus, self.drive, block as u32, &mut result_buf[block*512..(block+1)*512])?;
+            self.device.read_blocks(block as u64, &mut result_buf[block*512..(block+1)*512])?;
             //if block is not all zerso keep reading
             keep_reading = result_buf[block*512..(block*512)+1].iter().all(|&b| b != 0);
             block = block +1;
@@ -100,7 +157,7 @@ impl Disk {
             //give the buf a place to read into
             result_buf.extend_from_slice(&[0; 512]);
             //read current block
-            read(self.bus, self.drive, block as u32, &mut result_buf[block*512..(block+1)*512])?;
+            self.device.read_blocks(block as u64, &mut result_buf[block*512..(block+1)*512])?;
             //if block is not all zeros keep reading
             keep_reading = result_buf[block*512..(block*512)+1].iter().all(|&b| b != 0);
             block = block +1;
@@ -111,9 +168,9 @@ impl Disk {
     //appends to the end of the disk
     //useful for adding logs for changes to the map
     pub fn append_to_disk(&mut self, mut buf_to_write: Vec<u8>) -> Result<(), ()> {
-        //get the data from the current block
+        //get the data from the current block, usually still in the cache
         let mut result_buf = [0; 512].to_vec();
-        read(self.bus, self.drive, self.current_block as u32, &mut result_buf[0..512])?;
+        self.device.read_blocks(self.current_block as u64, &mut result_buf[0..512])?;
         let current_block_data = result_buf[0..(self.current_address_in_block)].to_vec();
         //adds the data from the current block to the front of buf_to_write
         buf_to_write.splice(0..0,current_block_data);
@@ -124,7 +181,7 @@ impl Disk {
         for i in 0..(length / 512) {
             let start_index = i * 512;
             let end_index = (i + 1) * 512;
-            write(self.bus, self.drive, (i+self.current_block) as u32, &buf_to_write[start_index..end_index])?;
+            self.device.write_blocks((i+self.current_block) as u64, &buf_to_write[start_index..end_index])?;
         }
         //save the current block and block adress so we can add logs
         self.current_block = self.current_block + (length / 512);
@@ -137,12 +194,12 @@ impl Disk {
             let mut padded_last_buf: Vec<u8> = [0; 512].to_vec();
             padded_last_buf[..last_block_size].copy_from_slice(&last_buf);
     
-            write(self.bus, self.drive, self.current_block as u32, &padded_last_buf)?;
+            self.device.write_blocks(self.current_block as u64, &padded_last_buf)?;
             self.current_address_in_block = self.current_address_in_block + length %512;
         }
         //write a whole block of 0 to signify end of data
         let empty_buf = [0; 512].to_vec();
-        write(self.bus, self.drive, (self.current_block +1) as u32, &empty_buf)?;
+        self.device.write_blocks((self.current_block +1) as u64, &empty_buf)?;
         Ok(())
     }
 }
\ No newline at end of file
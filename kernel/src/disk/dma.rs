@@ -0,0 +1,66 @@
+extern crate alloc;
+use alloc::alloc::{alloc_zeroed, dealloc};
+use core::alloc::Layout;
+use crate::memory::paging::{ActivePageTable, PAGE_SIZE};
+
+//zeroed heap memory a controller reads and writes, physically contiguous only within a page so
+//anything bigger is aligned to not cross more pages than it has to
+pub struct Dma {
+    ptr: *mut u8,
+    layout: Layout,
+}
+
+unsafe impl Send for Dma {}
+
+impl Dma {
+    //None when the heap has no room for it
+    pub fn new(size: usize, align: usize) -> Option<Self> {
+        let layout = Layout::from_size_align(size, align).unwrap();
+        let ptr = unsafe { alloc_zeroed(layout) };
+        if ptr.is_null() {
+            return None;
+        }
+        Some(Self { ptr, layout })
+    }
+
+    pub fn bytes(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.ptr, self.layout.size()) }
+    }
+
+    //virtual address of byte offset
+    pub fn address(&self, offset: usize) -> usize {
+        self.ptr as usize + offset
+    }
+
+    //physical address of byte offset
+    pub fn physical(&self, offset: usize) -> u64 {
+        let page_table = unsafe { ActivePageTable::new() };
+        page_table.translate(self.address(offset)).expect("dma memory is not mapped") as u64
+    }
+
+    //whether the pages follow each other in physical memory too
+    pub fn is_contiguous(&self) -> bool {
+        let start = self.physical(0);
+        (PAGE_SIZE..self.layout.size()).step_by(PAGE_SIZE).all(|offset| self.physical(offset) == start + offset as u64)
+    }
+
+    //(physical address, length) of the parts of len bytes from offset that do not cross a page
+    pub fn segments(&self, offset: usize, len: usize) -> impl Iterator<Item = (u64, usize)> + '_ {
+        let mut at = offset;
+        core::iter::from_fn(move || {
+            if at >= offset + len {
+                return None;
+            }
+            let part = (PAGE_SIZE - self.address(at) % PAGE_SIZE).min(offset + len - at);
+            let segment = (self.physical(at), part);
+            at += part;
+            Some(segment)
+        })
+    }
+}
+
+impl Drop for Dma {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.ptr, self.layout) }
+    }
+}
@@ -0,0 +1,356 @@
+extern crate alloc;
+use alloc::vec::Vec;
+use crate::common::hash::crc32;
+use crate::disk::block_device::{BlockDevice, CachedDrive};
+use crate::disk::disk_api::Disk;
+
+//the first block of the log's area is the superblock: magic, version, where the active
+//region starts, the sequence number of its first record and a crc32 over all of it
+//the rest of the area is split into two regions, compaction writes the live data into the
+//inactive one and then rewrites the superblock, a single block write, to switch over
+const MAGIC: &[u8; 8] = b"KVOSLOG\0";
+pub const VERSION: u64 = 2;
+const SUPERBLOCK_SIZE: usize = 512;
+
+//every record has a header: tag, sequence number, payload length and a crc32 over all of it
+//the last record of a region is always an end record with an empty payload
+const RECORD: &[u8; 8] = b"KVRECORD";
+const END: &[u8; 8] = b"KVLOGEND";
+pub const HEADER_SIZE: usize = 32;
+//longer payloads are checked against their crc this many bytes at a time before they are
+//read whole, so a damaged length cannot make recovery allocate most of the region
+const CHECK_CHUNK: usize = 16 << 10;
+
+//why recovery stopped before an end record
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Corruption {
+    //neither a record nor an end record, e.g. garbage or a sector that was never written
+    BadTag,
+    //the length runs past the end of the region
+    BadLength,
+    //torn or damaged record
+    BadChecksum,
+    //a valid record left over from an older log
+    BadSequence,
+    ReadError,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LogEnd {
+    //recovery reached the end record
+    Clean,
+    //the superblock area was all zeros, the disk was formatted
+    Formatted,
+    //the record at end_offset and everything after it were dropped
+    Corrupt(Corruption),
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct RecoveryReport {
+    //valid records handed back to the caller
+    pub records: u64,
+    pub end: LogEnd,
+    //byte offset where recovery stopped
+    pub end_offset: usize,
+    //groups of changes without a commit marker, filled in by the map
+    pub discarded_groups: u64,
+}
+
+pub struct Log<D: BlockDevice = CachedDrive> {
+    disk: Disk<D>,
+    //byte offset and length of the area the log lives in
+    base: usize,
+    span: usize,
+    first_seq: u64,
+    next_seq: u64,
+    //byte offsets of the active region and of its end record
+    start: usize,
+    limit: usize,
+    end: usize,
+    //payloads appended while a compaction is copying the live data
+    captured: Option<Vec<Vec<u8>>>,
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    u64::from_be_bytes([
+        bytes[0], bytes[1], bytes[2], bytes[3],
+        bytes[4], bytes[5], bytes[6], bytes[7],
+    ])
+}
+
+//crc of the header fields the payload is added to
+fn checksum_start(tag: &[u8], seq: u64, len: usize) -> u32 {
+    let crc = crc32(0, tag);
+    let crc = crc32(crc, &seq.to_be_bytes());
+    crc32(crc, &(len as u64).to_be_bytes())
+}
+
+fn checksum(tag: &[u8], seq: u64, payload: &[u8]) -> u64 {
+    crc32(checksum_start(tag, seq, payload.len()), payload) as u64
+}
+
+//the len byte payload of the record at offset with header, None if it does not match the crc
+fn read_payload<D: BlockDevice>(disk: &mut Disk<D>, offset: usize, header: &[u8], len: usize) -> Result<Option<Vec<u8>>, ()> {
+    let tag = &header[0..8];
+    let seq = read_u64(&header[8..16]);
+    let crc = read_u64(&header[24..32]);
+    if len > CHECK_CHUNK {
+        let mut running = checksum_start(tag, seq, len);
+        for at in (0..len).step_by(CHECK_CHUNK) {
+            running = crc32(running, &disk.read_bytes(offset + HEADER_SIZE + at, CHECK_CHUNK.min(len - at))?);
+        }
+        if running as u64 != crc {
+            return Ok(None);
+        }
+    }
+    let payload = disk.read_bytes(offset + HEADER_SIZE, len)?;
+    if checksum(tag, seq, &payload) != crc {
+        return Ok(None);
+    }
+    Ok(Some(payload))
+}
+
+fn frame(request: &mut Vec<u8>, tag: &[u8; 8], seq: u64, payload: &[u8]) {
+    request.extend_from_slice(tag);
+    request.extend_from_slice(&seq.to_be_bytes());
+    request.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+    request.extend_from_slice(&checksum(tag, seq, payload).to_be_bytes());
+    request.extend_from_slice(payload);
+}
+
+//(start, limit) of both regions of the area at base
+fn regions(base: usize, span: usize) -> [(usize, usize); 2] {
+    let blocks = span.saturating_sub(SUPERBLOCK_SIZE) / 512 / 2;
+    let first = base + SUPERBLOCK_SIZE;
+    let second = first + blocks * 512;
+    [(first, second), (second, second + blocks * 512)]
+}
+
+impl<D: BlockDevice> Log<D> {
+    //writes a new empty log over whatever is on disk
+    pub fn create(disk: Disk<D>, first_seq: u64) -> Result<Self, ()> {
+        let span = disk.size();
+        Self::create_at(disk, 0, span, first_seq)
+    }
+
+    //same as create but only uses span bytes starting at base, which has to be block aligned
+    pub fn create_at(disk: Disk<D>, base: usize, span: usize, first_seq: u64) -> Result<Self, ()> {
+        let (start, limit) = regions(base, span)[0];
+        let mut log = Self::empty_region(disk, base, span, start, limit, first_seq)?;
+        log.write_superblock()?;
+        Ok(log)
+    }
+
+    //an empty log in the region at start, not active until the superblock points to it
+    fn empty_region(mut disk: Disk<D>, base: usize, span: usize, start: usize, limit: usize, first_seq: u64) -> Result<Self, ()> {
+        let mut request = Vec::new();
+        frame(&mut request, END, first_seq, &[]);
+        disk.set_end(start);
+        disk.append_to_disk(request)?;
+        disk.set_end(start);
+        Ok(Self { disk, base, span, first_seq, next_seq: first_seq, start, limit, end: start, captured: None })
+    }
+
+    fn write_superblock(&mut self) -> Result<(), ()> {
+        let mut superblock = [0; SUPERBLOCK_SIZE].to_vec();
+        superblock[0..8].copy_from_slice(MAGIC);
+        superblock[8..16].copy_from_slice(&VERSION.to_be_bytes());
+        superblock[16..24].copy_from_slice(&(self.start as u64).to_be_bytes());
+        superblock[24..32].copy_from_slice(&self.first_seq.to_be_bytes());
+        let crc = crc32(0, &superblock[0..32]) as u64;
+        superblock[32..40].copy_from_slice(&crc.to_be_bytes());
+        self.disk.write_block(self.base / 512, &superblock)?;
+        self.disk.flush()
+    }
+
+    //reads the payloads of every valid record up to the end record or the first bad one
+    pub fn open(disk: Disk<D>) -> Result<(Self, Vec<Vec<u8>>, RecoveryReport), ()> {
+        let span = disk.size();
+        Self::open_at(disk, 0, span)
+    }
+
+    //opens the log create_at wrote at base
+    pub fn open_at(disk: Disk<D>, base: usize, span: usize) -> Result<(Self, Vec<Vec<u8>>, RecoveryReport), ()> {
+        let mut payloads = Vec::new();
+        let (log, report) = Self::open_at_with(disk, base, span, |_, _, payload| {
+            payloads.push(payload);
+            None
+        })?;
+        Ok((log, payloads, report))
+    }
+
+    //like open but hands each payload to f as soon as it is read instead of keeping all of them
+    pub fn open_with<F: FnMut(u64, usize, Vec<u8>) -> Option<(usize, u64)>>(disk: Disk<D>, f: F) -> Result<(Self, RecoveryReport), ()> {
+        let span = disk.size();
+        Self::open_at_with(disk, 0, span, f)
+    }
+
+    //f gets the seq of the record and the byte offset of its payload, it can return the
+    //offset and seq of a later record to skip the records in between without reading them
+    pub fn open_at_with<F: FnMut(u64, usize, Vec<u8>) -> Option<(usize, u64)>>(mut disk: Disk<D>, base: usize, span: usize, mut f: F) -> Result<(Self, RecoveryReport), ()> {
+        let superblock = disk.read_bytes(base, SUPERBLOCK_SIZE).map_err(|_| {
+            serial_errorln!("log: cannot read the superblock at {}", base);
+        })?;
+        //only a disk that was never written is formatted, anything else is left for the user
+        if superblock.iter().all(|&b| b == 0) {
+            let log = Self::create_at(disk, base, span, 1)?;
+            let report = RecoveryReport { records: 0, end: LogEnd::Formatted, end_offset: log.start, discarded_groups: 0 };
+            return Ok((log, report));
+        }
+        if &superblock[0..8] != MAGIC {
+            serial_errorln!("log: corrupt superblock at {}", base);
+            return Err(());
+        }
+        //version 1 logs fill the whole area and leave no region free to compact into, so they
+        //cannot be taken over in place, the version is checked first as they have no crc either
+        let version = read_u64(&superblock[8..16]);
+        if version != VERSION {
+            serial_errorln!("log: the log at {} is version {}, only version {} can be opened, format the disk to start over", base, version, VERSION);
+            return Err(());
+        }
+        if read_u64(&superblock[32..40]) != crc32(0, &superblock[0..32]) as u64 {
+            serial_errorln!("log: corrupt superblock at {}", base);
+            return Err(());
+        }
+        let (start, limit) = match regions(base, span).into_iter().find(|(start, _)| read_u64(&superblock[16..24]) == *start as u64) {
+            Some(region) => region,
+            None => {
+                serial_errorln!("log: the superblock at {} does not describe a log of {} bytes", base, span);
+                return Err(());
+            },
+        };
+        let first_seq = read_u64(&superblock[24..32]);
+        let mut seq = first_seq;
+        let mut offset = start;
+        let mut records = 0;
+        let end = loop {
+            let header = match disk.read_bytes(offset, HEADER_SIZE) {
+                Ok(header) => header,
+                Err(_) => break LogEnd::Corrupt(Corruption::ReadError),
+            };
+            let tag = &header[0..8];
+            if tag != RECORD && tag != END {
+                break LogEnd::Corrupt(Corruption::BadTag);
+            }
+            let len = read_u64(&header[16..24]) as usize;
+            if len > limit.saturating_sub(offset + HEADER_SIZE) {
+                break LogEnd::Corrupt(Corruption::BadLength);
+            }
+            let payload = match read_payload(&mut disk, offset, &header, len) {
+                Ok(Some(payload)) => payload,
+                Ok(None) => break LogEnd::Corrupt(Corruption::BadChecksum),
+                Err(_) => break LogEnd::Corrupt(Corruption::ReadError),
+            };
+            if read_u64(&header[8..16]) != seq {
+                break LogEnd::Corrupt(Corruption::BadSequence);
+            }
+            if tag == END {
+                break LogEnd::Clean;
+            }
+            records += 1;
+            match f(seq, offset + HEADER_SIZE, payload) {
+                Some((next, next_seq)) if next > offset && next < limit => {
+                    offset = next;
+                    seq = next_seq;
+                },
+                _ => {
+                    offset += HEADER_SIZE + len;
+                    seq += 1;
+                },
+            }
+        };
+        let mut log = Self { disk, base, span, first_seq, next_seq: seq, start, limit, end: offset, captured: None };
+        if end != LogEnd::Clean {
+            //cut the log off at the last valid record
+            log.append(Vec::new())?;
+        }
+        let report = RecoveryReport { records, end, end_offset: offset, discarded_groups: 0 };
+        Ok((log, report))
+    }
+
+    //writes each payload as its own record, all of them with one disk write
+    pub fn append(&mut self, payloads: Vec<Vec<u8>>) -> Result<(), ()> {
+        let mut request = Vec::new();
+        let mut seq = self.next_seq;
+        for payload in payloads.iter() {
+            frame(&mut request, RECORD, seq, payload);
+            seq += 1;
+        }
+        let end = self.end + request.len();
+        frame(&mut request, END, seq, &[]);
+        //append_to_disk also clears the block after the data
+        if end + HEADER_SIZE + 2 * 512 > self.limit {
+            return Err(());
+        }
+        //the new records go over the old end record
+        self.disk.set_end(self.end);
+        self.disk.append_to_disk(request)?;
+        //a record counts as written once it is on the disk, not just in the cache
+        self.disk.flush()?;
+        self.next_seq = seq;
+        self.end = end;
+        self.disk.set_end(self.end);
+        if let Some(captured) = self.captured.as_mut() {
+            captured.extend(payloads);
+        }
+        Ok(())
+    }
+
+    //starts copying everything appended from now on and returns an empty log in the
+    //inactive region, the caller fills it with the live data without holding this log
+    pub fn begin_compaction(&mut self) -> Result<Self, ()> {
+        let (start, limit) = regions(self.base, self.span).into_iter().find(|(start, _)| *start != self.start).unwrap();
+        //numbers above anything written to the other region before, so none of its old
+        //records can pass as part of the new log
+        let target = Self::empty_region(self.disk.clone(), self.base, self.span, start, limit, self.next_seq)?;
+        self.captured = Some(Vec::new());
+        Ok(target)
+    }
+
+    //appends what was captured since begin_compaction to target and switches over to it,
+    //until the superblock is written a crash recovers the old region
+    pub fn finish_compaction(&mut self, mut target: Self) -> Result<(), ()> {
+        let captured = self.captured.take().unwrap_or_default();
+        target.append(captured)?;
+        target.write_superblock()?;
+        *self = target;
+        Ok(())
+    }
+
+    //stops capturing after a failed compaction, the old region stays active
+    pub fn cancel_compaction(&mut self) {
+        self.captured = None;
+    }
+
+    //replaces the whole log with payloads
+    pub fn rewrite(&mut self, payloads: Vec<Vec<u8>>) -> Result<(), ()> {
+        let mut target = self.begin_compaction()?;
+        let result = target.append(payloads).and_then(|_| self.finish_compaction(target));
+        self.cancel_compaction();
+        result
+    }
+
+    //byte offset the next record goes to
+    pub fn end(&self) -> usize {
+        self.end
+    }
+
+    //byte offsets the payloads get if they are appended next
+    pub fn offsets(&self, payloads: &[Vec<u8>]) -> Vec<usize> {
+        let mut offset = self.end;
+        payloads.iter().map(|payload| {
+            offset += HEADER_SIZE + payload.len();
+            offset - payload.len()
+        }).collect()
+    }
+
+    //bytes in use by the active region
+    pub fn size(&self) -> usize {
+        self.end - self.start + HEADER_SIZE
+    }
+
+    //bytes the active region can hold
+    pub fn capacity(&self) -> usize {
+        self.limit - self.start
+    }
+}
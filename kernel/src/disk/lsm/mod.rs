@@ -0,0 +1,641 @@
+extern crate alloc;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::mem;
+use spin::mutex::Mutex;
+use crate::common::hash::crc32;
+use crate::disk::block_device::{BlockDevice, CachedDrive};
+use crate::disk::disk_api::Disk;
+use crate::disk::log::{Log, LogEnd, RecoveryReport};
+use crate::disk::persistentmap::{decode_record, group_record, insert_record, remove_record, PersistentMap, ToBeBytes};
+
+pub mod sstable;
+use sstable::{Change, MergeIter, Source, Table, TableBuilder, TableIter};
+
+//the disk holds the wal, two manifest slots and then the sstables
+//the wal only covers what is in the memtable and is emptied whenever the memtable is written
+//out, so boot reads the manifest, the table indexes and a short wal instead of all history
+const WAL_BYTES: usize = 4 << 20;
+const MANIFEST_SLOT_BYTES: usize = 64 << 10;
+const TABLES_START: usize = WAL_BYTES + 2 * MANIFEST_SLOT_BYTES;
+
+//a manifest lists every table with its level and goes into slot seq % 2, so a torn write
+//leaves the previous one intact: magic, seq, payload length, crc32 over seq and payload
+const MANIFEST_MAGIC: &[u8; 8] = b"KVLSMMAN";
+const MANIFEST_HEADER: usize = 32;
+
+//level 0 holds memtables as they were written out and its tables may overlap,
+//every deeper level is sorted and its tables do not overlap
+pub const LEVELS: usize = 4;
+
+#[derive(Clone, Copy, Debug)]
+pub struct LsmPolicy {
+    //the memtable is written out as level 0 tables at this size
+    pub memtable_bytes: usize,
+    //tables are cut once they reach this size
+    pub table_bytes: usize,
+    //level 0 is merged into level 1 once it has this many tables
+    pub level0_tables: usize,
+    //level 1 may hold this many bytes, every deeper level level_multiplier times more
+    pub level1_bytes: usize,
+    pub level_multiplier: usize,
+}
+
+impl LsmPolicy {
+    pub const fn new(memtable_bytes: usize, table_bytes: usize, level0_tables: usize, level1_bytes: usize, level_multiplier: usize) -> Self {
+        Self { memtable_bytes, table_bytes, level0_tables, level1_bytes, level_multiplier }
+    }
+
+    fn level_limit(&self, level: usize) -> usize {
+        let mut limit = self.level1_bytes;
+        for _ in 1..level {
+            limit = limit.saturating_mul(self.level_multiplier);
+        }
+        limit
+    }
+}
+
+impl Default for LsmPolicy {
+    fn default() -> Self {
+        Self::new(32 << 10, 32 << 10, 4, 128 << 10, 10)
+    }
+}
+
+//the newest changes, None is a remove that has to hide older values in the tables
+struct Memtable<K, V> {
+    entries: BTreeMap<K, Option<V>>,
+    bytes: usize,
+}
+
+impl<K: Ord + ToBeBytes, V: ToBeBytes> Memtable<K, V> {
+    fn put(&mut self, key: K, value: Option<V>) {
+        let key_len = key.to_be_bytes().as_ref().len();
+        let value_len = |value: &Option<V>| value.as_ref().map_or(0, |v| v.to_be_bytes().as_ref().len());
+        self.bytes += 8 + key_len + value_len(&value);
+        if let Some(old) = self.entries.insert(key, value) {
+            self.bytes -= 8 + key_len + value_len(&old);
+        }
+    }
+}
+
+//the tables of every level, level 0 newest first
+//replaced as a whole so readers can keep using the one they started with
+struct Version<K> {
+    levels: Vec<Vec<Arc<Table<K>>>>,
+}
+
+struct Tables<K> {
+    current: Arc<Version<K>>,
+    //tables no longer in current whose blocks may still be read through an older version
+    retired: Vec<Arc<Table<K>>>,
+    //(start, length) of tables written but not installed yet
+    reserved: Vec<(usize, usize)>,
+    next_id: u64,
+    manifest_seq: u64,
+}
+
+pub struct LsmMap<K, V, D: BlockDevice = CachedDrive> {
+    disk: Disk<D>,
+    wal: Mutex<Log<D>>,
+    //writers hold the memtable across their wal append so both see changes in the same order
+    memtable: Mutex<Memtable<K, V>>,
+    tables: Mutex<Tables<K>>,
+    //held by whoever is merging levels
+    compacting: Mutex<()>,
+    policy: Mutex<LsmPolicy>,
+    //what build_from_disk found in the wal, None for a map that formatted the disk
+    recovery: Option<RecoveryReport>,
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    u64::from_be_bytes([
+        bytes[0], bytes[1], bytes[2], bytes[3],
+        bytes[4], bytes[5], bytes[6], bytes[7],
+    ])
+}
+
+fn manifest_block(seq: u64) -> usize {
+    (WAL_BYTES + (seq % 2) as usize * MANIFEST_SLOT_BYTES) / 512
+}
+
+//whether both manifest slots are all zeros, a disk that never held a map
+fn manifest_blank<D: BlockDevice>(disk: &mut Disk<D>) -> bool {
+    (0..2u64).all(|slot| disk.read_bytes(manifest_block(slot) * 512, MANIFEST_HEADER)
+        .map_or(false, |header| header.iter().all(|&b| b == 0)))
+}
+
+//(seq, next table id, (id, level, start, length) of every table) of the newest valid manifest
+fn read_manifest<D: BlockDevice>(disk: &mut Disk<D>) -> Option<(u64, u64, Vec<(u64, usize, usize, usize)>)> {
+    let mut newest: Option<(u64, Vec<u8>)> = None;
+    for slot in 0..2u64 {
+        let header = match disk.read_bytes(manifest_block(slot) * 512, MANIFEST_HEADER) {
+            Ok(header) => header,
+            Err(_) => continue,
+        };
+        let seq = read_u64(&header[8..16]);
+        let len = read_u64(&header[16..24]) as usize;
+        if &header[0..8] != MANIFEST_MAGIC || seq % 2 != slot || len > MANIFEST_SLOT_BYTES - MANIFEST_HEADER {
+            continue;
+        }
+        let payload = match disk.read_bytes(manifest_block(slot) * 512 + MANIFEST_HEADER, len) {
+            Ok(payload) => payload,
+            Err(_) => continue,
+        };
+        if crc32(crc32(0, &seq.to_be_bytes()), &payload) as u64 != read_u64(&header[24..32]) {
+            continue;
+        }
+        if newest.as_ref().map_or(true, |(newest_seq, _)| seq > *newest_seq) {
+            newest = Some((seq, payload));
+        }
+    }
+    let (seq, payload) = newest?;
+    let next_id = read_u64(payload.get(0..8)?);
+    let count = read_u64(payload.get(8..16)?) as usize;
+    let mut tables = Vec::new();
+    for i in 0..count {
+        let table = payload.get(16 + i * 32..16 + (i + 1) * 32)?;
+        tables.push((read_u64(&table[0..8]), read_u64(&table[8..16]) as usize, read_u64(&table[16..24]) as usize, read_u64(&table[24..32]) as usize));
+    }
+    Some((seq, next_id, tables))
+}
+
+impl<K: Ord + Clone + ToBeBytes + 'static, V: Clone + ToBeBytes + 'static, D: BlockDevice + 'static> LsmMap<K, V, D> {
+    fn with_parts(disk: Disk<D>, wal: Log<D>, version: Version<K>, next_id: u64, manifest_seq: u64) -> Self {
+        Self {
+            disk,
+            wal: Mutex::new(wal),
+            memtable: Mutex::new(Memtable { entries: BTreeMap::new(), bytes: 0 }),
+            tables: Mutex::new(Tables {
+                current: Arc::new(version),
+                retired: Vec::new(),
+                reserved: Vec::new(),
+                next_id,
+                manifest_seq,
+            }),
+            compacting: Mutex::new(()),
+            policy: Mutex::new(LsmPolicy::default()),
+            recovery: None,
+        }
+    }
+
+    //starts an empty map, whatever was on disk is lost
+    fn format(disk: Disk<D>) -> Result<Self, ()> {
+        let wal = Log::create_at(disk.clone(), 0, WAL_BYTES, 1)?;
+        let map = Self::with_parts(disk, wal, Version { levels: vec![Vec::new(); LEVELS] }, 1, 0);
+        //an old manifest in slot 0 must not win over the empty one going into slot 1
+        map.disk.clone().write_blocks(manifest_block(0), &[0; MANIFEST_HEADER])?;
+        {
+            let mut tables = map.tables.lock();
+            let version = tables.current.clone();
+            map.write_manifest(&mut tables, &version)?;
+        }
+        Ok(map)
+    }
+
+    pub fn set_policy(&self, policy: LsmPolicy) {
+        *self.policy.lock() = policy;
+    }
+
+    pub fn recovery_report(&self) -> Option<RecoveryReport> {
+        self.recovery
+    }
+
+    //number of tables in every level
+    pub fn level_tables(&self) -> Vec<usize> {
+        self.tables.lock().current.levels.iter().map(|level| level.len()).collect()
+    }
+
+    //writes the memtable out as level 0 tables and empties the wal
+    pub fn flush(&self) -> Result<(), ()> {
+        let mut memtable = self.memtable.lock();
+        self.flush_memtable(&mut memtable)
+    }
+
+    fn flush_memtable(&self, memtable: &mut Memtable<K, V>) -> Result<(), ()> {
+        if memtable.entries.is_empty() {
+            return Ok(());
+        }
+        //tombstones are kept, older values may still be in deeper levels
+        let entries = memtable.entries.iter().map(|(key, value)| Ok((key.clone(), value.clone())));
+        let added = self.write_tables(entries, false)?;
+        self.install(0, added, &[])?;
+        memtable.entries.clear();
+        memtable.bytes = 0;
+        //everything the wal holds is in a table now
+        if self.wal.lock().rewrite(Vec::new()).is_err() {
+            serial_warnln!("wal truncation failed, boot replays changes that are in tables already");
+        }
+        Ok(())
+    }
+
+    fn maybe_flush(&self, memtable: &mut Memtable<K, V>) {
+        if memtable.bytes >= self.policy.lock().memtable_bytes {
+            if self.flush_memtable(memtable).is_err() {
+                serial_warnln!("memtable flush failed, its changes stay in the wal");
+            }
+        }
+    }
+
+    //writes the entries as tables of about table_bytes each, none of them is visible
+    //until it is installed
+    fn write_tables<I: Iterator<Item = Result<Change<K, V>, ()>>>(&self, entries: I, drop_tombstones: bool) -> Result<Vec<Arc<Table<K>>>, ()> {
+        let mut written = Vec::new();
+        let result = self.write_tables_into(entries, drop_tombstones, &mut written);
+        if result.is_err() {
+            let mut tables = self.tables.lock();
+            tables.reserved.retain(|(start, _)| !written.iter().any(|table| table.start == *start));
+        }
+        result.map(|_| written)
+    }
+
+    fn write_tables_into<I: Iterator<Item = Result<Change<K, V>, ()>>>(&self, entries: I, drop_tombstones: bool, written: &mut Vec<Arc<Table<K>>>) -> Result<(), ()> {
+        let table_bytes = self.policy.lock().table_bytes;
+        let mut builder = TableBuilder::new();
+        for entry in entries {
+            let (key, value) = entry?;
+            if drop_tombstones && value.is_none() {
+                continue;
+            }
+            builder.add(&key, value.as_ref());
+            if builder.size() >= table_bytes {
+                written.push(self.write_table(mem::replace(&mut builder, TableBuilder::new()))?);
+            }
+        }
+        if !builder.is_empty() {
+            written.push(self.write_table(builder)?);
+        }
+        Ok(())
+    }
+
+    fn write_table(&self, builder: TableBuilder) -> Result<Arc<Table<K>>, ()> {
+        let bytes = builder.finish();
+        let extent = (bytes.len() + 511) / 512 * 512;
+        let (id, start) = {
+            let mut tables = self.tables.lock();
+            let start = self.allocate(&mut tables, extent).ok_or(())?;
+            tables.reserved.push((start, extent));
+            tables.next_id += 1;
+            (tables.next_id - 1, start)
+        };
+        match Table::write(&mut self.disk.clone(), id, start, &bytes) {
+            Ok(table) => Ok(Arc::new(table)),
+            Err(_) => {
+                self.tables.lock().reserved.retain(|(reserved, _)| *reserved != start);
+                Err(())
+            },
+        }
+    }
+
+    //first fit over the space no table uses, retired tables are only reused once no
+    //reader has them anymore
+    fn allocate(&self, tables: &mut Tables<K>, len: usize) -> Option<usize> {
+        tables.retired.retain(|table| Arc::strong_count(table) > 1);
+        let mut used: Vec<(usize, usize)> = tables.current.levels.iter().flatten()
+            .chain(tables.retired.iter())
+            .map(|table| (table.start, table.extent()))
+            .chain(tables.reserved.iter().copied())
+            .collect();
+        used.sort_unstable();
+        let mut start = TABLES_START;
+        for (used_start, used_len) in used {
+            if used_start >= start + len {
+                break;
+            }
+            start = start.max(used_start + used_len);
+        }
+        if start + len <= self.disk.size() {
+            Some(start)
+        } else {
+            None
+        }
+    }
+
+    //makes added part of level and drops removed from every level, the new manifest is
+    //what makes the change durable
+    fn install(&self, level: usize, added: Vec<Arc<Table<K>>>, removed: &[Arc<Table<K>>]) -> Result<(), ()> {
+        let mut tables = self.tables.lock();
+        let mut levels = tables.current.levels.clone();
+        for tables_in_level in levels.iter_mut() {
+            tables_in_level.retain(|table| !removed.iter().any(|r| r.id == table.id));
+        }
+        if level == 0 {
+            for table in added.iter().rev() {
+                levels[0].insert(0, table.clone());
+            }
+        } else {
+            levels[level].extend(added.iter().cloned());
+            levels[level].sort_by(|a, b| a.smallest.cmp(&b.smallest));
+        }
+        let version = Version { levels };
+        let result = self.write_manifest(&mut tables, &version);
+        tables.reserved.retain(|(start, _)| !added.iter().any(|table| table.start == *start));
+        if result.is_ok() {
+            tables.current = Arc::new(version);
+            tables.retired.extend(removed.iter().cloned());
+        }
+        result
+    }
+
+    fn write_manifest(&self, tables: &mut Tables<K>, version: &Version<K>) -> Result<(), ()> {
+        let seq = tables.manifest_seq + 1;
+        let mut payload = Vec::new();
+        payload.extend_from_slice(&tables.next_id.to_be_bytes());
+        let count: usize = version.levels.iter().map(|level| level.len()).sum();
+        payload.extend_from_slice(&(count as u64).to_be_bytes());
+        for (level, tables_in_level) in version.levels.iter().enumerate() {
+            for table in tables_in_level {
+                payload.extend_from_slice(&table.id.to_be_bytes());
+                payload.extend_from_slice(&(level as u64).to_be_bytes());
+                payload.extend_from_slice(&(table.start as u64).to_be_bytes());
+                payload.extend_from_slice(&(table.len as u64).to_be_bytes());
+            }
+        }
+        if MANIFEST_HEADER + payload.len() > MANIFEST_SLOT_BYTES {
+            return Err(());
+        }
+        let mut manifest = MANIFEST_MAGIC.to_vec();
+        manifest.extend_from_slice(&seq.to_be_bytes());
+        manifest.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+        manifest.extend_from_slice(&(crc32(crc32(0, &seq.to_be_bytes()), &payload) as u64).to_be_bytes());
+        manifest.append(&mut payload);
+        //the tables have to be on the disk before the manifest that lists them
+        let mut disk = self.disk.clone();
+        disk.flush()?;
+        disk.write_blocks(manifest_block(seq), &manifest)?;
+        disk.flush()?;
+        tables.manifest_seq = seq;
+        Ok(())
+    }
+
+    //the level to compact and its tables to merge into the next level
+    fn pick_compaction(&self, version: &Version<K>) -> Option<(usize, Vec<Arc<Table<K>>>)> {
+        let policy = *self.policy.lock();
+        if version.levels[0].len() >= policy.level0_tables.max(1) {
+            return Some((0, version.levels[0].clone()));
+        }
+        //the last level can grow without limit
+        for level in 1..LEVELS - 1 {
+            let bytes: usize = version.levels[level].iter().map(|table| table.len).sum();
+            if bytes > policy.level_limit(level) {
+                return Some((level, vec![version.levels[level][0].clone()]));
+            }
+        }
+        None
+    }
+
+    fn maybe_compact(&self) {
+        let version = self.tables.lock().current.clone();
+        if self.pick_compaction(&version).is_some() && self.compact_levels().is_err() {
+            serial_warnln!("lsm compaction failed, the old tables stay in use");
+        }
+    }
+
+    //merges levels into the next one until every level is within its limit
+    fn compact_levels(&self) -> Result<(), ()> {
+        //someone else is already compacting and checks again after each merge
+        let _compacting = match self.compacting.try_lock() {
+            Some(compacting) => compacting,
+            None => return Ok(()),
+        };
+        loop {
+            let version = self.tables.lock().current.clone();
+            let (level, inputs) = match self.pick_compaction(&version) {
+                Some(compaction) => compaction,
+                None => return Ok(()),
+            };
+            let output = level + 1;
+            let smallest = inputs.iter().map(|table| &table.smallest).min().unwrap().clone();
+            let largest = inputs.iter().map(|table| &table.largest).max().unwrap().clone();
+            let overlapping: Vec<Arc<Table<K>>> = version.levels[output].iter()
+                .filter(|table| table.overlaps(&smallest, &largest))
+                .cloned()
+                .collect();
+            //inputs are newer than the level below, level 0 is already newest first
+            let mut sources: Vec<Source<K, V>> = Vec::new();
+            for table in inputs.iter() {
+                sources.push(Box::new(TableIter::new(table.clone(), self.disk.clone(), None)));
+            }
+            let disk = self.disk.clone();
+            let below = overlapping.clone();
+            sources.push(Box::new(below.into_iter().flat_map(move |table| TableIter::new(table, disk.clone(), None))));
+            //nothing deeper can hold an older value a tombstone would have to hide
+            let bottom = version.levels[output + 1..].iter().all(|level| level.is_empty());
+            drop(version);
+            let added = self.write_tables(MergeIter::new(sources)?, bottom)?;
+            let mut removed = inputs;
+            removed.extend(overlapping);
+            self.install(output, added, &removed)?;
+        }
+    }
+
+    fn get_from_tables(&self, version: &Version<K>, key: &K) -> Result<Option<V>, ()> {
+        let mut disk = self.disk.clone();
+        //level 0 tables may overlap, deeper levels have at most one table that can hold key
+        let candidates = version.levels[0].iter().chain(version.levels[1..].iter().filter_map(|level| {
+            level.get(level.partition_point(|table| &table.largest < key))
+        }));
+        for table in candidates {
+            match table.get::<V, D>(&mut disk, key) {
+                Ok(Some(value)) => return Ok(value),
+                Ok(None) => {},
+                Err(_) => {
+                    serial_warnln!("lsm: could not read table {}", table.id);
+                    return Err(());
+                },
+            }
+        }
+        Ok(None)
+    }
+
+    //whether key has a value, the memtable has to be locked by the caller
+    fn contains(&self, memtable: &Memtable<K, V>, key: &K) -> Result<bool, ()> {
+        match memtable.entries.get(key) {
+            Some(value) => Ok(value.is_some()),
+            None => {
+                let version = self.tables.lock().current.clone();
+                Ok(self.get_from_tables(&version, key)?.is_some())
+            },
+        }
+    }
+}
+
+impl<K: Ord + Clone + ToBeBytes + 'static, V: Clone + ToBeBytes + 'static, D: BlockDevice + 'static> PersistentMap for LsmMap<K, V, D> {
+    type Key = K;
+    type Value = V;
+    type Device = D;
+
+    fn new_on(disk: Disk<D>) -> Result<Self, ()> {
+        Self::format(disk)
+    }
+
+    //loads the table indexes named by the manifest and replays the wal into the memtable
+    fn build_from(mut disk: Disk<D>) -> Result<Self, ()> {
+        let (seq, next_id, entries) = match read_manifest(&mut disk) {
+            Some(manifest) => manifest,
+            //only a disk that was never written is formatted
+            None if manifest_blank(&mut disk) => {
+                let mut map = Self::format(disk)?;
+                let end_offset = map.wal.lock().size();
+                map.recovery = Some(RecoveryReport { records: 0, end: LogEnd::Formatted, end_offset, discarded_groups: 0 });
+                return Ok(map);
+            },
+            None => {
+                serial_errorln!("lsm: no valid manifest, the disk holds something else or is corrupt");
+                return Err(());
+            },
+        };
+        let mut levels = vec![Vec::new(); LEVELS];
+        //a table the manifest names has entries nothing else holds, so the map does not come up without it
+        for (id, level, start, len) in entries {
+            match Table::load(&mut disk, id, start, len) {
+                Ok(table) if level < LEVELS => levels[level].push(Arc::new(table)),
+                _ => {
+                    serial_errorln!("lsm: table {} named by the manifest could not be loaded", id);
+                    return Err(());
+                },
+            }
+        }
+        let (wal, payloads, mut report) = Log::open_at(disk.clone(), 0, WAL_BYTES)?;
+        let mut map = Self::with_parts(disk, wal, Version { levels }, next_id, seq);
+        {
+            let mut memtable = map.memtable.lock();
+            for payload in payloads {
+                let (changes, complete) = decode_record::<K, V>(&payload);
+                for (key, value) in changes {
+                    memtable.put(key, value);
+                }
+                if !complete {
+                    report.discarded_groups += 1;
+                }
+            }
+        }
+        if report.end != LogEnd::Clean || report.discarded_groups != 0 {
+            serial_warnln!("wal recovery: {:?} at byte {}, {} records replayed, {} groups discarded",
+                report.end, report.end_offset, report.records, report.discarded_groups);
+        }
+        map.recovery = Some(report);
+        Ok(map)
+    }
+
+    fn get(&self, key: &Self::Key) -> Result<Option<Self::Value>, ()> {
+        let version = {
+            let memtable = self.memtable.lock();
+            if let Some(value) = memtable.entries.get(key) {
+                return Ok(value.clone());
+            }
+            //taken before the memtable is unlocked so a flush cannot slip in between
+            self.tables.lock().current.clone()
+        };
+        self.get_from_tables(&version, key)
+    }
+
+    fn insert_no_log(&self, key: &Self::Key, value: &Self::Value) -> bool {
+        let mut memtable = self.memtable.lock();
+        memtable.put(key.clone(), Some(value.clone()));
+        self.maybe_flush(&mut memtable);
+        drop(memtable);
+        self.maybe_compact();
+        true
+    }
+
+    fn insert(&self, key: &Self::Key, value: &Self::Value) -> Result<bool,()> {
+        let mut memtable = self.memtable.lock();
+        self.wal.lock().append(vec![insert_record(key, value)])?;
+        memtable.put(key.clone(), Some(value.clone()));
+        self.maybe_flush(&mut memtable);
+        drop(memtable);
+        self.maybe_compact();
+        Ok(true)
+    }
+
+    fn remove_no_log(&self, key: &Self::Key) -> bool {
+        let mut memtable = self.memtable.lock();
+        if self.contains(&memtable, key) != Ok(true) {
+            return false;
+        }
+        memtable.put(key.clone(), None);
+        self.maybe_flush(&mut memtable);
+        drop(memtable);
+        self.maybe_compact();
+        true
+    }
+
+    fn remove(&self, key: &Self::Key) -> Result<bool,()> {
+        let mut memtable = self.memtable.lock();
+        if !self.contains(&memtable, key)? {
+            return Ok(false);
+        }
+        self.wal.lock().append(vec![remove_record(key)])?;
+        memtable.put(key.clone(), None);
+        self.maybe_flush(&mut memtable);
+        drop(memtable);
+        self.maybe_compact();
+        Ok(true)
+    }
+
+    //the wal is emptied by writing out the memtable, the levels are merged down to their limits
+    fn compact_logs(&self) -> Result<(),()> {
+        self.flush()?;
+        self.compact_levels()
+    }
+
+    fn commit_group(&self, writes: &[(&Self::Key, &Self::Value)], removes: &[&Self::Key]) -> Result<(),()> {
+        let mut memtable = self.memtable.lock();
+        self.wal.lock().append(vec![group_record(writes, removes)])?;
+        for (key, value) in writes {
+            memtable.put((*key).clone(), Some((*value).clone()));
+        }
+        for key in removes {
+            memtable.put((*key).clone(), None);
+        }
+        self.maybe_flush(&mut memtable);
+        drop(memtable);
+        self.maybe_compact();
+        Ok(())
+    }
+
+    fn scan(&self, start: &Self::Key, limit: usize) -> Vec<(Self::Key, Self::Value)> {
+        let (newest, version) = {
+            let memtable = self.memtable.lock();
+            let newest: Vec<Change<K, V>> = memtable.entries.range(start.clone()..)
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect();
+            (newest, self.tables.lock().current.clone())
+        };
+        let mut sources: Vec<Source<K, V>> = vec![Box::new(newest.into_iter().map(Ok))];
+        for table in version.levels[0].iter() {
+            sources.push(Box::new(TableIter::new(table.clone(), self.disk.clone(), Some(start.clone()))));
+        }
+        for level in version.levels[1..].iter() {
+            let first = level.partition_point(|table| &table.largest < start);
+            let (disk, from) = (self.disk.clone(), start.clone());
+            sources.push(Box::new(level[first..].to_vec().into_iter()
+                .flat_map(move |table| TableIter::new(table, disk.clone(), Some(from.clone())))));
+        }
+        let mut result = Vec::new();
+        let merged = match MergeIter::new(sources) {
+            Ok(merged) => merged,
+            Err(_) => {
+                serial_warnln!("lsm: scan could not read a table");
+                return result;
+            },
+        };
+        for entry in merged {
+            if result.len() >= limit {
+                break;
+            }
+            match entry {
+                Ok((key, Some(value))) => result.push((key, value)),
+                Ok((_, None)) => {},
+                Err(_) => {
+                    serial_warnln!("lsm: scan could not read a table");
+                    break;
+                },
+            }
+        }
+        result
+    }
+}
@@ -0,0 +1,349 @@
+extern crate alloc;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+use crate::common::hash::crc32;
+use crate::disk::block_device::BlockDevice;
+use crate::disk::disk_api::Disk;
+use crate::disk::persistentmap::ToBeBytes;
+
+//an sstable is a run of data blocks holding sorted entries, then an index with the last key,
+//position and crc32 of every data block, then a footer pointing at the index
+//an entry is key length, value length, key and value, removed keys keep a tombstone entry
+//with value length TOMBSTONE so they hide older values in lower levels
+const FOOTER_MAGIC: &[u8; 8] = b"KVSSTABL";
+const FOOTER_SIZE: usize = 32;
+const TOMBSTONE: u32 = u32::MAX;
+//data blocks are cut once they reach this size, only whole blocks are read
+const DATA_BLOCK_SIZE: usize = 4096;
+
+//an entry of a table or the memtable, None is a tombstone
+pub type Change<K, V> = (K, Option<V>);
+
+struct BlockHandle<K> {
+    last_key: K,
+    offset: usize,
+    len: usize,
+    crc: u32,
+}
+
+//an sstable on disk, only its index is kept in memory
+pub struct Table<K> {
+    pub id: u64,
+    //byte offset on disk, always block aligned, and length without the padding
+    pub start: usize,
+    pub len: usize,
+    pub smallest: K,
+    pub largest: K,
+    index: Vec<BlockHandle<K>>,
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    u64::from_be_bytes([
+        bytes[0], bytes[1], bytes[2], bytes[3],
+        bytes[4], bytes[5], bytes[6], bytes[7],
+    ])
+}
+
+//reads a u32 length and that many bytes at *pos
+fn read_bytes(buf: &[u8], pos: &mut usize) -> Option<Vec<u8>> {
+    let len = read_u32(buf.get(*pos..*pos + 4)?) as usize;
+    let bytes = buf.get(*pos + 4..*pos + 4 + len)?.to_vec();
+    *pos += 4 + len;
+    Some(bytes)
+}
+
+fn push_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+//serializes sorted entries into the bytes of one table
+pub struct TableBuilder {
+    buf: Vec<u8>,
+    block_start: usize,
+    //last key, offset, length and crc32 of the finished blocks
+    index: Vec<(Vec<u8>, usize, usize, u32)>,
+    first_key: Option<Vec<u8>>,
+    last_key: Vec<u8>,
+}
+
+impl TableBuilder {
+    pub fn new() -> Self {
+        Self { buf: Vec::new(), block_start: 0, index: Vec::new(), first_key: None, last_key: Vec::new() }
+    }
+
+    //keys have to be added in ascending order
+    pub fn add<K: ToBeBytes, V: ToBeBytes>(&mut self, key: &K, value: Option<&V>) {
+        let key = key.to_vec();
+        match value {
+            Some(value) => {
+                let value = value.to_vec();
+                self.buf.extend_from_slice(&(key.len() as u32).to_be_bytes());
+                self.buf.extend_from_slice(&(value.len() as u32).to_be_bytes());
+                self.buf.extend_from_slice(&key);
+                self.buf.extend_from_slice(&value);
+            },
+            None => {
+                self.buf.extend_from_slice(&(key.len() as u32).to_be_bytes());
+                self.buf.extend_from_slice(&TOMBSTONE.to_be_bytes());
+                self.buf.extend_from_slice(&key);
+            },
+        }
+        if self.first_key.is_none() {
+            self.first_key = Some(key.clone());
+        }
+        self.last_key = key;
+        if self.buf.len() - self.block_start >= DATA_BLOCK_SIZE {
+            self.finish_block();
+        }
+    }
+
+    fn finish_block(&mut self) {
+        if self.buf.len() == self.block_start {
+            return;
+        }
+        let crc = crc32(0, &self.buf[self.block_start..]);
+        self.index.push((self.last_key.clone(), self.block_start, self.buf.len() - self.block_start, crc));
+        self.block_start = self.buf.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.first_key.is_none()
+    }
+
+    //bytes written so far
+    pub fn size(&self) -> usize {
+        self.buf.len()
+    }
+
+    //appends the index and the footer
+    pub fn finish(mut self) -> Vec<u8> {
+        self.finish_block();
+        let index_offset = self.buf.len();
+        push_bytes(&mut self.buf, &self.first_key.unwrap_or_default());
+        self.buf.extend_from_slice(&(self.index.len() as u64).to_be_bytes());
+        for (last_key, offset, len, crc) in self.index.iter() {
+            push_bytes(&mut self.buf, last_key);
+            self.buf.extend_from_slice(&(*offset as u64).to_be_bytes());
+            self.buf.extend_from_slice(&(*len as u64).to_be_bytes());
+            self.buf.extend_from_slice(&crc.to_be_bytes());
+        }
+        let index_len = self.buf.len() - index_offset;
+        let crc = crc32(0, &self.buf[index_offset..]) as u64;
+        self.buf.extend_from_slice(FOOTER_MAGIC);
+        self.buf.extend_from_slice(&(index_offset as u64).to_be_bytes());
+        self.buf.extend_from_slice(&(index_len as u64).to_be_bytes());
+        self.buf.extend_from_slice(&crc.to_be_bytes());
+        self.buf
+    }
+}
+
+impl<K: Ord + Clone + ToBeBytes> Table<K> {
+    //writes the bytes of a finished builder at start
+    pub fn write<D: BlockDevice>(disk: &mut Disk<D>, id: u64, start: usize, bytes: &[u8]) -> Result<Self, ()> {
+        if bytes.len() < FOOTER_SIZE {
+            return Err(());
+        }
+        disk.write_blocks(start / 512, bytes)?;
+        let footer = &bytes[bytes.len() - FOOTER_SIZE..];
+        let index_offset = read_u64(&footer[8..16]) as usize;
+        let index_bytes = bytes.get(index_offset..bytes.len() - FOOTER_SIZE).ok_or(())?;
+        Self::parse(id, start, bytes.len(), index_bytes, footer)
+    }
+
+    //reads the footer and the index of the table written at start
+    pub fn load<D: BlockDevice>(disk: &mut Disk<D>, id: u64, start: usize, len: usize) -> Result<Self, ()> {
+        if len < FOOTER_SIZE {
+            return Err(());
+        }
+        let footer = disk.read_bytes(start + len - FOOTER_SIZE, FOOTER_SIZE)?;
+        let index_offset = read_u64(&footer[8..16]) as usize;
+        let index_len = read_u64(&footer[16..24]) as usize;
+        if &footer[0..8] != FOOTER_MAGIC || index_offset + index_len + FOOTER_SIZE != len {
+            return Err(());
+        }
+        let index_bytes = disk.read_bytes(start + index_offset, index_len)?;
+        Self::parse(id, start, len, &index_bytes, &footer)
+    }
+
+    //builds the table from its index bytes and footer
+    fn parse(id: u64, start: usize, len: usize, index_bytes: &[u8], footer: &[u8]) -> Result<Self, ()> {
+        if &footer[0..8] != FOOTER_MAGIC || crc32(0, index_bytes) as u64 != read_u64(&footer[24..32]) {
+            return Err(());
+        }
+        let mut pos = 0;
+        let smallest = K::from_vec(read_bytes(index_bytes, &mut pos).ok_or(())?)?;
+        let count = read_u64(index_bytes.get(pos..pos + 8).ok_or(())?) as usize;
+        pos += 8;
+        //every handle takes at least 24 bytes, a bad count cannot make us allocate more
+        let mut index = Vec::with_capacity(count.min(index_bytes.len() / 24));
+        for _ in 0..count {
+            let last_key = K::from_vec(read_bytes(index_bytes, &mut pos).ok_or(())?)?;
+            let handle = index_bytes.get(pos..pos + 20).ok_or(())?;
+            index.push(BlockHandle {
+                last_key,
+                offset: read_u64(&handle[0..8]) as usize,
+                len: read_u64(&handle[8..16]) as usize,
+                crc: read_u32(&handle[16..20]),
+            });
+            pos += 20;
+        }
+        let largest = index.last().ok_or(())?.last_key.clone();
+        Ok(Self { id, start, len, smallest, largest, index })
+    }
+
+    //bytes the table takes on disk
+    pub fn extent(&self) -> usize {
+        (self.len + 511) / 512 * 512
+    }
+
+    pub fn overlaps(&self, smallest: &K, largest: &K) -> bool {
+        &self.smallest <= largest && &self.largest >= smallest
+    }
+
+    //the first block that can hold key
+    fn find_block(&self, key: &K) -> usize {
+        self.index.partition_point(|handle| &handle.last_key < key)
+    }
+
+    fn read_block<V: ToBeBytes, D: BlockDevice>(&self, disk: &mut Disk<D>, block: usize) -> Result<VecDeque<Change<K, V>>, ()> {
+        let handle = &self.index[block];
+        let buf = disk.read_bytes(self.start + handle.offset, handle.len)?;
+        if crc32(0, &buf) != handle.crc {
+            return Err(());
+        }
+        let mut entries = VecDeque::new();
+        let mut pos = 0;
+        while pos < buf.len() {
+            let header = buf.get(pos..pos + 8).ok_or(())?;
+            let key_len = read_u32(&header[0..4]) as usize;
+            let value_len = read_u32(&header[4..8]);
+            pos += 8;
+            let key = K::from_vec(buf.get(pos..pos + key_len).ok_or(())?.to_vec())?;
+            pos += key_len;
+            let value = if value_len == TOMBSTONE {
+                None
+            } else {
+                let value = buf.get(pos..pos + value_len as usize).ok_or(())?.to_vec();
+                pos += value_len as usize;
+                Some(V::from_vec(value)?)
+            };
+            entries.push_back((key, value));
+        }
+        Ok(entries)
+    }
+
+    //Ok(None) if the table has no entry for key, Ok(Some(None)) if it has a tombstone
+    pub fn get<V: ToBeBytes, D: BlockDevice>(&self, disk: &mut Disk<D>, key: &K) -> Result<Option<Option<V>>, ()> {
+        if key < &self.smallest || key > &self.largest {
+            return Ok(None);
+        }
+        let block = self.find_block(key);
+        if block == self.index.len() {
+            return Ok(None);
+        }
+        let entries = self.read_block::<V, D>(disk, block)?;
+        Ok(entries.into_iter().find(|(k, _)| k == key).map(|(_, value)| value))
+    }
+}
+
+//walks the entries of a table in key order reading one data block at a time
+pub struct TableIter<K, V, D: BlockDevice> {
+    table: Arc<Table<K>>,
+    disk: Disk<D>,
+    block: usize,
+    from: Option<K>,
+    entries: VecDeque<Change<K, V>>,
+    failed: bool,
+    _marker: PhantomData<V>,
+}
+
+impl<K: Ord + Clone + ToBeBytes, V: ToBeBytes, D: BlockDevice> TableIter<K, V, D> {
+    //starts at the first entry with key >= from
+    pub fn new(table: Arc<Table<K>>, disk: Disk<D>, from: Option<K>) -> Self {
+        let block = from.as_ref().map_or(0, |from| table.find_block(from));
+        Self { table, disk, block, from, entries: VecDeque::new(), failed: false, _marker: PhantomData }
+    }
+}
+
+impl<K: Ord + Clone + ToBeBytes, V: ToBeBytes, D: BlockDevice> Iterator for TableIter<K, V, D> {
+    type Item = Result<Change<K, V>, ()>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.entries.is_empty() {
+            if self.failed || self.block >= self.table.index.len() {
+                return None;
+            }
+            match self.table.read_block(&mut self.disk, self.block) {
+                Ok(entries) => self.entries = entries,
+                Err(_) => {
+                    self.failed = true;
+                    return Some(Err(()));
+                },
+            }
+            self.block += 1;
+            if let Some(from) = self.from.take() {
+                self.entries.retain(|(key, _)| key >= &from);
+            }
+        }
+        self.entries.pop_front().map(Ok)
+    }
+}
+
+pub type Source<K, V> = alloc::boxed::Box<dyn Iterator<Item = Result<Change<K, V>, ()>>>;
+
+//merges sorted sources, for a key in several of them the earliest source wins
+pub struct MergeIter<K, V> {
+    sources: Vec<Source<K, V>>,
+    heads: Vec<Option<Change<K, V>>>,
+}
+
+impl<K: Ord, V> MergeIter<K, V> {
+    //sources have to be ordered newest first
+    pub fn new(mut sources: Vec<Source<K, V>>) -> Result<Self, ()> {
+        let mut heads = Vec::with_capacity(sources.len());
+        for source in sources.iter_mut() {
+            heads.push(source.next().transpose()?);
+        }
+        Ok(Self { sources, heads })
+    }
+
+    fn next_entry(&mut self) -> Result<Option<Change<K, V>>, ()> {
+        let mut newest: Option<usize> = None;
+        for (i, head) in self.heads.iter().enumerate() {
+            if let Some((key, _)) = head {
+                match newest {
+                    Some(n) if self.heads[n].as_ref().map_or(false, |(k, _)| k <= key) => {},
+                    _ => newest = Some(i),
+                }
+            }
+        }
+        let newest = match newest {
+            Some(newest) => newest,
+            None => return Ok(None),
+        };
+        let entry = self.heads[newest].take().unwrap();
+        self.heads[newest] = self.sources[newest].next().transpose()?;
+        //older versions of the key are shadowed
+        for i in 0..self.sources.len() {
+            while self.heads[i].as_ref().map_or(false, |(key, _)| key == &entry.0) {
+                self.heads[i] = self.sources[i].next().transpose()?;
+            }
+        }
+        Ok(Some(entry))
+    }
+}
+
+impl<K: Ord, V> Iterator for MergeIter<K, V> {
+    type Item = Result<Change<K, V>, ()>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_entry().transpose()
+    }
+}
@@ -1,3 +1,91 @@
+extern crate alloc;
+use alloc::vec::Vec;
+
+pub mod ahci;
 pub mod ata;
+pub mod block_device;
+pub mod btree;
+pub mod cache;
 pub mod disk_api;
-pub mod persistentmap;
\ No newline at end of file
+pub mod dma;
+pub mod log;
+pub mod lsm;
+pub mod persistentmap;
+pub mod request;
+pub mod virtio;
+
+//the disk the ata driver calls (bus, drive) is on an ahci port when an hba took the disks,
+//virtio disks are the drives of a bus of their own
+
+//bus of the virtio block devices, the drive is the index of the device
+pub const VIRTIO_BUS: u8 = 2;
+
+//the disk the key value store lives on, the first virtio disk when there is one and the
+//second disk of the primary bus otherwise
+pub fn kv_disk() -> (u8, u8) {
+    if virtio::block_count(0).is_some() {
+        (VIRTIO_BUS, 0)
+    } else {
+        (0, 1)
+    }
+}
+
+//every disk there is, the ata buses or the ahci ports standing in for them first, then the
+//virtio disks
+pub fn drives() -> Vec<(u8, u8)> {
+    let mut drives: Vec<(u8, u8)> = (0..2).flat_map(|bus| (0..2).map(move |drive| (bus, drive)))
+        .filter(|&(bus, drive)| block_count(bus, drive).is_some())
+        .collect();
+    drives.extend((0..u8::MAX).map_while(|disk| virtio::block_count(disk).map(|_| (VIRTIO_BUS, disk))));
+    drives
+}
+
+//reads the blocks from block on until buf is full from whichever controller has the disk
+pub fn read_blocks(bus: u8, drive: u8, block: u64, buf: &mut [u8]) -> Result<(), ()> {
+    if bus == VIRTIO_BUS {
+        return virtio::read(drive, block, buf);
+    }
+    let port = ahci::port_of(bus, drive);
+    if ahci::block_count(port).is_some() {
+        ahci::read(port, block, buf)
+    } else {
+        ata::read(bus, drive, block, buf)
+    }
+}
+
+//writes buf.len() / BLOCK_SIZE blocks from block on
+pub fn write_blocks(bus: u8, drive: u8, block: u64, buf: &[u8]) -> Result<(), ()> {
+    if bus == VIRTIO_BUS {
+        return virtio::write(drive, block, buf);
+    }
+    let port = ahci::port_of(bus, drive);
+    if ahci::block_count(port).is_some() {
+        ahci::write(port, block, buf)
+    } else {
+        ata::write(bus, drive, block, buf)
+    }
+}
+
+//size of the disk in blocks, None if there is no disk
+pub fn block_count(bus: u8, drive: u8) -> Option<u64> {
+    if bus == VIRTIO_BUS {
+        return virtio::block_count(drive);
+    }
+    ahci::block_count(ahci::port_of(bus, drive)).or_else(|| {
+        ata::init();
+        ata::Drive::open(bus, drive).map(|drive| drive.block_count())
+    })
+}
+
+//makes the finished writes of the disk durable, whichever controller has it
+pub fn flush_blocks(bus: u8, drive: u8) -> Result<(), ()> {
+    if bus == VIRTIO_BUS {
+        return virtio::flush(drive);
+    }
+    let port = ahci::port_of(bus, drive);
+    if ahci::block_count(port).is_some() {
+        ahci::flush(port)
+    } else {
+        ata::flush(bus, drive)
+    }
+}
@@ -1,20 +1,53 @@
 extern crate alloc;
-use alloc::string::{String, ToString};
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec;
 use alloc::vec::Vec;
-use spin::mutex::Mutex;
+use core::hint::spin_loop;
+use core::ops::Range;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use spin::mutex::{Mutex, MutexGuard};
 use crate::common::hash::Mix13Hash;
+use crate::common::index::KeyIndex;
+use crate::disk::block_device::{BlockDevice, CachedDrive};
 use crate::disk::disk_api::Disk;
+use crate::disk::log::{Log, LogEnd, RecoveryReport, HEADER_SIZE};
 pub trait PersistentMap {
     type Key;
     type Value;
-    fn new() -> Self;
-    fn get(&self, key: &Self::Key) -> Option<Self::Value>;
+    //what the map keeps its data on
+    type Device: BlockDevice;
+    //an empty map on the default device
+    fn new() -> Result<Self, ()> where Self: Sized, Self::Device: Default {
+        Self::new_on(Disk::with_device(Self::Device::default()))
+    }
+    //an empty map on disk, whatever was there is lost
+    fn new_on(disk: Disk<Self::Device>) -> Result<Self, ()> where Self: Sized;
+    //the value of key, an error if it could not be read from the disk
+    fn get(&self, key: &Self::Key) -> Result<Option<Self::Value>, ()>;
     fn insert(&self, key: &Self::Key, value: &Self::Value) -> Result<bool,()>;
     fn remove(&self, key: &Self::Key) -> Result<bool,()>;
-    fn build_from_disk() -> Self;
+    //the map the default device holds
+    fn build_from_disk() -> Result<Self, ()> where Self: Sized, Self::Device: Default {
+        Self::build_from(Disk::with_device(Self::Device::default()))
+    }
+    //the map disk holds, an error if the disk cannot be read or holds something else
+    fn build_from(disk: Disk<Self::Device>) -> Result<Self, ()> where Self: Sized;
+    //the map disk holds, spread over capacity buckets for a map that has them
+    fn build_from_with_capacity(disk: Disk<Self::Device>, _capacity: usize) -> Result<Self, ()> where Self: Sized {
+        Self::build_from(disk)
+    }
     fn insert_no_log(&self, key: &Self::Key, value: &Self::Value) -> bool;
     fn remove_no_log(&self, key: &Self::Key) -> bool;
-    fn compact_logs(&mut self) -> Result<(),()>;
+    //rewrites the log with only the live data, writers can keep going meanwhile
+    fn compact_logs(&self) -> Result<(),()>;
+    //runs the work writes put off, e.g. a compaction, for a caller that holds no locks
+    fn maintain(&self) {}
+    //logs all writes and removes as one record and applies them once it is on disk,
+    //after a crash either all of them or none are recovered
+    fn commit_group(&self, writes: &[(&Self::Key, &Self::Value)], removes: &[&Self::Key]) -> Result<(),()>;
+    //returns up to limit entries with key >= start in ascending key order
+    fn scan(&self, start: &Self::Key, limit: usize) -> Vec<(Self::Key, Self::Value)>;
 }
 //trait that allows a type to be converted to/from a byte array
 pub trait ToBeBytes {
@@ -30,7 +63,8 @@ pub trait ToBeBytes {
         self.to_be_bytes().as_ref().to_vec()
     }
 
-    fn from_vec(bytes: Vec<u8>) -> Self;
+    //the value to_be_bytes made, Err if bytes are not one, e.g. a number of the wrong width
+    fn from_vec(bytes: Vec<u8>) -> Result<Self, ()> where Self: Sized;
 
 }
 
@@ -42,184 +76,324 @@ impl ToBeBytes for String {
         bytes
     }
 
-    fn from_vec(bytes: Vec<u8>) -> Self {
-        String::from_utf8_lossy(&bytes).to_string()
+    //bytes that are not utf-8 are an error instead of being replaced
+    fn from_vec(bytes: Vec<u8>) -> Result<Self, ()> {
+        String::from_utf8(bytes).map_err(|_| ())
+    }
+}
+
+//raw bytes, the store does not look into them
+impl ToBeBytes for Vec<u8> {
+    type ByteArray = Vec<u8>;
+
+    fn to_be_bytes(&self) -> Self::ByteArray {
+        self.clone()
+    }
+
+    fn from_vec(bytes: Vec<u8>) -> Result<Self, ()> {
+        Ok(bytes)
+    }
+}
+
+impl<const N: usize> ToBeBytes for [u8; N] {
+    type ByteArray = [u8; N];
+
+    fn to_be_bytes(&self) -> Self::ByteArray {
+        *self
+    }
+
+    fn from_vec(bytes: Vec<u8>) -> Result<Self, ()> {
+        bytes.try_into().map_err(|_| ())
     }
 }
 
+//big endian so the bytes of unsigned numbers sort like the numbers
+macro_rules! int_to_be_bytes {
+    ($($t:ty),*) => {$(
+        impl ToBeBytes for $t {
+            type ByteArray = [u8; core::mem::size_of::<$t>()];
+
+            fn to_be_bytes(&self) -> Self::ByteArray {
+                <$t>::to_be_bytes(*self)
+            }
+
+            fn from_vec(bytes: Vec<u8>) -> Result<Self, ()> {
+                Ok(<$t>::from_be_bytes(bytes.try_into().map_err(|_| ())?))
+            }
+        }
+    )*};
+}
+
+int_to_be_bytes!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128);
+
+//how a map keeps its values
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ValueMode {
+    //values are kept in the buckets, reads never go to the disk
+    Cached,
+    //bitcask style keydir, the buckets only know where each value is in the log and get
+    //reads it from there, so the values do not have to fit in memory
+    KeyDir,
+}
+
+//where a value is in the log
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ValueLocation {
+    pub block: usize,
+    //byte offset in the block
+    pub offset: usize,
+    pub len: usize,
+}
+
+impl ValueLocation {
+    fn at(byte: usize, len: usize) -> Self {
+        Self { block: byte / 512, offset: byte % 512, len }
+    }
+
+    fn byte(&self) -> usize {
+        self.block * 512 + self.offset
+    }
+}
+
+enum Stored<V> {
+    Memory(V),
+    //keydir maps keep changes that were logged only as their location
+    Log(ValueLocation),
+}
+
 struct Entry<K, V> {
     key: K,
-    value: V,
+    value: Stored<V>,
 }
 
 struct Bucket<K, V> {
     data: Mutex<Vec<Entry<K, V>>>,
 }
 
-pub struct PersistentHashMap<K, V> {
+//markers framing the records of one group commit in the log
+const GROUP_BEGIN: &str = "KVBEGINT";
+const GROUP_COMMIT: &str = "KVCOMMIT";
+//first record of a log compacted by a keydir map, see hint_record
+const HINT: &str = "KVHINTKV";
+
+//records waiting for the next disk write
+struct CommitQueue {
+    batch: u64,
+    members: usize,
+    records: Vec<Vec<u8>>,
+}
+
+//committers queue their records and whoever gets the disk first writes all of them at once
+struct GroupCommit {
+    queue: Mutex<CommitQueue>,
+    //every batch below this one has been written or failed
+    flushed: AtomicU64,
+    //batch -> offsets of its records in the log or the error, and the members that did
+    //not pick theirs up yet
+    done: Mutex<BTreeMap<u64, (Result<Vec<usize>,()>, usize)>>,
+}
+
+impl GroupCommit {
+    fn new() -> Self {
+        Self {
+            queue: Mutex::new(CommitQueue { batch: 0, members: 0, records: Vec::new() }),
+            flushed: AtomicU64::new(0),
+            done: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    //returns once record is on disk together with whatever was queued alongside it, with
+    //the byte offset it got in the log, unapplied counts the written records until their
+    //members applied them
+    fn write<D: BlockDevice>(&self, log: &Mutex<Log<D>>, unapplied: &AtomicUsize, record: Vec<u8>) -> Result<usize,()> {
+        let (batch, index) = {
+            let mut queue = self.queue.lock();
+            queue.records.push(record);
+            queue.members += 1;
+            (queue.batch, queue.records.len() - 1)
+        };
+        while self.flushed.load(Ordering::SeqCst) <= batch {
+            let mut log = match log.try_lock() {
+                Some(log) => log,
+                None => {
+                    spin_loop();
+                    continue;
+                },
+            };
+            //the previous holder may have written our batch
+            if self.flushed.load(Ordering::SeqCst) > batch {
+                break;
+            }
+            let (id, members, records) = {
+                let mut queue = self.queue.lock();
+                let taken = (queue.batch, queue.members, core::mem::take(&mut queue.records));
+                queue.batch += 1;
+                queue.members = 0;
+                taken
+            };
+            let offsets = log.offsets(&records);
+            let result = log.append(records).map(|_| offsets);
+            if result.is_ok() {
+                unapplied.fetch_add(members, Ordering::SeqCst);
+            }
+            self.done.lock().insert(id, (result, members));
+            self.flushed.store(id + 1, Ordering::SeqCst);
+        }
+        let mut done = self.done.lock();
+        let (result, left) = done.get_mut(&batch).unwrap();
+        let offset = result.as_ref().map(|offsets| offsets[index]).map_err(|_| ());
+        *left -= 1;
+        if *left == 0 {
+            done.remove(&batch);
+        }
+        offset
+    }
+}
+
+//when writes start a compaction of the log
+#[derive(Clone, Copy, Debug)]
+pub struct CompactionPolicy {
+    //logs smaller than this are never compacted
+    pub min_log_bytes: usize,
+    //compact once this share of the log is overwritten or removed data
+    pub max_garbage_percent: usize,
+    //compact once the log fills this share of its region, unless there is hardly any garbage
+    pub max_fill_percent: usize,
+}
+
+impl CompactionPolicy {
+    pub const fn new(min_log_bytes: usize, max_garbage_percent: usize, max_fill_percent: usize) -> Self {
+        Self { min_log_bytes, max_garbage_percent, max_fill_percent }
+    }
+
+    fn should_compact(&self, size: usize, live: usize, capacity: usize) -> bool {
+        if size < self.min_log_bytes {
+            return false;
+        }
+        let garbage_percent = size.saturating_sub(live) * 100 / size;
+        garbage_percent >= self.max_garbage_percent
+            || (size * 100 >= capacity * self.max_fill_percent && garbage_percent >= 10)
+    }
+}
+
+impl Default for CompactionPolicy {
+    fn default() -> Self {
+        Self::new(1 << 20, 50, 75)
+    }
+}
+
+pub struct PersistentHashMap<K, V, D: BlockDevice = CachedDrive> {
     buckets: Vec<Bucket<K, V>>,
     num_buckets: usize,
-    disk: Mutex<Disk>,
+    mode: ValueMode,
+    //keydir reads go to the disk directly, not through the log
+    disk: Disk<D>,
+    log: Mutex<Log<D>>,
+    index: KeyIndex<K>,
+    group: GroupCommit,
+    //what build_from_disk found, None for a map that started a new log
+    recovery: Option<RecoveryReport>,
+    //records in the log that are not in the buckets yet
+    unapplied: AtomicUsize,
+    //bytes the current entries would take in a compacted log
+    live_bytes: AtomicUsize,
+    compacting: AtomicBool,
+    //a write found the log needs compacting, maintain runs it
+    compaction_due: AtomicBool,
+    compaction: Mutex<CompactionPolicy>,
 }
-impl <K: Eq + core::hash::Hash + AsRef<[u8]>, V: Clone>  PersistentHashMap<K, V> {
-    pub fn with_capacity(num_buckets:usize) -> Self {
+impl <K: Eq + Ord + Clone + core::hash::Hash + AsRef<[u8]>, V: Clone, D: BlockDevice>  PersistentHashMap<K, V, D> {
+    //starts a new log on the default device, whatever was on it is lost
+    pub fn with_capacity(num_buckets:usize) -> Result<Self, ()> where D: Default {
+        Self::with_mode(num_buckets, ValueMode::Cached)
+    }
+
+    pub fn with_mode(num_buckets:usize, mode: ValueMode) -> Result<Self, ()> where D: Default {
+        Self::with_mode_on(Disk::with_device(D::default()), num_buckets, mode)
+    }
+
+    //starts a new log on disk
+    pub fn with_mode_on(disk: Disk<D>, num_buckets:usize, mode: ValueMode) -> Result<Self, ()> {
+        let log = Log::create(disk.clone(), 1)?;
+        Ok(Self::with_log(num_buckets, mode, disk, log))
+    }
+
+    fn with_log(num_buckets:usize, mode: ValueMode, disk: Disk<D>, log: Log<D>) -> Self {
         let mut buckets = Vec::with_capacity(num_buckets);
         for _ in 0..num_buckets {
             buckets.push(Bucket {
                 data: Mutex::new(Vec::new()),
             });
         }
-        let disk = Disk::new(0,1);
 
-        PersistentHashMap { buckets, num_buckets,disk: Mutex::new(disk) }
-    } 
+        PersistentHashMap {
+            buckets,
+            num_buckets,
+            mode,
+            disk,
+            log: Mutex::new(log),
+            index: KeyIndex::new(),
+            group: GroupCommit::new(),
+            recovery: None,
+            unapplied: AtomicUsize::new(0),
+            live_bytes: AtomicUsize::new(0),
+            compacting: AtomicBool::new(false),
+            compaction_due: AtomicBool::new(false),
+            compaction: Mutex::new(CompactionPolicy::default()),
+        }
+    }
+
+    pub fn set_compaction_policy(&self, policy: CompactionPolicy) {
+        *self.compaction.lock() = policy;
+    }
+
+    pub fn value_mode(&self) -> ValueMode {
+        self.mode
+    }
+
+    pub fn recovery_report(&self) -> Option<RecoveryReport> {
+        self.recovery
+    }
+
+    //bytes the log takes on disk
+    pub fn log_size(&self) -> usize {
+        self.log.lock().size()
+    }
 }
-impl<K: Eq + Clone + core::hash::Hash + AsRef<[u8]> + ToBeBytes, V: Clone + ToBeBytes> PersistentMap for PersistentHashMap<K, V> {
+impl<K: Eq + Ord + Clone + core::hash::Hash + AsRef<[u8]> + ToBeBytes, V: Clone + ToBeBytes, D: BlockDevice> PersistentMap for PersistentHashMap<K, V, D> {
     type Key = K;
     type Value = V;
+    type Device = D;
 
-    fn new() -> Self {
-        let num_buckets = 16; // Default number of buckets
-        let mut buckets = Vec::with_capacity(num_buckets);
-        for _ in 0..num_buckets {
-            buckets.push(Bucket {
-                data: Mutex::new(Vec::new()),
-            });
-        }
-        let disk = Disk::new(0,1);
-        PersistentHashMap { buckets, num_buckets,disk: Mutex::new(disk), }
+    fn new_on(disk: Disk<D>) -> Result<Self, ()> {
+        Self::with_mode_on(disk, 16, ValueMode::Cached) // Default number of buckets
     }
 
     //builds a map from what is on disk
-    fn build_from_disk() -> Self {
-        let num_buckets = 16; // Default number of buckets
-        let mut buckets = Vec::with_capacity(num_buckets);
-        for _ in 0..num_buckets {
-            buckets.push(Bucket {
-                data: Mutex::new(Vec::new()),
-            });
-        }
-        //makes a disk for the map
-        let disk = Disk::new(0,1);
-        let resulting_map =PersistentHashMap { buckets, num_buckets,disk: Mutex::new(disk) };
-        //get the logs from disk
-        let mut disk_data:Vec<Vec<u8>> = resulting_map.disk.lock().read_whole_disk_leave_bytes().unwrap().chunks_exact(8).map(|chunk| chunk.to_vec()).collect();
-        //iterate over the logs in 8 byte chunks
-        let mut data_iter = disk_data.iter_mut();
-        while let Some(entry) = data_iter.next() {
-            match  String::from_utf8(entry.to_vec()).unwrap().as_str(){
-                "KVKVKVKV" => {
-                    if let Some(key_length_bytes) = data_iter.next() {
-                        //get the key length
-                        let key_length: u64 = u64::from_be_bytes([
-                            key_length_bytes[0], key_length_bytes[1], key_length_bytes[2], key_length_bytes[3],
-                            key_length_bytes[4], key_length_bytes[5], key_length_bytes[6], key_length_bytes[7],
-                        ]);
-                        //construct the key
-                        let mut key_value:Vec<u8> = Vec::with_capacity(key_length as usize);
-                        for _i in 0..(key_length/8){
-                            if let Some(chunk) = data_iter.next(){
-                                key_value.append(chunk);
-                            }
-                        }
-                        if key_length % 8 != 0 {
-                            if let Some(chunk) = data_iter.next(){
-                                key_value.append(chunk);
-                            }
-                        }
-                        //remove zeros from the end of the result
-                        while  key_value.len() > key_length as usize{
-                            key_value.pop();
-                        }
-                        let key: K = ToBeBytes::from_vec(key_value);
-                        //get the value
-                        if let Some(value_length_bytes) = data_iter.next() {
-                            //get value length
-                            let value_length: u64 = u64::from_be_bytes([
-                                value_length_bytes[0], value_length_bytes[1], value_length_bytes[2], value_length_bytes[3],
-                                value_length_bytes[4], value_length_bytes[5], value_length_bytes[6], value_length_bytes[7],
-                            ]);
-                            //construct the value
-                            let mut value_value:Vec<u8> = Vec::with_capacity(value_length as usize);
-                            
-                            for _i in 0..(value_length/8){
-                                if let Some(chunk) = data_iter.next(){
-                                    value_value.append(chunk);
-                                }
-                                
-                            }
-                            if value_length % 8 != 0 {
-                                if let Some(chunk) = data_iter.next(){
-                                    value_value.append(chunk);
-                                }
-                            }
-
-                            //remove zeros from the end of the result
-                            while  value_value.len() > value_length as usize{
-                                value_value.pop();
-                            }
-                            let value: V = ToBeBytes::from_vec(value_value);
-                            
-                            resulting_map.insert_no_log(&key,&value);
-                        }
-                    }
-                    
-                    
-                },
-                "KVREMOVE" => {
-                    if let Some(key_length_bytes) = data_iter.next() {
-                        //get key length
-                        let key_length: u64 = u64::from_be_bytes([
-                            key_length_bytes[0], key_length_bytes[1], key_length_bytes[2], key_length_bytes[3],
-                            key_length_bytes[4], key_length_bytes[5], key_length_bytes[6], key_length_bytes[7],
-                        ]);
-                        let mut key_value:Vec<u8> = Vec::with_capacity(key_length as usize);
-                        //construct key
-                        for _i in 0..(key_length/8){
-                            if let Some(chunk) = data_iter.next(){
-                                key_value.append(chunk);
-                            }
-                        }
-                        if key_length % 8 != 0 {
-                            if let Some(chunk) = data_iter.next(){
-                                key_value.append(chunk);
-                            }
-                        }
-                        //remove zeros from the end of the result
-                        while  key_value.len() > key_length as usize{
-                            key_value.pop();
-                        }
-                        let key: K = ToBeBytes::from_vec(key_value);
-                        resulting_map.remove_no_log(&key,);
-                    }
-                },
-                //all logs processed
-                _ => break,
-            }
-        }
-        //return the new map built from the logs
-        resulting_map
+    fn build_from(disk: Disk<D>) -> Result<Self, ()> {
+        Self::build_from_with(disk, 16, ValueMode::Cached)
+    }
+
+    fn build_from_with_capacity(disk: Disk<D>, num_buckets: usize) -> Result<Self, ()> {
+        Self::build_from_with(disk, num_buckets, ValueMode::Cached)
     }
 
-    fn get(&self, key: &Self::Key) -> Option<Self::Value> {
+    fn get(&self, key: &Self::Key) -> Result<Option<Self::Value>, ()> {
         let bucket_idx = self.compute_bucket_idx(key);
-        self.buckets[bucket_idx].data.lock().iter().find(|e| &e.key == key).map(|e| e.value.clone())
+        //the bucket stays locked while reading so a compaction cannot move the value meanwhile
+        let bucket_data = self.buckets[bucket_idx].data.lock();
+        match bucket_data.iter().find(|e| &e.key == key).map(|e| &e.value) {
+            None => Ok(None),
+            Some(Stored::Memory(value)) => Ok(Some(value.clone())),
+            Some(Stored::Log(location)) => self.read_value(location).map(Some),
+        }
     }
 
     fn insert_no_log(&self, key: &Self::Key, value: &Self::Value) -> bool {
         let bucket_idx = self.compute_bucket_idx(key);
-        let bucket = &self.buckets[bucket_idx];
-        let mut bucket_data = bucket.data.lock();
-        if let Some(entry) = bucket_data.iter_mut().find(|e| &e.key == key) {
-            entry.value = value.clone();
-            true
-        } else {
-            bucket_data.push(Entry {
-                key: key.clone(),
-                value: value.clone(),
-            });
-            true
-        }
+        let mut bucket_data = self.buckets[bucket_idx].data.lock();
+        self.put(&mut bucket_data, key, Stored::Memory(value.clone()));
+        true
     }
     fn insert(&self, key: &Self::Key, value: &Self::Value) ->Result<bool,()> {
 
@@ -227,35 +401,23 @@ impl<K: Eq + Clone + core::hash::Hash + AsRef<[u8]> + ToBeBytes, V: Clone + ToBe
         
         let bucket = &self.buckets[bucket_idx];
 
+        //the log is locked before the bucket so nobody holding a bucket waits for the log
+        let mut log = self.log.lock();
         let mut bucket_data = bucket.data.lock();
         
         //logging KV INSERT
-        let mut request : Vec<u8> = "KVKVKVKV".as_bytes().to_vec();
-        
-        request.append(&mut key.len());
-        request.append(&mut key.to_vec());
-        while request.len()%8 != 0{
-            request.push(0);
-        }
-        
-        request.append(&mut value.len());
-        request.append(&mut value.to_vec());
-        while request.len()%8 != 0{
-            request.push(0);
-        }
+        let request = insert_record(key, value);
+        let at = log.end() + HEADER_SIZE + value_offset(key.to_be_bytes().as_ref().len());
         //write log to disk
-        self.disk.lock().append_to_disk(request)?;
+        self.append_one(&mut log, request)?;
+        drop(log);
 
-        if let Some(entry) = bucket_data.iter_mut().find(|e| &e.key == key) {
-            entry.value = value.clone();
-            Ok(true)
-        } else {
-            bucket_data.push(Entry {
-                key: key.clone(),
-                value: value.clone(),
-            });
-            Ok(true)
-        }
+        let stored = self.stored(value, at);
+        self.put(&mut bucket_data, key, stored);
+        self.unapplied.fetch_sub(1, Ordering::SeqCst);
+        drop(bucket_data);
+        self.maybe_compact();
+        Ok(true)
     }
 
     fn remove_no_log(&self, key: &Self::Key) -> bool {
@@ -263,7 +425,9 @@ impl<K: Eq + Clone + core::hash::Hash + AsRef<[u8]> + ToBeBytes, V: Clone + ToBe
         let bucket = &self.buckets[bucket_idx];
         let mut bucket_data = bucket.data.lock();
         if let Some(pos) = bucket_data.iter().position(|e| &e.key == key) {
-            bucket_data.remove(pos);
+            let entry = bucket_data.remove(pos);
+            self.unaccount(&entry.key, &entry.value);
+            self.index.remove(key);
             true
         } else {
             false
@@ -273,51 +437,464 @@ impl<K: Eq + Clone + core::hash::Hash + AsRef<[u8]> + ToBeBytes, V: Clone + ToBe
     fn remove(&self, key: &Self::Key) -> Result<bool,()> {
         let bucket_idx = self.compute_bucket_idx(key);
         let bucket = &self.buckets[bucket_idx];
+        let mut log = self.log.lock();
         let mut bucket_data = bucket.data.lock();
         if let Some(pos) = bucket_data.iter().position(|e| &e.key == key) {
-            //logging KV INSERT
-            let mut request : Vec<u8> = "KVREMOVE".as_bytes().to_vec();
-            request.append(&mut key.len());
-            request.append(&mut key.to_vec());
-            while request.len()%8 != 0{
-                request.push(0);
-            }
-            self.disk.lock().append_to_disk(request)?;
+            //logging KV REMOVE
+            let request = remove_record(key);
+            self.append_one(&mut log, request)?;
+            drop(log);
             //Actually do the remove
-            bucket_data.remove(pos);
+            let entry = bucket_data.remove(pos);
+            self.unaccount(&entry.key, &entry.value);
+            self.index.remove(key);
+            self.unapplied.fetch_sub(1, Ordering::SeqCst);
+            drop(bucket_data);
+            self.maybe_compact();
             Ok(true)
         } else {
             Ok(false)
         }
     }
 
-    fn compact_logs(&mut self) -> Result<(),()>{
-        let mut request : Vec<u8> = Vec::new();
+    fn compact_logs(&self) -> Result<(),()>{
+        //another compaction already running is as good as this one
+        if self.compacting.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+        let result = self.compact_online();
+        self.compacting.store(false, Ordering::SeqCst);
+        result
+    }
+
+    fn maintain(&self) {
+        if self.compaction_due.swap(false, Ordering::SeqCst) && self.compact_logs().is_err() {
+            serial_warnln!("log compaction failed, the old log stays in use");
+        }
+    }
+
+    fn commit_group(&self, writes: &[(&Self::Key, &Self::Value)], removes: &[&Self::Key]) -> Result<(),()> {
+        let record = group_record(writes, removes);
+        let mut at = self.group.write(&self.log, &self.unapplied, record)? + GROUP_BEGIN.len();
+        //nothing is visible before the whole group is durable
+        for (key, value) in writes {
+            let key_len = key.to_be_bytes().as_ref().len();
+            let stored = self.stored(*value, at + value_offset(key_len));
+            at += insert_record_len(key_len, value.to_be_bytes().as_ref().len());
+            let bucket_idx = self.compute_bucket_idx(key);
+            self.put(&mut self.buckets[bucket_idx].data.lock(), key, stored);
+        }
+        for key in removes {
+            self.remove_no_log(key);
+        }
+        self.unapplied.fetch_sub(1, Ordering::SeqCst);
+        self.maybe_compact();
+        Ok(())
+    }
+
+    fn scan(&self, start: &Self::Key, limit: usize) -> Vec<(Self::Key, Self::Value)> {
+        //keys are collected first so the index lock is never held while taking a bucket lock
+        let keys = self.index.range_from(start, limit);
+        let mut result = Vec::with_capacity(keys.len());
+        for key in keys {
+            if let Ok(Some(value)) = self.get(&key) {
+                result.push((key, value));
+            }
+        }
+        result
+    }
+
+}
+
+impl<K: Eq + core::hash::Hash + AsRef<[u8]>, V, D: BlockDevice> PersistentHashMap<K, V, D> {
+    fn compute_bucket_idx(&self, key: &K) -> usize {
+        let hash = Mix13Hash::new().compute_hash(&key.as_ref().to_vec());
+        (hash % self.num_buckets as u64) as usize
+    }
+}
+
+impl<K: Eq + Ord + Clone + core::hash::Hash + AsRef<[u8]> + ToBeBytes, V: Clone + ToBeBytes, D: BlockDevice> PersistentHashMap<K, V, D> {
+    //builds a map from what is on the default device that keeps its values the way mode says
+    pub fn build_from_disk_with(mode: ValueMode) -> Result<Self, ()> where D: Default {
+        Self::build_from_with(Disk::with_device(D::default()), 16, mode)
+    }
+
+    //builds a map of num_buckets buckets from what is on disk that keeps its values the way mode says
+    pub fn build_from_with(disk: Disk<D>, num_buckets: usize, mode: ValueMode) -> Result<Self, ()> {
+        //the log is read one record at a time, only the latest change of each key is kept
+        let mut entries: BTreeMap<K, Stored<V>> = BTreeMap::new();
+        let mut discarded_groups = 0;
+        let mut first = true;
+        //recovery stops at the last record that passes its checks
+        let (log, mut report) = Log::open_with(disk.clone(), |seq, at, payload| {
+            //a keydir map skips the records the hint describes
+            if mode == ValueMode::KeyDir && core::mem::take(&mut first) {
+                if let Some((located, next, skipped)) = decode_hint::<K>(&payload) {
+                    entries.extend(located.into_iter().map(|(key, location)| (key, Stored::Log(location))));
+                    return Some((next, seq + 1 + skipped));
+                }
+            }
+            let (changes, complete) = decode_changes::<K, Stored<V>>(&payload, |bytes, pos| match mode {
+                ValueMode::Cached => V::from_vec(bytes.to_vec()).map(Stored::Memory),
+                ValueMode::KeyDir => Ok(Stored::Log(ValueLocation::at(at + pos, bytes.len()))),
+            });
+            for (key, value) in changes {
+                match value {
+                    Some(value) => entries.insert(key, value),
+                    None => entries.remove(&key),
+                };
+            }
+            if !complete {
+                discarded_groups += 1;
+            }
+            None
+        })?;
+        report.discarded_groups = discarded_groups;
+        let mut resulting_map = Self::with_log(num_buckets, mode, disk, log);
+        for (key, value) in entries {
+            let bucket_idx = resulting_map.compute_bucket_idx(&key);
+            resulting_map.put(&mut resulting_map.buckets[bucket_idx].data.lock(), &key, value);
+        }
+        if report.end != LogEnd::Clean || report.discarded_groups != 0 {
+            serial_warnln!("log recovery: {:?} at byte {}, {} records replayed, {} groups discarded",
+                report.end, report.end_offset, report.records, report.discarded_groups);
+        }
+        resulting_map.recovery = Some(report);
+        //return the new map built from the logs
+        Ok(resulting_map)
+    }
+
+    //sets the entry of key in the locked bucket
+    fn put(&self, bucket_data: &mut Vec<Entry<K, V>>, key: &K, value: Stored<V>) {
+        self.account(key, &value);
+        if let Some(entry) = bucket_data.iter_mut().find(|e| &e.key == key) {
+            self.unaccount(&entry.key, &entry.value);
+            entry.value = value;
+        } else {
+            bucket_data.push(Entry {
+                key: key.clone(),
+                value,
+            });
+            self.index.insert(key);
+        }
+    }
+
+    //how to keep a value that was just logged at byte offset at
+    fn stored(&self, value: &V, at: usize) -> Stored<V> {
+        match self.mode {
+            ValueMode::Cached => Stored::Memory(value.clone()),
+            ValueMode::KeyDir => Stored::Log(ValueLocation::at(at, value.to_be_bytes().as_ref().len())),
+        }
+    }
+
+    fn read_value(&self, location: &ValueLocation) -> Result<V, ()> {
+        let bytes = self.disk.clone().read_bytes(location.byte(), location.len)?;
+        V::from_vec(bytes)
+    }
+
+    fn value_bytes(&self, value: &Stored<V>) -> Result<Vec<u8>, ()> {
+        match value {
+            Stored::Memory(value) => Ok(value.to_vec()),
+            Stored::Log(location) => self.disk.clone().read_bytes(location.byte(), location.len),
+        }
+    }
+
+    //appends a single record and counts it as not applied yet
+    fn append_one(&self, log: &mut Log<D>, request: Vec<u8>) -> Result<(),()> {
+        log.append(vec![request])?;
+        self.unapplied.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn account(&self, key: &K, value: &Stored<V>) {
+        self.live_bytes.fetch_add(entry_bytes(key, value), Ordering::SeqCst);
+    }
+
+    fn unaccount(&self, key: &K, value: &Stored<V>) {
+        self.live_bytes.fetch_sub(entry_bytes(key, value), Ordering::SeqCst);
+    }
+
+    //asks for a compaction when the policy says the log got too big or has too much garbage,
+    //it is left to maintain so no write waits for it
+    fn maybe_compact(&self) {
+        let (size, capacity) = match self.log.try_lock() {
+            Some(log) => (log.size(), log.capacity()),
+            //someone else is writing, they check after their write
+            None => return,
+        };
+        if self.compaction.lock().should_compact(size, self.live_bytes.load(Ordering::SeqCst), capacity) {
+            self.compaction_due.store(true, Ordering::SeqCst);
+        }
+    }
+
+    //locks the log once every logged change is in the buckets, changes are only counted with
+    //the log locked so none can come in after the check, writers are not held up meanwhile
+    fn lock_applied(&self) -> MutexGuard<'_, Log<D>> {
+        loop {
+            while self.unapplied.load(Ordering::SeqCst) != 0 {
+                spin_loop();
+            }
+            let log = self.log.lock();
+            if self.unapplied.load(Ordering::SeqCst) == 0 {
+                return log;
+            }
+        }
+    }
+
+    //copies the live entries into the other log region without holding up writers,
+    //then switches over with the log locked only for the changes made in the meantime
+    fn compact_online(&self) -> Result<(),()> {
+        let (mut target, captured_from) = {
+            //every logged change has to be in the buckets before they are copied,
+            //changes logged from here on are captured by the log
+            let mut log = self.lock_applied();
+            let captured_from = log.end();
+            (log.begin_compaction()?, captured_from)
+        };
+        let written = self.copy_live(&mut target);
+        //the captured changes have to be in the buckets before their locations move
+        let mut log = self.lock_applied();
+        let result = written.and_then(|moved| {
+            let captured_to = target.end();
+            log.finish_compaction(target)?;
+            if self.mode == ValueMode::KeyDir {
+                self.relocate(&moved, captured_from, captured_to);
+            }
+            Ok(())
+        });
+        if result.is_err() {
+            log.cancel_compaction();
+        }
+        result
+    }
+
+    //writes an insert record for every entry to target, a keydir map puts a hint in front
+    //of them and gets back where the values it keeps in the log went
+    fn copy_live(&self, target: &mut Log<D>) -> Result<BTreeMap<K, ValueLocation>, ()> {
+        let keydir = self.mode == ValueMode::KeyDir;
+        let mut records : Vec<Vec<u8>> = Vec::new();
+        let mut keys = Vec::new();
+        if keydir {
+            records.push(Vec::new());
+        }
         for bucket in self.buckets.iter(){
             for entry in bucket.data.lock().iter(){
-                request.append(&mut "KVKVKVKV".as_bytes().to_vec());
-                //add key to vec
-                request.append(&mut entry.key.len());
-                request.append(&mut entry.key.to_vec());
-                while request.len()%8 != 0{
-                    request.push(0);
+                let value = self.value_bytes(&entry.value)?;
+                records.push(insert_record_bytes(entry.key.to_be_bytes().as_ref(), &value));
+                if keydir {
+                    keys.push((entry.key.clone(), value.len(), matches!(entry.value, Stored::Log(_))));
                 }
-                //add value to vec
-                request.append(&mut entry.value.len());
-                request.append(&mut entry.value.to_vec());
-                while request.len()%8 != 0{
-                    request.push(0);
+            }
+        }
+        let mut moved = BTreeMap::new();
+        if keydir {
+            //the hint has the same size whatever locations it holds
+            let placeholder: Vec<(&K, ValueLocation)> = keys.iter().map(|(key, _, _)| (key, ValueLocation::at(0, 0))).collect();
+            records[0] = hint_record(0, 0, &placeholder);
+            let offsets = target.offsets(&records);
+            let located: Vec<(&K, ValueLocation)> = keys.iter().zip(offsets[1..].iter()).map(|((key, len, _), at)| {
+                (key, ValueLocation::at(at + value_offset(key.to_be_bytes().as_ref().len()), *len))
+            }).collect();
+            let last = records.len() - 1;
+            records[0] = hint_record(offsets[last] + records[last].len(), last, &located);
+            for ((key, location), (_, _, on_disk)) in located.into_iter().zip(keys.iter()) {
+                if *on_disk {
+                    moved.insert(key.clone(), location);
                 }
             }
         }
-        self.disk.lock().over_write_disk(request)
+        target.append(records)?;
+        Ok(moved)
     }
 
+    //points the entries kept in the log to where the compaction moved their values,
+    //changes logged from captured_from on were appended to the new region at captured_to
+    fn relocate(&self, moved: &BTreeMap<K, ValueLocation>, captured_from: usize, captured_to: usize) {
+        for bucket in self.buckets.iter() {
+            for entry in bucket.data.lock().iter_mut() {
+                if let Stored::Log(location) = &mut entry.value {
+                    if location.byte() >= captured_from {
+                        *location = ValueLocation::at(location.byte() - captured_from + captured_to, location.len);
+                    } else if let Some(new_location) = moved.get(&entry.key) {
+                        *location = *new_location;
+                    }
+                }
+            }
+        }
+    }
 }
 
-impl<K: Eq + core::hash::Hash + AsRef<[u8]>, V> PersistentHashMap<K, V> {
-    fn compute_bucket_idx(&self, key: &K) -> usize {
-        let hash = Mix13Hash::new().compute_hash(&key.as_ref().to_vec());
-        (hash % self.num_buckets as u64) as usize
+impl<V: ToBeBytes> Stored<V> {
+    fn len(&self) -> usize {
+        match self {
+            Stored::Memory(value) => value.to_be_bytes().as_ref().len(),
+            Stored::Log(location) => location.len,
+        }
+    }
+}
+
+fn padded(len: usize) -> usize {
+    (len + 7) / 8 * 8
+}
+
+//where the value starts in an insert record
+fn value_offset(key_len: usize) -> usize {
+    8 + 8 + padded(key_len) + 8
+}
+
+fn insert_record_len(key_len: usize, value_len: usize) -> usize {
+    value_offset(key_len) + padded(value_len)
+}
+
+//bytes an entry takes in a compacted log
+fn entry_bytes<K: ToBeBytes, V: ToBeBytes>(key: &K, value: &Stored<V>) -> usize {
+    HEADER_SIZE + insert_record_len(key.to_be_bytes().as_ref().len(), value.len())
+}
+
+//appends the length and the bytes padded to 8 bytes
+fn push_log_bytes(request: &mut Vec<u8>, bytes: &[u8]) {
+    request.extend_from_slice(&(bytes.len() as u64).to_be_bytes());
+    request.extend_from_slice(bytes);
+    while request.len()%8 != 0{
+        request.push(0);
+    }
+}
+
+pub(crate) fn insert_record<K: ToBeBytes, V: ToBeBytes>(key: &K, value: &V) -> Vec<u8> {
+    insert_record_bytes(key.to_be_bytes().as_ref(), value.to_be_bytes().as_ref())
+}
+
+fn insert_record_bytes(key: &[u8], value: &[u8]) -> Vec<u8> {
+    let mut request : Vec<u8> = "KVKVKVKV".as_bytes().to_vec();
+    push_log_bytes(&mut request, key);
+    push_log_bytes(&mut request, value);
+    request
+}
+
+pub(crate) fn remove_record<K: ToBeBytes>(key: &K) -> Vec<u8> {
+    let mut request : Vec<u8> = "KVREMOVE".as_bytes().to_vec();
+    push_log_bytes(&mut request, key.to_be_bytes().as_ref());
+    request
+}
+
+//keydir of a compacted log, boot reads it instead of the records that follow it: the
+//offset right after them, how many they are and where each of their values is
+fn hint_record<K: ToBeBytes>(next: usize, records: usize, located: &[(&K, ValueLocation)]) -> Vec<u8> {
+    let mut record : Vec<u8> = HINT.as_bytes().to_vec();
+    record.extend_from_slice(&(next as u64).to_be_bytes());
+    record.extend_from_slice(&(records as u64).to_be_bytes());
+    for (key, location) in located {
+        push_log_bytes(&mut record, key.to_be_bytes().as_ref());
+        for field in [location.block, location.offset, location.len] {
+            record.extend_from_slice(&(field as u64).to_be_bytes());
+        }
+    }
+    record
+}
+
+//what hint_record wrote, None if the payload is not a hint
+fn decode_hint<K: ToBeBytes>(payload: &[u8]) -> Option<(Vec<(K, ValueLocation)>, usize, u64)> {
+    if payload.get(0..8)? != HINT.as_bytes() {
+        return None;
+    }
+    let next = read_u64(payload, 8)? as usize;
+    let records = read_u64(payload, 16)?;
+    let mut pos = 24;
+    let mut located = Vec::new();
+    while pos < payload.len() {
+        let key = read_log_bytes(payload, &mut pos)?;
+        let block = read_u64(payload, pos)? as usize;
+        let offset = read_u64(payload, pos + 8)? as usize;
+        let len = read_u64(payload, pos + 16)? as usize;
+        pos += 24;
+        located.push((K::from_vec(payload[key].to_vec()).ok()?, ValueLocation { block, offset, len }));
+    }
+    Some((located, next, records))
+}
+
+//all writes and removes framed by the group markers
+pub(crate) fn group_record<K: ToBeBytes, V: ToBeBytes>(writes: &[(&K, &V)], removes: &[&K]) -> Vec<u8> {
+    let mut record : Vec<u8> = GROUP_BEGIN.as_bytes().to_vec();
+    for (key, value) in writes {
+        record.append(&mut insert_record(*key, *value));
+    }
+    for key in removes {
+        record.append(&mut remove_record(*key));
+    }
+    record.append(&mut GROUP_COMMIT.as_bytes().to_vec());
+    record
+}
+
+//the changes in the payload of one log record in order, None for a remove
+//false if it held a group without its commit marker, that group is left out
+pub(crate) fn decode_record<K: ToBeBytes, V: ToBeBytes>(payload: &[u8]) -> (Vec<(K, Option<V>)>, bool) {
+    decode_changes(payload, |bytes, _| V::from_vec(bytes.to_vec()))
+}
+
+//decode_record but value gets the bytes of each value and where they start in the payload
+//a key or value that does not decode ends the payload like a bad length does
+fn decode_changes<K: ToBeBytes, T>(payload: &[u8], mut value: impl FnMut(&[u8], usize) -> Result<T, ()>) -> (Vec<(K, Option<T>)>, bool) {
+    //walk over the payload in 8 byte steps
+    let mut pos = 0;
+    let mut changes = Vec::new();
+    //changes of a group seen so far, None outside of a group
+    let mut group: Option<Vec<(K, Option<T>)>> = None;
+    while let Some(tag) = payload.get(pos..pos + 8) {
+        pos += 8;
+        let change = match tag {
+            b"KVKVKVKV" => {
+                let key = match read_log_bytes(payload, &mut pos) {
+                    Some(key) => key,
+                    None => break,
+                };
+                let bytes = match read_log_bytes(payload, &mut pos) {
+                    Some(bytes) => bytes,
+                    None => break,
+                };
+                match (K::from_vec(payload[key].to_vec()), value(&payload[bytes.clone()], bytes.start)) {
+                    (Ok(key), Ok(value)) => (key, Some(value)),
+                    _ => break,
+                }
+            },
+            b"KVREMOVE" => {
+                match read_log_bytes(payload, &mut pos).map(|key| K::from_vec(payload[key].to_vec())) {
+                    Some(Ok(key)) => (key, None),
+                    _ => break,
+                }
+            },
+            tag if tag == GROUP_BEGIN.as_bytes() => {
+                group = Some(Vec::new());
+                continue;
+            },
+            tag if tag == GROUP_COMMIT.as_bytes() => {
+                changes.append(&mut group.take().unwrap_or_default());
+                continue;
+            },
+            //all logs processed
+            _ => break,
+        };
+        match group.as_mut() {
+            Some(group_changes) => group_changes.push(change),
+            None => changes.push(change),
+        }
+    }
+    let complete = group.is_none();
+    (changes, complete)
+}
+
+fn read_u64(payload: &[u8], pos: usize) -> Option<u64> {
+    Some(u64::from_be_bytes(payload.get(pos..pos + 8)?.try_into().ok()?))
+}
+
+//finds what push_log_bytes wrote at pos and moves pos past it
+fn read_log_bytes(payload: &[u8], pos: &mut usize) -> Option<Range<usize>> {
+    let length = read_u64(payload, *pos)? as usize;
+    let start = *pos + 8;
+    let end = start.checked_add(padded(length))?;
+    if end > payload.len() {
+        return None;
     }
+    *pos = end;
+    //the padding is left out
+    Some(start..start + length)
 }
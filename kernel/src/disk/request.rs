@@ -0,0 +1,108 @@
+extern crate alloc;
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::hint::spin_loop;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, Waker};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+//what a submitter waits on, filled in by the driver when the transfer is done
+pub struct Request {
+    done: AtomicBool,
+    //the blocks read, empty for a write
+    result: Mutex<Option<Result<Vec<u8>, ()>>>,
+    waker: Mutex<Option<Waker>>,
+}
+
+impl Request {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self { done: AtomicBool::new(false), result: Mutex::new(None), waker: Mutex::new(None) })
+    }
+
+    //wake_cores is for waiters halted on cores the interrupt does not go to
+    pub fn finish(&self, result: Result<Vec<u8>, ()>, wake_cores: bool) {
+        *self.result.lock() = Some(result);
+        self.done.store(true, Ordering::SeqCst);
+        if let Some(waker) = self.waker.lock().take() {
+            waker.wake();
+        }
+        if wake_cores {
+            crate::interrupts::wake_cores();
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        self.done.load(Ordering::SeqCst)
+    }
+
+    fn take(&self) -> Result<Vec<u8>, ()> {
+        self.result.lock().take().unwrap_or(Err(()))
+    }
+}
+
+//a submitted transfer, wait blocks until it is done, or await it
+pub struct Pending {
+    request: Arc<Request>,
+    //set while the driver finishes requests from its interrupt handler, polled otherwise
+    interrupts: Option<&'static AtomicBool>,
+    //moves the transfers of the controller along, for waiters that do not get the interrupt
+    service: Box<dyn Fn() + Send + Sync>,
+}
+
+impl Pending {
+    pub fn new(request: Arc<Request>, interrupts: Option<&'static AtomicBool>, service: impl Fn() + Send + Sync + 'static) -> Self {
+        Self { request, interrupts, service: Box::new(service) }
+    }
+
+    //a request the controller never saw
+    pub fn failed() -> Self {
+        let request = Request::new();
+        request.finish(Err(()), false);
+        Self::new(request, None, || {})
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.request.is_done()
+    }
+
+    //halts until the interrupt handler finished the transfer, without interrupts the
+    //controller is polled instead, the blocks read or an empty vec for a write
+    pub fn wait(self) -> Result<Vec<u8>, ()> {
+        while !self.is_done() {
+            let halt = self.interrupts.map_or(false, |enabled| enabled.load(Ordering::SeqCst));
+            if halt && interrupts::are_enabled() {
+                //the interrupt could come between the check and hlt
+                interrupts::disable();
+                if self.is_done() {
+                    interrupts::enable();
+                    break;
+                }
+                interrupts::enable_and_hlt();
+            } else {
+                spin_loop();
+            }
+            (self.service)();
+        }
+        self.request.take()
+    }
+}
+
+impl Future for Pending {
+    type Output = Result<Vec<u8>, ()>;
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<Self::Output> {
+        if !self.is_done() {
+            interrupts::without_interrupts(|| *self.request.waker.lock() = Some(context.waker().clone()));
+            (self.service)();
+        }
+        if self.is_done() {
+            Poll::Ready(self.request.take())
+        } else {
+            Poll::Pending
+        }
+    }
+}
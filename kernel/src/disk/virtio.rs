@@ -0,0 +1,525 @@
+extern crate alloc;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::hint::spin_loop;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{fence, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+use crate::disk::ata::BLOCK_SIZE;
+use crate::disk::dma::Dma;
+use crate::disk::request::Request;
+pub use crate::disk::request::Pending;
+use crate::memory::paging::PAGE_SIZE;
+use crate::pci::{self, Bar, Device, Driver};
+
+//blocks moved by one request
+pub const MAX_BLOCKS: usize = 128;
+//requests a read or write of a range has in the queue at once, each holds a buffer of
+//MAX_BLOCKS on the heap until it is waited for
+const IN_FLIGHT: usize = 2;
+//descriptors of the queue of a modern device, so the whole split queue fits in one page
+const MODERN_QUEUE_SIZE: u16 = 64;
+
+//device status
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FEATURES_OK: u8 = 8;
+
+//features
+const BLK_F_FLUSH: u64 = 1 << 9;
+const F_VERSION_1: u64 = 1 << 32;
+
+//legacy registers, from the io bar 0
+const LEGACY_DEVICE_FEATURES: u16 = 0x00;
+const LEGACY_DRIVER_FEATURES: u16 = 0x04;
+const LEGACY_QUEUE_ADDRESS: u16 = 0x08;
+const LEGACY_QUEUE_SIZE: u16 = 0x0C;
+const LEGACY_QUEUE_SELECT: u16 = 0x0E;
+const LEGACY_QUEUE_NOTIFY: u16 = 0x10;
+const LEGACY_STATUS: u16 = 0x12;
+//the device config, without msi-x
+const LEGACY_CONFIG: u16 = 0x14;
+
+//modern common config registers
+const COMMON_DEVICE_FEATURE_SELECT: usize = 0x00;
+const COMMON_DEVICE_FEATURE: usize = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: usize = 0x08;
+const COMMON_DRIVER_FEATURE: usize = 0x0C;
+const COMMON_STATUS: usize = 0x14;
+const COMMON_QUEUE_SELECT: usize = 0x16;
+const COMMON_QUEUE_SIZE: usize = 0x18;
+const COMMON_QUEUE_MSIX_VECTOR: usize = 0x1A;
+const COMMON_QUEUE_ENABLE: usize = 0x1C;
+const COMMON_QUEUE_NOTIFY_OFF: usize = 0x1E;
+const COMMON_QUEUE_DESC: usize = 0x20;
+const COMMON_QUEUE_DRIVER: usize = 0x28;
+const COMMON_QUEUE_DEVICE: usize = 0x30;
+
+//vendor capability types of a modern device
+const CAPABILITY_VENDOR: u8 = 0x09;
+const CFG_COMMON: u8 = 1;
+const CFG_NOTIFY: u8 = 2;
+const CFG_DEVICE: u8 = 4;
+
+//descriptor flags
+const DESC_NEXT: u16 = 1;
+const DESC_WRITE: u16 = 2;
+//the used ring is polled, the device does not have to interrupt
+const AVAIL_NO_INTERRUPT: u16 = 1;
+
+//request types
+const REQUEST_IN: u32 = 0;
+const REQUEST_OUT: u32 = 1;
+const REQUEST_FLUSH: u32 = 4;
+
+//how the registers of the device are reached
+enum Transport {
+    //io ports from bar 0
+    Legacy { base: u16 },
+    //mmio regions the vendor capabilities point at, notify is the address for queue 0
+    Modern { common: usize, notify: usize, device: usize },
+}
+
+fn mmio_read<T>(address: usize) -> T {
+    unsafe { read_volatile(address as *const T) }
+}
+
+fn mmio_write<T>(address: usize, value: T) {
+    unsafe { write_volatile(address as *mut T, value) }
+}
+
+impl Transport {
+    //the modern registers when the device has the capabilities, the legacy ones otherwise
+    fn find(device: &Device) -> Option<Self> {
+        let mut common = None;
+        let mut notify = None;
+        let mut config = None;
+        for (id, offset) in device.capabilities() {
+            if id != CAPABILITY_VENDOR {
+                continue;
+            }
+            let header = device.read_config(offset);
+            let bar = device.read_config(offset + 4) as u8;
+            let address = match device.bars.get(bar as usize).copied().flatten() {
+                Some(Bar::Memory { addr, .. }) => addr as usize + device.read_config(offset + 8) as usize,
+                _ => continue,
+            };
+            match (header >> 24) as u8 {
+                CFG_COMMON => common = Some(address),
+                CFG_NOTIFY => notify = Some((address, device.read_config(offset + 16) as usize)),
+                CFG_DEVICE => config = Some(address),
+                _ => (),
+            }
+        }
+        if let (Some(common), Some((notify, multiplier)), Some(device)) = (common, notify, config) {
+            //every queue has its own notify address
+            mmio_write::<u16>(common + COMMON_QUEUE_SELECT, 0);
+            let offset = mmio_read::<u16>(common + COMMON_QUEUE_NOTIFY_OFF) as usize;
+            return Some(Transport::Modern { common, notify: notify + offset * multiplier, device });
+        }
+        match device.bars[0] {
+            Some(Bar::Io { port, .. }) => Some(Transport::Legacy { base: port }),
+            _ => None,
+        }
+    }
+
+    fn status(&self) -> u8 {
+        match self {
+            Transport::Legacy { base } => unsafe { Port::<u8>::new(base + LEGACY_STATUS).read() },
+            Transport::Modern { common, .. } => mmio_read(common + COMMON_STATUS),
+        }
+    }
+
+    fn set_status(&self, status: u8) {
+        match self {
+            Transport::Legacy { base } => unsafe { Port::<u8>::new(base + LEGACY_STATUS).write(status) },
+            Transport::Modern { common, .. } => mmio_write(common + COMMON_STATUS, status),
+        }
+    }
+
+    fn device_features(&self) -> u64 {
+        match self {
+            Transport::Legacy { base } => unsafe { Port::<u32>::new(base + LEGACY_DEVICE_FEATURES).read() as u64 },
+            Transport::Modern { common, .. } => {
+                mmio_write::<u32>(common + COMMON_DEVICE_FEATURE_SELECT, 0);
+                let low = mmio_read::<u32>(common + COMMON_DEVICE_FEATURE) as u64;
+                mmio_write::<u32>(common + COMMON_DEVICE_FEATURE_SELECT, 1);
+                low | (mmio_read::<u32>(common + COMMON_DEVICE_FEATURE) as u64) << 32
+            },
+        }
+    }
+
+    fn set_driver_features(&self, features: u64) {
+        match self {
+            Transport::Legacy { base } => unsafe { Port::<u32>::new(base + LEGACY_DRIVER_FEATURES).write(features as u32) },
+            Transport::Modern { common, .. } => {
+                mmio_write::<u32>(common + COMMON_DRIVER_FEATURE_SELECT, 0);
+                mmio_write::<u32>(common + COMMON_DRIVER_FEATURE, features as u32);
+                mmio_write::<u32>(common + COMMON_DRIVER_FEATURE_SELECT, 1);
+                mmio_write::<u32>(common + COMMON_DRIVER_FEATURE, (features >> 32) as u32);
+            },
+        }
+    }
+
+    //the capacity in 512 byte sectors, the first field of the blk config
+    fn capacity(&self) -> u64 {
+        match self {
+            Transport::Legacy { base } => unsafe {
+                let low = Port::<u32>::new(base + LEGACY_CONFIG).read() as u64;
+                low | (Port::<u32>::new(base + LEGACY_CONFIG + 4).read() as u64) << 32
+            },
+            Transport::Modern { device, .. } => {
+                mmio_read::<u32>(*device) as u64 | (mmio_read::<u32>(device + 4) as u64) << 32
+            },
+        }
+    }
+
+    //hands queue 0 to the device, None if it has no queue or the memory is missing or not contiguous
+    fn setup_queue(&self) -> Option<Queue> {
+        match self {
+            Transport::Legacy { base } => unsafe {
+                Port::<u16>::new(base + LEGACY_QUEUE_SELECT).write(0);
+                //a legacy device picks the size and wants the rings in one physically contiguous block
+                let size = Port::<u16>::new(base + LEGACY_QUEUE_SIZE).read();
+                if size == 0 {
+                    return None;
+                }
+                let queue = Queue::legacy(size)?;
+                if !queue.memory.is_contiguous() {
+                    serial_errorln!("virtio: queue memory is not contiguous");
+                    return None;
+                }
+                Port::<u32>::new(base + LEGACY_QUEUE_ADDRESS).write((queue.memory.physical(0) / PAGE_SIZE as u64) as u32);
+                Some(queue)
+            },
+            Transport::Modern { common, .. } => {
+                mmio_write::<u16>(common + COMMON_QUEUE_SELECT, 0);
+                let size = mmio_read::<u16>(common + COMMON_QUEUE_SIZE).min(MODERN_QUEUE_SIZE);
+                if size == 0 {
+                    return None;
+                }
+                let queue = Queue::modern(size)?;
+                mmio_write::<u16>(common + COMMON_QUEUE_SIZE, size);
+                for (register, offset) in [(COMMON_QUEUE_DESC, queue.desc), (COMMON_QUEUE_DRIVER, queue.avail), (COMMON_QUEUE_DEVICE, queue.used)] {
+                    let address = queue.memory.physical(offset);
+                    mmio_write::<u32>(common + register, address as u32);
+                    mmio_write::<u32>(common + register + 4, (address >> 32) as u32);
+                }
+                //no msi-x vector, the used ring is polled
+                mmio_write::<u16>(common + COMMON_QUEUE_MSIX_VECTOR, 0xFFFF);
+                mmio_write::<u16>(common + COMMON_QUEUE_ENABLE, 1);
+                Some(queue)
+            },
+        }
+    }
+
+    fn notify(&self) {
+        match self {
+            Transport::Legacy { base } => unsafe { Port::<u16>::new(base + LEGACY_QUEUE_NOTIFY).write(0) },
+            Transport::Modern { notify, .. } => mmio_write::<u16>(*notify, 0),
+        }
+    }
+
+    fn is_modern(&self) -> bool {
+        matches!(self, Transport::Modern { .. })
+    }
+}
+
+//a request the device has not put in the used ring yet
+struct InFlight {
+    //the request header, then the status byte the device writes at STATUS_OFFSET
+    header: Dma,
+    data: Option<Dma>,
+    len: usize,
+    write: bool,
+    descriptors: Vec<u16>,
+    request: Arc<Request>,
+}
+
+const STATUS_OFFSET: usize = 16;
+
+impl InFlight {
+    fn finish(mut self) {
+        let ok = self.header.bytes()[STATUS_OFFSET] == 0;
+        let data = match (self.write, self.data) {
+            (false, Some(mut data)) => data.bytes()[..self.len].to_vec(),
+            _ => Vec::new(),
+        };
+        self.request.finish(if ok { Ok(data) } else { Err(()) }, false);
+    }
+}
+
+//a split virtqueue, descriptor table, available ring and used ring in one block of memory
+struct Queue {
+    memory: Dma,
+    size: u16,
+    //offsets of the parts in memory
+    desc: usize,
+    avail: usize,
+    used: usize,
+    free: Vec<u16>,
+    next_avail: u16,
+    last_used: u16,
+    //by head descriptor
+    in_flight: BTreeMap<u16, InFlight>,
+}
+
+impl Queue {
+    //the used ring starts on the page after the available ring
+    fn legacy(size: u16) -> Option<Self> {
+        let n = size as usize;
+        let used = (16 * n + 6 + 2 * n + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE;
+        let len = (used + 6 + 8 * n + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE;
+        Some(Self::new(Dma::new(len, PAGE_SIZE)?, size, 0, 16 * n, used))
+    }
+
+    //the parts only have to be aligned to themselves
+    fn modern(size: u16) -> Option<Self> {
+        let n = size as usize;
+        let avail = 16 * n;
+        let used = (avail + 6 + 2 * n + 3) / 4 * 4;
+        Some(Self::new(Dma::new(PAGE_SIZE, PAGE_SIZE)?, size, 0, avail, used))
+    }
+
+    fn new(memory: Dma, size: u16, desc: usize, avail: usize, used: usize) -> Self {
+        let mut queue = Self { memory, size, desc, avail, used, free: (0..size).rev().collect(), next_avail: 0, last_used: 0, in_flight: BTreeMap::new() };
+        queue.write_u16(queue.avail, AVAIL_NO_INTERRUPT);
+        queue
+    }
+
+    fn read_u16(&self, offset: usize) -> u16 {
+        mmio_read(self.memory.address(offset))
+    }
+
+    fn write_u16(&mut self, offset: usize, value: u16) {
+        mmio_write(self.memory.address(offset), value)
+    }
+
+    fn write_descriptor(&mut self, index: u16, address: u64, len: usize, flags: u16, next: u16) {
+        let offset = self.desc + 16 * index as usize;
+        let bytes = &mut self.memory.bytes()[offset..offset + 16];
+        bytes[..8].copy_from_slice(&address.to_le_bytes());
+        bytes[8..12].copy_from_slice(&(len as u32).to_le_bytes());
+        bytes[12..14].copy_from_slice(&flags.to_le_bytes());
+        bytes[14..16].copy_from_slice(&next.to_le_bytes());
+    }
+
+    //chains header, data and status and puts the chain in the available ring, false if there
+    //are not enough free descriptors and Err if there is no memory for the buffers
+    fn push(&mut self, kind: u32, block: u64, blocks: usize, data: Option<&[u8]>, request: &Arc<Request>) -> Result<bool, ()> {
+        let len = blocks * BLOCK_SIZE;
+        let mut buffer = if blocks > 0 { Some(Dma::new(len, BLOCK_SIZE).ok_or(())?) } else { None };
+        if let (Some(buffer), Some(data)) = (buffer.as_mut(), data) {
+            buffer.bytes()[..len].copy_from_slice(data);
+        }
+        //one descriptor for every page the data touches, the buffer is contiguous in virtual memory only
+        let segments: Vec<(u64, usize)> = buffer.as_ref().map_or(Vec::new(), |buffer| buffer.segments(0, len).collect());
+        if self.free.len() < segments.len() + 2 {
+            return Ok(false);
+        }
+        //aligned so the header and status never cross a page
+        let mut header = Dma::new(STATUS_OFFSET + 1, 32).ok_or(())?;
+        let bytes = header.bytes();
+        bytes[..4].copy_from_slice(&kind.to_le_bytes());
+        bytes[8..16].copy_from_slice(&block.to_le_bytes());
+        bytes[STATUS_OFFSET] = 0xFF;
+        let descriptors: Vec<u16> = (0..segments.len() + 2).map(|_| self.free.pop().unwrap()).collect();
+        self.write_descriptor(descriptors[0], header.physical(0), STATUS_OFFSET, DESC_NEXT, descriptors[1]);
+        //the device writes the data of a read
+        let data_flags = if kind == REQUEST_IN { DESC_NEXT | DESC_WRITE } else { DESC_NEXT };
+        for (i, (address, part)) in segments.iter().enumerate() {
+            self.write_descriptor(descriptors[i + 1], *address, *part, data_flags, descriptors[i + 2]);
+        }
+        self.write_descriptor(descriptors[segments.len() + 1], header.physical(STATUS_OFFSET), 1, DESC_WRITE, 0);
+        let head = descriptors[0];
+        self.in_flight.insert(head, InFlight { header, data: buffer, len, write: kind != REQUEST_IN, descriptors, request: request.clone() });
+        let slot = self.avail + 4 + 2 * (self.next_avail % self.size) as usize;
+        self.write_u16(slot, head);
+        //the descriptors and the ring entry are visible before the index that hands them over
+        fence(Ordering::SeqCst);
+        self.next_avail = self.next_avail.wrapping_add(1);
+        self.write_u16(self.avail + 2, self.next_avail);
+        fence(Ordering::SeqCst);
+        Ok(true)
+    }
+
+    //finishes the requests in the used ring
+    fn service(&mut self) {
+        loop {
+            let used_index = self.read_u16(self.used + 2);
+            if used_index == self.last_used {
+                return;
+            }
+            fence(Ordering::SeqCst);
+            let entry = self.used + 4 + 8 * (self.last_used % self.size) as usize;
+            let head = mmio_read::<u32>(self.memory.address(entry)) as u16;
+            self.last_used = self.last_used.wrapping_add(1);
+            if let Some(in_flight) = self.in_flight.remove(&head) {
+                self.free.extend(in_flight.descriptors.iter().rev());
+                in_flight.finish();
+            }
+        }
+    }
+}
+
+struct VirtioBlock {
+    transport: Transport,
+    queue: Queue,
+    blocks: u64,
+    flush: bool,
+}
+
+//virtio block devices in the order they were found, the index is the drive on VIRTIO_BUS
+static DISKS: Mutex<Vec<VirtioBlock>> = Mutex::new(Vec::new());
+
+fn with_disk<R>(disk: u8, f: impl FnOnce(&mut VirtioBlock) -> R) -> Option<R> {
+    interrupts::without_interrupts(|| DISKS.lock().get_mut(disk as usize).map(f))
+}
+
+static VIRTIO_BLOCK_DRIVER: Driver = Driver {
+    name: "virtio-blk",
+    //transitional and modern block devices
+    ids: &[(0x1AF4, 0x1001), (0x1AF4, 0x1042)],
+    probe,
+};
+
+//registers the driver, every block device found is added to the disks
+pub fn init() {
+    pci::register_driver(&VIRTIO_BLOCK_DRIVER);
+}
+
+fn probe(device: &Device) -> Result<(), ()> {
+    let transport = Transport::find(device).ok_or(())?;
+    device.enable_bus_master();
+    //reset, then say we saw it and can drive it
+    transport.set_status(0);
+    while transport.status() != 0 {
+        spin_loop();
+    }
+    transport.set_status(STATUS_ACKNOWLEDGE);
+    transport.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+    let offered = transport.device_features();
+    let mut features = offered & BLK_F_FLUSH;
+    if transport.is_modern() {
+        if offered & F_VERSION_1 == 0 {
+            return Err(());
+        }
+        features |= F_VERSION_1;
+    }
+    transport.set_driver_features(features);
+    let mut status = STATUS_ACKNOWLEDGE | STATUS_DRIVER;
+    //a legacy device has no features ok handshake
+    if transport.is_modern() {
+        status |= STATUS_FEATURES_OK;
+        transport.set_status(status);
+        if transport.status() & STATUS_FEATURES_OK == 0 {
+            return Err(());
+        }
+    }
+    let queue = transport.setup_queue().ok_or(())?;
+    transport.set_status(status | STATUS_DRIVER_OK);
+    let blocks = transport.capacity();
+    serial_infoln!("virtio-blk {}: {} blocks, {}", device.address, blocks, if transport.is_modern() { "modern" } else { "legacy" });
+    let disk = VirtioBlock { transport, queue, blocks, flush: features & BLK_F_FLUSH != 0 };
+    interrupts::without_interrupts(|| DISKS.lock().push(disk));
+    Ok(())
+}
+
+//blocks of the virtio disk, None if there is none
+pub fn block_count(disk: u8) -> Option<u64> {
+    with_disk(disk, |disk| disk.blocks)
+}
+
+//queues a read of blocks blocks from block on, at most MAX_BLOCKS
+pub fn submit_read(disk: u8, block: u64, blocks: usize) -> Pending {
+    if blocks == 0 {
+        return Pending::failed();
+    }
+    submit(disk, REQUEST_IN, block, blocks, None)
+}
+
+//queues a write of buf, a multiple of BLOCK_SIZE and at most MAX_BLOCKS blocks, from block on
+pub fn submit_write(disk: u8, block: u64, buf: &[u8]) -> Pending {
+    if buf.is_empty() || buf.len() % BLOCK_SIZE != 0 {
+        return Pending::failed();
+    }
+    submit(disk, REQUEST_OUT, block, buf.len() / BLOCK_SIZE, Some(buf))
+}
+
+fn submit(number: u8, kind: u32, block: u64, blocks: usize, data: Option<&[u8]>) -> Pending {
+    if blocks > MAX_BLOCKS {
+        return Pending::failed();
+    }
+    let request = Request::new();
+    //a full queue means waiting for the device to hand some descriptors back
+    loop {
+        let pushed = with_disk(number, |disk| {
+            if block + blocks as u64 > disk.blocks {
+                return Err(());
+            }
+            disk.queue.service();
+            if !disk.queue.push(kind, block, blocks, data, &request)? {
+                return Ok(false);
+            }
+            disk.transport.notify();
+            Ok(true)
+        });
+        match pushed {
+            Some(Ok(true)) => break,
+            Some(Ok(false)) => spin_loop(),
+            _ => return Pending::failed(),
+        }
+    }
+    //the device is polled, it never interrupts
+    Pending::new(request, None, move || {
+        with_disk(number, |disk| disk.queue.service());
+    })
+}
+
+//read from the virtio disk, the blocks from block on until buf is full, IN_FLIGHT requests are
+//in the queue at once
+pub fn read(disk: u8, block: u64, buf: &mut [u8]) -> Result<(), ()> {
+    if buf.is_empty() || buf.len() % BLOCK_SIZE != 0 {
+        return Err(());
+    }
+    let finish = |(pending, chunk): (Pending, &mut [u8])| pending.wait().map(|data| chunk.copy_from_slice(&data));
+    let mut queued = VecDeque::new();
+    let mut result = Ok(());
+    for (i, chunk) in buf.chunks_mut(MAX_BLOCKS * BLOCK_SIZE).enumerate() {
+        if queued.len() == IN_FLIGHT {
+            result = result.and(finish(queued.pop_front().unwrap()));
+        }
+        queued.push_back((submit_read(disk, block + (i * MAX_BLOCKS) as u64, chunk.len() / BLOCK_SIZE), chunk));
+    }
+    queued.into_iter().fold(result, |result, queued| result.and(finish(queued)))
+}
+
+//write buf.len() / BLOCK_SIZE blocks from block on to the virtio disk, IN_FLIGHT requests at a time
+pub fn write(disk: u8, block: u64, buf: &[u8]) -> Result<(), ()> {
+    if buf.is_empty() || buf.len() % BLOCK_SIZE != 0 {
+        return Err(());
+    }
+    let finish = |pending: Pending| pending.wait().map(|_| ());
+    let mut queued = VecDeque::new();
+    let mut result = Ok(());
+    for (i, chunk) in buf.chunks(MAX_BLOCKS * BLOCK_SIZE).enumerate() {
+        if queued.len() == IN_FLIGHT {
+            result = result.and(finish(queued.pop_front().unwrap()));
+        }
+        queued.push_back(submit_write(disk, block + (i * MAX_BLOCKS) as u64, chunk));
+    }
+    queued.into_iter().fold(result, |result, pending| result.and(finish(pending)))
+}
+
+//makes the finished writes durable, a device without the flush feature writes through
+pub fn flush(disk: u8) -> Result<(), ()> {
+    match with_disk(disk, |disk| disk.flush) {
+        Some(true) => submit(disk, REQUEST_FLUSH, 0, 0, None).wait().map(|_| ()),
+        Some(false) => Ok(()),
+        None => Err(()),
+    }
+}
@@ -4,6 +4,7 @@
 use uart_16550::SerialPort;
 use spin::Mutex;
 use lazy_static::lazy_static;
+use core::sync::atomic::{AtomicU8, Ordering};
 
 lazy_static! {
     /// Serial port for writing to qemu stdout
@@ -14,6 +15,29 @@ lazy_static! {
     };
 }
 
+/// How much the leveled serial macros print, every level prints what the ones before it do
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Trace as u8);
+
+/// Only print messages up to level from now on
+pub fn set_log_level(level: LogLevel) {
+    LOG_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+#[doc(hidden)]
+pub fn log_enabled(level: LogLevel) -> bool {
+    level as u8 <= LOG_LEVEL.load(Ordering::Relaxed)
+}
+
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
@@ -41,62 +65,53 @@ macro_rules! serial_println {
             concat!($fmt, "\n"), $($arg)*));
 }
 
-/// Print formatted trace in qemu
+#[doc(hidden)]
 #[macro_export]
-macro_rules! serial_traceln {
-    () => ($crate::serial_println!("[TRACE: {}:{}]\t", file!(), line!()));
-
-    ($fmt:expr) => ($crate::serial_println!(concat!("[TRACE: {}:{}]\t", $fmt), file!(), line!()));
+macro_rules! serial_logln {
+    ($level:ident, $tag:literal,) => (
+        if $crate::drivers::serial::log_enabled($crate::drivers::serial::LogLevel::$level) {
+            $crate::serial_println!(concat!("[", $tag, ": {}:{}]\t"), file!(), line!());
+        });
+
+    ($level:ident, $tag:literal, $fmt:expr) => (
+        if $crate::drivers::serial::log_enabled($crate::drivers::serial::LogLevel::$level) {
+            $crate::serial_println!(concat!("[", $tag, ": {}:{}]\t", $fmt), file!(), line!());
+        });
+
+    ($level:ident, $tag:literal, $fmt:expr, $($arg:tt)*) => (
+        if $crate::drivers::serial::log_enabled($crate::drivers::serial::LogLevel::$level) {
+            $crate::serial_println!(
+                concat!("[", $tag, ": {}:{}]\t", $fmt), file!(), line!(),
+                $($arg)*);
+        });
+}
 
-    ($fmt:expr, $($arg:tt)*) => ($crate::serial_println!(
-            concat!("[TRACE: {}:{}]\t", $fmt), file!(), line!(),
-            $($arg)*));
+/// Print formatted trace in qemu, unless the log level is below Trace
+#[macro_export]
+macro_rules! serial_traceln {
+    ($($arg:tt)*) => ($crate::serial_logln!(Trace, "TRACE", $($arg)*));
 }
 
-/// Print formatted info in qemu
+/// Print formatted info in qemu, unless the log level is below Info
 #[macro_export]
 macro_rules! serial_infoln {
-    () => ($crate::serial_println!("[INFO: {}:{}]\t", file!(), line!()));
-
-    ($fmt:expr) => ($crate::serial_println!(concat!("[INFO: {}:{}]\t", $fmt), file!(), line!()));
-
-    ($fmt:expr, $($arg:tt)*) => ($crate::serial_println!(
-            concat!("[INFO: {}:{}]\t", $fmt), file!(), line!(),
-            $($arg)*));
+    ($($arg:tt)*) => ($crate::serial_logln!(Info, "INFO", $($arg)*));
 }
 
-/// Print formatted warning in qemu
+/// Print formatted warning in qemu, unless the log level is below Warn
 #[macro_export]
 macro_rules! serial_warnln {
-    () => ($crate::serial_println!("[WARN: {}:{}]\t", file!(), line!()));
-
-    ($fmt:expr) => ($crate::serial_println!(concat!("[WARN: {}:{}]\t", $fmt), file!(), line!()));
-
-    ($fmt:expr, $($arg:tt)*) => ($crate::serial_println!(
-            concat!("[WARN: {}:{}]\t", $fmt), file!(), line!(),
-            $($arg)*));
+    ($($arg:tt)*) => ($crate::serial_logln!(Warn, "WARN", $($arg)*));
 }
 
-/// Print formatted error in qemu
+/// Print formatted error in qemu, unless the log level is below Error
 #[macro_export]
 macro_rules! serial_errorln {
-    () => ($crate::serial_println!("[ERROR: {}:{}]\t", file!(), line!()));
-
-    ($fmt:expr) => ($crate::serial_println!(concat!("[ERROR: {}:{}]\t", $fmt), file!(), line!()));
-
-    ($fmt:expr, $($arg:tt)*) => ($crate::serial_println!(
-            concat!("[ERROR: {}:{}]\t", $fmt), file!(), line!(),
-            $($arg)*));
+    ($($arg:tt)*) => ($crate::serial_logln!(Error, "ERROR", $($arg)*));
 }
 
-/// Print formatted debug statement in qemu
+/// Print formatted debug statement in qemu, unless the log level is below Debug
 #[macro_export]
 macro_rules! serial_debugln {
-    () => ($crate::serial_println!("[DEBUG: {}:{}]\t", file!(), line!()));
-
-    ($fmt:expr) => ($crate::serial_println!(concat!("[DEBUG: {}:{}]\t", $fmt), file!(), line!()));
-
-    ($fmt:expr, $($arg:tt)*) => ($crate::serial_println!(
-            concat!("[DEBUG: {}:{}]\t", $fmt), file!(), line!(),
-            $($arg)*));
+    ($($arg:tt)*) => ($crate::serial_logln!(Debug, "DEBUG", $($arg)*));
 }
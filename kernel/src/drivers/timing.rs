@@ -10,7 +10,8 @@ const PIT_DIVIDER : usize = 1193;
 const PIT_INTERVAL : f64 = (PIT_DIVIDER as f64) / PIT_FREQUENCY;
 
 static PIT_TICKS : AtomicU64 = AtomicU64::new(0);
-static NANOSECONDS_PER_TICK : AtomicU64 = AtomicU64::new(0);
+//length of a tsc cycle in fixed point, a tsc above 1 GHz has less than a nanosecond per cycle
+static PICOSECONDS_PER_TICK : AtomicU64 = AtomicU64::new(0);
 
 pub fn set_pit_frequency(divider : u16, channel : u8) {
     interrupts::without_interrupts(|| {
@@ -53,9 +54,7 @@ pub fn nanosleep(ns : u64) {
     let start = rdtsc();
     let mut end = rdtsc();
 
-    let factor = NANOSECONDS_PER_TICK.load(Ordering::Relaxed);
-
-    while (end - start) * factor < ns {
+    while cycles_to_ns(end - start) < ns {
         end = rdtsc(); 
     }
 
@@ -79,5 +78,19 @@ pub fn init_nanosleep() {
     let end = rdtsc();
     let ns = (end_pit - start_pit) * 1e9 as f64;
 
-    NANOSECONDS_PER_TICK.store(max((ns / ((end - start) as f64)) as u64, 1), Ordering::SeqCst);
+    PICOSECONDS_PER_TICK.store(max((ns * 1e3 / ((end - start) as f64)) as u64, 1), Ordering::SeqCst);
+}
+
+//before init_nanosleep calibrates the tsc assume a nanosecond per cycle
+fn cycles_to_ns(cycles: u64) -> u64 {
+    let ps = match PICOSECONDS_PER_TICK.load(Ordering::Relaxed) {
+        0 => 1000,
+        ps => ps,
+    };
+    (cycles as u128 * ps as u128 / 1000) as u64
+}
+
+/// Nanoseconds since reset as measured by the tsc
+pub fn now_ns() -> u64 {
+    cycles_to_ns(rdtsc())
 }
@@ -64,6 +64,20 @@ lazy_static! {
             .set_handler_fn(keyboard_interrupt_handler);
         idt[39]
             .set_handler_fn(spurious_interrupt_handler);
+        idt[WAKE_VECTOR as usize]
+            .set_handler_fn(wake_interrupt_handler);
+        idt[ATA_PRIMARY_VECTOR as usize]
+            .set_handler_fn(ata_primary_interrupt_handler);
+        idt[ATA_SECONDARY_VECTOR as usize]
+            .set_handler_fn(ata_secondary_interrupt_handler);
+        let msi_handlers : [extern "x86-interrupt" fn(InterruptStackFrame); MSI_VECTOR_COUNT] = [
+            msi_interrupt_handler_0, msi_interrupt_handler_1, msi_interrupt_handler_2, msi_interrupt_handler_3,
+            msi_interrupt_handler_4, msi_interrupt_handler_5, msi_interrupt_handler_6, msi_interrupt_handler_7,
+        ];
+        for (slot, handler) in msi_handlers.iter().enumerate() {
+            idt[MSI_VECTOR_BASE as usize + slot]
+                .set_handler_fn(*handler);
+        }
         //idt[InterruptIndex::Keyboard.as_usize()]
         //    .set_handler_fn(keyboard_interrupt_handler);
         idt
@@ -77,6 +91,17 @@ pub fn init_idt() {
     IDT.load();
 }
 
+/// Vector other cores are sent to wake them from hlt
+pub const WAKE_VECTOR : u8 = 40;
+
+/// Vectors the IOAPIC sends irq 14 and 15 of the ATA buses to
+pub const ATA_PRIMARY_VECTOR : u8 = 46;
+pub const ATA_SECONDARY_VECTOR : u8 = 47;
+
+/// Vectors handed out to pci devices for their message signaled interrupts
+pub const MSI_VECTOR_BASE : u8 = 48;
+pub const MSI_VECTOR_COUNT : usize = 8;
+
 pub static LOCAL_APIC : LAPIC = LAPIC::zeroed();
 pub static IOAPIC : IOAPIC = IOAPIC::zeroed();
 
@@ -103,6 +128,20 @@ pub fn init_interrupts() {
     x86_64::instructions::interrupts::enable();
 }
 
+/// Wait for an interrupt, halting if interrupts can wake us and spinning otherwise
+pub fn wait_for_wakeup() {
+    if x86_64::instructions::interrupts::are_enabled() {
+        x86_64::instructions::hlt();
+    } else {
+        core::hint::spin_loop();
+    }
+}
+
+/// Wake every other core that is halted in wait_for_wakeup
+pub fn wake_cores() {
+    LOCAL_APIC.send_ipi_all_but_self(WAKE_VECTOR);
+}
+
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
     println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
@@ -118,6 +157,10 @@ extern "x86-interrupt" fn page_fault_handler(stack_frame: InterruptStackFrame,
     println!("EXCEPTION: PAGE_FAULT\n{:#?}", stack_frame);
     println!("Accessed Addr {:?}", Cr2::read());
     println!("Error code {:?}", error_code);
+    //the process gets no further, it should not keep its locks either
+    if error_code.contains(PageFaultErrorCode::USER_MODE) {
+        crate::userspace::exit_current(usize::MAX);
+    }
     hlt_loop();
 }
 
@@ -178,6 +221,9 @@ extern "x86-interrupt" fn stack_segment_fault_handler(stack_frame: InterruptStac
 
 extern "x86-interrupt" fn general_protection_fault_handler(stack_frame: InterruptStackFrame, _error_code : u64) {
     println!("EXCEPTION: GENERAL PROTECTION FAULT\n{:#?}", stack_frame);
+    if stack_frame.code_segment & 3 == 3 {
+        crate::userspace::exit_current(usize::MAX);
+    }
     hlt_loop();
 }
 
@@ -260,6 +306,46 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(
     LOCAL_APIC.eoi();
 }
 
+extern "x86-interrupt" fn wake_interrupt_handler(
+    _stack_frame: InterruptStackFrame) {
+
+    LOCAL_APIC.eoi();
+}
+
+extern "x86-interrupt" fn ata_primary_interrupt_handler(
+    _stack_frame: InterruptStackFrame) {
+
+    crate::disk::ata::handle_interrupt(14);
+    LOCAL_APIC.eoi();
+}
+
+extern "x86-interrupt" fn ata_secondary_interrupt_handler(
+    _stack_frame: InterruptStackFrame) {
+
+    crate::disk::ata::handle_interrupt(15);
+    LOCAL_APIC.eoi();
+}
+
+macro_rules! msi_interrupt_handler {
+    ($name:ident, $slot:expr) => {
+        extern "x86-interrupt" fn $name(
+            _stack_frame: InterruptStackFrame) {
+
+            crate::pci::handle_msi($slot);
+            LOCAL_APIC.eoi();
+        }
+    };
+}
+
+msi_interrupt_handler!(msi_interrupt_handler_0, 0);
+msi_interrupt_handler!(msi_interrupt_handler_1, 1);
+msi_interrupt_handler!(msi_interrupt_handler_2, 2);
+msi_interrupt_handler!(msi_interrupt_handler_3, 3);
+msi_interrupt_handler!(msi_interrupt_handler_4, 4);
+msi_interrupt_handler!(msi_interrupt_handler_5, 5);
+msi_interrupt_handler!(msi_interrupt_handler_6, 6);
+msi_interrupt_handler!(msi_interrupt_handler_7, 7);
+
 extern "x86-interrupt" fn spurious_interrupt_handler(
     stack_frame: InterruptStackFrame) {
 
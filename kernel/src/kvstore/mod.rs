@@ -1,126 +1,395 @@
-use crate::cc::{Transaction, WDTXPersist, WDTX};
-use crate::common::{locktable::LockTable, map::SimpleHashMap};
+use crate::cc::{CCMode, Transaction, TxError, WDTXPersist, WDTX};
+use crate::cc::mvcc::{CommitLog, VersionStore, SITX};
+use crate::common::{locktable::{LockPolicy, LockTable}, map::{Map, SimpleHashMap}};
+use crate::disk::disk_api::Disk;
 use crate::disk::persistentmap::PersistentMap;
 use crate::disk::persistentmap::{PersistentHashMap, ToBeBytes};
+use crate::drivers::timing;
 extern crate alloc;
 use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+use spin::mutex::Mutex;
 pub trait KVStore {
     type Key;
     type Value;
     type Transaction;
     fn begin(&self) -> Self::Transaction;
     fn new(map_size: u64, locktable_size: u64) -> Arc<Self>;
-    fn transact<F>(&self, f: F, persist: bool)
+    //runs f in a transaction and commits it, retrying under the store's RetryPolicy
+    fn transact<F, R>(&self, f: F, persist: bool) -> Result<R, TxError>
     where
-        F: Fn(&mut Self::Transaction) -> ();
+        F: Fn(&mut Self::Transaction) -> Result<R, TxError>;
 
-    fn transact_mut<F>(&self, f: &mut F, persist: bool)
+    fn transact_mut<F, R>(&self, f: &mut F, persist: bool) -> Result<R, TxError>
     where
-        F: FnMut(&mut Self::Transaction) -> ();
+        F: FnMut(&mut Self::Transaction) -> Result<R, TxError>;
 }
-pub struct TxKVStore<K: Eq + core::hash::Hash + AsRef<[u8]>, V: Clone> {
-    map: Arc<SimpleHashMap<K, V>>,
+
+//how transact re-runs transactions that died or lost a conflict
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    //wait before the second attempt, doubled for every attempt after it
+    pub backoff_ns: u64,
+    pub max_backoff_ns: u64,
+}
+
+impl RetryPolicy {
+    pub const fn new(max_attempts: u32, backoff_ns: u64, max_backoff_ns: u64) -> Self {
+        Self { max_attempts, backoff_ns, max_backoff_ns }
+    }
+
+    //nanosleep spins on the tsc so it also works inside syscalls where interrupts are off
+    fn backoff(&self, retry: u32) {
+        let ns = self.backoff_ns.saturating_mul(1u64 << retry.min(32)).min(self.max_backoff_ns);
+        timing::nanosleep(ns);
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy::new(16, 1_000, 1_000_000)
+    }
+}
+
+//which commits of a persistent store are logged
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Durability {
+    //the persist flag of transact decides
+    Request,
+    //every commit is on disk before it returns
+    Always,
+    //nothing is logged, commits are lost on reboot
+    Memory,
+}
+
+impl Durability {
+    //whether a commit that asked for persist or not is logged
+    pub fn persist(&self, requested: bool) -> bool {
+        match self {
+            Durability::Request => requested,
+            Durability::Always => true,
+            Durability::Memory => false,
+        }
+    }
+}
+
+impl Default for Durability {
+    fn default() -> Self {
+        Durability::Request
+    }
+}
+
+fn run_with_retry<T, R, B, F>(policy: RetryPolicy, begin: B, f: &mut F, persist: bool) -> Result<R, TxError>
+where
+    T: Transaction,
+    B: Fn() -> T,
+    F: FnMut(&mut T) -> Result<R, TxError>,
+{
+    let mut attempts = 0;
+    loop {
+        let mut tx = begin();
+        let result = f(&mut tx).and_then(|r| {
+            let committed = if persist { tx.try_commit_persist() } else { tx.try_commit() };
+            committed.map(|_| r)
+        });
+        let e = match result {
+            Ok(r) => return Ok(r),
+            Err(e) => e,
+        };
+        //f may have returned an error of its own with the locks still held
+        tx.abort();
+        attempts += 1;
+        if !e.is_retryable() || attempts >= policy.max_attempts {
+            return Err(e);
+        }
+        policy.backoff(attempts - 1);
+    }
+}
+
+//where the committed data of a store lives, depends on its CCMode
+enum Committed<K, V, M> {
+    //wait-die or wound-wait, whichever policy the lock table has
+    Map(Arc<M>),
+    Snapshot(Arc<VersionStore<K, V>>),
+}
+
+fn lock_policy(mode: CCMode) -> LockPolicy {
+    match mode {
+        CCMode::WoundWait => LockPolicy::WoundWait,
+        _ => LockPolicy::WaitDie,
+    }
+}
+
+//the in memory store defaults to the hash map but any Map can back it,
+//e.g. TxKVStore<K, V, SkipMap<K, V>> for ordered lock-free storage
+pub struct TxKVStore<K: Eq + core::hash::Hash + AsRef<[u8]>, V: Clone, M = SimpleHashMap<K, V>> {
+    data: Committed<K, V, M>,
     lock_table: Arc<LockTable>,
+    retry: Mutex<RetryPolicy>,
+    _types: PhantomData<(K, V)>,
+}
+
+impl<K: Eq + Ord + core::hash::Hash + Clone + AsRef<[u8]>, V: Clone, M: Map<Key = K, Value = V>> TxKVStore<K, V, M> {
+    pub fn with_mode(map_size: u64, lock_table_size: u64, mode: CCMode) -> Arc<Self> {
+        //snapshot mode keeps everything in the version store, no map is allocated
+        let data = match mode {
+            CCMode::Snapshot => Committed::Snapshot(Arc::new(VersionStore::new())),
+            _ => Committed::Map(Arc::new(M::with_capacity(map_size as usize))),
+        };
+        let lock_table = Arc::new(LockTable::with_policy(lock_table_size, lock_policy(mode)));
+        Arc::new(Self {
+            data: data,
+            lock_table: lock_table,
+            retry: Mutex::new(RetryPolicy::default()),
+            _types: PhantomData,
+        })
+    }
+
+    pub fn set_retry_policy(&self, policy: RetryPolicy) {
+        *self.retry.lock() = policy;
+    }
+
+    pub fn mode(&self) -> CCMode {
+        match (&self.data, self.lock_table.policy()) {
+            (Committed::Snapshot(_), _) => CCMode::Snapshot,
+            (Committed::Map(_), LockPolicy::WoundWait) => CCMode::WoundWait,
+            (Committed::Map(_), LockPolicy::WaitDie) => CCMode::WaitDie,
+        }
+    }
 }
-impl<K: Eq + core::hash::Hash + Clone + AsRef<[u8]>, V: Clone> KVStore for TxKVStore<K, V> {
+
+impl<K: Eq + Ord + core::hash::Hash + Clone + AsRef<[u8]>, V: Clone, M: Map<Key = K, Value = V>> KVStore for TxKVStore<K, V, M> {
     type Key = K;
     type Value = V;
-    type Transaction = WDTX<K, V, SimpleHashMap<K, V>>;
+    type Transaction = TxKVTransaction<K, V, WDTX<K, V, M>>;
     fn begin(&self) -> Self::Transaction {
-        WDTX::new(self.lock_table.clone(), self.map.clone())
+        match &self.data {
+            Committed::Snapshot(versions) => TxKVTransaction::Snapshot(SITX::new(versions.clone())),
+            Committed::Map(map) => TxKVTransaction::Locking(WDTX::new(self.lock_table.clone(), map.clone())),
+        }
     }
     fn new(map_size: u64, lock_table_size: u64) -> Arc<Self> {
-        let map = Arc::new(SimpleHashMap::with_capacity(map_size as usize));
-        let lock_table = Arc::new(LockTable::new(lock_table_size));
-        Arc::new(Self {
-            map: map,
-            lock_table: lock_table,
-        })
+        Self::with_mode(map_size, lock_table_size, CCMode::WaitDie)
     }
 
-    fn transact<F>(&self, f: F, _persist: bool)
+    //there is no log to persist to so persist is ignored
+    fn transact<F, R>(&self, f: F, _persist: bool) -> Result<R, TxError>
     where
-        F: Fn(&mut Self::Transaction) -> (),
+        F: Fn(&mut Self::Transaction) -> Result<R, TxError>,
     {
-        let mut committed = false;
-        while !committed {
-            let mut tx = self.begin();
-            f(&mut tx);
-            committed = tx.try_commit();
-            assert_eq!(committed, true);
-        }
+        let mut f = f;
+        run_with_retry(*self.retry.lock(), || self.begin(), &mut f, false)
     }
 
-    fn transact_mut<F>(&self, f: &mut F, _persist :bool)
+    fn transact_mut<F, R>(&self, f: &mut F, _persist :bool) -> Result<R, TxError>
     where
-        F: FnMut(&mut Self::Transaction) -> (),
+        F: FnMut(&mut Self::Transaction) -> Result<R, TxError>,
     {
-        let mut committed = false;
-        while !committed {
-            let mut tx = self.begin();
-            f(&mut tx);
-            committed = tx.try_commit();
+        run_with_retry(*self.retry.lock(), || self.begin(), f, false)
+    }
+}
+
+//transaction of a TxKVStore or TxKVStorePersist, depends on the mode the store was built with
+pub enum TxKVTransaction<K: Eq + Clone, V: Clone, T> {
+    //WDTX or WDTXPersist, wait-die or wound-wait whichever policy the lock table has
+    Locking(T),
+    Snapshot(SITX<K, V>),
+}
+
+impl<K: Eq + Ord + Clone + AsRef<[u8]>, V: Clone, T: Transaction<Key = K, Value = V>> Transaction for TxKVTransaction<K, V, T> {
+    type Key = K;
+    type Value = V;
+
+    fn read(&mut self, key: &K) -> Result<Option<V>, TxError> {
+        match self {
+            TxKVTransaction::Locking(tx) => tx.read(key),
+            TxKVTransaction::Snapshot(tx) => tx.read(key),
+        }
+    }
+
+    fn write(&mut self, key: &K, value: &V) -> Result<(), TxError> {
+        match self {
+            TxKVTransaction::Locking(tx) => tx.write(key, value),
+            TxKVTransaction::Snapshot(tx) => tx.write(key, value),
+        }
+    }
+
+    fn delete(&mut self, key: &K) -> Result<bool, TxError> {
+        match self {
+            TxKVTransaction::Locking(tx) => tx.delete(key),
+            TxKVTransaction::Snapshot(tx) => tx.delete(key),
+        }
+    }
+
+    fn scan(&mut self, start: &K, end: Option<&K>, limit: usize) -> Result<Vec<(K, V)>, TxError> {
+        match self {
+            TxKVTransaction::Locking(tx) => tx.scan(start, end, limit),
+            TxKVTransaction::Snapshot(tx) => tx.scan(start, end, limit),
+        }
+    }
+
+    fn scan_prefix(&mut self, prefix: &K, limit: usize) -> Result<Vec<(K, V)>, TxError> {
+        match self {
+            TxKVTransaction::Locking(tx) => tx.scan_prefix(prefix, limit),
+            TxKVTransaction::Snapshot(tx) => tx.scan_prefix(prefix, limit),
+        }
+    }
+
+    fn abort(&mut self) {
+        match self {
+            TxKVTransaction::Locking(tx) => tx.abort(),
+            TxKVTransaction::Snapshot(tx) => tx.abort(),
+        }
+    }
+
+    fn try_commit(&mut self) -> Result<(), TxError> {
+        match self {
+            TxKVTransaction::Locking(tx) => tx.try_commit(),
+            TxKVTransaction::Snapshot(tx) => tx.try_commit(),
+        }
+    }
+
+    fn try_commit_persist(&mut self) -> Result<(), TxError> {
+        match self {
+            TxKVTransaction::Locking(tx) => tx.try_commit_persist(),
+            TxKVTransaction::Snapshot(tx) => tx.try_commit_persist(),
         }
     }
 }
 
+//the persistent store defaults to the hash map, TxKVStorePersist<K, V, LsmMap<K, V>>
+//keeps the data in sstables instead so it does not have to fit in memory
 pub struct TxKVStorePersist<
     K: Eq + core::hash::Hash + AsRef<[u8]> + ToBeBytes,
     V: Clone + ToBeBytes,
+    M = PersistentHashMap<K, V>,
 > {
-    map: Arc<PersistentHashMap<K, V>>,
+    //in snapshot mode it only holds what was persisted, versions is what transactions read
+    map: Arc<M>,
+    versions: Option<Arc<VersionStore<K, V>>>,
     lock_table: Arc<LockTable>,
+    retry: Mutex<RetryPolicy>,
+    durability: Mutex<Durability>,
+    _types: PhantomData<(K, V)>,
 }
-impl<K: Eq + core::hash::Hash + Clone + AsRef<[u8]> + ToBeBytes, V: Clone + ToBeBytes> KVStore
-    for TxKVStorePersist<K, V>
+impl<K: Eq + Ord + core::hash::Hash + Clone + AsRef<[u8]> + ToBeBytes + Send + Sync + 'static, V: Clone + ToBeBytes + Send + Sync + 'static, M: PersistentMap<Key = K, Value = V> + Send + Sync + 'static> KVStore
+    for TxKVStorePersist<K, V, M>
+where
+    M::Device: Default,
 {
     type Key = K;
     type Value = V;
-    type Transaction = WDTXPersist<K, V, PersistentHashMap<K, V>>;
+    type Transaction = TxKVTransaction<K, V, WDTXPersist<K, V, M>>;
     fn begin(&self) -> Self::Transaction {
-        WDTXPersist::new(self.lock_table.clone(), self.map.clone())
+        match &self.versions {
+            Some(versions) => {
+                let log: Arc<dyn CommitLog<K, V>> = self.map.clone();
+                TxKVTransaction::Snapshot(SITX::with_log(versions.clone(), Some(log)))
+            },
+            None => TxKVTransaction::Locking(WDTXPersist::new(self.lock_table.clone(), self.map.clone())),
+        }
     }
-    fn new(_map_size: u64, lock_table_size: u64) -> Arc<Self> {
-        // let map = Arc::new(PersistentHashMap::with_capacity(map_size as usize));
-        let map = Arc::new(PersistentHashMap::build_from_disk());
-        let lock_table = Arc::new(LockTable::new(lock_table_size));
-        Arc::new(Self {
-            map: map,
-            lock_table: lock_table,
-        })
+    fn new(map_size: u64, lock_table_size: u64) -> Arc<Self> {
+        Self::with_disk(Disk::with_device(M::Device::default()), map_size, lock_table_size, CCMode::WaitDie)
+            .expect("no store on the default device")
     }
 
-    fn transact<F>(&self, f: F, persist: bool)
+    fn transact<F, R>(&self, f: F, persist: bool) -> Result<R, TxError>
     where
-        F: Fn(&mut Self::Transaction) -> (),
+        F: Fn(&mut Self::Transaction) -> Result<R, TxError>,
     {
-        let mut committed = false;
-        while !committed {
-            let mut tx = self.begin();
-            f(&mut tx);
-            if persist {
-                committed = tx.try_commit_persist();
-            }else {
-                committed = tx.try_commit();
-            }
-            
-            assert_eq!(committed, true);
-        }
+        let mut f = f;
+        let persist = self.durability.lock().persist(persist);
+        let result = run_with_retry(*self.retry.lock(), || self.begin(), &mut f, persist);
+        self.maintain();
+        result
     }
 
-    fn transact_mut<F>(&self, f: &mut F, persist: bool)
+    fn transact_mut<F, R>(&self, f: &mut F, persist: bool) -> Result<R, TxError>
     where
-        F: FnMut(&mut Self::Transaction) -> (),
+        F: FnMut(&mut Self::Transaction) -> Result<R, TxError>,
     {
-        let mut committed = false;
-        while !committed {
-            let mut tx = self.begin();
-            f(&mut tx);
-            if persist {
-                committed = tx.try_commit_persist();
-            } else {
-                committed = tx.try_commit();
+        let persist = self.durability.lock().persist(persist);
+        let result = run_with_retry(*self.retry.lock(), || self.begin(), f, persist);
+        self.maintain();
+        result
+    }
+}
+
+//entries the version store of a snapshot store is loaded with per scan of the map
+const LOAD_PAGE: usize = 256;
+
+impl<K: Eq + Ord + core::hash::Hash + Clone + AsRef<[u8]> + ToBeBytes, V: Clone + ToBeBytes, M> TxKVStorePersist<K, V, M> {
+    //a store over the map disk holds, e.g. on a ram disk instead of the kv disk, map_size
+    //is the bucket count of a hash map, an error if the disk cannot be read or holds no map
+    pub fn with_disk(disk: Disk<M::Device>, map_size: u64, lock_table_size: u64, mode: CCMode) -> Result<Arc<Self>, ()> where M: PersistentMap<Key = K, Value = V> {
+        let map = Arc::new(M::build_from_with_capacity(disk, map_size as usize)?);
+        let versions = match mode {
+            CCMode::Snapshot => Some(Arc::new(Self::load_versions(&map))),
+            _ => None,
+        };
+        let lock_table = Arc::new(LockTable::with_policy(lock_table_size, lock_policy(mode)));
+        Ok(Arc::new(Self {
+            map: map,
+            versions: versions,
+            lock_table: lock_table,
+            retry: Mutex::new(RetryPolicy::default()),
+            durability: Mutex::new(Durability::default()),
+            _types: PhantomData,
+        }))
+    }
+
+    //every entry of map as the first version of its key, the empty key is the least one
+    fn load_versions(map: &M) -> VersionStore<K, V> where M: PersistentMap<Key = K, Value = V> {
+        let versions = VersionStore::new();
+        let mut cursor = match K::from_vec(Vec::new()) {
+            Ok(key) => key,
+            Err(_) => return versions,
+        };
+        let mut inclusive = true;
+        loop {
+            let page = map.scan(&cursor, LOAD_PAGE + 1);
+            let mut advanced = false;
+            for (key, value) in page {
+                if !inclusive && key == cursor {
+                    continue;
+                }
+                versions.load(&key, &value);
+                cursor = key;
+                inclusive = false;
+                advanced = true;
+            }
+            if !advanced {
+                return versions;
             }
         }
     }
+
+    pub fn mode(&self) -> CCMode {
+        match (&self.versions, self.lock_table.policy()) {
+            (Some(_), _) => CCMode::Snapshot,
+            (None, LockPolicy::WoundWait) => CCMode::WoundWait,
+            (None, LockPolicy::WaitDie) => CCMode::WaitDie,
+        }
+    }
+
+    pub fn set_retry_policy(&self, policy: RetryPolicy) {
+        *self.retry.lock() = policy;
+    }
+
+    pub fn set_durability(&self, durability: Durability) {
+        *self.durability.lock() = durability;
+    }
+
+    pub fn durability(&self) -> Durability {
+        *self.durability.lock()
+    }
+
+    //runs what the map put off during commits, e.g. a log compaction, once the caller's
+    //transaction is over
+    pub fn maintain(&self) where M: PersistentMap {
+        self.map.maintain();
+    }
 }
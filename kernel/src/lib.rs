@@ -31,19 +31,23 @@ pub mod common {
     pub mod set;
     pub mod map;
     pub mod locktable;
+    pub mod index;
 }
 pub mod tests;
 pub mod apic;
 pub mod userspace;
 pub mod syscall;
 pub mod acpi;
+pub mod pci;
 pub mod console;
 pub mod benchmark;
+pub mod config;
 
-use alloc::{sync::Arc, string::String};
+use alloc::{sync::Arc, vec::Vec};
 use multiboot2::BootInformation;
 use lazy_static::lazy_static;
-use crate::kvstore::{KVStore, TxKVStorePersist};
+use crate::config::{BootMode, Config};
+use crate::kvstore::TxKVStorePersist;
 
 //pub mod task
 
@@ -51,7 +55,12 @@ use crate::kvstore::{KVStore, TxKVStorePersist};
 static TESTING : core::sync::atomic::AtomicBool = core::sync::atomic::AtomicBool::new(false);
 
 lazy_static! {
-    pub static ref KVSTORE: Arc<TxKVStorePersist<String, String>>  = TxKVStorePersist::new(1024, 1024);
+    //built on first use from the configuration the command line set
+    pub static ref KVSTORE: Arc<TxKVStorePersist<Vec<u8>, Vec<u8>>>  = config::build_store().unwrap_or_else(|e| {
+        //nothing can run without the store, stop here instead of touching the disk
+        serial_errorln!("{}", e);
+        hlt_loop()
+    });
 }
 
 
@@ -127,6 +136,7 @@ pub fn init(kernel_start : usize, kernel_end : usize, multiboot_start: usize, mu
     let rsdt_addr : *const u8 = rsdpv1.rsdt_address() as *const u8;
 
     let (ioapic_info, cpus) = acpi::init(rsdt_addr).expect("No ioapic");
+    let mcfg = acpi::mcfg(rsdt_addr);
 
     drivers::timing::init();
 
@@ -160,6 +170,14 @@ pub fn init(kernel_start : usize, kernel_end : usize, multiboot_start: usize, mu
 
     drivers::timing::init_nanosleep();
 
+    println!("Init ata interrupts");
+    disk::ata::enable_interrupts();
+
+    println!("Scanning pci");
+    pci::init(mcfg, &mut frame_allocator);
+    disk::ahci::init();
+    disk::virtio::init();
+
     frame_allocator
 }
 
@@ -265,14 +283,21 @@ pub extern "C" fn kernel_main(multiboot_information_address: usize, stack_start
 
     //drivers::buzzer::songs(); //buzz(329.63, 100.0);    
 
-    if command_line == "test" {
+    let (config, errors) = Config::parse(command_line);
+    for error in errors {
+        println!("Command line: {}", error);
+        serial_warnln!("command line: {}", error);
+    }
+    config::set(config);
+
+    if config.mode == BootMode::Test {
         TESTING.store(true, core::sync::atomic::Ordering::SeqCst);
         println!("testing...");
         tests::run_tests(); 
         println!("Testing done");
         exit_qemu(QemuExitCode::Success);
         hlt_loop();
-    } else if command_line == "bench" {
+    } else if config.mode == BootMode::Bench {
         println!("benchmarking...");
         hlt_loop();
     } else {
@@ -0,0 +1,103 @@
+extern crate alloc;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::mutex::Mutex;
+
+//epoch based reclamation for the skipmap
+//a pointer unlinked while the global epoch is e may still be held by readers pinned in
+//an epoch <= e, the epoch only moves past e + 1 once all of those readers have unpinned
+//so the garbage of epoch e is freed when the global epoch reaches e + 2
+const EPOCHS: usize = 3;
+
+struct Garbage {
+    ptr: *mut u8,
+    drop_fn: unsafe fn(*mut u8),
+}
+
+//garbage is only freed by whichever core advances the epoch
+unsafe impl Send for Garbage {}
+
+unsafe fn drop_box<T>(ptr: *mut u8) {
+    drop(Box::from_raw(ptr as *mut T));
+}
+
+pub struct Collector {
+    epoch: AtomicUsize,
+    active: [AtomicUsize; EPOCHS],
+    limbo: [Mutex<Vec<Garbage>>; EPOCHS],
+}
+
+pub struct Guard<'a> {
+    collector: &'a Collector,
+    epoch: usize,
+}
+
+impl Collector {
+    pub fn new() -> Self {
+        Collector {
+            epoch: AtomicUsize::new(0),
+            active: [AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0)],
+            limbo: [Mutex::new(Vec::new()), Mutex::new(Vec::new()), Mutex::new(Vec::new())],
+        }
+    }
+
+    //nodes reached while the guard is alive are not freed until it is dropped
+    pub fn pin(&self) -> Guard<'_> {
+        loop {
+            let epoch = self.epoch.load(Ordering::SeqCst);
+            self.active[epoch % EPOCHS].fetch_add(1, Ordering::SeqCst);
+            if self.epoch.load(Ordering::SeqCst) == epoch {
+                return Guard { collector: self, epoch };
+            }
+            self.active[epoch % EPOCHS].fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    //ptr must come from Box::into_raw and already be unreachable for new readers
+    pub unsafe fn retire<T>(&self, ptr: *mut T) {
+        let garbage = Garbage {
+            ptr: ptr as *mut u8,
+            drop_fn: drop_box::<T>,
+        };
+        let epoch = self.epoch.load(Ordering::SeqCst);
+        self.limbo[epoch % EPOCHS].lock().push(garbage);
+        self.try_advance();
+    }
+
+    fn try_advance(&self) {
+        let epoch = self.epoch.load(Ordering::SeqCst);
+        //the slot of epoch - 1 must be empty before anyone may enter epoch + 1
+        if self.active[(epoch + EPOCHS - 1) % EPOCHS].load(Ordering::SeqCst) != 0 {
+            return;
+        }
+        if self.epoch.compare_exchange(epoch, epoch + 1, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+            return;
+        }
+        //now epoch + 1 = (epoch - 1) + 2 so the garbage of epoch - 1 is safe to free
+        let garbage = core::mem::take(&mut *self.limbo[(epoch + EPOCHS - 1) % EPOCHS].lock());
+        for g in garbage {
+            unsafe { (g.drop_fn)(g.ptr) };
+        }
+    }
+
+    pub fn pending(&self) -> usize {
+        self.limbo.iter().map(|l| l.lock().len()).sum()
+    }
+}
+
+impl Drop for Collector {
+    fn drop(&mut self) {
+        for limbo in self.limbo.iter() {
+            for g in core::mem::take(&mut *limbo.lock()) {
+                unsafe { (g.drop_fn)(g.ptr) };
+            }
+        }
+    }
+}
+
+impl<'a> Drop for Guard<'a> {
+    fn drop(&mut self) {
+        self.collector.active[self.epoch % EPOCHS].fetch_sub(1, Ordering::SeqCst);
+    }
+}
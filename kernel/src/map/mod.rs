@@ -1,97 +1,74 @@
+mod epoch;
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeSet;
 use alloc::vec::Vec;
 use core::ptr;
-use core::sync::atomic::{AtomicUsize, Ordering};
-use alloc::boxed::Box;
+use core::sync::atomic::{AtomicPtr, AtomicU32, AtomicUsize, Ordering};
+use crate::common::map::Map;
+use epoch::Collector;
 
 const MAX_LEVEL: usize = 32;
 
-fn get_rand_level(max_level: usize) -> usize {
-    unsafe {
-        static mut Y: u32  = 2463534242u32;
-        Y ^= Y << 13;
-        Y ^= Y >> 17;
-        Y ^= Y << 5;
-        let mut temp = Y;
-        let mut level = 1;
-        while (temp >> 1) & 1 != 0 {
-            level += 1;
-            temp >>= 1;
-        }
-        
-        if level > max_level {
-            max_level as usize
-        } else {
-            level as usize
-        }
-    }
+fn xorshift(mut y: u32) -> u32 {
+    y ^= y << 13;
+    y ^= y >> 17;
+    y ^= y << 5;
+    y
 }
 
 struct SkipNode<K, V> {
-    key: K,
-    val: V,
-    marker: bool,
-    deleted: AtomicUsize,
-    toplevel: usize,
+    //only the head has no key
+    key: Option<K>,
+    val: AtomicPtr<V>,
+    //number of levels the node is linked into plus one held by the inserter
+    //until the tower is built, the node is retired when it drops to 0. a link
+    //is counted before the cas that makes it so a remover never sees it missing
+    links: AtomicUsize,
     next: Vec<AtomicUsize>,
 }
 
-impl <K: Default + Ord + Copy, V: Default + Copy> SkipNode<K, V> {
-    fn new(key: K, val: V, toplevel: usize) -> Self {
-        let mut next = Vec::with_capacity(MAX_LEVEL);
-        for _ in 0..MAX_LEVEL {
+impl<K, V> SkipNode<K, V> {
+    fn new(key: Option<K>, val: *mut V, toplevel: usize) -> Self {
+        let mut next = Vec::with_capacity(toplevel);
+        for _ in 0..toplevel {
             next.push(AtomicUsize::new(0));
         }
         SkipNode {
             key,
-            val,
-            marker: false,
-            deleted: AtomicUsize::new(0),
-            toplevel,
+            val: AtomicPtr::new(val),
+            //the inserter and level 0
+            links: AtomicUsize::new(2),
             next,
         }
     }
+
+    fn key(&self) -> &K {
+        self.key.as_ref().unwrap()
+    }
 }
 
-impl <K: Default + Ord + Clone + Copy, V: Clone + Default + Copy> Clone for SkipNode<K, V> {
-    fn clone(&self) -> Self {
-        let next = self.next.iter().map(|atomic| AtomicUsize::new(atomic.load(Ordering::Relaxed))).collect();
-        SkipNode {
-            key: self.key,
-            val: self.val,
-            marker: self.marker,
-            deleted: AtomicUsize::new(self.deleted.load(Ordering::Relaxed)),
-            toplevel: self.toplevel,
-            next,
+impl<K, V> Drop for SkipNode<K, V> {
+    fn drop(&mut self) {
+        let val = self.val.load(Ordering::Relaxed);
+        if !val.is_null() {
+            unsafe { drop(Box::from_raw(val)) };
         }
     }
 }
 
-
+//lock-free skiplist, removal marks the next pointers of a node top down and
+//the first traversal that finds a marked node unlinks it at that level
 pub struct SkipMap<K, V> {
     head: *mut SkipNode<K, V>,
+    seed: AtomicU32,
+    collector: Collector,
 }
 
-impl <K:Default + Ord + Copy, V: Default + Copy> SkipMap<K, V> {
-    pub fn new() -> Self {
-        let head = Box::into_raw(Box::new(SkipNode::new(K::default(), V::default(), MAX_LEVEL)));
-        let tail = Box::into_raw(Box::new(SkipNode::new(K::default(), V::default(), MAX_LEVEL)));
-
-        //set as marked 
-        unsafe {
-            (*tail).marker = true;
-        }
-
-        for i in 0..MAX_LEVEL {
-            unsafe {
-                (*head).next[i].store(tail as usize, Ordering::Relaxed);
-            }
-        }
-
-        SkipMap {
-            head,
-        }
-    }
+unsafe impl<K: Send + Sync, V: Send + Sync> Send for SkipMap<K, V> {}
+unsafe impl<K: Send + Sync, V: Send + Sync> Sync for SkipMap<K, V> {}
 
+impl<K: Ord + Clone, V: Clone> SkipMap<K, V> {
     fn is_marked(i: usize) -> bool {
         i & 0x01 == 0x01
     }
@@ -104,132 +81,229 @@ impl <K:Default + Ord + Copy, V: Default + Copy> SkipMap<K, V> {
         i | 0x01
     }
 
-    fn search(&self, key: K, left_list: &mut [*mut SkipNode<K, V>; MAX_LEVEL], right_list: &mut [*mut SkipNode<K, V>; MAX_LEVEL]) {
-        let mut left: *mut SkipNode<K, V>;
-        let mut _right: *mut SkipNode<K, V>;
-        let mut left_next: usize;
-        let mut right_next: usize;
+    fn random_level(&self) -> usize {
+        let prev = self.seed.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |y| Some(xorshift(y))).unwrap();
+        let mut temp = xorshift(prev);
+        let mut level = 1;
+        while (temp >> 1) & 1 != 0 {
+            level += 1;
+            temp >>= 1;
+        }
+        core::cmp::min(level, MAX_LEVEL)
+    }
+
+    //called once the node is no longer reachable at one of its levels
+    fn release(&self, node: *mut SkipNode<K, V>) {
+        if unsafe { (*node).links.fetch_sub(1, Ordering::SeqCst) } == 1 {
+            unsafe { self.collector.retire(node) };
+        }
+    }
+
+    //fills preds and succs with the nodes around key on every level and unlinks
+    //marked nodes on the way, must be called while pinned
+    fn find(&self, key: &K, preds: &mut [*mut SkipNode<K, V>; MAX_LEVEL], succs: &mut [*mut SkipNode<K, V>; MAX_LEVEL]) -> bool {
         'retry: loop {
-            left = self.head;
+            let mut pred = self.head;
             for i in (0..MAX_LEVEL).rev() {
-                left_next = unsafe { (*left).next[i].load(Ordering::Relaxed) };
-                if Self::is_marked(left_next) {
-                    continue 'retry;
-                }
-                let mut right = left_next as *mut SkipNode<K, V>;
-                loop {
-                    loop {
-                        right_next = unsafe {(*right).next[i].load(Ordering::Relaxed)};
-
-                        if !Self::is_marked(right_next) {
+                let mut curr = Self::unset_mark(unsafe { (*pred).next[i].load(Ordering::SeqCst) }) as *mut SkipNode<K, V>;
+                while !curr.is_null() {
+                    let mut succ = unsafe { (*curr).next[i].load(Ordering::SeqCst) };
+                    while Self::is_marked(succ) {
+                        let next = Self::unset_mark(succ);
+                        if unsafe { (*pred).next[i].compare_exchange(curr as usize, next, Ordering::SeqCst, Ordering::SeqCst) }.is_err() {
+                            continue 'retry;
+                        }
+                        self.release(curr);
+                        curr = next as *mut SkipNode<K, V>;
+                        if curr.is_null() {
                             break;
                         }
-                        right = Self::unset_mark(right_next) as *mut SkipNode<K, V>
+                        succ = unsafe { (*curr).next[i].load(Ordering::SeqCst) };
                     }
-
-                    if unsafe{(*right).marker || (*right).key >= key} {
+                    if curr.is_null() || unsafe { (*curr).key() } >= key {
                         break;
                     }
-                    left = right;
-                    left_next = right_next;
-                    right = right_next as *mut SkipNode<K, V>;
-
+                    pred = curr;
+                    curr = succ as *mut SkipNode<K, V>;
                 }
-                if left_next != right as usize && unsafe { !(*left).next[i].compare_exchange(left_next, right as usize, Ordering::Relaxed, Ordering::Relaxed).is_ok() } {
-                    continue 'retry;
-                }
-                left_list[i] = left;
-                right_list[i] = right;
-
+                preds[i] = pred;
+                succs[i] = curr;
             }
-            break;
+            return !succs[0].is_null() && unsafe { (*succs[0]).key() } == key;
         }
     }
 
-    // ... remaining methods ...
+    //number of unlinked nodes and values waiting to be freed
+    pub fn pending_reclaim(&self) -> usize {
+        self.collector.pending()
+    }
+}
+
+impl<K: Ord + Clone, V: Clone> Map for SkipMap<K, V> {
+    type Key = K;
+    type Value = V;
+
+    fn new() -> Self {
+        let head = Box::into_raw(Box::new(SkipNode::new(None, ptr::null_mut(), MAX_LEVEL)));
+        SkipMap {
+            head,
+            seed: AtomicU32::new(2463534242u32),
+            collector: Collector::new(),
+        }
+    }
+
+    //the list grows one node at a time so there is nothing to preallocate
+    fn with_capacity(_capacity: usize) -> Self {
+        Self::new()
+    }
+
+    fn get(&self, key: &K) -> Option<V> {
+        let _guard = self.collector.pin();
+        let mut pred = self.head;
+        let mut curr: *mut SkipNode<K, V> = ptr::null_mut();
+        for i in (0..MAX_LEVEL).rev() {
+            curr = Self::unset_mark(unsafe { (*pred).next[i].load(Ordering::SeqCst) }) as *mut SkipNode<K, V>;
+            while !curr.is_null() {
+                let succ = unsafe { (*curr).next[i].load(Ordering::SeqCst) };
+                if Self::is_marked(succ) {
+                    curr = Self::unset_mark(succ) as *mut SkipNode<K, V>;
+                } else if unsafe { (*curr).key() } < key {
+                    pred = curr;
+                    curr = succ as *mut SkipNode<K, V>;
+                } else {
+                    break;
+                }
+            }
+        }
+        if !curr.is_null() && unsafe { (*curr).key() } == key {
+            unsafe { Some((*(*curr).val.load(Ordering::SeqCst)).clone()) }
+        } else {
+            None
+        }
+    }
 
-    pub fn insert(&self, key:K, val: V) -> bool {
-        let new_node = Box::into_raw(Box::new(SkipNode::new(key, val, get_rand_level(MAX_LEVEL))));
-        let mut new_next: usize;
+    fn insert(&self, key: &K, value: &V) -> bool {
+        let _guard = self.collector.pin();
         let mut preds: [*mut SkipNode<K, V>; MAX_LEVEL] = [ptr::null_mut(); MAX_LEVEL];
         let mut succs: [*mut SkipNode<K, V>; MAX_LEVEL] = [ptr::null_mut(); MAX_LEVEL];
+        let mut node: *mut SkipNode<K, V> = ptr::null_mut();
 
         loop {
-            self.search(key, &mut preds, &mut succs);
-
-            if unsafe{ (*succs[0]).key } == key {
-                if unsafe{ (*succs[0]).deleted.load(Ordering::Relaxed) } == 0 {
-                    Self::mark_node_ptrs(&self, succs[0]);
+            if self.find(key, &mut preds, &mut succs) {
+                //a marked node is being removed, find unlinks it on the next try
+                if Self::is_marked(unsafe { (*succs[0]).next[0].load(Ordering::SeqCst) }) {
                     continue;
                 }
-                return false;
+                //the old value may still be read by a pinned get so it is retired
+                let val = Box::into_raw(Box::new(value.clone()));
+                let old = unsafe { (*succs[0]).val.swap(val, Ordering::SeqCst) };
+                unsafe { self.collector.retire(old) };
+                if !node.is_null() {
+                    unsafe { drop(Box::from_raw(node)) };
+                }
+                return true;
             }
-            for i in 0..unsafe{(*new_node).toplevel} {
-                loop {
-                    let pred = preds[i];
-                    let mut succ = succs[i];
-
-                    new_next = unsafe {(*new_node).next[i].load(Ordering::Relaxed)};
-
-                    if new_next != succ as usize && unsafe{!(*new_node).next[i].compare_exchange(Self::unset_mark(new_next), succ as usize, Ordering::Relaxed, Ordering::Relaxed).is_ok()} {
-                        break;
-                    }
-
-                    if unsafe{(*succ).key} == key {
-                        unsafe {succ = Self::unset_mark((*succ).next[0].load(Ordering::Relaxed)) as *mut SkipNode<K, V>}
-                    }
+            if node.is_null() {
+                let val = Box::into_raw(Box::new(value.clone()));
+                node = Box::into_raw(Box::new(SkipNode::new(Some(key.clone()), val, self.random_level())));
+            }
+            for i in 0..unsafe { (*node).next.len() } {
+                unsafe { (*node).next[i].store(succs[i] as usize, Ordering::SeqCst) };
+            }
+            if unsafe { (*preds[0]).next[0].compare_exchange(succs[0] as usize, node as usize, Ordering::SeqCst, Ordering::SeqCst) }.is_ok() {
+                break;
+            }
+        }
 
-                    if unsafe{(*pred).next[i].compare_exchange(succ as usize,  new_node as usize, Ordering::Relaxed, Ordering::Relaxed).is_ok()} {
-                        break;
-                    }
-                    Self::search(&self, key, &mut preds, &mut succs);
+        'tower: for i in 1..unsafe { (*node).next.len() } {
+            loop {
+                let next = unsafe { (*node).next[i].load(Ordering::SeqCst) };
+                //a remove has started marking the tower so stop building it
+                if Self::is_marked(next) {
+                    break 'tower;
+                }
+                if next != succs[i] as usize && unsafe { (*node).next[i].compare_exchange(next, succs[i] as usize, Ordering::SeqCst, Ordering::SeqCst) }.is_err() {
+                    break 'tower;
                 }
+                unsafe { (*node).links.fetch_add(1, Ordering::SeqCst) };
+                if unsafe { (*preds[i]).next[i].compare_exchange(succs[i] as usize, node as usize, Ordering::SeqCst, Ordering::SeqCst) }.is_ok() {
+                    break;
+                }
+                //the inserter still holds its own link so this never reaches 0
+                unsafe { (*node).links.fetch_sub(1, Ordering::SeqCst) };
+                self.find(key, &mut preds, &mut succs);
             }
-            break true;
         }
-    }
 
-    pub fn get(&self, key: K) -> Option<V> {
-        let mut preds: [*mut SkipNode<K, V>; MAX_LEVEL] = [ptr::null_mut(); MAX_LEVEL];
-        let mut succs: [*mut SkipNode<K, V>; MAX_LEVEL] = [ptr::null_mut(); MAX_LEVEL];
-        self.search(key, &mut preds, &mut succs);
-        if !succs[0].is_null() && unsafe { (*succs[0]).key == key } {
-            return Some(unsafe { (*succs[0]).val});
-        } else {
-            None
+        //a remove that finished its cleanup before the tower was built would leave upper levels linked
+        if Self::is_marked(unsafe { (*node).next[0].load(Ordering::SeqCst) }) {
+            self.find(key, &mut preds, &mut succs);
         }
+        self.release(node);
+        true
     }
 
-    pub fn remove(&self, key: K) -> bool{
+    fn remove(&self, key: &K) -> bool {
+        let _guard = self.collector.pin();
         let mut preds: [*mut SkipNode<K, V>; MAX_LEVEL] = [ptr::null_mut(); MAX_LEVEL];
         let mut succs: [*mut SkipNode<K, V>; MAX_LEVEL] = [ptr::null_mut(); MAX_LEVEL];
-        self.search(key, &mut preds, &mut succs);
-        if succs[0].is_null() || unsafe { (*succs[0]).key != key } {
+        if !self.find(key, &mut preds, &mut succs) {
             return false;
         }
-        if unsafe { (*succs[0]).deleted.load(Ordering::Relaxed) } != 0 {
-            return false;
+        let node = succs[0];
+        for i in (1..unsafe { (*node).next.len() }).rev() {
+            loop {
+                let next = unsafe { (*node).next[i].load(Ordering::SeqCst) };
+                if Self::is_marked(next) || unsafe { (*node).next[i].compare_exchange(next, Self::set_mark(next), Ordering::SeqCst, Ordering::SeqCst) }.is_ok() {
+                    break;
+                }
+            }
         }
-        unsafe {
-            (*succs[0]).deleted.fetch_add(1, Ordering::Relaxed);
+        //marking the bottom level is what removes the key, only one remover wins it
+        loop {
+            let next = unsafe { (*node).next[0].load(Ordering::SeqCst) };
+            if Self::is_marked(next) {
+                return false;
+            }
+            if unsafe { (*node).next[0].compare_exchange(next, Self::set_mark(next), Ordering::SeqCst, Ordering::SeqCst) }.is_ok() {
+                self.find(key, &mut preds, &mut succs);
+                return true;
+            }
         }
-        self.mark_node_ptrs(succs[0]);
-        self.search(key, &mut preds, &mut succs);
-        return true;
     }
 
-    fn mark_node_ptrs(&self, node: *mut SkipNode<K, V>) {
-        let mut n_next;
-        for i in (0..unsafe { (*node).toplevel }).rev() {
-            loop {
-                n_next = unsafe { (*node).next[i].load(Ordering::Relaxed) };
-                if Self::is_marked(n_next) {
-                    break;
-                }
-                if unsafe { (*node).next[i].compare_exchange(n_next, Self::set_mark(n_next), Ordering::Relaxed, Ordering::Relaxed).is_ok() } {
-                    break;
-                }
+    fn scan(&self, start: &K, limit: usize) -> Vec<(K, V)> {
+        let _guard = self.collector.pin();
+        let mut preds: [*mut SkipNode<K, V>; MAX_LEVEL] = [ptr::null_mut(); MAX_LEVEL];
+        let mut succs: [*mut SkipNode<K, V>; MAX_LEVEL] = [ptr::null_mut(); MAX_LEVEL];
+        self.find(start, &mut preds, &mut succs);
+        let mut result = Vec::new();
+        let mut curr = succs[0];
+        while !curr.is_null() && result.len() < limit {
+            let next = unsafe { (*curr).next[0].load(Ordering::SeqCst) };
+            if !Self::is_marked(next) {
+                unsafe { result.push(((*curr).key().clone(), (*(*curr).val.load(Ordering::SeqCst)).clone())) };
             }
+            curr = Self::unset_mark(next) as *mut SkipNode<K, V>;
+        }
+        result
+    }
+}
+
+impl<K, V> Drop for SkipMap<K, V> {
+    fn drop(&mut self) {
+        //a node can be unlinked from the bottom level but still linked above it
+        let mut nodes = BTreeSet::new();
+        for i in 0..MAX_LEVEL {
+            let mut curr = unsafe { (*self.head).next[i].load(Ordering::Relaxed) } & !0x01;
+            while curr != 0 {
+                nodes.insert(curr);
+                curr = unsafe { (*(curr as *mut SkipNode<K, V>)).next[i].load(Ordering::Relaxed) } & !0x01;
+            }
+        }
+        for node in nodes {
+            unsafe { drop(Box::from_raw(node as *mut SkipNode<K, V>)) };
         }
+        unsafe { drop(Box::from_raw(self.head)) };
     }
 }
@@ -124,6 +124,45 @@ where A : paging::frameallocator::FrameAllocator
     Err(())
 }
 
+/// Identity map the physical range addr..addr + length, for device memory that has to be reached at
+/// its physical address. Pages already mapped to the same frame are kept
+pub unsafe fn map_physical_memory<A>(addr : paging::PhysicalAddress, length : usize, flags : EntryFlags, alloc : &mut A) -> Result<paging::VirtualAddress, ()>
+where A : paging::frameallocator::FrameAllocator
+{
+    map_physical_memory_impl(addr, length, flags, alloc)
+}
+
+fn map_physical_memory_impl<A>(addr : paging::PhysicalAddress, length : usize, flags : EntryFlags, alloc : &mut A) -> Result<paging::VirtualAddress, ()>
+where A : paging::frameallocator::FrameAllocator
+{
+    use paging::translation::{Frame, Page};
+
+    let mut active_page_table = unsafe { crate::memory::paging::ActivePageTable::new() };
+
+    if addr < 0x1000 || length == 0 {
+        return Err(());
+    }
+    let start_page = Page::containing_address(addr);
+    let end_page = Page::containing_address(addr + length - 1);
+
+    // another mapping in the way means the range cannot be reached at its physical address
+    for p in Page::range_inclusive(start_page.clone(), end_page.clone()) {
+        if let Some(frame) = active_page_table.translate_page(p.clone()) {
+            if frame.start_address() != p.start_address() {
+                serial_debugln!("Page at {:x} is mapped elsewhere", p.start_address());
+                return Err(());
+            }
+        }
+    }
+    for p in Page::range_inclusive(start_page, end_page) {
+        if active_page_table.translate_page(p.clone()).is_none() {
+            let frame = Frame::containing_address(p.start_address());
+            active_page_table.map_to(p, frame, flags, alloc);
+        }
+    }
+    Ok(addr)
+}
+
 pub unsafe fn change_map_memory(addr : paging::VirtualAddress, length : usize, flags : EntryFlags)
 {
     change_map_memory_impl(addr, length, flags)
@@ -118,6 +118,46 @@ impl Mapper {
           .or_else(huge_page)
     }
 
+    //flags of the entry that maps page, None if it is not mapped. writable and user
+    //accessible only stay set if every table on the way to it has them too
+    pub fn page_flags(&self, page: Page) -> Option<EntryFlags> {
+        let inherited = EntryFlags::WRITABLE | EntryFlags::USER_ACCESSIBLE;
+        let effective = |upper: EntryFlags, leaf: EntryFlags| leaf & (upper | !inherited);
+
+        let p4_entry = &self.p4()[page.p4_index()];
+        if !p4_entry.flags().contains(EntryFlags::PRESENT) {
+            return None;
+        }
+        let mut upper = p4_entry.flags() & inherited;
+
+        let p3 = self.p4().next_table(page.p4_index())?;
+        let p3_entry = &p3[page.p3_index()];
+        if !p3_entry.flags().contains(EntryFlags::PRESENT) {
+            return None;
+        }
+        if p3_entry.flags().contains(EntryFlags::HUGE_PAGE) {
+            return Some(effective(upper, p3_entry.flags()));
+        }
+        upper &= p3_entry.flags();
+
+        let p2 = p3.next_table(page.p3_index())?;
+        let p2_entry = &p2[page.p2_index()];
+        if !p2_entry.flags().contains(EntryFlags::PRESENT) {
+            return None;
+        }
+        if p2_entry.flags().contains(EntryFlags::HUGE_PAGE) {
+            return Some(effective(upper, p2_entry.flags()));
+        }
+        upper &= p2_entry.flags();
+
+        let p1 = p2.next_table(page.p2_index())?;
+        let p1_entry = &p1[page.p1_index()];
+        if !p1_entry.flags().contains(EntryFlags::PRESENT) {
+            return None;
+        }
+        Some(effective(upper, p1_entry.flags()))
+    }
+
     pub fn map<A>(&mut self, page: Page, flags: EntryFlags, allocator: &mut A)
         where A: FrameAllocator 
     {
@@ -0,0 +1,475 @@
+//!
+//! PCI bus enumeration, config space access and driver registration
+//!
+
+use alloc::vec::Vec;
+use core::fmt;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+use crate::acpi::MCFGInfo;
+use crate::interrupts::{LOCAL_APIC, MSI_VECTOR_BASE, MSI_VECTOR_COUNT};
+use crate::memory::map_physical_memory;
+use crate::memory::paging::entry::EntryFlags;
+use crate::memory::paging::frameallocator::FrameAllocator;
+
+//legacy config mechanism, an address to 0xCF8 selects the dword read or written at 0xCFC
+const CONFIG_ADDRESS: u16 = 0xCF8;
+const CONFIG_DATA: u16 = 0xCFC;
+
+//config space registers
+const VENDOR_ID: u16 = 0x00;
+const COMMAND: u16 = 0x04;
+const CLASS: u16 = 0x08;
+const HEADER_TYPE: u16 = 0x0C;
+const BAR0: u16 = 0x10;
+const SECONDARY_BUS: u16 = 0x18;
+const CAPABILITIES: u16 = 0x34;
+const INTERRUPT_LINE: u16 = 0x3C;
+
+//command register bits
+const COMMAND_IO: u32 = 1 << 0;
+const COMMAND_MEMORY: u32 = 1 << 1;
+const COMMAND_BUS_MASTER: u32 = 1 << 2;
+const COMMAND_INTX_DISABLE: u32 = 1 << 10;
+//status bit saying there is a capability list
+const STATUS_CAPABILITIES: u32 = 1 << 20;
+
+const CAPABILITY_MSI: u8 = 0x05;
+
+//messages to 0xFEE00000 reach the local apic whose id is in bits 12 to 19
+const MSI_ADDRESS: u32 = 0xFEE0_0000;
+
+//bars bigger than this are left for the driver to map, large prefetchable windows would eat the
+//frame allocator in page tables
+const MAX_MAPPED_BAR: u64 = 256 << 20;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Address {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl Address {
+    pub const fn new(bus: u8, device: u8, function: u8) -> Self {
+        Self { bus, device, function }
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Bar {
+    Io { port: u16, size: u32 },
+    //identity mapped when it fits under MAX_MAPPED_BAR
+    Memory { addr: u64, size: u64, prefetchable: bool },
+}
+
+#[derive(Clone, Debug)]
+pub struct Device {
+    pub address: Address,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    pub header_type: u8,
+    pub interrupt_line: u8,
+    pub interrupt_pin: u8,
+    pub bars: [Option<Bar>; 6],
+    //name of the driver that took the device
+    pub driver: Option<&'static str>,
+    //offset of the msi capability
+    msi: Option<u16>,
+}
+
+impl Device {
+    pub fn read_config(&self, offset: u16) -> u32 {
+        read_config(self.address, offset)
+    }
+
+    pub fn write_config(&self, offset: u16, value: u32) {
+        write_config(self.address, offset, value)
+    }
+
+    //lets the device answer memory accesses and do dma
+    pub fn enable_bus_master(&self) {
+        let command = self.read_config(COMMAND) & 0xFFFF;
+        self.write_config(COMMAND, command | COMMAND_MEMORY | COMMAND_IO | COMMAND_BUS_MASTER);
+    }
+
+    pub fn has_msi(&self) -> bool {
+        self.msi.is_some()
+    }
+
+    //sends the interrupts of the device to handler on this core as a message instead of a pin,
+    //the vector it got
+    pub fn enable_msi(&self, handler: fn()) -> Result<u8, ()> {
+        let cap = self.msi.ok_or(())?;
+        let slot = allocate_msi(handler)?;
+        let vector = MSI_VECTOR_BASE + slot as u8;
+        let control = self.read_config(cap) >> 16;
+        let lapic_id = LOCAL_APIC.get_apic_id() as u32 & 0xFF;
+        self.write_config(cap + 4, MSI_ADDRESS | lapic_id << 12);
+        //bit 7 of message control says the address is 64 bit and the data comes after the high half
+        if control & (1 << 7) != 0 {
+            self.write_config(cap + 8, 0);
+            self.write_config(cap + 12, vector as u32);
+        } else {
+            self.write_config(cap + 8, vector as u32);
+        }
+        //one message, edge triggered fixed delivery, then enable it and mask the pin
+        let control = (control & !(0b111 << 4)) | 1;
+        self.write_config(cap, (self.read_config(cap) & 0xFFFF) | control << 16);
+        let command = self.read_config(COMMAND) & 0xFFFF;
+        self.write_config(COMMAND, command | COMMAND_INTX_DISABLE);
+        Ok(vector)
+    }
+
+    //reads the device at address, None if nothing answers
+    fn probe(address: Address) -> Option<Self> {
+        let id = read_config(address, VENDOR_ID);
+        if id & 0xFFFF == 0xFFFF {
+            return None;
+        }
+        let class = read_config(address, CLASS);
+        let header_type = (read_config(address, HEADER_TYPE) >> 16) as u8 & 0x7F;
+        let interrupt = read_config(address, INTERRUPT_LINE);
+        let mut device = Self {
+            address,
+            vendor_id: id as u16,
+            device_id: (id >> 16) as u16,
+            class: (class >> 24) as u8,
+            subclass: (class >> 16) as u8,
+            prog_if: (class >> 8) as u8,
+            revision: class as u8,
+            header_type,
+            interrupt_line: interrupt as u8,
+            interrupt_pin: (interrupt >> 8) as u8,
+            bars: [None; 6],
+            driver: None,
+            msi: None,
+        };
+        device.read_bars();
+        device.msi = device.find_capability(CAPABILITY_MSI);
+        Some(device)
+    }
+
+    //sizes the bars by writing all ones and reading back which bits stuck
+    fn read_bars(&mut self) {
+        //bridges only have two
+        let count = match self.header_type {
+            0 => 6,
+            1 => 2,
+            _ => 0,
+        };
+        //the device must not decode the bogus addresses while they are sized
+        let command = self.read_config(COMMAND) & 0xFFFF;
+        self.write_config(COMMAND, command & !(COMMAND_IO | COMMAND_MEMORY));
+        let mut i = 0;
+        while i < count {
+            let offset = BAR0 + 4 * i as u16;
+            let bar = self.read_config(offset);
+            self.write_config(offset, 0xFFFF_FFFF);
+            let mask = self.read_config(offset);
+            self.write_config(offset, bar);
+            if bar & 1 == 1 {
+                let size = (!(mask & !0b11)).wrapping_add(1);
+                if mask != 0 && bar & !0b11 != 0 {
+                    self.bars[i] = Some(Bar::Io { port: (bar & !0b11) as u16, size: size & 0xFFFF });
+                }
+                i += 1;
+                continue;
+            }
+            let prefetchable = bar & (1 << 3) != 0;
+            let mut addr = (bar & !0xF) as u64;
+            let mut mask = (mask & !0xF) as u64 | 0xFFFF_FFFF_0000_0000;
+            //type 2 is a 64 bit bar that takes the next one for the high half
+            let wide = (bar >> 1) & 0b11 == 2 && i + 1 < count;
+            if wide {
+                let high = self.read_config(offset + 4);
+                self.write_config(offset + 4, 0xFFFF_FFFF);
+                let high_mask = self.read_config(offset + 4);
+                self.write_config(offset + 4, high);
+                addr |= (high as u64) << 32;
+                mask = (mask & 0xFFFF_FFFF) | (high_mask as u64) << 32;
+            }
+            let implemented = mask & 0xFFFF_FFF0 != 0 || (wide && mask >> 32 != 0);
+            if implemented && addr != 0 {
+                self.bars[i] = Some(Bar::Memory { addr, size: (!mask).wrapping_add(1), prefetchable });
+            }
+            i += if wide { 2 } else { 1 };
+        }
+        self.write_config(COMMAND, command);
+    }
+
+    //(id, offset) of every capability in the list
+    pub fn capabilities(&self) -> Vec<(u8, u16)> {
+        let mut capabilities = Vec::new();
+        if self.read_config(COMMAND) & STATUS_CAPABILITIES == 0 {
+            return capabilities;
+        }
+        let mut offset = (self.read_config(CAPABILITIES) & 0xFC) as u16;
+        //the list lives in the 256 byte header so a broken one cannot go on forever
+        for _ in 0..48 {
+            if offset == 0 {
+                break;
+            }
+            let cap = self.read_config(offset);
+            capabilities.push((cap as u8, offset));
+            offset = ((cap >> 8) & 0xFC) as u16;
+        }
+        capabilities
+    }
+
+    //the offset of the first capability with id
+    fn find_capability(&self, id: u8) -> Option<u16> {
+        self.capabilities().into_iter().find(|(cap, _)| *cap == id).map(|(_, offset)| offset)
+    }
+
+    fn is_bridge(&self) -> bool {
+        self.class == 0x06 && self.subclass == 0x04
+    }
+}
+
+impl fmt::Display for Device {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {:04x}:{:04x} class {:02x}.{:02x}.{:02x}", self.address, self.vendor_id, self.device_id, self.class, self.subclass, self.prog_if)
+    }
+}
+
+//a driver gets probe called for every device with one of its ids, Ok takes the device
+pub struct Driver {
+    pub name: &'static str,
+    //(vendor id, device id)
+    pub ids: &'static [(u16, u16)],
+    pub probe: fn(&Device) -> Result<(), ()>,
+}
+
+impl Driver {
+    fn matches(&self, device: &Device) -> bool {
+        self.ids.iter().any(|(vendor, id)| *vendor == device.vendor_id && *id == device.device_id)
+    }
+}
+
+//how config space is reached
+enum ConfigSpace {
+    Ports,
+    //1 MiB for every bus from start_bus on, only the buses in mapped can be read this way
+    Ecam { base: u64, start_bus: u8, end_bus: u8, mapped: [u64; 4] },
+}
+
+impl ConfigSpace {
+    //the address of the dword if it can be reached through ecam
+    fn ecam_address(&self, address: Address, offset: u16) -> Option<*mut u32> {
+        match self {
+            ConfigSpace::Ecam { base, start_bus, end_bus, mapped } => {
+                let bus = address.bus;
+                if bus < *start_bus || bus > *end_bus || mapped[bus as usize / 64] & (1 << (bus % 64)) == 0 {
+                    return None;
+                }
+                let offset = ((bus - start_bus) as u64) << 20 | (address.device as u64) << 15 | (address.function as u64) << 12 | (offset & 0xFFC) as u64;
+                Some((base + offset) as *mut u32)
+            },
+            ConfigSpace::Ports => None,
+        }
+    }
+
+    fn read(&self, address: Address, offset: u16) -> u32 {
+        if let Some(ptr) = self.ecam_address(address, offset) {
+            return unsafe { core::ptr::read_volatile(ptr) };
+        }
+        if offset >= 0x100 {
+            return 0xFFFF_FFFF;
+        }
+        unsafe {
+            Port::<u32>::new(CONFIG_ADDRESS).write(Self::port_address(address, offset));
+            Port::<u32>::new(CONFIG_DATA).read()
+        }
+    }
+
+    fn write(&self, address: Address, offset: u16, value: u32) {
+        if let Some(ptr) = self.ecam_address(address, offset) {
+            unsafe { core::ptr::write_volatile(ptr, value) };
+            return;
+        }
+        if offset >= 0x100 {
+            return;
+        }
+        unsafe {
+            Port::<u32>::new(CONFIG_ADDRESS).write(Self::port_address(address, offset));
+            Port::<u32>::new(CONFIG_DATA).write(value);
+        }
+    }
+
+    fn port_address(address: Address, offset: u16) -> u32 {
+        1 << 31 | (address.bus as u32) << 16 | (address.device as u32) << 11 | (address.function as u32) << 8 | (offset & 0xFC) as u32
+    }
+
+    //maps the ecam window of a bus before it is scanned
+    fn map_bus<A>(&mut self, bus: u8, alloc: &mut A) where A: FrameAllocator {
+        if let ConfigSpace::Ecam { base, start_bus, end_bus, mapped } = self {
+            if bus < *start_bus || bus > *end_bus {
+                return;
+            }
+            let addr = (*base + (((bus - *start_bus) as u64) << 20)) as usize;
+            if unsafe { map_physical_memory(addr, 1 << 20, EntryFlags::WRITABLE | EntryFlags::NO_CACHE, alloc) }.is_ok() {
+                mapped[bus as usize / 64] |= 1 << (bus % 64);
+            } else {
+                serial_warnln!("Unable to map ecam of pci bus {}", bus);
+            }
+        }
+    }
+}
+
+//config space lock, taken on its own so drivers can read config while probing
+lazy_static! {
+    static ref CONFIG: Mutex<ConfigSpace> = Mutex::new(ConfigSpace::Ports);
+    static ref DEVICES: Mutex<Vec<Device>> = Mutex::new(Vec::new());
+    static ref DRIVERS: Mutex<Vec<&'static Driver>> = Mutex::new(Vec::new());
+}
+
+//handler of each msi vector, taken by interrupt handlers so it is only locked with interrupts off
+static MSI_HANDLERS: Mutex<[Option<fn()>; MSI_VECTOR_COUNT]> = Mutex::new([None; MSI_VECTOR_COUNT]);
+
+pub fn read_config(address: Address, offset: u16) -> u32 {
+    interrupts::without_interrupts(|| CONFIG.lock().read(address, offset))
+}
+
+pub fn write_config(address: Address, offset: u16, value: u32) {
+    interrupts::without_interrupts(|| CONFIG.lock().write(address, offset, value))
+}
+
+//scans every bus behind the host bridges, through ecam if the firmware gave us an MCFG table
+pub fn init<A>(mcfg: Option<MCFGInfo>, alloc: &mut A) where A: FrameAllocator {
+    if let Some(mcfg) = mcfg {
+        *CONFIG.lock() = ConfigSpace::Ecam { base: mcfg.base, start_bus: mcfg.start_bus, end_bus: mcfg.end_bus, mapped: [0; 4] };
+    }
+    let mut devices = Vec::new();
+    let mut scanned = [0u64; 4];
+    let host = Device::probe(Address::new(0, 0, 0));
+    //a multi function host bridge has a host bridge for bus n in function n
+    let multi = (read_config(Address::new(0, 0, 0), HEADER_TYPE) >> 16) & 0x80 != 0;
+    if host.is_some() && multi {
+        for function in 0..8 {
+            if Device::probe(Address::new(0, 0, function)).is_some() {
+                scan_bus(function, &mut scanned, &mut devices, alloc);
+            }
+        }
+    } else {
+        scan_bus(0, &mut scanned, &mut devices, alloc);
+    }
+    for device in devices.iter() {
+        serial_infoln!("PCI {}", device);
+        map_bars(device, alloc);
+    }
+    *DEVICES.lock() = devices;
+}
+
+fn scan_bus<A>(bus: u8, scanned: &mut [u64; 4], devices: &mut Vec<Device>, alloc: &mut A) where A: FrameAllocator {
+    //a bridge pointing back at a bus already seen would loop
+    if scanned[bus as usize / 64] & (1 << (bus % 64)) != 0 {
+        return;
+    }
+    scanned[bus as usize / 64] |= 1 << (bus % 64);
+    CONFIG.lock().map_bus(bus, alloc);
+    for slot in 0..32 {
+        let first = match Device::probe(Address::new(bus, slot, 0)) {
+            Some(device) => device,
+            None => continue,
+        };
+        let functions = if (first.read_config(HEADER_TYPE) >> 16) & 0x80 != 0 { 8 } else { 1 };
+        for function in 0..functions {
+            let device = match function {
+                0 => first.clone(),
+                _ => match Device::probe(Address::new(bus, slot, function)) {
+                    Some(device) => device,
+                    None => continue,
+                },
+            };
+            if device.is_bridge() {
+                let secondary = (device.read_config(SECONDARY_BUS) >> 8) as u8;
+                devices.push(device);
+                scan_bus(secondary, scanned, devices, alloc);
+            } else {
+                devices.push(device);
+            }
+        }
+    }
+}
+
+fn map_bars<A>(device: &Device, alloc: &mut A) where A: FrameAllocator {
+    for bar in device.bars.iter().flatten() {
+        if let Bar::Memory { addr, size, .. } = bar {
+            if *size > MAX_MAPPED_BAR {
+                continue;
+            }
+            let flags = EntryFlags::WRITABLE | EntryFlags::NO_CACHE;
+            if unsafe { map_physical_memory(*addr as usize, *size as usize, flags, alloc) }.is_err() {
+                serial_warnln!("Unable to map bar at {:x} of pci {}", addr, device.address);
+            }
+        }
+    }
+}
+
+//every device found by init
+pub fn devices() -> Vec<Device> {
+    DEVICES.lock().clone()
+}
+
+//probes the devices the driver matches that no other driver took, the number it took
+pub fn register_driver(driver: &'static Driver) -> usize {
+    DRIVERS.lock().push(driver);
+    let candidates: Vec<Device> = DEVICES.lock().iter()
+        .filter(|device| device.driver.is_none() && driver.matches(device))
+        .cloned()
+        .collect();
+    let mut taken = 0;
+    //probe is called without the devices lock so it can look at other devices
+    for device in candidates {
+        if (driver.probe)(&device).is_ok() {
+            if let Some(found) = DEVICES.lock().iter_mut().find(|found| found.address == device.address) {
+                found.driver = Some(driver.name);
+            }
+            taken += 1;
+        }
+    }
+    taken
+}
+
+//forgets driver and frees the devices it took, a driver that is not registered changes nothing
+pub fn unregister_driver(driver: &'static Driver) {
+    let mut drivers = DRIVERS.lock();
+    let before = drivers.len();
+    drivers.retain(|registered| !core::ptr::eq(*registered, driver));
+    if drivers.len() == before {
+        return;
+    }
+    for device in DEVICES.lock().iter_mut().filter(|device| device.driver == Some(driver.name)) {
+        device.driver = None;
+    }
+}
+
+fn allocate_msi(handler: fn()) -> Result<usize, ()> {
+    interrupts::without_interrupts(|| {
+        let mut handlers = MSI_HANDLERS.lock();
+        let slot = handlers.iter().position(Option::is_none).ok_or(())?;
+        handlers[slot] = Some(handler);
+        Ok(slot)
+    })
+}
+
+//called by the interrupt handler of msi vector MSI_VECTOR_BASE + slot
+pub fn handle_msi(slot: usize) {
+    let handler = MSI_HANDLERS.lock()[slot];
+    if let Some(handler) = handler {
+        handler();
+    }
+}
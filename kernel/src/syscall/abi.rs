@@ -0,0 +1,69 @@
+//!
+//! Types syscalls share with userspace, stdlib::syscall has the same definitions
+//!
+//! Byte strings cross the boundary as ByteSlice descriptors, the kernel copies what they point
+//! to in and out through syscall::user.
+//!
+//! | syscall          | arg1                  | arg2                    | arg3                   |
+//! |------------------|-----------------------|-------------------------|------------------------|
+//! | PRINT            | *const u8             | len                     |                        |
+//! | READ_KV          | keys *const ByteSlice | slots *mut ValueSlot    | number of keys         |
+//! | WRITE_KV         | keys *const ByteSlice | values *const ByteSlice | number of keys         |
+//! | DELETE_KV        | keys *const ByteSlice |                         | number of keys         |
+//! | READ_IN          | *mut u8               | len                     |                        |
+//! | WRITE_KV_PERSIST | keys *const ByteSlice | values *const ByteSlice | number of keys         |
+//! | TX_BEGIN         |                       |                         |                        |
+//! | TX_READ          | handle                | key *const ByteSlice    | *mut ValueSlot         |
+//! | TX_WRITE         | handle                | key *const ByteSlice    | value *const ByteSlice |
+//! | TX_DELETE        | handle                | key *const ByteSlice    |                        |
+//! | TX_COMMIT        | handle                | persist, 0 or 1         |                        |
+//! | TX_ABORT         | handle                |                         |                        |
+//! | EXIT             | exit code             |                         |                        |
+//!
+//! TX_BEGIN returns the handle, TX_DELETE 1 if the key was there and 0 if not, DELETE_KV how
+//! many of the keys were there and READ_IN the length of the line. EXIT does not return.
+//!
+
+//len bytes at ptr in the memory of the calling process
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct ByteSlice {
+    pub ptr: *const u8,
+    pub len: usize,
+}
+
+//read_status values
+pub const READ_FOUND: u64 = 0;
+pub const READ_NOT_FOUND: u64 = 1;
+//the value did not fit, the first capacity bytes were copied and value_len is the whole length
+pub const READ_TRUNCATED: u64 = 2;
+
+//one slot per key of READ_KV, the caller sets buf and capacity and the kernel the rest
+//a capacity of 0 only asks for the length so the caller can allocate and read again
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct ValueSlot {
+    pub buf: *mut u8,
+    pub capacity: usize,
+    pub value_len: usize,
+    pub read_status: u64,
+}
+
+impl ValueSlot {
+    //records how reading value went, returns the part of it that goes to buf
+    pub fn fill<'a>(&mut self, value: Option<&'a [u8]>) -> &'a [u8] {
+        match value {
+            Some(value) => {
+                let n = value.len().min(self.capacity);
+                self.value_len = value.len();
+                self.read_status = if n < value.len() { READ_TRUNCATED } else { READ_FOUND };
+                &value[..n]
+            },
+            None => {
+                self.value_len = 0;
+                self.read_status = READ_NOT_FOUND;
+                &[]
+            },
+        }
+    }
+}
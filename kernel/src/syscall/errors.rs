@@ -0,0 +1,66 @@
+use crate::cc::TxError;
+
+//syscalls return errors in rax as the negated code, like linux,
+//so they never collide with lengths returned on success.
+//stdlib::syscall::KvError has a variant for each of them. a key that is not there is no
+//error, the reads report it in the read_status of its ValueSlot
+
+//the transaction was killed by the deadlock policy or timed out waiting for a lock
+pub const EABORTED: usize = 1;
+//another transaction committed a write to the same key first
+pub const ECONFLICT: usize = 2;
+//the disk failed
+pub const EIO: usize = 3;
+//an argument that makes no sense, e.g. invalid utf-8 to print
+pub const EINVAL: usize = 4;
+//a pointer that is not mapped user accessible, or not writable where the kernel writes
+pub const EFAULT: usize = 5;
+//no syscall has that number
+pub const ENOSYS: usize = 6;
+//the arguments are more than the kernel copies in one syscall
+pub const E2BIG: usize = 7;
+//no open transaction has that handle
+pub const EBADTX: usize = 8;
+//the process has as many transactions open as it may
+pub const ETXLIMIT: usize = 9;
+
+//Ok is what goes in rax, Err the encoded error
+pub type SyscallResult = Result<usize, usize>;
+
+pub fn encode(code: usize) -> usize {
+    code.wrapping_neg()
+}
+
+//the code in rax, None if it is a success
+pub fn decode(res: usize) -> Option<usize> {
+    if (res as isize) < 0 {
+        Some(res.wrapping_neg())
+    } else {
+        None
+    }
+}
+
+pub fn from_tx_error(e: TxError) -> usize {
+    let code = match e {
+        TxError::Aborted(_) => EABORTED,
+        TxError::Conflict => ECONFLICT,
+        TxError::IoError => EIO,
+        TxError::NotPersistent | TxError::Finished => EINVAL,
+    };
+    encode(code)
+}
+
+pub fn name(code: usize) -> &'static str {
+    match code {
+        EABORTED => "EABORTED",
+        ECONFLICT => "ECONFLICT",
+        EIO => "EIO",
+        EINVAL => "EINVAL",
+        EFAULT => "EFAULT",
+        ENOSYS => "ENOSYS",
+        E2BIG => "E2BIG",
+        EBADTX => "EBADTX",
+        ETXLIMIT => "ETXLIMIT",
+        _ => "unknown error",
+    }
+}
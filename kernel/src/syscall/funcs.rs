@@ -1,72 +1,66 @@
-use alloc::string::String;
+use alloc::vec::Vec;
 use crate::kvstore::KVStore;
-use crate::cc::Transaction;
+use crate::cc::{Transaction, TxError};
+use super::abi::ValueSlot;
+use super::errors::{self, SyscallResult};
+use super::user;
 use crate::KVSTORE;
 use crate::console;
 
-pub fn print(s: &str) -> usize {
+pub fn print(s: &str) -> SyscallResult {
     print!("{}", s);
-    0 
+    Ok(0)
 }
 
-pub fn read_kv(keys: &[String], values: &mut [[u8; 32]], len: usize) -> usize {
-    KVSTORE.transact_mut(&mut |tx| {
-        for i in 0..len {
-            // for (j, c) in tx.read(&keys[i]).unwrap().as_bytes().iter().enumerate() {
-            //     values[i][j] = *c;
-            // }
-            let val = tx.read(&keys[i]);
-            match val {
-                Some(v) => {
-                    for (j, c) in v.as_bytes().iter().enumerate() {
-                        values[i][j] = *c;
-                    }
-                },
-                None => {
-                    for (j, c) in "None".as_bytes().iter().enumerate() {
-                        values[i][j] = *c;
-                    }
-                }
-            }
-        }
-    }, false);
-    0
+//reads every key in one transaction, then fills the slots so a retried transaction never
+//leaves half written buffers behind. the buffers of slots are in user memory
+pub fn read_kv(keys: &[Vec<u8>], slots: &mut [ValueSlot]) -> SyscallResult {
+    let values = KVSTORE.transact_mut(&mut |tx| {
+        keys.iter().map(|key| tx.read(key)).collect::<Result<Vec<_>, TxError>>()
+    }, false).map_err(errors::from_tx_error)?;
+    for (slot, value) in slots.iter_mut().zip(values.iter()) {
+        let part = slot.fill(value.as_deref());
+        user::copy_to_user(slot.buf as usize, part)?;
+    }
+    Ok(0)
 }
 
-pub fn write_kv(keys: &[String], values: &[String], len: usize) -> usize {
+pub fn write_kv(keys: &[Vec<u8>], values: &[Vec<u8>], len: usize) -> SyscallResult {
     KVSTORE.transact_mut(&mut |tx| {
         for i in 0..len {
-            tx.write(&keys[i], &values[i]);
+            tx.write(&keys[i], &values[i])?;
         }
-    }, false);
-    0
+        Ok(0)
+    }, false).map_err(errors::from_tx_error)
 }
 
-pub fn write_kv_persist(keys: &[String], values: &[String], len: usize) -> usize {
+pub fn write_kv_persist(keys: &[Vec<u8>], values: &[Vec<u8>], len: usize) -> SyscallResult {
     KVSTORE.transact_mut(&mut |tx| {
         for i in 0..len {
-            tx.write(&keys[i], &values[i]);
+            tx.write(&keys[i], &values[i])?;
         }
-    }, true);
-    0
+        Ok(0)
+    }, true).map_err(errors::from_tx_error)
 }
 
-pub fn delete_kv(keys: &[String], len: usize) -> usize {
+//returns how many of the keys were there
+pub fn delete_kv(keys: &[Vec<u8>], len: usize) -> SyscallResult {
     KVSTORE.transact_mut(&mut |tx| {
+        let mut deleted = 0;
         for i in 0..len {
-            tx.delete(&keys[i]);
+            if tx.delete(&keys[i])? {
+                deleted += 1;
+            }
         }
-    }, false);
-    0
+        Ok(deleted)
+    }, false).map_err(errors::from_tx_error)
 }
 
-pub fn read_in(s: &mut [u8], len: usize) -> usize {
-    let stdin = console::read_line(); 
-    for (i, c) in stdin.as_bytes().iter().enumerate() {
-        if i > len {
-            break;
-        }
-        s[i] = *c;
-    }
-    stdin.len()
-}
\ No newline at end of file
+//copies as much of the line typed so far as fits in len bytes at ptr, returns its whole length
+pub fn read_in(ptr: usize, len: usize) -> SyscallResult {
+    user::check(ptr, len, true)?;
+    let stdin = console::read_line();
+    let n = stdin.len().min(len);
+    user::copy_to_user(ptr, &stdin.as_bytes()[..n])?;
+    Ok(stdin.len())
+}
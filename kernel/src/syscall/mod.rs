@@ -1,46 +1,110 @@
-use alloc::{slice, string::String};
+use alloc::vec::Vec;
+use abi::ValueSlot;
+use errors::SyscallResult;
+use crate::userspace;
 
+pub mod abi;
 pub mod numbers;
 pub mod funcs;
+pub mod errors;
+pub mod tx;
+pub mod user;
 
 pub fn dispatcher(n: usize, arg1: usize, arg2: usize, arg3: usize, _arg4: usize) -> usize {
-    match n {
-        numbers::PRINT => {
-            let s = unsafe { core::slice::from_raw_parts(arg1 as *const u8, arg2 as usize) };
-            let s = core::str::from_utf8(s).unwrap();
-            funcs::print(s);
-            0
-        },
-        numbers::READ_KV => {
-            let keys: &[String]  = unsafe { slice::from_raw_parts(arg1 as *mut String, arg3) };
-            let values: &mut [[u8; 32]] = unsafe { slice::from_raw_parts_mut(arg2 as *mut [u8; 32], arg3) };        
-            funcs::read_kv(keys, values, arg3);
-            0
-        },
-        numbers::WRITE_KV => {
-            let keys: &[String]  = unsafe { slice::from_raw_parts(arg1 as *mut String, arg3) };
-            let values: &[String] = unsafe { slice::from_raw_parts(arg2 as *mut String, arg3) };        
-            funcs::write_kv(keys, values, arg3);
-            0
-        },
-        numbers::DELETE_KV => {
-            let keys: &[String]  = unsafe { slice::from_raw_parts(arg1 as *mut String, arg3) };
-            funcs::delete_kv(keys, arg3);
-            0
-        },
-        numbers::READ_IN => {
-            let s = unsafe { core::slice::from_raw_parts_mut(arg1 as *mut u8, arg2 as usize) };
-            funcs::read_in(s, arg2)
-        },
-        numbers::WRITE_KV_PERSIST => {
-            let keys: &[String]  = unsafe { slice::from_raw_parts(arg1 as *mut String, arg3) };
-            let values: &[String] = unsafe { slice::from_raw_parts(arg2 as *mut String, arg3) };        
-            funcs::write_kv_persist(keys, values, arg3);
-            0
+    let pid = userspace::current_pid();
+    let res = match n {
+        numbers::PRINT => print(arg1, arg2),
+        numbers::READ_KV => read_kv(arg1, arg2, arg3),
+        numbers::WRITE_KV => write_kv(arg1, arg2, arg3, false),
+        numbers::DELETE_KV => delete_kv(arg1, arg3),
+        numbers::READ_IN => funcs::read_in(arg1, arg2),
+        numbers::WRITE_KV_PERSIST => write_kv(arg1, arg2, arg3, true),
+        numbers::TX_BEGIN => tx::begin(pid),
+        numbers::TX_READ => tx_read(pid, arg1, arg2, arg3),
+        numbers::TX_WRITE => tx_write(pid, arg1, arg2, arg3),
+        numbers::TX_DELETE => tx_delete(pid, arg1, arg2),
+        numbers::TX_COMMIT => tx::commit(pid, arg1, arg2 != 0),
+        numbers::TX_ABORT => tx::abort(pid, arg1),
+        numbers::EXIT => {
+            userspace::exit_current(arg1);
+            //there is no other process to switch to and the one that exited must not run again
+            crate::hlt_loop()
         },
         _ => {
-            println!("Unknown syscall number: {}", n);
-            0
+            serial_warnln!("Unknown syscall number: {}", n);
+            Err(errors::encode(errors::ENOSYS))
         }
+    };
+    //errors are already encoded
+    match res {
+        Ok(res) => res,
+        Err(e) => {
+            if let Some(code) = errors::decode(e) {
+                serial_debugln!("Syscall {} failed with {}", n, errors::name(code));
+            }
+            e
+        },
     }
 }
+
+//the arguments of each syscall are copied out of user memory, see abi for their layout
+
+fn print(ptr: usize, len: usize) -> SyscallResult {
+    let s = user::copy_from_user(ptr, len)?;
+    let s = core::str::from_utf8(&s).map_err(|_| errors::encode(errors::EINVAL))?;
+    funcs::print(s)
+}
+
+fn read_kv(keys: usize, slots: usize, len: usize) -> SyscallResult {
+    let keys = user::read_slices(keys, len)?;
+    let mut values = user::read_array::<ValueSlot>(slots, len)?;
+    //every buffer is checked before the transaction runs
+    for slot in values.iter() {
+        user::check(slot.buf as usize, slot.capacity, true)?;
+    }
+    let res = funcs::read_kv(&keys, &mut values);
+    user::write_array(slots, &values)?;
+    res
+}
+
+fn write_kv(keys: usize, values: usize, len: usize, persist: bool) -> SyscallResult {
+    let keys = user::read_slices(keys, len)?;
+    let values = user::read_slices(values, len)?;
+    if persist {
+        funcs::write_kv_persist(&keys, &values, len)
+    } else {
+        funcs::write_kv(&keys, &values, len)
+    }
+}
+
+fn delete_kv(keys: usize, len: usize) -> SyscallResult {
+    let keys = user::read_slices(keys, len)?;
+    funcs::delete_kv(&keys, len)
+}
+
+//the bytes the one ByteSlice at ptr describes
+fn read_bytes(ptr: usize) -> Result<Vec<u8>, usize> {
+    Ok(user::read_slices(ptr, 1)?.remove(0))
+}
+
+fn tx_read(pid: userspace::Pid, handle: usize, key: usize, slot: usize) -> SyscallResult {
+    let key = read_bytes(key)?;
+    let mut value_slot = user::read_array::<ValueSlot>(slot, 1)?.remove(0);
+    user::check(value_slot.buf as usize, value_slot.capacity, true)?;
+    let value = tx::read(pid, handle, &key)?;
+    let part = value_slot.fill(value.as_deref());
+    user::copy_to_user(value_slot.buf as usize, part)?;
+    user::write_array(slot, &[value_slot])?;
+    Ok(0)
+}
+
+fn tx_write(pid: userspace::Pid, handle: usize, key: usize, value: usize) -> SyscallResult {
+    let key = read_bytes(key)?;
+    let value = read_bytes(value)?;
+    tx::write(pid, handle, &key, &value)
+}
+
+fn tx_delete(pid: userspace::Pid, handle: usize, key: usize) -> SyscallResult {
+    let key = read_bytes(key)?;
+    tx::delete(pid, handle, &key)
+}
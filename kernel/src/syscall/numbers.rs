@@ -4,3 +4,10 @@ pub const WRITE_KV: usize = 0x2;
 pub const DELETE_KV: usize = 0x3;
 pub const READ_IN:  usize = 0x4;
 pub const WRITE_KV_PERSIST: usize = 0x5;
+pub const TX_BEGIN: usize = 0x6;
+pub const TX_READ:  usize = 0x7;
+pub const TX_WRITE: usize = 0x8;
+pub const TX_DELETE: usize = 0x9;
+pub const TX_COMMIT: usize = 0xA;
+pub const TX_ABORT: usize = 0xB;
+pub const EXIT:     usize = 0xC;
@@ -0,0 +1,123 @@
+//!
+//! Transactions userspace keeps open across syscalls
+//!
+//! TX_BEGIN hands out a handle into a table of the calling process. The transaction holds its
+//! locks until TX_COMMIT or TX_ABORT with that handle, or until the process exits.
+//!
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use crate::cc::Transaction;
+use crate::kvstore::{KVStore, TxKVStorePersist};
+use crate::userspace::Pid;
+use crate::KVSTORE;
+use super::errors::{self, SyscallResult, EBADTX, ETXLIMIT};
+
+type UserTx = <TxKVStorePersist<Vec<u8>, Vec<u8>> as KVStore>::Transaction;
+
+//open transactions a process can have, each keeps its locks and redo log in the kernel heap
+pub const MAX_OPEN: usize = 16;
+
+//the transactions of one process by handle
+struct TxTable {
+    next_handle: usize,
+    open: BTreeMap<usize, UserTx>,
+}
+
+lazy_static! {
+    static ref TABLES: Mutex<BTreeMap<Pid, TxTable>> = Mutex::new(BTreeMap::new());
+}
+
+//the transaction is taken out of the table while it works so a lock wait does not hold TABLES
+fn with_tx<R>(pid: Pid, handle: usize, f: impl FnOnce(&mut UserTx) -> Result<R, usize>) -> Result<R, usize> {
+    let mut tx = take(pid, handle)?;
+    let result = f(&mut tx);
+    let mut tables = TABLES.lock();
+    match tables.get_mut(&pid) {
+        Some(table) => {
+            table.open.insert(handle, tx);
+        },
+        //the process exited meanwhile, nothing may keep locks for it
+        None => {
+            drop(tables);
+            tx.abort();
+        },
+    }
+    result
+}
+
+//the handle of a new transaction of pid
+pub fn begin(pid: Pid) -> SyscallResult {
+    let mut tables = TABLES.lock();
+    let table = tables.entry(pid).or_insert_with(|| TxTable { next_handle: 0, open: BTreeMap::new() });
+    if table.open.len() >= MAX_OPEN {
+        return Err(errors::encode(ETXLIMIT));
+    }
+    let handle = table.next_handle;
+    table.next_handle += 1;
+    table.open.insert(handle, KVSTORE.begin());
+    Ok(handle)
+}
+
+pub fn read(pid: Pid, handle: usize, key: &Vec<u8>) -> Result<Option<Vec<u8>>, usize> {
+    with_tx(pid, handle, |tx| tx.read(key).map_err(errors::from_tx_error))
+}
+
+pub fn write(pid: Pid, handle: usize, key: &Vec<u8>, value: &Vec<u8>) -> SyscallResult {
+    with_tx(pid, handle, |tx| tx.write(key, value).map(|_| 0).map_err(errors::from_tx_error))
+}
+
+//1 if key was there, 0 if not
+pub fn delete(pid: Pid, handle: usize, key: &Vec<u8>) -> SyscallResult {
+    with_tx(pid, handle, |tx| tx.delete(key).map(usize::from).map_err(errors::from_tx_error))
+}
+
+//commits and closes the handle, whether the commit worked or not. persist is the same request
+//as the one of WRITE_KV_PERSIST, the durability of the store has the last word
+pub fn commit(pid: Pid, handle: usize, persist: bool) -> SyscallResult {
+    let mut tx = take(pid, handle)?;
+    let result = if KVSTORE.durability().persist(persist) {
+        tx.try_commit_persist()
+    } else {
+        tx.try_commit()
+    };
+    if result.is_err() {
+        tx.abort();
+    }
+    drop(tx);
+    KVSTORE.maintain();
+    result.map(|_| 0).map_err(errors::from_tx_error)
+}
+
+//aborts and closes the handle
+pub fn abort(pid: Pid, handle: usize) -> SyscallResult {
+    take(pid, handle)?.abort();
+    Ok(0)
+}
+
+fn take(pid: Pid, handle: usize) -> Result<UserTx, usize> {
+    TABLES.lock().get_mut(&pid)
+        .and_then(|table| table.open.remove(&handle))
+        .ok_or(errors::encode(EBADTX))
+}
+
+//aborts every transaction pid left open and forgets its table, returns how many there were
+pub fn abort_all(pid: Pid) -> usize {
+    let table = TABLES.lock().remove(&pid);
+    match table {
+        Some(mut table) => {
+            let open = table.open.len();
+            for tx in table.open.values_mut() {
+                tx.abort();
+            }
+            open
+        },
+        None => 0,
+    }
+}
+
+//transactions pid has open
+pub fn open_count(pid: Pid) -> usize {
+    TABLES.lock().get(&pid).map_or(0, |table| table.open.len())
+}
@@ -0,0 +1,101 @@
+//!
+//! Copies between the kernel and the memory of the process that made the syscall
+//!
+//! Every range is checked against the current page tables first, so a bad pointer from
+//! userspace is an EFAULT instead of a page fault in ring 0.
+//!
+use alloc::vec::Vec;
+use crate::memory::paging::entry::EntryFlags;
+use crate::memory::paging::translation::Page;
+use crate::memory::paging::{ActivePageTable, PAGE_SIZE};
+use super::abi::ByteSlice;
+use super::errors::{self, E2BIG, EFAULT, EINVAL};
+
+//userspace lives in the lower half
+const USER_END: usize = 0x0000_8000_0000_0000;
+//most bytes one syscall copies into the kernel, the kernel heap is small
+pub const MAX_COPY_IN: usize = 64 * 1024;
+
+//Ok if len bytes from ptr are mapped user accessible, and writable if write is set
+pub fn check(ptr: usize, len: usize, write: bool) -> Result<(), usize> {
+    if len == 0 {
+        return Ok(());
+    }
+    let end = ptr.checked_add(len).ok_or(errors::encode(EFAULT))?;
+    if end > USER_END {
+        return Err(errors::encode(EFAULT));
+    }
+    let mut required = EntryFlags::PRESENT | EntryFlags::USER_ACCESSIBLE;
+    if write {
+        required |= EntryFlags::WRITABLE;
+    }
+    let page_table = unsafe { ActivePageTable::new() };
+    let mut page = ptr - ptr % PAGE_SIZE;
+    while page < end {
+        match page_table.page_flags(Page::containing_address(page)) {
+            Some(flags) if flags.contains(required) => page += PAGE_SIZE,
+            _ => return Err(errors::encode(EFAULT)),
+        }
+    }
+    Ok(())
+}
+
+//len bytes from ptr
+pub fn copy_from_user(ptr: usize, len: usize) -> Result<Vec<u8>, usize> {
+    if len > MAX_COPY_IN {
+        return Err(errors::encode(E2BIG));
+    }
+    check(ptr, len, false)?;
+    if len == 0 {
+        return Ok(Vec::new());
+    }
+    Ok(unsafe { core::slice::from_raw_parts(ptr as *const u8, len) }.to_vec())
+}
+
+//data to ptr
+pub fn copy_to_user(ptr: usize, data: &[u8]) -> Result<(), usize> {
+    check(ptr, data.len(), true)?;
+    if !data.is_empty() {
+        unsafe { core::ptr::copy_nonoverlapping(data.as_ptr(), ptr as *mut u8, data.len()) };
+    }
+    Ok(())
+}
+
+//count values of T from ptr, T is one of the repr(C) types of abi
+pub fn read_array<T: Copy>(ptr: usize, count: usize) -> Result<Vec<T>, usize> {
+    let len = count.checked_mul(core::mem::size_of::<T>()).ok_or(errors::encode(E2BIG))?;
+    if len > MAX_COPY_IN {
+        return Err(errors::encode(E2BIG));
+    }
+    check(ptr, len, false)?;
+    if ptr % core::mem::align_of::<T>() != 0 {
+        return Err(errors::encode(EINVAL));
+    }
+    if count == 0 {
+        return Ok(Vec::new());
+    }
+    Ok(unsafe { core::slice::from_raw_parts(ptr as *const T, count) }.to_vec())
+}
+
+//values back to ptr, where read_array found them
+pub fn write_array<T: Copy>(ptr: usize, values: &[T]) -> Result<(), usize> {
+    let len = core::mem::size_of_val(values);
+    check(ptr, len, true)?;
+    if ptr % core::mem::align_of::<T>() != 0 {
+        return Err(errors::encode(EINVAL));
+    }
+    if !values.is_empty() {
+        unsafe { core::ptr::copy_nonoverlapping(values.as_ptr(), ptr as *mut T, values.len()) };
+    }
+    Ok(())
+}
+
+//the byte strings count descriptors from ptr point to, at most MAX_COPY_IN bytes in all
+pub fn read_slices(ptr: usize, count: usize) -> Result<Vec<Vec<u8>>, usize> {
+    let slices = read_array::<ByteSlice>(ptr, count)?;
+    let total = slices.iter().try_fold(0usize, |total, slice| total.checked_add(slice.len));
+    if total.map_or(true, |total| total > MAX_COPY_IN) {
+        return Err(errors::encode(E2BIG));
+    }
+    slices.iter().map(|slice| copy_from_user(slice.ptr as usize, slice.len)).collect()
+}
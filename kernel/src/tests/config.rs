@@ -0,0 +1,90 @@
+use super::KernelTest;
+use crate::cc::CCMode;
+use crate::config::{BootMode, Config, KvDevice, MAX_BUCKETS};
+use crate::drivers::serial::LogLevel;
+use crate::kvstore::Durability;
+
+/////////////////////////////////////////////////////////////
+//// Tests
+////////////////////////////////////////////////////////////
+
+//test the literal command lines grub passes still pick the mode
+fn test_config_modes() {
+    assert_eq!(Config::parse(""), (Config::default(), [].to_vec()));
+    assert_eq!(Config::parse("test").0.mode, BootMode::Test);
+    assert_eq!(Config::parse("bench").0.mode, BootMode::Bench);
+    assert_eq!(Config::parse("mode=run").0.mode, BootMode::Run);
+}
+
+//test every option
+fn test_config_options() {
+    let (config, errors) = Config::parse("test kv=0:1 buckets=64 locks=512 durability=always cc=snapshot format=yes log=warn");
+    assert!(errors.is_empty());
+    assert_eq!(config, Config {
+        mode: BootMode::Test,
+        kv_device: KvDevice::Drive { bus: 0, drive: 1 },
+        buckets: 64,
+        locks: 512,
+        durability: Durability::Always,
+        cc_mode: CCMode::Snapshot,
+        format: true,
+        log_level: LogLevel::Warn,
+    });
+    assert_eq!(Config::parse("kv=2:3").0.kv_device, KvDevice::Drive { bus: 2, drive: 3 });
+    assert_eq!(Config::parse("kv=1").0.kv_device, KvDevice::Index(1));
+    assert_eq!(Config::parse("  durability=memory   log=trace ").0.durability, Durability::Memory);
+    assert_eq!(Config::parse("cc=woundwait").0.cc_mode, CCMode::WoundWait);
+    assert!(LogLevel::Error < LogLevel::Warn && LogLevel::Debug < LogLevel::Trace);
+}
+
+//test wrong values are reported and keep their defaults while the rest still applies
+fn test_config_errors() {
+    let (config, errors) = Config::parse("buckets=0 locks=lots kv=0:2 durability=never cc=2pl format=1 log=loud foo=1 buckets=32 testing");
+    assert_eq!(errors.len(), 9);
+    assert!(errors[0].contains("buckets=0"));
+    assert_eq!(config.buckets, 32);
+    assert_eq!(config.locks, Config::default().locks);
+    assert_eq!(config.kv_device, KvDevice::Default);
+    assert_eq!(config.durability, Durability::Request);
+    assert_eq!(config.cc_mode, CCMode::WaitDie);
+    assert!(!config.format);
+    assert_eq!(config.log_level, LogLevel::Trace);
+    assert_eq!(config.mode, BootMode::Run);
+    let too_many = alloc::format!("buckets={}", MAX_BUCKETS + 1);
+    assert_eq!(Config::parse(&too_many).1.len(), 1);
+    assert_eq!(Config::parse("kv=3:0").1.len(), 1);
+}
+
+//test the kv disk a configuration names has to exist
+fn test_config_kv_drive() {
+    assert_eq!(Config::default().kv_drive(), Ok(crate::disk::kv_disk()));
+    assert!(Config::parse("kv=2:5").0.kv_drive().is_err());
+    assert!(Config::parse("kv=100").0.kv_drive().is_err());
+    assert_eq!(Config::parse("kv=0:1").0.kv_drive(), Ok((0, 1)));
+}
+
+pub fn run_tests() {
+    let tests = [
+        KernelTest {
+            name : "test_config_modes",
+            test_fn : test_config_modes,
+        },
+        KernelTest {
+            name : "test_config_options",
+            test_fn : test_config_options,
+        },
+        KernelTest {
+            name : "test_config_errors",
+            test_fn : test_config_errors,
+        },
+        KernelTest {
+            name : "test_config_kv_drive",
+            test_fn : test_config_kv_drive,
+        },
+    ];
+    for t in tests.iter() {
+        serial_print!("{}...\t", t.name);
+        (t.test_fn)();
+        serial_print!("[ok]\n");
+    }
+}
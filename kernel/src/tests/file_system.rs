@@ -1,19 +1,27 @@
 extern crate alloc;
 use alloc::string::String;
 use alloc::string::ToString;
+use alloc::format;
+use alloc::vec;
 use alloc::vec::Vec;
 use crate::tests::KernelTest;
 
+use crate::disk::ahci;
 use crate::disk::ata;
 use crate::disk::ata::write;
 use crate::disk::ata::read;
 use crate::disk::ata::Drive;
+use crate::disk::virtio;
+use crate::disk::request::Pending;
+use crate::disk::VIRTIO_BUS;
 //print benchmark and test results
 use crate::serial_println;
 //timing for benchmarks
 use crate::drivers::timing;
 //writing vec u8 
 use crate::disk::disk_api::Disk;
+use crate::disk::cache;
+use crate::disk::cache::{BlockCache, CacheStats};
 
 //basic ATA test
 fn test_read_write_disk(){
@@ -24,6 +32,8 @@ fn test_read_write_disk(){
     let block = 1;
     ata::init();
     Drive::open(bus, drive);
+    //these tests write around the block cache
+    cache::invalidate(bus, drive).unwrap();
     //the 512 bytes of dead beed to write
     let buf_dead_beef: &[u8] = "DEADBEEFDEADBEEFDEADBEEFDEADBEEFDEADBEEFDEADBEEFDEADBEEFDEADBEEFDEADBEEFDEADBEEFDEADBEEFDEADBEEFDEADBEEFDEADBEEFDEADBEEFDEADBEEFDEADBEEFDEADBEEFDEADBEEFDEADBEEFDEADBEEFDEADBEEFDEADBEEFDEADBEEFDEADBEEFDEADBEEFDEADBEEFDEADBEEFDEADBEEFDEADBEEFDEADBEEFDEADBEEFDEADBEEFDEADBEEFDEADBEEFDEADBEEFDEADBEEFDEADBEEFDEADBEEFDEADBEEFDEADBEEFDEADBEEFDEADBEEFDEADBEEFDEADBEEFDEADBEEFDEADBEEFDEADBEEFDEADBEEFDEADBEEFDEADBEEFDEADBEEFDEADBEEFDEADBEEFDEADBEEFDEADBEEFDEADBEEFDEADBEEFDEADBEEFDEADBEEFDEADBEEFDEADBEEFDEADBEEFDEADBEEF".as_bytes();
     //write the dead beef
@@ -51,6 +61,8 @@ fn test_disk_multiple_blocks(){
     let drive = 1;
     ata::init();
     Drive::open(bus, drive);
+    //these tests write around the block cache
+    cache::invalidate(bus, drive).unwrap();
     //from block 0 to 9 write the block number
     for block in 0..10{
         let buf_512_data: &[u8] = &[block + 48; 512];
@@ -79,6 +91,181 @@ fn test_disk_multiple_blocks(){
     }
 }
 
+//test transfers of many blocks, more than one command can move
+fn test_disk_block_ranges(){
+    let bus = 0;
+    let drive = 1;
+    ata::init();
+    let disk = Drive::open(bus, drive).unwrap();
+    //the unit test disk is 128 MiB
+    assert_eq!(disk.block_count(), 128 * 1024 * 1024 / 512);
+    cache::invalidate(bus, drive).unwrap();
+    //just over one lba28 command, the buffers live on the heap since the stack is small
+    let buf_data: Vec<u8> = (0..260 * 512).map(|i| (i / 512 + i % 7) as u8).collect();
+    let mut buf_read = vec![0; 260 * 512];
+    assert_eq!(write(bus, drive, 10, &buf_data), Ok(()));
+    assert_eq!(read(bus, drive, 10, &mut buf_read), Ok(()));
+    assert_eq!(buf_data, buf_read);
+    //a single block past the first command
+    assert_eq!(read(bus, drive, 267, &mut buf_read[..512]), Ok(()));
+    assert_eq!(&buf_data[257 * 512..258 * 512], &buf_read[..512]);
+    //only whole blocks
+    assert_eq!(read(bus, drive, 10, &mut buf_read[..100]), Err(()));
+    assert_eq!(write(bus, drive, 10, &[]), Err(()));
+    buf_read.fill(0);
+    assert_eq!(write(bus, drive, 10, &buf_read), Ok(()));
+}
+
+//test queued transfers complete in the order they were submitted
+fn test_ata_request_queue(){
+    let bus = 0;
+    let drive = 1;
+    ata::init();
+    Drive::open(bus, drive);
+    cache::invalidate(bus, drive).unwrap();
+    let first = ata::submit_write(bus, drive, 20, [7; 3 * 512].to_vec());
+    let second = ata::submit_write(bus, drive, 21, [8; 512].to_vec());
+    let read_back = ata::submit_read(bus, drive, 20, 3);
+    assert_eq!(read_back.wait(), Ok([[7; 512], [8; 512], [7; 512]].concat()));
+    //the earlier requests are done by the time a later one is
+    assert!(first.is_done() && second.is_done());
+    assert_eq!(first.wait(), Ok(Vec::new()));
+    assert_eq!(second.wait(), Ok(Vec::new()));
+    //a request the bus never sees fails right away
+    assert_eq!(ata::submit_write(bus, drive, 20, [0; 100].to_vec()).wait(), Err(()));
+    assert_eq!(ata::submit_read(bus, drive, 20, 0).wait(), Err(()));
+    //a flush waits for the queued writes and then empties the write cache of the drive
+    let queued = ata::submit_write(bus, drive, 22, [9; 512].to_vec());
+    assert_eq!(ata::flush(bus, drive), Ok(()));
+    assert!(queued.is_done());
+    assert_eq!(queued.wait(), Ok(Vec::new()));
+}
+
+//the queued interface ahci and virtio disks both have, so one check covers the two drivers
+struct Commands {
+    disk: u8,
+    max_blocks: usize,
+    submit_read: fn(u8, u64, usize) -> Pending,
+    submit_write: fn(u8, u64, &[u8]) -> Pending,
+    read: fn(u8, u64, &mut [u8]) -> Result<(), ()>,
+    write: fn(u8, u64, &[u8]) -> Result<(), ()>,
+    flush: fn(u8) -> Result<(), ()>,
+}
+
+//queued commands and a range just over one command, the buffers live on the heap since the stack is small
+fn check_commands(commands: &Commands, blocks: u64) {
+    let disk = commands.disk;
+    let first = (commands.submit_write)(disk, 30, &[5; 2 * 512]);
+    let second = (commands.submit_write)(disk, 40, &[6; 512]);
+    assert_eq!(first.wait(), Ok(Vec::new()));
+    assert_eq!(second.wait(), Ok(Vec::new()));
+    assert_eq!((commands.submit_read)(disk, 30, 2).wait(), Ok([5; 2 * 512].to_vec()));
+    assert_eq!((commands.submit_read)(disk, 40, 1).wait(), Ok([6; 512].to_vec()));
+    let len = (commands.max_blocks + 4) * 512;
+    let buf_data: Vec<u8> = (0..len).map(|i| (i / 512 + i % 11) as u8).collect();
+    let mut buf_read = vec![0; len];
+    assert_eq!((commands.write)(disk, 50, &buf_data), Ok(()));
+    assert_eq!((commands.flush)(disk), Ok(()));
+    assert_eq!((commands.read)(disk, 50, &mut buf_read), Ok(()));
+    assert_eq!(buf_data, buf_read);
+    //more blocks than one command moves or than the disk has
+    assert!((commands.submit_read)(disk, 0, commands.max_blocks + 1).wait().is_err());
+    assert!((commands.submit_read)(disk, blocks, 1).wait().is_err());
+    assert!((commands.submit_write)(disk, 30, &[0; 100]).wait().is_err());
+}
+
+//test queued ahci commands, only on q35 where an hba has the disk
+fn test_ahci_commands(){
+    let port = ahci::port_of(0, 1);
+    let blocks = match ahci::block_count(port) {
+        Some(blocks) => blocks,
+        None => return,
+    };
+    assert_eq!(blocks, 128 * 1024 * 1024 / 512);
+    cache::invalidate(0, 1).unwrap();
+    check_commands(&Commands {
+        disk: port,
+        max_blocks: ahci::MAX_BLOCKS,
+        submit_read: ahci::submit_read,
+        submit_write: ahci::submit_write,
+        read: ahci::read,
+        write: ahci::write,
+        flush: ahci::flush,
+    }, blocks);
+}
+
+//test virtio-blk requests through the split queue, only when the runner attached a virtio disk
+fn test_virtio_commands(){
+    let blocks = match virtio::block_count(0) {
+        Some(blocks) => blocks,
+        None => return,
+    };
+    cache::invalidate(VIRTIO_BUS, 0).unwrap();
+    check_commands(&Commands {
+        disk: 0,
+        max_blocks: virtio::MAX_BLOCKS,
+        submit_read: virtio::submit_read,
+        submit_write: virtio::submit_write,
+        read: virtio::read,
+        write: virtio::write,
+        flush: virtio::flush,
+    }, blocks);
+    //through the disk dispatch too
+    assert_eq!(crate::disk::block_count(VIRTIO_BUS, 0), Some(blocks));
+}
+
+//test lru eviction, pinning and write-back with a small cache of its own
+fn test_block_cache(){
+    let bus = 0;
+    let drive = 1;
+    ata::init();
+    Drive::open(bus, drive);
+    cache::invalidate(bus, drive).unwrap();
+    let buf_read: &mut [u8] = &mut [0; 512];
+    let mut blocks = BlockCache::new(4);
+    for block in 0..4 {
+        assert_eq!(blocks.write((bus, drive, block), &[block as u8 + 1; 512]), Ok(()));
+    }
+    //nothing reaches the disk before a flush
+    assert_eq!(read(bus, drive, 0, buf_read), Ok(()));
+    assert_eq!(buf_read, &[0; 512]);
+    assert_eq!(blocks.pin((bus, drive, 0)), Ok(()));
+    assert_eq!(blocks.read((bus, drive, 1), buf_read), Ok(()));
+    assert_eq!(buf_read, &[2; 512]);
+    //evicts 2, 3 and 1, the pinned block stays even though it is the oldest
+    for block in 4..7 {
+        assert_eq!(blocks.write((bus, drive, block), &[block as u8 + 1; 512]), Ok(()));
+    }
+    assert_eq!(read(bus, drive, 2, buf_read), Ok(()));
+    assert_eq!(buf_read, &[3; 512]);
+    assert_eq!(blocks.read((bus, drive, 0), buf_read), Ok(()));
+    assert_eq!(buf_read, &[1; 512]);
+    assert_eq!(blocks.len(), 4);
+    blocks.unpin((bus, drive, 0));
+    assert_eq!(blocks.flush(bus, drive), Ok(()));
+    assert_eq!(blocks.stats(), CacheStats { hits: 3, misses: 7, evictions: 3, writebacks: 7 });
+    for block in 0..7 {
+        assert_eq!(read(bus, drive, block, buf_read), Ok(()));
+        assert_eq!(buf_read, &[block as u8 + 1; 512]);
+    }
+
+    //disks go through the shared cache
+    let mut disk = Disk::new(bus, drive);
+    assert_eq!(disk.write_blocks(8, &[9; 512]), Ok(()));
+    assert_eq!(disk.read_bytes(8 * 512, 512), Ok([9; 512].to_vec()));
+    assert_eq!(read(bus, drive, 8, buf_read), Ok(()));
+    assert_eq!(buf_read, &[0; 512]);
+    assert_eq!(disk.flush(), Ok(()));
+    assert_eq!(read(bus, drive, 8, buf_read), Ok(()));
+    assert_eq!(buf_read, &[9; 512]);
+
+    //leave no trace of the test
+    for block in 0..10 {
+        assert_eq!(write(bus, drive, block, &[0; 512]), Ok(()));
+    }
+    cache::invalidate(bus, drive).unwrap();
+}
+
 //benckmarking the ata
 //each test writes/reads 512 bytes 50 times which is 0.0256 MB
 fn bench_ata(){
@@ -87,6 +274,8 @@ fn bench_ata(){
     let drive = 1;
     let block = 0;
     Drive::open(0, 1);
+    //measures the drive itself, not the block cache
+    cache::invalidate(bus, drive).unwrap();
     let buf_512_data: &[u8] = &[0xFF; 512];
     let buf_read: &mut [u8] = &mut [0; 512];
     let mut start = timing::get_ticks();
@@ -251,88 +440,542 @@ fn log_to_disk() {
     assert_eq!(write_result, Ok(()));
 
 }
-use crate::disk::persistentmap::{PersistentMap, PersistentHashMap};
+use crate::disk::persistentmap::{CompactionPolicy, PersistentMap, PersistentHashMap, ToBeBytes, ValueMode};
+use crate::disk::log::{Corruption, Log, LogEnd};
+use crate::disk::block_device::{BlockDevice, RamDisk};
+//test the ram disk blocks and a log on top of it
+fn test_ram_disk() {
+    let ram = RamDisk::new(64);
+    assert_eq!(ram.block_count(), 64);
+    assert_eq!(ram.block_size(), 512);
+    assert_eq!(ram.write_blocks(3, &[9; 2 * 512]), Ok(()));
+    let mut buf = [0; 2 * 512];
+    assert_eq!(ram.read_blocks(3, &mut buf), Ok(()));
+    assert_eq!(buf, [9; 2 * 512]);
+    //not whole blocks or past the end
+    assert_eq!(ram.write_blocks(3, &[9; 100]), Err(()));
+    assert_eq!(ram.read_blocks(63, &mut buf), Err(()));
+    //clones share the blocks
+    let mut disk = Disk::with_device(ram.clone());
+    assert_eq!(disk.size(), 64 * 512);
+    assert_eq!(disk.read_bytes(3 * 512 + 10, 20), Ok([9; 20].to_vec()));
+    let mut log = Log::create(disk, 1).unwrap();
+    log.append(vec![b"on a ram disk".to_vec()]).unwrap();
+    let (_, payloads, report) = Log::open(Disk::with_device(ram)).unwrap();
+    assert_eq!(payloads, vec![b"on a ram disk".to_vec()]);
+    assert_eq!(report.end, LogEnd::Clean);
+}
+
+//test a record bigger than one checked chunk and a damaged length that still fits the region
+fn test_log_damaged_length() {
+    let ram = RamDisk::new(128);
+    let big: Vec<u8> = (0..20 << 10).map(|i| (i % 251) as u8).collect();
+    let mut log = Log::create(Disk::with_device(ram.clone()), 1).unwrap();
+    log.append(vec![big.clone()]).unwrap();
+    let (_, payloads, report) = Log::open(Disk::with_device(ram.clone())).unwrap();
+    assert_eq!(payloads, vec![big]);
+    assert_eq!(report.end, LogEnd::Clean);
+    //the length of the record right after the superblock now runs over garbage
+    let mut disk = Disk::with_device(ram.clone());
+    let mut header = disk.read_bytes(512, 512).unwrap();
+    header[16..24].copy_from_slice(&(30u64 << 10).to_be_bytes());
+    disk.write_block(1, &header).unwrap();
+    let (_, payloads, report) = Log::open(Disk::with_device(ram)).unwrap();
+    assert!(payloads.is_empty());
+    assert_eq!(report.end, LogEnd::Corrupt(Corruption::BadChecksum));
+}
+
+//test a persistent map on a ram disk is rebuilt from it and leaves the kv disk alone
+fn test_persistent_map_ram_disk() {
+    let ram = RamDisk::new(128);
+    let map: PersistentHashMap<String, String, RamDisk> = PersistentHashMap::new_on(Disk::with_device(ram.clone())).unwrap();
+    assert!(map.insert(&"key1".to_string(), &"value1".to_string()).unwrap());
+    assert!(map.insert(&"key2".to_string(), &"value2".to_string()).unwrap());
+    assert!(map.remove(&"key1".to_string()).unwrap());
+    let new_map: PersistentHashMap<String, String, RamDisk> = PersistentHashMap::build_from(Disk::with_device(ram.clone())).unwrap();
+    assert_eq!(new_map.get(&"key1".to_string()), Ok(None));
+    assert_eq!(new_map.get(&"key2".to_string()), Ok(Some("value2".to_string())));
+    //keydir reads go to the ram disk too
+    let keydir: PersistentHashMap<String, String, RamDisk> = PersistentHashMap::build_from_with(Disk::with_device(ram), 16, ValueMode::KeyDir).unwrap();
+    assert_eq!(keydir.get(&"key2".to_string()), Ok(Some("value2".to_string())));
+    //a fresh ram disk holds an empty map
+    let empty: PersistentHashMap<String, String, RamDisk> = PersistentHashMap::build_from_disk().unwrap();
+    assert_eq!(empty.get(&"key2".to_string()), Ok(None));
+}
+
+//test a damaged superblock is reported and nothing on the disk is formatted over
+fn test_persistent_map_bad_superblock() {
+    let ram = RamDisk::new(64);
+    let map: PersistentHashMap<String, String, RamDisk> = PersistentHashMap::new_on(Disk::with_device(ram.clone())).unwrap();
+    assert!(map.insert(&"key1".to_string(), &"value1".to_string()).unwrap());
+    let mut disk = Disk::with_device(ram.clone());
+    let superblock = disk.read_bytes(0, 512).unwrap();
+    let mut damaged = superblock.clone();
+    damaged[20] ^= 0xFF;
+    disk.write_block(0, &damaged).unwrap();
+    let records = disk.read_bytes(512, 512).unwrap();
+    assert!(PersistentHashMap::<String, String, RamDisk>::build_from(Disk::with_device(ram.clone())).is_err());
+    assert_eq!(disk.read_bytes(0, 512).unwrap(), damaged);
+    assert_eq!(disk.read_bytes(512, 512).unwrap(), records);
+    //once it is repaired the map is all there
+    disk.write_block(0, &superblock).unwrap();
+    let new_map: PersistentHashMap<String, String, RamDisk> = PersistentHashMap::build_from(Disk::with_device(ram)).unwrap();
+    assert_eq!(new_map.get(&"key1".to_string()), Ok(Some("value1".to_string())));
+}
+
+//test a log written by a kernel with version 1 of the log is refused and left as it is
+fn test_persistent_map_old_version() {
+    let ram = RamDisk::new(64);
+    let mut disk = Disk::with_device(ram.clone());
+    let mut superblock = [0u8; 512];
+    superblock[0..8].copy_from_slice(b"KVOSLOG\0");
+    superblock[8..16].copy_from_slice(&1u64.to_be_bytes());
+    superblock[16..24].copy_from_slice(&1u64.to_be_bytes());
+    disk.write_block(0, &superblock).unwrap();
+    assert!(PersistentHashMap::<String, String, RamDisk>::build_from(Disk::with_device(ram)).is_err());
+    assert_eq!(disk.read_bytes(0, 512).unwrap(), superblock.to_vec());
+}
+
+//test the byte types round trip and bad bytes fail to decode
+fn test_to_be_bytes() {
+    assert_eq!(u32::from_vec(0xdeadbeefu32.to_be_bytes().to_vec()), Ok(0xdeadbeef));
+    assert_eq!(i64::from_vec((-5i64).to_be_bytes().to_vec()), Ok(-5));
+    assert_eq!(u128::from_vec(u128::MAX.to_be_bytes().to_vec()), Ok(u128::MAX));
+    assert_eq!(u16::from_vec(vec![1, 2, 3]), Err(()));
+    assert_eq!(<[u8; 4]>::from_vec(vec![0, 255, 0, 7]), Ok([0, 255, 0, 7]));
+    assert_eq!(<[u8; 4]>::from_vec(vec![0; 5]), Err(()));
+    let blob = vec![0u8, 0xff, 0xfe, 0, 10];
+    assert_eq!(Vec::<u8>::from_vec(blob.to_be_bytes()), Ok(blob.clone()));
+    assert_eq!(String::from_vec(blob), Err(()));
+    assert_eq!(String::from_vec("kvos".to_string().to_be_bytes()), Ok("kvos".to_string()));
+}
+
+//test binary keys and values survive a rebuild, zero bytes included
+fn test_persistent_map_binary() {
+    let ram = RamDisk::new(128);
+    let map: PersistentHashMap<Vec<u8>, Vec<u8>, RamDisk> = PersistentHashMap::new_on(Disk::with_device(ram.clone())).unwrap();
+    let key = vec![0u8, 1, 0, 0xff];
+    let value = vec![0u8; 40].into_iter().chain(0..=255u8).collect::<Vec<u8>>();
+    assert!(map.insert(&key, &value).unwrap());
+    assert!(map.insert(&vec![0xc3, 0x28], &vec![]).unwrap());
+    let new_map: PersistentHashMap<Vec<u8>, Vec<u8>, RamDisk> = PersistentHashMap::build_from(Disk::with_device(ram.clone())).unwrap();
+    assert_eq!(new_map.get(&key), Ok(Some(value)));
+    assert_eq!(new_map.get(&vec![0xc3, 0x28]), Ok(Some(vec![])));
+    assert_eq!(new_map.get(&vec![0u8, 1, 0]), Ok(None));
+    //fixed width values on the same kind of disk
+    let ints: PersistentHashMap<[u8; 2], u64, RamDisk> = PersistentHashMap::new_on(Disk::with_device(RamDisk::new(128))).unwrap();
+    assert!(ints.insert(&[0, 0], &u64::MAX).unwrap());
+    assert_eq!(ints.get(&[0, 0]), Ok(Some(u64::MAX)));
+}
+
 //test insert into persistent map
 fn test_persistent_map() {
 
-    let map: PersistentHashMap<String, String> = PersistentHashMap::new();
+    let map: PersistentHashMap<String, String> = PersistentHashMap::new().unwrap();
 
     assert!(map.insert(&"key1".to_string(), &"value1".to_string()).unwrap());
 
-    assert_eq!(map.get(&"key1".to_string()), Some("value1".to_string()));
+    assert_eq!(map.get(&"key1".to_string()), Ok(Some("value1".to_string())));
 
     assert!(map.insert(&"key2".to_string(), &"valueTWO".to_string()).unwrap());
-    assert_eq!(map.get(&"key2".to_string()), Some("valueTWO".to_string()));
+    assert_eq!(map.get(&"key2".to_string()), Ok(Some("valueTWO".to_string())));
     //make new map from disk, make sure it gets the values from logs correctly
-    let new_map: PersistentHashMap<String, String> = PersistentHashMap::build_from_disk();
-    assert_eq!(new_map.get(&"key1".to_string()), Some("value1".to_string()));
+    let new_map: PersistentHashMap<String, String> = PersistentHashMap::build_from_disk().unwrap();
+    assert_eq!(new_map.get(&"key1".to_string()), Ok(Some("value1".to_string())));
 
-    assert_eq!(new_map.get(&"key2".to_string()), Some("valueTWO".to_string()));
+    assert_eq!(new_map.get(&"key2".to_string()), Ok(Some("valueTWO".to_string())));
     assert!(new_map.remove(&"key1".to_string()).unwrap());
-    assert_eq!(new_map.get(&"key1".to_string()), None);
+    assert_eq!(new_map.get(&"key1".to_string()), Ok(None));
 }
 //tests really long entires into the map
 fn test_persistent_map_long_entries() {
 
-    let map: PersistentHashMap<String, String> = PersistentHashMap::new();
+    let map: PersistentHashMap<String, String> = PersistentHashMap::new().unwrap();
 
     assert!(map.insert(&"keykeykeykeykeykeyONE".to_string(), &"value1value1value1value1value1value1value1value1".to_string()).unwrap());
 
-    assert_eq!(map.get(&"keykeykeykeykeykeyONE".to_string()), Some("value1value1value1value1value1value1value1value1".to_string()));
+    assert_eq!(map.get(&"keykeykeykeykeykeyONE".to_string()), Ok(Some("value1value1value1value1value1value1value1value1".to_string())));
 
     assert!(map.insert(&"key2".to_string(), &"valueTWO".to_string()).unwrap());
-    assert_eq!(map.get(&"key2".to_string()), Some("valueTWO".to_string()));
+    assert_eq!(map.get(&"key2".to_string()), Ok(Some("valueTWO".to_string())));
 
     assert!(map.insert(&"keykeykeykeykeykeyThree".to_string(), &"value3value3".to_string()).unwrap());
 
-    assert_eq!(map.get(&"keykeykeykeykeykeyThree".to_string()), Some("value3value3".to_string()));
+    assert_eq!(map.get(&"keykeykeykeykeykeyThree".to_string()), Ok(Some("value3value3".to_string())));
 
-    let new_map: PersistentHashMap<String, String> = PersistentHashMap::build_from_disk();
-    assert_eq!(new_map.get(&"keykeykeykeykeykeyThree".to_string()), Some("value3value3".to_string()));
-    assert_eq!(new_map.get(&"keykeykeykeykeykeyONE".to_string()), Some("value1value1value1value1value1value1value1value1".to_string()));
+    let new_map: PersistentHashMap<String, String> = PersistentHashMap::build_from_disk().unwrap();
+    assert_eq!(new_map.get(&"keykeykeykeykeykeyThree".to_string()), Ok(Some("value3value3".to_string())));
+    assert_eq!(new_map.get(&"keykeykeykeykeykeyONE".to_string()), Ok(Some("value1value1value1value1value1value1value1value1".to_string())));
 
-    assert_eq!(new_map.get(&"key2".to_string()), Some("valueTWO".to_string()));
+    assert_eq!(new_map.get(&"key2".to_string()), Ok(Some("valueTWO".to_string())));
 }
 //test remove log
 fn test_persistent_map_remove() {
 
-    let map: PersistentHashMap<String, String> = PersistentHashMap::new();
+    let map: PersistentHashMap<String, String> = PersistentHashMap::new().unwrap();
 
     assert!(map.insert(&"key1".to_string(), &"value1".to_string()).unwrap());
 
-    assert_eq!(map.get(&"key1".to_string()), Some("value1".to_string()));
+    assert_eq!(map.get(&"key1".to_string()), Ok(Some("value1".to_string())));
 
     assert!(map.insert(&"key2".to_string(), &"valueTWO".to_string()).unwrap());
-    assert_eq!(map.get(&"key2".to_string()), Some("valueTWO".to_string()));
+    assert_eq!(map.get(&"key2".to_string()), Ok(Some("valueTWO".to_string())));
     assert!(map.remove(&"key1".to_string()).unwrap());
 
 
-    let new_map: PersistentHashMap<String, String> = PersistentHashMap::build_from_disk();
-    assert_eq!(new_map.get(&"key1".to_string()), None);
+    let new_map: PersistentHashMap<String, String> = PersistentHashMap::build_from_disk().unwrap();
+    assert_eq!(new_map.get(&"key1".to_string()), Ok(None));
 
-    assert_eq!(new_map.get(&"key2".to_string()), Some("valueTWO".to_string()));
+    assert_eq!(new_map.get(&"key2".to_string()), Ok(Some("valueTWO".to_string())));
 }
 //test map compaction
 fn test_persistent_map_compaction() {
 
-    let mut map: PersistentHashMap<String, String> = PersistentHashMap::new();
+    let map: PersistentHashMap<String, String> = PersistentHashMap::new().unwrap();
 
     assert!(map.insert(&"key2".to_string(), &"valueTWO".to_string()).unwrap());
-    assert_eq!(map.get(&"key2".to_string()), Some("valueTWO".to_string()));
+    assert_eq!(map.get(&"key2".to_string()), Ok(Some("valueTWO".to_string())));
 
     for _i in 0..100{
         assert!(map.insert(&"key1".to_string(), &"value1".to_string()).unwrap());
         assert!(map.remove(&"key1".to_string()).unwrap());
 
     }
-    let bus = 0;
-    let drive = 1;
-    let mut disk = Disk::new(bus,drive);
-    assert_eq!(disk.read_whole_disk().unwrap().len(),1020);
+    let log_size = map.log_size();
+    assert_eq!(map.compact_logs(), Ok(()));
+    assert!(map.log_size() < log_size / 10);
+
+    let new_map: PersistentHashMap<String, String> = PersistentHashMap::build_from_disk().unwrap();
+    assert_eq!(new_map.get(&"key1".to_string()), Ok(None));
+    assert_eq!(new_map.get(&"key2".to_string()), Ok(Some("valueTWO".to_string())));
+    assert_eq!(new_map.recovery_report().unwrap().end, LogEnd::Clean);
+}
+
+//test that a group is applied as a whole
+fn test_persistent_map_group_commit() {
+
+    let map: PersistentHashMap<String, String> = PersistentHashMap::new().unwrap();
+    assert!(map.insert(&"key1".to_string(), &"value1".to_string()).unwrap());
+    let (key2, value2) = ("key2".to_string(), "value2".to_string());
+    let (key3, value3) = ("key3".to_string(), "value3value3".to_string());
+    assert_eq!(map.commit_group(&[(&key2, &value2), (&key3, &value3)], &[&"key1".to_string()]), Ok(()));
+    assert_eq!(map.get(&"key1".to_string()), Ok(None));
+    assert_eq!(map.get(&key3), Ok(Some(value3.clone())));
+
+    let new_map: PersistentHashMap<String, String> = PersistentHashMap::build_from_disk().unwrap();
+    assert_eq!(new_map.get(&"key1".to_string()), Ok(None));
+    assert_eq!(new_map.get(&key2), Ok(Some(value2)));
+    assert_eq!(new_map.get(&key3), Ok(Some(value3)));
+}
+
+//appends a KVKVKVKV log entry the way the map writes it
+fn push_insert_log(log: &mut Vec<u8>, key: &str, value: &str) {
+    log.extend_from_slice("KVKVKVKV".as_bytes());
+    for item in [key, value] {
+        log.extend_from_slice(&(item.len() as u64).to_be_bytes());
+        log.extend_from_slice(item.as_bytes());
+        while log.len()%8 != 0 {
+            log.push(0);
+        }
+    }
+}
+
+//test that a group without its commit marker is not recovered
+fn test_persistent_map_torn_group() {
+
+    let mut single: Vec<u8> = Vec::new();
+    push_insert_log(&mut single, "key1", "value1");
+    let mut committed: Vec<u8> = "KVBEGINT".as_bytes().to_vec();
+    push_insert_log(&mut committed, "key2", "value2");
+    committed.extend_from_slice("KVCOMMIT".as_bytes());
+    let mut torn: Vec<u8> = "KVBEGINT".as_bytes().to_vec();
+    push_insert_log(&mut torn, "key3", "value3");
+    push_insert_log(&mut torn, "key1", "torn");
+    let mut log = Log::create(Disk::new(0,1), 1).unwrap();
+    assert_eq!(log.append([single, committed, torn].to_vec()), Ok(()));
+
+    let map: PersistentHashMap<String, String> = PersistentHashMap::build_from_disk().unwrap();
+    assert_eq!(map.get(&"key1".to_string()), Ok(Some("value1".to_string())));
+    assert_eq!(map.get(&"key2".to_string()), Ok(Some("value2".to_string())));
+    assert_eq!(map.get(&"key3".to_string()), Ok(None));
+    let report = map.recovery_report().unwrap();
+    assert_eq!(report.end, LogEnd::Clean);
+    assert_eq!(report.records, 3);
+    assert_eq!(report.discarded_groups, 1);
+}
+
+//test that recovery stops at a damaged record
+fn test_persistent_map_bad_checksum() {
+
+    let map: PersistentHashMap<String, String> = PersistentHashMap::new().unwrap();
+    assert!(map.insert(&"\0key1".to_string(), &"value1".to_string()).unwrap());
+    let end = map.log_size();
+    assert!(map.insert(&"key2".to_string(), &"value2".to_string()).unwrap());
+    assert!(map.insert(&"key3".to_string(), &"value3".to_string()).unwrap());
+
+    //flip a byte inside the header of the key2 record, the log region starts after the superblock
+    let mut buf = [0u8; 512];
+    let offset = 512 + end - 32 + 8;
+    assert_eq!(read(0, 1, (offset / 512) as u64, &mut buf), Ok(()));
+    buf[offset % 512] ^= 0xFF;
+    assert_eq!(write(0, 1, (offset / 512) as u64, &buf), Ok(()));
+    //the damage was done around the cache, a reboot starts without it
+    cache::invalidate(0, 1).unwrap();
+
+    let new_map: PersistentHashMap<String, String> = PersistentHashMap::build_from_disk().unwrap();
+    assert_eq!(new_map.get(&"\0key1".to_string()), Ok(Some("value1".to_string())));
+    assert_eq!(new_map.get(&"key2".to_string()), Ok(None));
+    assert_eq!(new_map.get(&"key3".to_string()), Ok(None));
+    let report = new_map.recovery_report().unwrap();
+    assert_eq!(report.end, LogEnd::Corrupt(Corruption::BadChecksum));
+    assert_eq!(report.records, 1);
+
+    //the log was cut at the damaged record and keeps working
+    assert!(new_map.insert(&"key4".to_string(), &"value4".to_string()).unwrap());
+    let newer_map: PersistentHashMap<String, String> = PersistentHashMap::build_from_disk().unwrap();
+    assert_eq!(newer_map.get(&"key4".to_string()), Ok(Some("value4".to_string())));
+    assert_eq!(newer_map.recovery_report().unwrap().end, LogEnd::Clean);
+}
+//test that writes compact the log once it is mostly garbage
+fn test_persistent_map_auto_compaction() {
+
+    let map: PersistentHashMap<String, String> = PersistentHashMap::new().unwrap();
+    map.set_compaction_policy(CompactionPolicy::new(4096, 50, 75));
+    assert!(map.insert(&"stable".to_string(), &"value".to_string()).unwrap());
+    for i in 0..500 {
+        assert!(map.insert(&"counter".to_string(), &i.to_string()).unwrap());
+        //the write only asks for the compaction, it runs once the caller is done
+        map.maintain();
+        assert!(map.log_size() < 8192);
+    }
+    //without maintain the log keeps growing
+    let size = map.log_size();
+    for i in 0..100 {
+        assert!(map.insert(&"counter".to_string(), &i.to_string()).unwrap());
+    }
+    assert!(map.log_size() > size);
+    map.maintain();
+    assert!(map.log_size() < size);
+
+    let new_map: PersistentHashMap<String, String> = PersistentHashMap::build_from_disk().unwrap();
+    assert_eq!(new_map.get(&"stable".to_string()), Ok(Some("value".to_string())));
+    assert_eq!(new_map.get(&"counter".to_string()), Ok(Some("99".to_string())));
+    assert_eq!(new_map.recovery_report().unwrap().end, LogEnd::Clean);
+}
+
+//test that the old log survives a compaction that never switched over
+fn test_log_compaction_switch() {
+
+    let mut log = Log::create(Disk::new(0,1), 1).unwrap();
+    assert_eq!(log.append(["old".as_bytes().to_vec()].to_vec()), Ok(()));
+    let mut target = log.begin_compaction().unwrap();
+    assert_eq!(target.append(["new".as_bytes().to_vec()].to_vec()), Ok(()));
+    //appends during the compaction are carried over
+    assert_eq!(log.append(["during".as_bytes().to_vec()].to_vec()), Ok(()));
+
+    //crash before the switch
+    let (_, payloads, _) = Log::open(Disk::new(0,1)).unwrap();
+    assert_eq!(payloads, ["old".as_bytes().to_vec(), "during".as_bytes().to_vec()].to_vec());
+
+    assert_eq!(log.finish_compaction(target), Ok(()));
+    let (_, payloads, report) = Log::open(Disk::new(0,1)).unwrap();
+    assert_eq!(payloads, ["new".as_bytes().to_vec(), "during".as_bytes().to_vec()].to_vec());
+    assert_eq!(report.end, LogEnd::Clean);
+}
+
+//test a keydir map, values are read back from the log
+fn test_persistent_map_keydir() {
+
+    let map: PersistentHashMap<String, String> = PersistentHashMap::with_mode(16, ValueMode::KeyDir).unwrap();
+    assert!(map.insert(&"key1".to_string(), &"value1".to_string()).unwrap());
+    assert!(map.insert(&"key2".to_string(), &"x".repeat(1500)).unwrap());
+    assert!(map.insert(&"key1".to_string(), &"valueONE".to_string()).unwrap());
+    let writes = [(&"key3".to_string(), &"value3".to_string()), (&"key4".to_string(), &"value4".to_string())];
+    assert_eq!(map.commit_group(&writes, &[&"key2".to_string()]), Ok(()));
+    assert_eq!(map.get(&"key1".to_string()), Ok(Some("valueONE".to_string())));
+    assert_eq!(map.get(&"key2".to_string()), Ok(None));
+    assert_eq!(map.get(&"key3".to_string()), Ok(Some("value3".to_string())));
+
+    let new_map: PersistentHashMap<String, String> = PersistentHashMap::build_from_disk_with(ValueMode::KeyDir).unwrap();
+    assert_eq!(new_map.value_mode(), ValueMode::KeyDir);
+    assert_eq!(new_map.scan(&"key".to_string(), 10), [
+        ("key1".to_string(), "valueONE".to_string()),
+        ("key3".to_string(), "value3".to_string()),
+        ("key4".to_string(), "value4".to_string()),
+    ].to_vec());
+}
+
+//test that compaction moves the values and leaves a hint for the next boot
+fn test_persistent_map_keydir_hint() {
+
+    let map: PersistentHashMap<String, String> = PersistentHashMap::with_mode(16, ValueMode::KeyDir).unwrap();
+    for i in 0..50 {
+        assert!(map.insert(&format!("key{}", i), &format!("value{}", i)).unwrap());
+    }
+    for i in 0..50 {
+        assert!(map.insert(&format!("key{}", i), &format!("new{}", i)).unwrap());
+    }
     assert_eq!(map.compact_logs(), Ok(()));
-    assert_eq!(disk.read_whole_disk().unwrap().len(),5*8);
+    assert!(map.insert(&"key0".to_string(), &"newest".to_string()).unwrap());
+    assert_eq!(map.get(&"key0".to_string()), Ok(Some("newest".to_string())));
+    assert_eq!(map.get(&"key49".to_string()), Ok(Some("new49".to_string())));
+
+    //boot reads the hint and the one record after it
+    let new_map: PersistentHashMap<String, String> = PersistentHashMap::build_from_disk_with(ValueMode::KeyDir).unwrap();
+    assert_eq!(new_map.recovery_report().unwrap().records, 2);
+    assert_eq!(new_map.get(&"key0".to_string()), Ok(Some("newest".to_string())));
+    for i in 1..50 {
+        assert_eq!(new_map.get(&format!("key{}", i)), Ok(Some(format!("new{}", i))));
+    }
+    //the compacted log is still a plain log for a map that caches its values
+    let cached: PersistentHashMap<String, String> = PersistentHashMap::build_from_disk().unwrap();
+    assert_eq!(cached.get(&"key0".to_string()), Ok(Some("newest".to_string())));
+    assert_eq!(cached.get(&"key7".to_string()), Ok(Some("new7".to_string())));
+}
+
+use crate::disk::lsm::{LsmMap, LsmPolicy};
+//test the lsm map with everything still in the memtable and the wal
+fn test_lsm_map() {
+
+    let map: LsmMap<String, String> = LsmMap::new().unwrap();
+    assert!(map.insert(&"key1".to_string(), &"value1".to_string()).unwrap());
+    assert!(map.insert(&"key2".to_string(), &"valueTWO".to_string()).unwrap());
+    assert!(map.insert(&"key3".to_string(), &"value3".to_string()).unwrap());
+    assert!(map.remove(&"key2".to_string()).unwrap());
+    assert!(!map.remove(&"key2".to_string()).unwrap());
+    assert_eq!(map.get(&"key1".to_string()), Ok(Some("value1".to_string())));
+    assert_eq!(map.get(&"key2".to_string()), Ok(None));
+    assert_eq!(map.scan(&"key".to_string(), 10), [
+        ("key1".to_string(), "value1".to_string()),
+        ("key3".to_string(), "value3".to_string()),
+    ].to_vec());
+
+    let new_map: LsmMap<String, String> = LsmMap::build_from_disk().unwrap();
+    assert_eq!(new_map.get(&"key1".to_string()), Ok(Some("value1".to_string())));
+    assert_eq!(new_map.get(&"key2".to_string()), Ok(None));
+    assert_eq!(new_map.get(&"key3".to_string()), Ok(Some("value3".to_string())));
+    assert_eq!(new_map.recovery_report().unwrap().end, LogEnd::Clean);
+}
+
+//test that flushed and compacted tables keep the newest value of every key
+fn test_lsm_map_levels() {
+
+    let map: LsmMap<String, String> = LsmMap::new().unwrap();
+    map.set_policy(LsmPolicy::new(1024, 2048, 2, 4096, 4));
+    for i in 0..300 {
+        assert!(map.insert(&format!("key{:03}", i), &format!("value{}", i)).unwrap());
+    }
+    for i in (0..300).step_by(3) {
+        assert!(map.insert(&format!("key{:03}", i), &"new".to_string()).unwrap());
+    }
+    for i in (1..300).step_by(3) {
+        assert!(map.remove(&format!("key{:03}", i)).unwrap());
+    }
+    let levels = map.level_tables();
+    assert!(levels[1..].iter().sum::<usize>() > 0);
+
+    let check = |map: &LsmMap<String, String>| {
+        for i in 0..300 {
+            let expected = match i % 3 {
+                0 => Some("new".to_string()),
+                1 => None,
+                _ => Some(format!("value{}", i)),
+            };
+            assert_eq!(map.get(&format!("key{:03}", i)), Ok(expected));
+        }
+        let scanned = map.scan(&"key100".to_string(), 1000);
+        assert_eq!(scanned.len(), (100..300).filter(|i| i % 3 != 1).count());
+        assert!(scanned.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        assert_eq!(scanned[0], ("key101".to_string(), "value101".to_string()));
+    };
+    check(&map);
+
+    //boot only replays what was not flushed yet
+    let new_map: LsmMap<String, String> = LsmMap::build_from_disk().unwrap();
+    assert_eq!(new_map.level_tables(), levels);
+    assert!(new_map.recovery_report().unwrap().records < 100);
+    check(&new_map);
+
+    assert_eq!(new_map.compact_logs(), Ok(()));
+    check(&new_map);
 }
 
+//test a table the manifest names but boot cannot read keeps the map from coming up
+fn test_lsm_map_lost_table() {
+
+    let map: LsmMap<String, String> = LsmMap::new().unwrap();
+    assert!(map.insert(&"key1".to_string(), &"value1".to_string()).unwrap());
+    assert_eq!(map.flush(), Ok(()));
+    assert_eq!(map.level_tables()[0], 1);
+    //the first table goes right after the 4 MiB wal and the two 64 KiB manifest slots, it
+    //is small enough for its footer to be in the first block
+    let mut disk = Disk::kv();
+    let block = ((4 << 20) + 2 * (64 << 10)) / 512;
+    let table = disk.read_bytes(block * 512, 512).unwrap();
+    disk.write_block(block, &[0; 512]).unwrap();
+    assert!(LsmMap::<String, String>::build_from_disk().is_err());
+    //once it is repaired the map is all there
+    disk.write_block(block, &table).unwrap();
+    let new_map: LsmMap<String, String> = LsmMap::build_from_disk().unwrap();
+    assert_eq!(new_map.get(&"key1".to_string()), Ok(Some("value1".to_string())));
+}
+
+use crate::disk::btree::{PersistentBTree, MAX_ENTRY_BYTES};
+//test the b+tree with everything in the root leaf
+fn test_btree_map() {
+
+    let map: PersistentBTree<String, String> = PersistentBTree::new().unwrap();
+    assert!(map.insert(&"key2".to_string(), &"valueTWO".to_string()).unwrap());
+    assert!(map.insert(&"key1".to_string(), &"value1".to_string()).unwrap());
+    assert!(map.insert(&"key3".to_string(), &"value3".to_string()).unwrap());
+    assert!(map.remove(&"key2".to_string()).unwrap());
+    assert!(!map.remove(&"key2".to_string()).unwrap());
+    assert_eq!(map.get(&"key1".to_string()), Ok(Some("value1".to_string())));
+    assert_eq!(map.get(&"key2".to_string()), Ok(None));
+    //entries have to leave room for a few of them in a page
+    assert!(map.insert(&"big".to_string(), &"x".repeat(MAX_ENTRY_BYTES)).is_err());
+
+    let new_map: PersistentBTree<String, String> = PersistentBTree::build_from_disk().unwrap();
+    assert_eq!(new_map.scan(&"key".to_string(), 10), [
+        ("key1".to_string(), "value1".to_string()),
+        ("key3".to_string(), "value3".to_string()),
+    ].to_vec());
+    assert_eq!(new_map.height(), 1);
+}
+
+//test that splits and merges keep every key reachable
+fn test_btree_map_splits() {
+
+    let map: PersistentBTree<String, String> = PersistentBTree::new().unwrap();
+    for i in 0..600 {
+        assert!(map.insert(&format!("key{:04}", i), &format!("value{}", i)).unwrap());
+    }
+    assert!(map.height() > 1);
+    let writes = [(&"key0000".to_string(), &"new".to_string())];
+    let removes: Vec<String> = (1..600).step_by(2).map(|i| format!("key{:04}", i)).collect();
+    let removes: Vec<&String> = removes.iter().collect();
+    assert_eq!(map.commit_group(&writes, &removes), Ok(()));
+
+    let new_map: PersistentBTree<String, String> = PersistentBTree::build_from_disk().unwrap();
+    assert_eq!(new_map.get(&"key0000".to_string()), Ok(Some("new".to_string())));
+    for i in 1..600 {
+        let expected = if i % 2 == 0 { Some(format!("value{}", i)) } else { None };
+        assert_eq!(new_map.get(&format!("key{:04}", i)), Ok(expected));
+    }
+    let scanned = new_map.scan(&"key0100".to_string(), 1000);
+    assert_eq!(scanned.len(), 250);
+    assert!(scanned.windows(2).all(|pair| pair[0].0 < pair[1].0));
+
+    //emptied pages are merged away and reused
+    let pages = new_map.pages_in_use();
+    for i in (0..600).step_by(2) {
+        assert!(new_map.remove(&format!("key{:04}", i)).unwrap());
+    }
+    assert_eq!(new_map.height(), 1);
+    assert_eq!(new_map.pages_in_use(), 1);
+    assert!(new_map.insert(&"key".to_string(), &"value".to_string()).unwrap());
+    assert!(PersistentBTree::<String, String>::build_from_disk().unwrap().pages_in_use() < pages);
+}
 
 pub fn run_tests() {
     let tests = [
@@ -344,6 +987,54 @@ pub fn run_tests() {
             name : "test_disk_multiple_blocks",
             test_fn : test_disk_multiple_blocks,
         },
+        KernelTest {
+            name : "test_disk_block_ranges",
+            test_fn : test_disk_block_ranges,
+        },
+        KernelTest {
+            name : "test_ata_request_queue",
+            test_fn : test_ata_request_queue,
+        },
+        KernelTest {
+            name : "test_ahci_commands",
+            test_fn : test_ahci_commands,
+        },
+        KernelTest {
+            name : "test_ram_disk",
+            test_fn : test_ram_disk,
+        },
+        KernelTest {
+            name : "test_log_damaged_length",
+            test_fn : test_log_damaged_length,
+        },
+        KernelTest {
+            name : "test_persistent_map_ram_disk",
+            test_fn : test_persistent_map_ram_disk,
+        },
+        KernelTest {
+            name : "test_persistent_map_bad_superblock",
+            test_fn : test_persistent_map_bad_superblock,
+        },
+        KernelTest {
+            name : "test_persistent_map_old_version",
+            test_fn : test_persistent_map_old_version,
+        },
+        KernelTest {
+            name : "test_to_be_bytes",
+            test_fn : test_to_be_bytes,
+        },
+        KernelTest {
+            name : "test_persistent_map_binary",
+            test_fn : test_persistent_map_binary,
+        },
+        KernelTest {
+            name : "test_virtio_commands",
+            test_fn : test_virtio_commands,
+        },
+        KernelTest {
+            name : "test_block_cache",
+            test_fn : test_block_cache,
+        },
         KernelTest {
             name : "test_bench_ata",
             test_fn : bench_ata,
@@ -372,6 +1063,54 @@ pub fn run_tests() {
             name : "test_persistent_map_compaction",
             test_fn : test_persistent_map_compaction,
         },
+        KernelTest {
+            name : "test_persistent_map_group_commit",
+            test_fn : test_persistent_map_group_commit,
+        },
+        KernelTest {
+            name : "test_persistent_map_torn_group",
+            test_fn : test_persistent_map_torn_group,
+        },
+        KernelTest {
+            name : "test_persistent_map_bad_checksum",
+            test_fn : test_persistent_map_bad_checksum,
+        },
+        KernelTest {
+            name : "test_persistent_map_auto_compaction",
+            test_fn : test_persistent_map_auto_compaction,
+        },
+        KernelTest {
+            name : "test_log_compaction_switch",
+            test_fn : test_log_compaction_switch,
+        },
+        KernelTest {
+            name : "test_persistent_map_keydir",
+            test_fn : test_persistent_map_keydir,
+        },
+        KernelTest {
+            name : "test_persistent_map_keydir_hint",
+            test_fn : test_persistent_map_keydir_hint,
+        },
+        KernelTest {
+            name : "test_lsm_map",
+            test_fn : test_lsm_map,
+        },
+        KernelTest {
+            name : "test_lsm_map_levels",
+            test_fn : test_lsm_map_levels,
+        },
+        KernelTest {
+            name : "test_lsm_map_lost_table",
+            test_fn : test_lsm_map_lost_table,
+        },
+        KernelTest {
+            name : "test_btree_map",
+            test_fn : test_btree_map,
+        },
+        KernelTest {
+            name : "test_btree_map_splits",
+            test_fn : test_btree_map_splits,
+        },
     ];
     for t in tests.iter() {
         serial_print!("{}...\t", t.name);
@@ -1,14 +1,23 @@
 use super::KernelTest;
 use crate::serial_print;
-use crate::cc::Transaction;
+use crate::cc::{AbortReason, CCMode, Transaction, TxError, WDTXPersist};
 use crate::common::hash::{Mix13Hash,mix13};
-use crate::common::locktable::{LockTable, TryLockResult};
+use crate::common::locktable::{LockMode, LockPolicy, LockResult, LockTable, TryLockResult};
 use crate::common::map::{Map, SimpleHashMap};
 use crate::common::set::SimpleSet;
 use crate::cc::redo::RedoLog;
-use crate::kvstore::{KVStore,TxKVStorePersist};
+use crate::kvstore::{KVStore,RetryPolicy,TxKVStore,TxKVStorePersist};
+use crate::disk::block_device::{BlockDevice, CachedDrive, RamDisk};
+use crate::disk::btree::PersistentBTree;
+use crate::disk::disk_api::Disk;
+use crate::disk::persistentmap::{PersistentHashMap, PersistentMap};
+use crate::disk::lsm::LsmMap;
 use crate::map::SkipMap;
 use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use alloc::vec;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 /////////////////////////////////////////////////////////////
 //// Tests
@@ -112,6 +121,21 @@ fn test_simple_map_with_capacity() {
     assert_eq!(map.get(&"key3"), Some(30));
 }
 
+fn test_simple_map_scan() {
+    let map: SimpleHashMap<&str, i32> = SimpleHashMap::new();
+    map.insert(&"b", &2);
+    map.insert(&"a", &1);
+    map.insert(&"c", &3);
+
+    // Entries come back in key order starting at the first key >= start
+    assert_eq!(map.scan(&"b", 10), vec![("b", 2), ("c", 3)]);
+    assert_eq!(map.scan(&"a", 1), vec![("a", 1)]);
+
+    // Removed keys leave the index
+    map.remove(&"b");
+    assert_eq!(map.scan(&"a", 10), vec![("a", 1), ("c", 3)]);
+}
+
 fn test_set_insert() {
     let mut set = SimpleSet::<u32>::new();
     assert!(set.insert(&5));
@@ -184,37 +208,249 @@ fn test_redo_size() {
 }
 
 fn test_tx() {
-    let kv = TxKVStorePersist::new(32, 32);
+    //the disk holds whatever the tests before left
+    PersistentHashMap::<String, String>::new().unwrap();
+    let kv = TxKVStorePersist::<String, String>::new(32, 32);
     // let tx = kv.begin();
     kv.transact(|tx| {
-        tx.write(&String::from("bootloader_name"), &String::from("crate_boot"));
-        let name = tx.read(&String::from("bootloader_name"));
+        tx.write(&String::from("bootloader_name"), &String::from("crate_boot"))?;
+        let name = tx.read(&String::from("bootloader_name"))?;
         assert_eq!(name, Some(String::from("crate_boot")));
-    }, true);
+        Ok(())
+    }, true).unwrap();
 
     kv.transact(|tx| {
-        let name = tx.read(&String::from("bootloader_name"));
+        let name = tx.read(&String::from("bootloader_name"))?;
         assert_eq!(name, Some(String::from("crate_boot")));
-    }, true);
+        Ok(())
+    }, true).unwrap();
+}
+
+//writes through a store on device and reads it back through a store built again from it
+fn persist_roundtrip<M>(device: M::Device)
+where
+    M: PersistentMap<Key = String, Value = String> + Send + Sync + 'static,
+    M::Device: Default,
+{
+    //the device holds whatever the tests before left
+    M::new_on(Disk::with_device(device.clone())).unwrap();
+    let kv = TxKVStorePersist::<String, String, M>::with_disk(Disk::with_device(device.clone()), 32, 32, CCMode::WaitDie).unwrap();
+    kv.transact(|tx| {
+        tx.write(&String::from("bootloader_name"), &String::from("crate_boot"))?;
+        tx.write(&String::from("kernel_name"), &String::from("kvos"))?;
+        Ok(())
+    }, true).unwrap();
+
+    let kv = TxKVStorePersist::<String, String, M>::with_disk(Disk::with_device(device), 32, 32, CCMode::WaitDie).unwrap();
+    kv.transact(|tx| {
+        assert_eq!(tx.read(&String::from("bootloader_name"))?, Some(String::from("crate_boot")));
+        assert_eq!(tx.read(&String::from("kernel_name"))?, Some(String::from("kvos")));
+        Ok(())
+    }, true).unwrap();
+}
+
+//a store on a ram disk, rebuilt from the same ram disk
+fn test_tx_ram_disk() {
+    persist_roundtrip::<PersistentHashMap<String, String, RamDisk>>(RamDisk::new(128));
+}
+
+//a snapshot store loads what the disk holds and persists its own commits to it
+fn test_tx_ram_disk_snapshot() {
+    type RamStore = TxKVStorePersist<String, String, PersistentHashMap<String, String, RamDisk>>;
+    let ram = RamDisk::new(128);
+    let kv = RamStore::with_disk(Disk::with_device(ram.clone()), 32, 32, CCMode::WaitDie).unwrap();
+    kv.transact(|tx| tx.write(&String::from("a"), &String::from("locking")), true).unwrap();
+
+    let kv = RamStore::with_disk(Disk::with_device(ram.clone()), 32, 32, CCMode::Snapshot).unwrap();
+    assert_eq!(kv.mode(), CCMode::Snapshot);
+    let mut old = kv.begin();
+    assert_eq!(old.read(&String::from("a")), Ok(Some(String::from("locking"))));
+    kv.transact(|tx| tx.write(&String::from("b"), &String::from("snapshot")), true).unwrap();
+    kv.transact(|tx| tx.write(&String::from("c"), &String::from("lost")), false).unwrap();
+    assert_eq!(old.read(&String::from("b")), Ok(None));
+    old.abort();
+
+    let kv = RamStore::with_disk(Disk::with_device(ram), 32, 32, CCMode::Snapshot).unwrap();
+    kv.transact(|tx| {
+        assert_eq!(tx.scan(&String::new(), None, 10)?, vec![
+            (String::from("a"), String::from("locking")),
+            (String::from("b"), String::from("snapshot")),
+        ]);
+        Ok(())
+    }, false).unwrap();
+}
+
+//a read only commit with persist does not write a log record
+fn test_tx_read_only_persist() {
+    let map = Arc::new(PersistentHashMap::<String, String, RamDisk>::new_on(Disk::with_device(RamDisk::new(64))).unwrap());
+    let lock_table = Arc::new(LockTable::new(32));
+    let mut tx = WDTXPersist::new(lock_table.clone(), map.clone());
+    tx.write(&String::from("a"), &String::from("1")).unwrap();
+    tx.try_commit_persist().unwrap();
+    let size = map.log_size();
+    let mut tx = WDTXPersist::new(lock_table, map.clone());
+    assert_eq!(tx.read(&String::from("a")), Ok(Some(String::from("1"))));
+    assert_eq!(tx.try_commit_persist(), Ok(()));
+    assert_eq!(map.log_size(), size);
+}
+
+fn test_tx_lsm() {
+    persist_roundtrip::<LsmMap<String, String>>(CachedDrive::default());
+}
+
+fn test_tx_btree() {
+    persist_roundtrip::<PersistentBTree<String, String>>(CachedDrive::default());
+}
+
+//a btree store on a ram disk, rebuilt from the same ram disk
+fn test_tx_btree_ram_disk() {
+    persist_roundtrip::<PersistentBTree<String, String, RamDisk>>(RamDisk::new(64));
+}
+
+//a ram disk whose reads fail once it is broken
+#[derive(Clone, Default)]
+struct BrokenDisk {
+    ram: RamDisk,
+    broken: Arc<AtomicBool>,
+}
+
+impl BlockDevice for BrokenDisk {
+    fn read_blocks(&self, block: u64, buf: &mut [u8]) -> Result<(), ()> {
+        if self.broken.load(Ordering::SeqCst) {
+            return Err(());
+        }
+        self.ram.read_blocks(block, buf)
+    }
+
+    fn write_blocks(&self, block: u64, buf: &[u8]) -> Result<(), ()> {
+        self.ram.write_blocks(block, buf)
+    }
+
+    fn flush(&self) -> Result<(), ()> {
+        self.ram.flush()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.ram.block_count()
+    }
+}
+
+//a read the disk fails is an io error, not a missing key
+fn test_tx_read_error() {
+    let device = BrokenDisk::default();
+    let kv = TxKVStorePersist::<String, String, PersistentBTree<String, String, BrokenDisk>>::with_disk(Disk::with_device(device.clone()), 32, 32, CCMode::WaitDie).unwrap();
+    kv.transact(|tx| tx.write(&String::from("kernel_name"), &String::from("kvos")), true).unwrap();
+    device.broken.store(true, Ordering::SeqCst);
+    assert_eq!(kv.transact(|tx| tx.read(&String::from("kernel_name")), false), Err(TxError::IoError));
+    device.broken.store(false, Ordering::SeqCst);
+    assert_eq!(kv.transact(|tx| tx.read(&String::from("kernel_name")), false), Ok(Some(String::from("kvos"))));
+}
+
+fn test_transact_retry_policy() {
+    let kv = TxKVStore::<String, String>::new(32, 32);
+    kv.set_retry_policy(RetryPolicy::new(3, 0, 0));
+
+    // An older transaction holds the lock so every attempt dies
+    let mut holder = kv.begin();
+    holder.write(&String::from("a"), &String::from("old")).unwrap();
+    let mut attempts = 0;
+    let result = kv.transact_mut(&mut |tx| {
+        attempts += 1;
+        tx.write(&String::from("a"), &String::from("new"))
+    }, false);
+    assert_eq!(result, Err(TxError::Aborted(AbortReason::WaitDie)));
+    assert_eq!(attempts, 3);
+
+    // Once the lock is free the value of the closure is returned
+    holder.try_commit().unwrap();
+    let value = kv.transact(|tx| tx.read(&String::from("a")), false);
+    assert_eq!(value, Ok(Some(String::from("old"))));
+}
+
+fn scanned_keys(page: Vec<(String, String)>) -> Vec<String> {
+    page.into_iter().map(|(k, _)| k).collect()
+}
+
+fn test_tx_scan() {
+    let kv = TxKVStore::<String, String>::new(32, 32);
+    kv.transact(|tx| {
+        for key in ["user/1", "user/2", "user/3", "zzz"] {
+            tx.write(&String::from(key), &String::from("value"))?;
+        }
+        Ok(())
+    }, false).unwrap();
+
+    kv.transact(|tx| {
+        let page = tx.scan(&String::from("user/"), Some(&String::from("user/3")), 10)?;
+        assert_eq!(scanned_keys(page), ["user/1", "user/2"]);
+
+        // Paging with a limit
+        let page = tx.scan(&String::from("user/2"), None, 2)?;
+        assert_eq!(scanned_keys(page), ["user/2", "user/3"]);
+
+        // Our own writes are visible and our own deletes are hidden
+        tx.write(&String::from("user/15"), &String::from("value"))?;
+        tx.delete(&String::from("user/2"))?;
+        let page = tx.scan(&String::from("user/"), None, 3)?;
+        assert_eq!(scanned_keys(page), ["user/1", "user/15", "user/3"]);
+        Ok(())
+    }, false).unwrap();
+}
+
+fn test_tx_scan_prefix() {
+    let kv = TxKVStore::<String, String>::new(32, 32);
+    kv.transact(|tx| {
+        for key in ["user/4", "user/42/a", "user/42/b", "user/43"] {
+            tx.write(&String::from(key), &String::from("value"))?;
+        }
+        Ok(())
+    }, false).unwrap();
+
+    kv.transact(|tx| {
+        let page = tx.scan_prefix(&String::from("user/42/"), 10)?;
+        assert_eq!(scanned_keys(page), ["user/42/a", "user/42/b"]);
+        let page = tx.scan_prefix(&String::from("user/5"), 10)?;
+        assert!(page.is_empty());
+        Ok(())
+    }, false).unwrap();
+}
+
+fn test_tx_scan_blocks_phantom() {
+    let kv = TxKVStore::<String, String>::new(32, 1024);
+    kv.transact(|tx| {
+        tx.write(&String::from("user/1"), &String::from("a"))?;
+        tx.write(&String::from("user/3"), &String::from("c"))
+    }, false).unwrap();
+
+    let mut scanner = kv.begin();
+    let mut writer = kv.begin();
+    let page = scanner.scan_prefix(&String::from("user/"), 10).unwrap();
+    assert_eq!(page.len(), 2);
+
+    // The younger writer has to lock user/3 to insert before it, so it dies
+    let died = Err(TxError::Aborted(AbortReason::WaitDie));
+    assert_eq!(writer.write(&String::from("user/2"), &String::from("b")), died);
+    assert_eq!(writer.try_commit(), died);
+    assert!(scanner.try_commit().is_ok());
 }
 
 fn test_skipmap_insert_get() {
     let map: SkipMap<&str, i32> = SkipMap::new();
 
     // Test insert
-    assert!(map.insert(&"key1", 10));
-    assert!(map.insert(&"key2", 20));
+    assert!(map.insert(&"key1", &10));
+    assert!(map.insert(&"key2", &20));
     assert_eq!(map.get(&"key1"), Some(10));
     assert_eq!(map.get(&"key2"), Some(20));
 
     // Test update
-    assert!(map.insert(&"key1", 20));
+    assert!(map.insert(&"key1", &20));
     assert_eq!(map.get(&"key1"), Some(20));
 }
 
 fn test_skipmap_remove() {
     let map: SkipMap<&str, i32> = SkipMap::new();
-    assert!(map.insert(&"key1", 10));
+    assert!(map.insert(&"key1", &10));
 
     // Test remove existing key
     assert!(map.remove(&"key1"));
@@ -224,6 +460,173 @@ fn test_skipmap_remove() {
     assert!(!map.remove(&"key1"));
 }
 
+fn test_skipmap_string_scan() {
+    let map: SkipMap<String, String> = SkipMap::new();
+    for key in ["c", "a", "d", "b"] {
+        assert!(map.insert(&String::from(key), &String::from(key).repeat(2)));
+    }
+    assert!(map.remove(&String::from("c")));
+
+    let entries = map.scan(&String::from("b"), 10);
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0], (String::from("b"), String::from("bb")));
+    assert_eq!(entries[1], (String::from("d"), String::from("dd")));
+}
+
+fn test_skipmap_reclaims_nodes() {
+    let map: SkipMap<String, String> = SkipMap::new();
+    for i in 0..256 {
+        let key = alloc::format!("key{}", i);
+        map.insert(&key, &key);
+        map.insert(&key, &String::from("updated"));
+        assert!(map.remove(&key));
+    }
+    // Removed nodes and replaced values are freed as the epoch advances
+    assert!(map.pending_reclaim() < 16);
+}
+
+fn test_skipmap_kvstore() {
+    let kv = TxKVStore::<String, String, SkipMap<String, String>>::new(0, 1024);
+    kv.transact(|tx| {
+        tx.write(&String::from("b"), &String::from("2"))?;
+        tx.write(&String::from("a"), &String::from("1"))
+    }, false).unwrap();
+
+    let mut tx = kv.begin();
+    assert_eq!(scanned_keys(tx.scan(&String::from("a"), None, 10).unwrap()), vec!["a", "b"]);
+    assert!(tx.try_commit().is_ok());
+}
+
+fn test_snapshot_reads_are_stable() {
+    let kv = TxKVStore::<String, String>::with_mode(32, 32, CCMode::Snapshot);
+    assert_eq!(kv.mode(), CCMode::Snapshot);
+    kv.transact(|tx| {
+        tx.write(&String::from("a"), &String::from("1"))?;
+        tx.write(&String::from("b"), &String::from("1"))
+    }, false).unwrap();
+
+    let mut reader = kv.begin();
+    assert_eq!(reader.read(&String::from("a")), Ok(Some(String::from("1"))));
+
+    // A writer commits while the reader is running without blocking on it
+    let mut writer = kv.begin();
+    writer.write(&String::from("a"), &String::from("2")).unwrap();
+    writer.delete(&String::from("b")).unwrap();
+    writer.write(&String::from("c"), &String::from("2")).unwrap();
+    assert!(writer.try_commit().is_ok());
+
+    assert_eq!(reader.read(&String::from("a")), Ok(Some(String::from("1"))));
+    assert_eq!(reader.read(&String::from("b")), Ok(Some(String::from("1"))));
+    assert_eq!(scanned_keys(reader.scan(&String::from("a"), None, 10).unwrap()), vec!["a", "b"]);
+    assert!(reader.try_commit().is_ok());
+
+    let mut later = kv.begin();
+    assert_eq!(later.read(&String::from("a")), Ok(Some(String::from("2"))));
+    assert_eq!(later.read(&String::from("b")), Ok(None));
+    assert_eq!(scanned_keys(later.scan(&String::from("a"), None, 10).unwrap()), vec!["a", "c"]);
+    assert!(later.try_commit().is_ok());
+}
+
+fn test_snapshot_first_committer_wins() {
+    let kv = TxKVStore::<String, String>::with_mode(32, 32, CCMode::Snapshot);
+    let mut first = kv.begin();
+    let mut second = kv.begin();
+    first.write(&String::from("a"), &String::from("1")).unwrap();
+    second.write(&String::from("a"), &String::from("2")).unwrap();
+    assert!(first.try_commit().is_ok());
+    assert_eq!(second.try_commit(), Err(TxError::Conflict));
+
+    let mut tx = kv.begin();
+    assert_eq!(tx.read(&String::from("a")), Ok(Some(String::from("1"))));
+    assert!(tx.try_commit().is_ok());
+}
+
+fn test_try_lock_wound_wait() {
+    let table = LockTable::with_policy(10, LockPolicy::WoundWait);
+    assert_eq!(table.try_lock(5, 2), TryLockResult::Success);
+    //younger requester waits instead of dying
+    assert_eq!(table.try_lock(5, 3), TryLockResult::Wait);
+    assert!(!table.is_wounded(2));
+    //older requester wounds the holder
+    assert_eq!(table.try_lock(5, 1), TryLockResult::Wait);
+    assert!(table.is_wounded(2));
+    table.unlock(5);
+    table.forget(2);
+    assert_eq!(table.lock(5, 1), LockResult::Acquired);
+    assert_eq!(table.waiters(5), 0);
+}
+
+fn test_try_lock_shared() {
+    let table = LockTable::new(10);
+    assert_eq!(table.try_lock_mode(5, 2, LockMode::Shared), TryLockResult::Success);
+    assert_eq!(table.try_lock_mode(5, 3, LockMode::Shared), TryLockResult::Success);
+    assert_eq!(table.readers(5), 2);
+    //writers have to be older than every reader to wait
+    assert_eq!(table.try_lock_mode(5, 4, LockMode::Exclusive), TryLockResult::Die);
+    assert_eq!(table.try_lock_mode(5, 1, LockMode::Exclusive), TryLockResult::Wait);
+    assert_eq!(table.try_lock_mode(5, 2, LockMode::Exclusive), TryLockResult::Wait);
+    //the last reader left upgrades
+    table.release(5, 3);
+    assert_eq!(table.try_lock_mode(5, 2, LockMode::Exclusive), TryLockResult::Success);
+    assert_eq!(table.readers(5), 0);
+    assert_eq!(table.try_lock_mode(5, 3, LockMode::Shared), TryLockResult::Die);
+    table.release(5, 2);
+    assert_eq!(table.try_lock_mode(5, 3, LockMode::Shared), TryLockResult::Success);
+}
+
+fn test_lock_times_out() {
+    let mut table = LockTable::new(10);
+    table.set_wait_timeout(1_000_000);
+    assert_eq!(table.lock(5, 2), LockResult::Acquired);
+    assert_eq!(table.lock(5, 3), LockResult::Die);
+    //nobody releases the stripe on this core, the older transaction gives up instead of hanging
+    assert_eq!(table.lock(5, 1), LockResult::TimedOut);
+    assert_eq!(table.waiters(5), 0);
+}
+
+fn test_tx_readers_share() {
+    let kv = TxKVStore::<String, String>::new(32, 32);
+    kv.transact(|tx| tx.write(&String::from("a"), &String::from("1")), false).unwrap();
+    let mut old = kv.begin();
+    let mut young = kv.begin();
+    assert_eq!(old.read(&String::from("a")), Ok(Some(String::from("1"))));
+    assert_eq!(young.read(&String::from("a")), Ok(Some(String::from("1"))));
+    //upgrading while an older reader holds the key still dies
+    assert_eq!(young.write(&String::from("a"), &String::from("2")), Err(TxError::Aborted(AbortReason::WaitDie)));
+    old.write(&String::from("a"), &String::from("3")).unwrap();
+    assert!(old.try_commit().is_ok());
+    assert_eq!(kv.transact(|tx| tx.read(&String::from("a")), false), Ok(Some(String::from("3"))));
+}
+
+fn test_tx_wound_wait() {
+    let kv = TxKVStore::<String, String>::with_mode(32, 32, CCMode::WoundWait);
+    assert_eq!(kv.mode(), CCMode::WoundWait);
+    let mut old = kv.begin();
+    let mut young = kv.begin();
+    young.write(&String::from("a"), &String::from("young")).unwrap();
+    //the young holder cannot run while old waits, so old times out after wounding it
+    assert_eq!(old.write(&String::from("a"), &String::from("old")), Err(TxError::Aborted(AbortReason::LockTimeout)));
+    assert_eq!(young.read(&String::from("a")), Err(TxError::Aborted(AbortReason::Wounded)));
+    assert!(TxError::Aborted(AbortReason::Wounded).is_retryable());
+
+    assert_eq!(kv.transact(|tx| {
+        tx.write(&String::from("a"), &String::from("retried"))?;
+        tx.read(&String::from("a"))
+    }, false), Ok(Some(String::from("retried"))));
+}
+
+//an older transaction dropped while holding a lock must not make younger ones die
+fn test_tx_drop_releases_locks() {
+    let kv = TxKVStore::<String, String>::new(32, 32);
+    let mut old = kv.begin();
+    old.write(&String::from("a"), &String::from("old")).unwrap();
+    drop(old);
+    let mut young = kv.begin();
+    assert_eq!(young.write(&String::from("a"), &String::from("young")), Ok(()));
+    assert!(young.try_commit().is_ok());
+    assert_eq!(kv.transact(|tx| tx.read(&String::from("a")), false), Ok(Some(String::from("young"))));
+}
+
 pub fn run_tests() {
     let tests = [
         KernelTest {
@@ -262,6 +665,10 @@ pub fn run_tests() {
             name : "test_simple_map_with_capacity",
             test_fn : test_simple_map_with_capacity,
         },
+        KernelTest {
+            name : "test_simple_map_scan",
+            test_fn : test_simple_map_scan,
+        },
         KernelTest {
             name : "test_set_insert",
             test_fn : test_set_insert,
@@ -298,6 +705,50 @@ pub fn run_tests() {
             name : "test_tx",
             test_fn : test_tx,
         },
+        KernelTest {
+            name : "test_tx_ram_disk",
+            test_fn : test_tx_ram_disk,
+        },
+        KernelTest {
+            name : "test_tx_ram_disk_snapshot",
+            test_fn : test_tx_ram_disk_snapshot,
+        },
+        KernelTest {
+            name : "test_tx_read_only_persist",
+            test_fn : test_tx_read_only_persist,
+        },
+        KernelTest {
+            name : "test_tx_lsm",
+            test_fn : test_tx_lsm,
+        },
+        KernelTest {
+            name : "test_tx_btree",
+            test_fn : test_tx_btree,
+        },
+        KernelTest {
+            name : "test_tx_btree_ram_disk",
+            test_fn : test_tx_btree_ram_disk,
+        },
+        KernelTest {
+            name : "test_tx_read_error",
+            test_fn : test_tx_read_error,
+        },
+        KernelTest {
+            name : "test_transact_retry_policy",
+            test_fn : test_transact_retry_policy,
+        },
+        KernelTest {
+            name : "test_tx_scan",
+            test_fn : test_tx_scan,
+        },
+        KernelTest {
+            name : "test_tx_scan_prefix",
+            test_fn : test_tx_scan_prefix,
+        },
+        KernelTest {
+            name : "test_tx_scan_blocks_phantom",
+            test_fn : test_tx_scan_blocks_phantom,
+        },
         KernelTest {
             name : "test_skipmap_insert_get",
             test_fn : test_skipmap_insert_get,
@@ -306,6 +757,50 @@ pub fn run_tests() {
             name : "test_skipmap_remove",
             test_fn : test_skipmap_remove,
         },
+        KernelTest {
+            name : "test_skipmap_string_scan",
+            test_fn : test_skipmap_string_scan,
+        },
+        KernelTest {
+            name : "test_skipmap_reclaims_nodes",
+            test_fn : test_skipmap_reclaims_nodes,
+        },
+        KernelTest {
+            name : "test_skipmap_kvstore",
+            test_fn : test_skipmap_kvstore,
+        },
+        KernelTest {
+            name : "test_snapshot_reads_are_stable",
+            test_fn : test_snapshot_reads_are_stable,
+        },
+        KernelTest {
+            name : "test_snapshot_first_committer_wins",
+            test_fn : test_snapshot_first_committer_wins,
+        },
+        KernelTest {
+            name : "test_try_lock_wound_wait",
+            test_fn : test_try_lock_wound_wait,
+        },
+        KernelTest {
+            name : "test_try_lock_shared",
+            test_fn : test_try_lock_shared,
+        },
+        KernelTest {
+            name : "test_lock_times_out",
+            test_fn : test_lock_times_out,
+        },
+        KernelTest {
+            name : "test_tx_readers_share",
+            test_fn : test_tx_readers_share,
+        },
+        KernelTest {
+            name : "test_tx_wound_wait",
+            test_fn : test_tx_wound_wait,
+        },
+        KernelTest {
+            name : "test_tx_drop_releases_locks",
+            test_fn : test_tx_drop_releases_locks,
+        },
     ];
     for t in tests.iter() {
         serial_print!("{}...\t", t.name);
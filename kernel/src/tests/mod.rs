@@ -5,6 +5,9 @@ mod vga_tests;
 mod heap_allocation;
 mod kvstore;
 mod file_system;
+mod pci;
+mod config;
+mod syscall;
 use crate::serial_println;
 use crate::serial_print;
 
@@ -55,6 +58,9 @@ pub fn run_tests() {
     heap_allocation::run_tests();
     kvstore::run_tests();
     file_system::run_tests();
+    pci::run_tests();
+    config::run_tests();
+    syscall::run_tests();
     serial_println!("Success");
 }
 
@@ -0,0 +1,88 @@
+use super::KernelTest;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::pci::{self, Bar, Driver};
+
+/////////////////////////////////////////////////////////////
+//// Tests
+////////////////////////////////////////////////////////////
+
+//the host bridge is always there, i440fx on pc and the q35 one otherwise
+fn pci_host_bridge() {
+    let devices = pci::devices();
+    let host = devices.iter().find(|device| device.address == pci::Address::new(0, 0, 0)).unwrap();
+    assert_eq!((host.class, host.subclass), (0x06, 0x00));
+    assert_eq!(host.vendor_id, 0x8086);
+    for device in devices.iter() {
+        assert_ne!(device.vendor_id, 0xFFFF);
+    }
+}
+
+//the disk controller the tests run on has its registers in a bar
+fn pci_storage_bars() {
+    let devices = pci::devices();
+    let storage = devices.iter().find(|device| device.class == 0x01).unwrap();
+    assert!(storage.bars.iter().flatten().any(|bar| match bar {
+        Bar::Io { size, .. } => *size > 0,
+        Bar::Memory { size, .. } => *size > 0,
+    }));
+    //config reads see the same ids
+    assert_eq!(storage.read_config(0) & 0xFFFF, storage.vendor_id as u32);
+}
+
+static PROBED: AtomicUsize = AtomicUsize::new(0);
+
+fn probe_host_bridge(_device: &pci::Device) -> Result<(), ()> {
+    PROBED.fetch_add(1, Ordering::SeqCst);
+    Ok(())
+}
+
+static HOST_BRIDGE_DRIVER: Driver = Driver {
+    name: "test host bridge",
+    ids: &[(0x8086, 0x1237), (0x8086, 0x29C0)],
+    probe: probe_host_bridge,
+};
+
+static SECOND_HOST_BRIDGE_DRIVER: Driver = Driver {
+    name: "second test host bridge",
+    ids: &[(0x8086, 0x1237), (0x8086, 0x29C0)],
+    probe: probe_host_bridge,
+};
+
+//a device only goes to the first driver that takes it
+fn pci_register_driver() {
+    assert_eq!(pci::register_driver(&HOST_BRIDGE_DRIVER), 1);
+    assert_eq!(PROBED.load(Ordering::SeqCst), 1);
+    assert_eq!(pci::register_driver(&SECOND_HOST_BRIDGE_DRIVER), 0);
+    assert_eq!(PROBED.load(Ordering::SeqCst), 1);
+    let host = pci::devices().into_iter().find(|device| device.address == pci::Address::new(0, 0, 0)).unwrap();
+    assert_eq!(host.driver, Some("test host bridge"));
+    //leave the registry the way the kernel set it up
+    pci::unregister_driver(&SECOND_HOST_BRIDGE_DRIVER);
+    pci::unregister_driver(&HOST_BRIDGE_DRIVER);
+    let host = pci::devices().into_iter().find(|device| device.address == pci::Address::new(0, 0, 0)).unwrap();
+    assert_eq!(host.driver, None);
+    assert_eq!(pci::register_driver(&SECOND_HOST_BRIDGE_DRIVER), 1);
+    pci::unregister_driver(&SECOND_HOST_BRIDGE_DRIVER);
+}
+
+pub fn run_tests() {
+    let tests = [
+        KernelTest {
+            name : "pci_host_bridge",
+            test_fn : pci_host_bridge,
+        },
+        KernelTest {
+            name : "pci_storage_bars",
+            test_fn : pci_storage_bars,
+        },
+        KernelTest {
+            name : "pci_register_driver",
+            test_fn : pci_register_driver,
+        },
+    ];
+    for t in tests.iter() {
+        serial_print!("{}...\t", t.name);
+        (t.test_fn)();
+        serial_print!("[ok]\n");
+    }
+}
@@ -0,0 +1,176 @@
+use super::KernelTest;
+use alloc::vec;
+use crate::syscall::abi::{ByteSlice, ValueSlot, READ_FOUND, READ_NOT_FOUND, READ_TRUNCATED};
+use crate::cc::{AbortReason, TxError};
+use crate::syscall::{dispatcher, numbers, tx};
+use crate::syscall::errors::{decode, encode, from_tx_error, E2BIG, EABORTED, EBADTX, EFAULT, EINVAL, EIO, ENOSYS, ETXLIMIT};
+use crate::syscall::user;
+
+/////////////////////////////////////////////////////////////
+//// Tests
+////////////////////////////////////////////////////////////
+
+fn slot(capacity: usize) -> ValueSlot {
+    ValueSlot { buf: core::ptr::null_mut(), capacity, value_len: 0, read_status: READ_NOT_FOUND }
+}
+
+//test values that fit are handed over whole and missing keys say so
+fn test_value_slot_fill() {
+    let mut s = slot(8);
+    assert_eq!(s.fill(Some(&[1, 0, 2])), &[1, 0, 2]);
+    assert_eq!((s.value_len, s.read_status), (3, READ_FOUND));
+    assert_eq!(s.fill(None), &[]);
+    assert_eq!((s.value_len, s.read_status), (0, READ_NOT_FOUND));
+    assert_eq!(s.fill(Some(&[])), &[]);
+    assert_eq!((s.value_len, s.read_status), (0, READ_FOUND));
+}
+
+//test long values stop at the capacity and report their length
+fn test_value_slot_truncates() {
+    let value: alloc::vec::Vec<u8> = (0..100).collect();
+    let mut s = slot(32);
+    assert_eq!(s.fill(Some(&value)), &value[..32]);
+    assert_eq!((s.value_len, s.read_status), (100, READ_TRUNCATED));
+    //a zero capacity only asks for the length
+    let mut s = slot(0);
+    assert_eq!(s.fill(Some(&value)), &[]);
+    assert_eq!((s.value_len, s.read_status), (100, READ_TRUNCATED));
+}
+
+//test pointers outside user memory are refused instead of followed
+fn test_user_pointers_checked() {
+    let efault = encode(EFAULT);
+    assert_eq!(user::check(0, 1, false).err(), Some(efault));
+    assert_eq!(user::check(0x0000_7fff_ffff_fff0, 0x20, false).err(), Some(efault));
+    assert_eq!(user::check(usize::MAX - 4, 8, false).err(), Some(efault));
+    assert_eq!(user::check(0xffff_8000_0000_0000, 8, false).err(), Some(efault));
+    //nothing to copy is never a fault
+    assert_eq!(user::check(0, 0, true), Ok(()));
+    assert_eq!(user::copy_from_user(0, 0), Ok(vec![]));
+    //kernel memory is mapped but not user accessible
+    let kernel = vec![1u8; 16];
+    assert_eq!(user::copy_from_user(kernel.as_ptr() as usize, kernel.len()).err(), Some(efault));
+    assert_eq!(user::copy_to_user(kernel.as_ptr() as usize, &[0; 4]).err(), Some(efault));
+    assert_eq!(kernel, vec![1u8; 16]);
+}
+
+//test descriptors are checked as well as what they point to
+fn test_user_slices_checked() {
+    let kernel = [ByteSlice { ptr: 8 as *const u8, len: 8 }];
+    assert_eq!(user::read_slices(kernel.as_ptr() as usize, 1), Err(encode(EFAULT)));
+    assert_eq!(user::read_slices(0, 0), Ok(vec![]));
+    assert_eq!(user::read_array::<ByteSlice>(0x1000, usize::MAX / 2).err(), Some(encode(E2BIG)));
+    assert_eq!(user::copy_from_user(0x1000, user::MAX_COPY_IN + 1), Err(encode(E2BIG)));
+}
+
+//test every failure comes back as a negative code in rax
+fn test_syscall_errors() {
+    assert_eq!(dispatcher(0x7f, 0, 0, 0, 0), encode(ENOSYS));
+    let kernel = [ByteSlice { ptr: b"key".as_ptr(), len: 3 }];
+    assert_eq!(dispatcher(numbers::PRINT, 0, 8, 0, 0), encode(EFAULT));
+    assert_eq!(dispatcher(numbers::DELETE_KV, kernel.as_ptr() as usize, 0, 1, 0), encode(EFAULT));
+    assert_eq!(dispatcher(numbers::READ_IN, 0x10, 16, 0, 0), encode(EFAULT));
+    assert_eq!(decode(encode(EIO)), Some(EIO));
+    assert_eq!(decode(3), None);
+    assert_eq!(from_tx_error(TxError::Aborted(AbortReason::LockTimeout)), encode(EABORTED));
+    assert_eq!(from_tx_error(TxError::Finished), encode(EINVAL));
+}
+
+//pids no process has, so the tests have tables of their own
+const TEST_PID: u64 = 1000;
+const OTHER_PID: u64 = 1001;
+
+//test a transaction stays open across calls and its handle closes with commit or abort
+fn test_tx_handles() {
+    let key = b"tx_handles".to_vec();
+    let h = tx::begin(TEST_PID).unwrap();
+    assert_eq!(tx::read(TEST_PID, h, &key), Ok(None));
+    assert_eq!(tx::write(TEST_PID, h, &key, &vec![1, 0, 1]), Ok(0));
+    assert_eq!(tx::read(TEST_PID, h, &key), Ok(Some(vec![1, 0, 1])));
+    assert_eq!(tx::read(OTHER_PID, h, &key), Err(encode(EBADTX)));
+    assert_eq!(tx::commit(TEST_PID, h, false), Ok(0));
+    assert_eq!(tx::commit(TEST_PID, h, false), Err(encode(EBADTX)));
+    //an aborted delete leaves the value
+    let h = tx::begin(TEST_PID).unwrap();
+    assert_eq!(tx::delete(TEST_PID, h, &key), Ok(1));
+    assert_eq!(tx::abort(TEST_PID, h), Ok(0));
+    assert_eq!(tx::read(TEST_PID, h, &key), Err(encode(EBADTX)));
+    let h = tx::begin(TEST_PID).unwrap();
+    assert_eq!(tx::read(TEST_PID, h, &key), Ok(Some(vec![1, 0, 1])));
+    assert_eq!(tx::delete(TEST_PID, h, &key), Ok(1));
+    assert_eq!(tx::commit(TEST_PID, h, false), Ok(0));
+    assert_eq!(tx::open_count(TEST_PID), 0);
+}
+
+//test the transactions a process leaves open are aborted and their locks freed when it exits
+fn test_tx_exit_aborts() {
+    let key = b"tx_exit".to_vec();
+    let h1 = tx::begin(TEST_PID).unwrap();
+    let h2 = tx::begin(TEST_PID).unwrap();
+    assert_ne!(h1, h2);
+    assert_eq!(tx::write(TEST_PID, h1, &key, &vec![1]), Ok(0));
+    assert_eq!(tx::abort_all(TEST_PID), 2);
+    assert_eq!(tx::open_count(TEST_PID), 0);
+    assert_eq!(tx::write(TEST_PID, h1, &key, &vec![1]), Err(encode(EBADTX)));
+    //a younger transaction gets the key without dying on h1's lock and sees nothing was written
+    let h = tx::begin(OTHER_PID).unwrap();
+    assert_eq!(tx::read(OTHER_PID, h, &key), Ok(None));
+    assert_eq!(tx::write(OTHER_PID, h, &key, &vec![3]), Ok(0));
+    assert_eq!(tx::delete(OTHER_PID, h, &key), Ok(1));
+    assert_eq!(tx::commit(OTHER_PID, h, false), Ok(0));
+    assert_eq!(tx::abort_all(OTHER_PID), 0);
+}
+
+//test a process can only have so many transactions open
+fn test_tx_limit() {
+    for _ in 0..tx::MAX_OPEN {
+        assert!(tx::begin(TEST_PID).is_ok());
+    }
+    assert_eq!(tx::begin(TEST_PID), Err(encode(ETXLIMIT)));
+    assert_eq!(tx::abort_all(TEST_PID), tx::MAX_OPEN);
+    let h = tx::begin(TEST_PID).unwrap();
+    assert_eq!(tx::abort(TEST_PID, h), Ok(0));
+    assert_eq!(dispatcher(numbers::TX_ABORT, h, 0, 0, 0), encode(EBADTX));
+}
+
+pub fn run_tests() {
+    let tests = [
+        KernelTest {
+            name : "test_value_slot_fill",
+            test_fn : test_value_slot_fill,
+        },
+        KernelTest {
+            name : "test_value_slot_truncates",
+            test_fn : test_value_slot_truncates,
+        },
+        KernelTest {
+            name : "test_user_pointers_checked",
+            test_fn : test_user_pointers_checked,
+        },
+        KernelTest {
+            name : "test_user_slices_checked",
+            test_fn : test_user_slices_checked,
+        },
+        KernelTest {
+            name : "test_syscall_errors",
+            test_fn : test_syscall_errors,
+        },
+        KernelTest {
+            name : "test_tx_handles",
+            test_fn : test_tx_handles,
+        },
+        KernelTest {
+            name : "test_tx_exit_aborts",
+            test_fn : test_tx_exit_aborts,
+        },
+        KernelTest {
+            name : "test_tx_limit",
+            test_fn : test_tx_limit,
+        },
+    ];
+    for t in tests.iter() {
+        serial_print!("{}...\t", t.name);
+        (t.test_fn)();
+        serial_print!("[ok]\n");
+    }
+}
@@ -7,6 +7,34 @@ use crate::memory::paging::frameallocator::FrameAllocator;
 use crate::memory::paging::entry::EntryFlags;
 use crate::memory::{change_map_memory, map_memory, map_memory_expect};
 use crate::gdt::GDT;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Process id
+pub type Pid = u64;
+
+/// Pid of the init process, the only one there is for now
+pub const INIT_PID: Pid = 1;
+
+//the process in userspace, 0 while there is none
+static CURRENT_PID: AtomicU64 = AtomicU64::new(0);
+
+/// The process that made the syscall being handled
+pub fn current_pid() -> Pid {
+    CURRENT_PID.load(Ordering::SeqCst)
+}
+
+/// End the current process, the transactions it left open are aborted so their locks are free
+pub fn exit_current(code: usize) {
+    let pid = CURRENT_PID.swap(0, Ordering::SeqCst);
+    if pid == 0 {
+        return;
+    }
+    let aborted = crate::syscall::tx::abort_all(pid);
+    println!("Process {} exited with {}", pid, code as isize);
+    if aborted > 0 {
+        serial_infoln!("Aborted {} transactions process {} left open", aborted, pid);
+    }
+}
 
 /// Module loaded by multiboot2 compliant bootloader
 #[derive(Debug)]
@@ -232,6 +260,7 @@ pub fn initproc<A>(boot_info : &BootInformation, frame_allocator : &mut A) -> !
     println!("{:?}", module);
     let exe = module.to_exe_object();
     let user_code = exe.setup(frame_allocator).expect("Successful setup"); 
+    CURRENT_PID.store(INIT_PID, Ordering::SeqCst);
     user_code.switch_to_userspace()
 }
 
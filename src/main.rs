@@ -14,6 +14,12 @@ struct Args {
     gtk: bool,
     #[arg(short, long, action)]
     buzzer: bool,
+    /// Boot a q35 machine, the disks are on its AHCI controller instead of IDE
+    #[arg(short, long, action)]
+    q35: bool,
+    /// Attach the KV disk as a virtio-blk device instead of the second IDE/AHCI disk
+    #[arg(short, long, action)]
+    virtio: bool,
 }
 
 #[test]
@@ -27,13 +33,6 @@ fn test_all() {
     cmd.arg("stdio");
     cmd.arg("-display");
     cmd.arg("none");
-    //ACHI
-    // cmd.arg("-machine");
-    // cmd.arg("q35");
-    // cmd.arg("-drive");
-    // cmd.arg(format!("file={}\\..\\build\\harddrive.raw,index=0,if=ide,format=raw", iso_path));
-    //
-    //
     cmd.arg("-drive");
     cmd.arg("file=disk.img,format=raw");
     cmd.arg("-drive");
@@ -85,13 +84,21 @@ fn main() {
         cmd.arg("int");
     }
 
+    if args.q35 {
+        cmd.args(&["-machine", "q35"]);
+    }
+
     if args.buzzer {
         cmd.args(&["-audiodev","pa,id=speaker", "-machine", "pcspk-audiodev=speaker"]); 
     }
     cmd.arg("-drive");
     cmd.arg("file=disk.img,format=raw");
     cmd.arg("-drive");
-    cmd.arg("file=unit_test_disk.img,format=raw");
+    if args.virtio {
+        cmd.arg("file=unit_test_disk.img,format=raw,if=virtio");
+    } else {
+        cmd.arg("file=unit_test_disk.img,format=raw");
+    }
     // cmd.arg("-monitor");
     // cmd.arg("stdio");
     cmd.arg("-cdrom");
@@ -1,5 +1,4 @@
-use alloc::string::{String, ToString};
-use alloc::vec;
+use alloc::string::ToString;
 
 use crate::println;
 use crate::rand::Random;
@@ -9,9 +8,7 @@ use crate::syscall::{write_kv, read_kv, delete_kv};
 pub fn populate(max_key: i32, pop:i32, rng: &mut Random) {
     for _ in 0..pop {
         let key = rng.get_random(0, max_key as u64).to_string();
-        let key_vec = vec![key];
-        let val_vac = vec![String::from("value")];
-        write_kv(key_vec, val_vac);
+        let _ = write_kv(&[key], &["value"]);
     }
 }
 
@@ -25,19 +22,17 @@ pub fn benchmark(max_key: i32,
     
     for _ in 1..iters {
         let key = rng.get_random(0, max_key as u64).to_string();
-        let key_vec = vec![key];
         let op  = rng.get_random(0, 100 as u64);
 
         if op <= ratio {
             //read
-            read_kv(key_vec);
+            let _ = read_kv(&[&key]);
         } else if op <= ratio + (100-ratio) / 2 {
             //remove
-            delete_kv(key_vec)
+            let _ = delete_kv(&[&key]);
         } else {
             //insert
-            let val_vac = vec![String::from("value")];
-            write_kv(key_vec, val_vac);
+            let _ = write_kv(&[&key], &["value"]);
         }
     }
     let end = asm::rdtsc();
@@ -46,8 +41,7 @@ pub fn benchmark(max_key: i32,
 
 fn clear_kvstore(max_key: i32) {
     for key in 0..max_key {
-        let key_vec = vec![key.to_string()];
-        delete_kv(key_vec);
+        let _ = delete_kv(&[key.to_string()]);
     }
 }
 
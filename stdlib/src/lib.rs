@@ -4,6 +4,7 @@ extern crate alloc;
 extern crate asm;
 
 pub mod syscall;
+pub mod tx;
 pub mod shell;
 pub mod benchmark;
 pub mod rand;
@@ -3,7 +3,6 @@ use crate::print;
 use crate::println;
 
 use alloc::string::ToString;
-use alloc::vec;
 use alloc::vec::Vec;
 use alloc::string::String;
 use syscall::{read_kv, write_kv, delete_kv, read_in};
@@ -14,7 +13,8 @@ fn input(prompt: String) -> String {
     let mut len;
     let mut old_len = 0;
     loop {
-        len = read_in(&mut buf, 256);
+        //the kernel reports the whole line, only what fits in buf was copied
+        len = read_in(&mut buf, 256).unwrap_or(old_len).min(buf.len());
         if len > old_len {
             //check if the last char is \n
             if buf[len-1] == 10 {
@@ -36,17 +36,26 @@ pub fn shell() {
 
         match parts.as_slice() {
             ["read_kv", key] => {
-                let values: Vec<String> = read_kv(vec![key.to_string()]);
-                let val = values.get(0).unwrap();
-                println!("{}: {}", key, val)
+                match read_kv(&[key]) {
+                    Ok(values) => match &values[0] {
+                        Some(value) => println!("{}: {}", key, String::from_utf8_lossy(value)),
+                        None => println!("{} not found", key),
+                    },
+                    Err(e) => println!("read_kv failed: {}", e),
+                }
             },
             ["write_kv", key, val] => {
-                write_kv(vec![key.to_string()], vec![val.to_string()]);
-                println!("Value written");
+                match write_kv(&[key], &[val]) {
+                    Ok(()) => println!("Value written"),
+                    Err(e) => println!("write_kv failed: {}", e),
+                }
             },
             ["delete_kv", key] => {
-                delete_kv(vec![key.to_string()]);
-                println!("Value deleted");
+                match delete_kv(&[key]) {
+                    Ok(0) => println!("{} not found", key),
+                    Ok(_) => println!("Value deleted"),
+                    Err(e) => println!("delete_kv failed: {}", e),
+                }
             }
             ["echo", val] => {
                 println!("{}", val);
@@ -59,6 +68,7 @@ pub fn shell() {
                 println!("Commands:");
                 println!("read_kv <key>");
                 println!("write_kv <key> <value>");
+                println!("delete_kv <key>");
                 println!("exit");
             },
             _ => println!("Unknown command"),
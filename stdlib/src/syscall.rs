@@ -1,71 +1,291 @@
 use core::arch::asm;
-use alloc::{vec::Vec, string::String};
+use core::fmt;
+use alloc::vec;
+use alloc::vec::Vec;
 
-pub fn print(ptr: *const u8, len :usize) {
-    unsafe{syscall2(0, ptr as usize, len)};
- }
+//error codes of the syscalls, the kernel returns them negated in rax
+pub const EABORTED: usize = 1;
+pub const ECONFLICT: usize = 2;
+pub const EIO: usize = 3;
+pub const EINVAL: usize = 4;
+pub const EFAULT: usize = 5;
+pub const ENOSYS: usize = 6;
+pub const E2BIG: usize = 7;
+pub const EBADTX: usize = 8;
+pub const ETXLIMIT: usize = 9;
+
+//why a syscall failed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KvError {
+    //the transaction was killed by the deadlock policy or timed out waiting for a lock
+    Aborted,
+    //another transaction committed a write to the same key first
+    Conflict,
+    Io,
+    InvalidArgument,
+    //a pointer the kernel could not read or write
+    BadPointer,
+    NoSuchSyscall,
+    //get found no value, the kernel has no code for it since the reads report a missing key
+    //in read_status
+    NotFound,
+    //more bytes than the kernel copies in one syscall
+    TooBig,
+    //no open transaction has the handle
+    BadTransaction,
+    //the process has as many transactions open as it may
+    TooManyTransactions,
+    //a code this stdlib does not know
+    Unknown(usize),
+}
+
+impl KvError {
+    pub fn from_code(code: usize) -> Self {
+        match code {
+            EABORTED => KvError::Aborted,
+            ECONFLICT => KvError::Conflict,
+            EIO => KvError::Io,
+            EINVAL => KvError::InvalidArgument,
+            EFAULT => KvError::BadPointer,
+            ENOSYS => KvError::NoSuchSyscall,
+            E2BIG => KvError::TooBig,
+            EBADTX => KvError::BadTransaction,
+            ETXLIMIT => KvError::TooManyTransactions,
+            code => KvError::Unknown(code),
+        }
+    }
+
+    //running the same transaction again may work
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, KvError::Aborted | KvError::Conflict)
+    }
+}
+
+impl fmt::Display for KvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KvError::Aborted => write!(f, "transaction aborted"),
+            KvError::Conflict => write!(f, "conflict with another transaction"),
+            KvError::Io => write!(f, "disk error"),
+            KvError::InvalidArgument => write!(f, "invalid argument"),
+            KvError::BadPointer => write!(f, "bad pointer"),
+            KvError::NoSuchSyscall => write!(f, "no such syscall"),
+            KvError::NotFound => write!(f, "key not found"),
+            KvError::TooBig => write!(f, "arguments too large"),
+            KvError::BadTransaction => write!(f, "no such transaction"),
+            KvError::TooManyTransactions => write!(f, "too many open transactions"),
+            KvError::Unknown(code) => write!(f, "unknown error {}", code),
+        }
+    }
+}
+
+fn check(res: usize) -> Result<usize, KvError> {
+    if (res as isize) < 0 {
+        Err(KvError::from_code(res.wrapping_neg()))
+    } else {
+        Ok(res)
+    }
+}
+
+pub fn print(ptr: *const u8, len :usize) -> Result<(), KvError> {
+    check(unsafe { syscall2(0, ptr as usize, len) }).map(|_| ())
+}
  
-pub fn read_kv(keys: Vec<String>) -> Vec<String> {
-    // make key ptr
-    let len = keys.len();
-    let keys_ptr = keys.as_ptr() as usize;
-    
-    // make val ptr
-    let mut vals = Vec::<[u8; 32]>::with_capacity(len);
-    for _ in 0..len {
-        vals.insert(0, ['\0' as u8; 32]);
+//len bytes at ptr, how keys and values are passed to the kernel, which copies them
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct ByteSlice {
+    pub ptr: *const u8,
+    pub len: usize,
+}
+
+impl ByteSlice {
+    pub fn new(bytes: &[u8]) -> Self {
+        Self { ptr: bytes.as_ptr(), len: bytes.len() }
     }
-    let vals_ptr = vals.as_mut_ptr() as usize;
-    
-    // cal syscall 
-    unsafe { syscall3(1, keys_ptr as usize, vals_ptr, len) };
+}
+
+//descriptors pointing into items, items has to live until the syscall returns
+fn to_slices<T: AsRef<[u8]>>(items: &[T]) -> Vec<ByteSlice> {
+    items.iter().map(|item| ByteSlice::new(item.as_ref())).collect()
+}
+
+//read_status of a ValueSlot
+pub const READ_FOUND: u64 = 0;
+pub const READ_NOT_FOUND: u64 = 1;
+pub const READ_TRUNCATED: u64 = 2;
+
+//bytes read_kv gives every value on the first try
+pub(crate) const READ_CAPACITY: usize = 64;
 
-    // convert vals to Vec<String>
-    let mut res = Vec::<String>::with_capacity(len);
-    for i in 0..len {
-        res.push(String::from_utf8(vals.get(i).unwrap().to_vec()).unwrap());
-        res[i] = String::from(res[i].trim_matches(char::from(0)));
+//one per key of READ_KV, the kernel copies up to capacity bytes into buf and sets value_len to
+//the whole length of the value
+#[repr(C)]
+pub struct ValueSlot {
+    pub buf: *mut u8,
+    pub capacity: usize,
+    pub value_len: usize,
+    pub read_status: u64,
+}
+
+//reads keys in one transaction into bufs, one per key
+fn read_slots<K: AsRef<[u8]>>(keys: &[K], bufs: &mut [Vec<u8>]) -> Result<Vec<ValueSlot>, KvError> {
+    let keys = to_slices(keys);
+    let mut slots: Vec<ValueSlot> = bufs.iter_mut().map(|buf| ValueSlot {
+        buf: buf.as_mut_ptr(),
+        capacity: buf.len(),
+        value_len: 0,
+        read_status: READ_NOT_FOUND,
+    }).collect();
+    check(unsafe { syscall3(1, keys.as_ptr() as usize, slots.as_mut_ptr() as usize, keys.len()) })?;
+    Ok(slots)
+}
+
+//the values of keys, None for a key that is not there. values too long for the first read
+//are read again with room for them, all keys every time so they come from one transaction
+pub fn read_kv<K: AsRef<[u8]>>(keys: &[K]) -> Result<Vec<Option<Vec<u8>>>, KvError> {
+    let mut bufs: Vec<Vec<u8>> = keys.iter().map(|_| vec![0u8; READ_CAPACITY]).collect();
+    loop {
+        let slots = read_slots(keys, &mut bufs)?;
+        if slots.iter().all(|slot| slot.read_status != READ_TRUNCATED) {
+            let values = slots.iter().zip(bufs.into_iter()).map(|(slot, mut buf)| {
+                if slot.read_status == READ_NOT_FOUND {
+                    return None;
+                }
+                buf.truncate(slot.value_len);
+                Some(buf)
+            }).collect();
+            return Ok(values);
+        }
+        for (slot, buf) in slots.iter().zip(bufs.iter_mut()) {
+            buf.resize(slot.value_len.max(buf.len()), 0);
+        }
     }
-    res
 }
 
-pub fn write_kv(keys: Vec<String>, values: Vec<String>) {
+//reads the value of key into buf without allocating, returns the whole length of the value,
+//more than buf.len() if only the start of it fit, or None if the key is not there.
+//an empty buf only asks for the length
+pub fn read_kv_into<K: AsRef<[u8]>>(key: K, buf: &mut [u8]) -> Result<Option<usize>, KvError> {
+    let keys = [ByteSlice::new(key.as_ref())];
+    let mut slot = ValueSlot {
+        buf: buf.as_mut_ptr(),
+        capacity: buf.len(),
+        value_len: 0,
+        read_status: READ_NOT_FOUND,
+    };
+    check(unsafe { syscall3(1, keys.as_ptr() as usize, &mut slot as *mut ValueSlot as usize, 1) })?;
+    match slot.read_status {
+        READ_NOT_FOUND => Ok(None),
+        _ => Ok(Some(slot.value_len)),
+    }
+}
+
+//the value of key, NotFound if it is not there
+pub fn get<K: AsRef<[u8]>>(key: K) -> Result<Vec<u8>, KvError> {
+    read_kv(&[key])?.pop().flatten().ok_or(KvError::NotFound)
+}
+
+pub fn write_kv<K: AsRef<[u8]>, V: AsRef<[u8]>>(keys: &[K], values: &[V]) -> Result<(), KvError> {
+    if keys.len() != values.len() {
+        return Err(KvError::InvalidArgument);
+    }
     // make key ptr
+    let keys = to_slices(keys);
     let len = keys.len();
     let keys_ptr = keys.as_ptr() as usize;
     
     // make val ptr
+    let values = to_slices(values);
     let vals_ptr = values.as_ptr() as usize;
     
     // cal syscall 
-    unsafe { syscall3(2, keys_ptr as usize, vals_ptr, len) };
+    check(unsafe { syscall3(2, keys_ptr as usize, vals_ptr, len) }).map(|_| ())
 }
 
-pub fn delete_kv(keys: Vec<String>) {
+//returns how many of the keys were there
+pub fn delete_kv<K: AsRef<[u8]>>(keys: &[K]) -> Result<usize, KvError> {
     // make key ptr
+    let keys = to_slices(keys);
     let len = keys.len();
     let keys_ptr = keys.as_ptr() as usize;
     
     // cal syscall 
-    unsafe { syscall2(3, keys_ptr as usize, len) };
+    check(unsafe { syscall3(3, keys_ptr as usize, 0, len) })
 }
 
 
-pub fn write_kv_persist(keys: Vec<String>, values: Vec<String>) {
+pub fn write_kv_persist<K: AsRef<[u8]>, V: AsRef<[u8]>>(keys: &[K], values: &[V]) -> Result<(), KvError> {
+    if keys.len() != values.len() {
+        return Err(KvError::InvalidArgument);
+    }
     // make key ptr
+    let keys = to_slices(keys);
     let len = keys.len();
     let keys_ptr = keys.as_ptr() as usize;
     
     // make val ptr
+    let values = to_slices(values);
     let vals_ptr = values.as_ptr() as usize;
     
     // cal syscall 
-    unsafe { syscall3(5, keys_ptr as usize, vals_ptr, len) };
+    check(unsafe { syscall3(5, keys_ptr as usize, vals_ptr, len) }).map(|_| ())
+}
+
+
+//raw transaction syscalls, stdlib::tx wraps them
+
+pub fn tx_begin() -> Result<usize, KvError> {
+    check(unsafe { syscall0(6) })
+}
+
+//reads the value of key in the transaction like read_kv_into
+pub fn tx_read_into<K: AsRef<[u8]>>(handle: usize, key: K, buf: &mut [u8]) -> Result<Option<usize>, KvError> {
+    let key = ByteSlice::new(key.as_ref());
+    let mut slot = ValueSlot {
+        buf: buf.as_mut_ptr(),
+        capacity: buf.len(),
+        value_len: 0,
+        read_status: READ_NOT_FOUND,
+    };
+    check(unsafe { syscall3(7, handle, &key as *const ByteSlice as usize, &mut slot as *mut ValueSlot as usize) })?;
+    match slot.read_status {
+        READ_NOT_FOUND => Ok(None),
+        _ => Ok(Some(slot.value_len)),
+    }
+}
+
+pub fn tx_write<K: AsRef<[u8]>, V: AsRef<[u8]>>(handle: usize, key: K, value: V) -> Result<(), KvError> {
+    let key = ByteSlice::new(key.as_ref());
+    let value = ByteSlice::new(value.as_ref());
+    check(unsafe { syscall3(8, handle, &key as *const ByteSlice as usize, &value as *const ByteSlice as usize) }).map(|_| ())
 }
 
+//whether key was there
+pub fn tx_delete<K: AsRef<[u8]>>(handle: usize, key: K) -> Result<bool, KvError> {
+    let key = ByteSlice::new(key.as_ref());
+    check(unsafe { syscall2(9, handle, &key as *const ByteSlice as usize) }).map(|deleted| deleted != 0)
+}
+
+//the handle is closed whether the commit worked or not
+pub fn tx_commit(handle: usize, persist: bool) -> Result<(), KvError> {
+    check(unsafe { syscall2(10, handle, persist as usize) }).map(|_| ())
+}
+
+pub fn tx_abort(handle: usize) -> Result<(), KvError> {
+    check(unsafe { syscall1(11, handle) }).map(|_| ())
+}
+
+//ends the process, the kernel aborts the transactions it has open
+pub fn exit(code: usize) -> ! {
+    unsafe { syscall1(12, code) };
+    //the kernel never returns to a process that exited
+    loop {}
+}
 
-pub fn read_in(s: &mut [u8], len: usize) -> usize {
-    unsafe { syscall2(4, s.as_mut_ptr() as usize, len) }
+//the length of the line typed so far, up to len bytes of it are copied to s
+pub fn read_in(s: &mut [u8], len: usize) -> Result<usize, KvError> {
+    check(unsafe { syscall2(4, s.as_mut_ptr() as usize, len.min(s.len())) })
 }
 
 
@@ -0,0 +1,109 @@
+//!
+//! Transactions that stay open across syscalls, for read-modify-write
+//!
+use alloc::vec;
+use alloc::vec::Vec;
+use crate::syscall::{self, KvError, READ_CAPACITY};
+
+//attempts transact makes before it gives up, the same as the kernel's retry policy
+pub const MAX_ATTEMPTS: u32 = 16;
+
+//a transaction of the kernel store, aborted when dropped without commit
+pub struct Transaction {
+    handle: usize,
+    open: bool,
+}
+
+impl Transaction {
+    pub fn begin() -> Result<Self, KvError> {
+        Ok(Self { handle: syscall::tx_begin()?, open: true })
+    }
+
+    //the value of key, None if it is not there
+    pub fn read<K: AsRef<[u8]>>(&mut self, key: K) -> Result<Option<Vec<u8>>, KvError> {
+        let mut buf = vec![0u8; READ_CAPACITY];
+        loop {
+            match syscall::tx_read_into(self.handle, key.as_ref(), &mut buf)? {
+                None => return Ok(None),
+                Some(len) if len <= buf.len() => {
+                    buf.truncate(len);
+                    return Ok(Some(buf));
+                },
+                //the transaction holds the key so the second read fits
+                Some(len) => buf.resize(len, 0),
+            }
+        }
+    }
+
+    pub fn write<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, value: V) -> Result<(), KvError> {
+        syscall::tx_write(self.handle, key, value)
+    }
+
+    //whether key was there
+    pub fn delete<K: AsRef<[u8]>>(&mut self, key: K) -> Result<bool, KvError> {
+        syscall::tx_delete(self.handle, key)
+    }
+
+    pub fn commit(mut self) -> Result<(), KvError> {
+        self.open = false;
+        syscall::tx_commit(self.handle, false)
+    }
+
+    //commits and logs the writes to disk before returning
+    pub fn commit_persist(mut self) -> Result<(), KvError> {
+        self.open = false;
+        syscall::tx_commit(self.handle, true)
+    }
+
+    pub fn abort(mut self) -> Result<(), KvError> {
+        self.open = false;
+        syscall::tx_abort(self.handle)
+    }
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        if self.open {
+            let _ = syscall::tx_abort(self.handle);
+        }
+    }
+}
+
+//runs f in a transaction and commits it. aborts and conflicts start over with a new
+//transaction, up to MAX_ATTEMPTS times, any other error of f or the commit is returned
+pub fn transact<R, F>(f: F) -> Result<R, KvError>
+where
+    F: FnMut(&mut Transaction) -> Result<R, KvError>,
+{
+    transact_with(f, false)
+}
+
+//transact with the commit logged to disk
+pub fn transact_persist<R, F>(f: F) -> Result<R, KvError>
+where
+    F: FnMut(&mut Transaction) -> Result<R, KvError>,
+{
+    transact_with(f, true)
+}
+
+fn transact_with<R, F>(mut f: F, persist: bool) -> Result<R, KvError>
+where
+    F: FnMut(&mut Transaction) -> Result<R, KvError>,
+{
+    let mut attempts = 0;
+    loop {
+        let mut tx = Transaction::begin()?;
+        let result = f(&mut tx).and_then(|r| {
+            let committed = if persist { tx.commit_persist() } else { tx.commit() };
+            committed.map(|_| r)
+        });
+        let e = match result {
+            Ok(r) => return Ok(r),
+            Err(e) => e,
+        };
+        attempts += 1;
+        if !e.is_retryable() || attempts >= MAX_ATTEMPTS {
+            return Err(e);
+        }
+    }
+}